@@ -0,0 +1,52 @@
+// https://github.com/GWBasic/sync-tokens
+// (c) Andrew Rondeau
+// Apache 2.0 license
+// See https://github.com/GWBasic/sync-tokens/blob/main/LICENSE
+
+// Compares a Coordinator against a separate CancelationToken and CompletionToken per operation
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use futures::executor::block_on;
+use sync_tokens::cancelation_token::CancelationToken;
+use sync_tokens::completion_token::CompletionToken;
+use sync_tokens::coordinator::Coordinator;
+
+fn coordinator_operations(n: usize) {
+	for i in 0..n {
+		let coordinator = Coordinator::new();
+		let (cancel_token, cancelable) = (coordinator.cancel_token(), coordinator.cancelable());
+		let (completion_token, completable) = (coordinator.completion_token(), coordinator.completable());
+
+		completable.complete(i);
+		cancel_token.cancel();
+
+		assert!(cancelable.is_canceled());
+		assert_eq!(block_on(completion_token), i);
+	}
+}
+
+fn separate_tokens_operations(n: usize) {
+	for i in 0..n {
+		let (cancelation_token, cancelable) = CancelationToken::new();
+		let (completion_token, completable) = CompletionToken::new();
+
+		completable.complete(i);
+		cancelation_token.cancel();
+
+		assert!(cancelable.is_canceled());
+		assert_eq!(block_on(completion_token), i);
+	}
+}
+
+fn bench_operations(c: &mut Criterion) {
+	let mut group = c.benchmark_group("operations");
+
+	for n in [100, 1000, 10000].iter() {
+		group.bench_with_input(BenchmarkId::new("coordinator", n), n, |b, n| b.iter(|| coordinator_operations(*n)));
+		group.bench_with_input(BenchmarkId::new("separate_tokens", n), n, |b, n| b.iter(|| separate_tokens_operations(*n)));
+	}
+
+	group.finish();
+}
+
+criterion_group!(benches, bench_operations);
+criterion_main!(benches);
@@ -0,0 +1,100 @@
+// https://github.com/GWBasic/sync-tokens
+// (c) Andrew Rondeau
+// Apache 2.0 license
+// See https://github.com/GWBasic/sync-tokens/blob/main/LICENSE
+
+// Compares NotifyCancelationToken, which wakes with tokio's Notify, with the Mutex-backed CancelationToken: polling a
+// future that isn't canceled, and canceling with waiting futures. Requires the tokio feature:
+// cargo bench --bench notify_cancelation --features tokio
+//
+// Notify doesn't come out ahead. On one machine, poll_uncanceled took 2.20 µs, 20.3 µs and 231 µs with the Mutex, and
+// 2.45 µs, 25.5 µs and 255 µs with Notify, for 100, 1000 and 10000 polls. cancel took 314 ns, 6.46 µs and 557 µs with
+// the Mutex, and 250 ns, 12.9 µs and 1.40 ms with Notify, for 1, 100 and 10000 waiters; Notify is only faster with a
+// single waiter. The Notify futures are boxed, because they're async blocks, which is included in cancel's numbers
+use std::future::Future;
+use std::pin::Pin;
+use std::task::Context;
+
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use futures::task::noop_waker_ref;
+use sync_tokens::cancelation_token::CancelationToken;
+use sync_tokens::notify_cancelation_token::NotifyCancelationToken;
+
+// Polls the same future n times with the same waker, as an executor does when the task is woken for other reasons
+fn poll_uncanceled_mutex(n: usize) {
+	let (_cancelation_token, cancelable) = CancelationToken::new();
+	let mut future = cancelable.future();
+	let mut cx = Context::from_waker(noop_waker_ref());
+
+	for _ in 0..n {
+		assert!(Pin::new(&mut future).poll(&mut cx).is_pending());
+	}
+}
+
+fn poll_uncanceled_notify(n: usize) {
+	let (_notify_cancelation_token, notify_cancelable) = NotifyCancelationToken::new();
+	let mut future = Box::pin(notify_cancelable.future());
+	let mut cx = Context::from_waker(noop_waker_ref());
+
+	for _ in 0..n {
+		assert!(future.as_mut().poll(&mut cx).is_pending());
+	}
+}
+
+// Waits on the token with waiters futures, cancels it, and then polls and drops them
+fn cancel_with_waiters_mutex(waiters: usize) {
+	let (cancelation_token, cancelable) = CancelationToken::new();
+	let mut futures: Vec<_> = (0..waiters).map(|_| cancelable.future()).collect();
+	let mut cx = Context::from_waker(noop_waker_ref());
+
+	for future in futures.iter_mut() {
+		assert!(Pin::new(future).poll(&mut cx).is_pending());
+	}
+
+	cancelation_token.cancel();
+
+	for future in futures.iter_mut() {
+		assert!(Pin::new(future).poll(&mut cx).is_ready());
+	}
+}
+
+fn cancel_with_waiters_notify(waiters: usize) {
+	let (notify_cancelation_token, notify_cancelable) = NotifyCancelationToken::new();
+	let mut futures: Vec<_> = (0..waiters).map(|_| Box::pin(notify_cancelable.future())).collect();
+	let mut cx = Context::from_waker(noop_waker_ref());
+
+	for future in futures.iter_mut() {
+		assert!(future.as_mut().poll(&mut cx).is_pending());
+	}
+
+	notify_cancelation_token.cancel();
+
+	for future in futures.iter_mut() {
+		assert!(future.as_mut().poll(&mut cx).is_ready());
+	}
+}
+
+fn bench_poll(c: &mut Criterion) {
+	let mut group = c.benchmark_group("poll_uncanceled");
+
+	for n in [100, 1000, 10000].iter() {
+		group.bench_with_input(BenchmarkId::new("mutex", n), n, |b, n| b.iter(|| poll_uncanceled_mutex(*n)));
+		group.bench_with_input(BenchmarkId::new("notify", n), n, |b, n| b.iter(|| poll_uncanceled_notify(*n)));
+	}
+
+	group.finish();
+}
+
+fn bench_cancel(c: &mut Criterion) {
+	let mut group = c.benchmark_group("cancel");
+
+	for waiters in [1, 100, 10000].iter() {
+		group.bench_with_input(BenchmarkId::new("mutex", waiters), waiters, |b, waiters| b.iter(|| cancel_with_waiters_mutex(*waiters)));
+		group.bench_with_input(BenchmarkId::new("notify", waiters), waiters, |b, waiters| b.iter(|| cancel_with_waiters_notify(*waiters)));
+	}
+
+	group.finish();
+}
+
+criterion_group!(benches, bench_poll, bench_cancel);
+criterion_main!(benches);
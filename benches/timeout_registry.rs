@@ -0,0 +1,92 @@
+// https://github.com/GWBasic/sync-tokens
+// (c) Andrew Rondeau
+// Apache 2.0 license
+// See https://github.com/GWBasic/sync-tokens/blob/main/LICENSE
+
+// Compares a TimeoutRegistry against one timer per scheduled cancelation
+use std::time::Duration;
+
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use futures::executor::block_on;
+use futures::future::{join_all, select};
+use sync_tokens::cancelation_token::CancelationToken;
+use sync_tokens::timeout_registry::TimeoutRegistry;
+use sync_tokens::timer::Timer;
+
+const DEADLINE: Duration = Duration::from_millis(1);
+
+fn registry_fires(n: usize) {
+	let timeout_registry = TimeoutRegistry::new();
+
+	let (schedule_handles, cancelables): (Vec<_>, Vec<_>) = (0..n).map(|_| {
+		let (cancelation_token, cancelable) = CancelationToken::new();
+		(timeout_registry.schedule_after(cancelation_token, DEADLINE), cancelable)
+	}).unzip();
+
+	let canceled = join_all(cancelables.iter().map(|cancelable| cancelable.future()));
+	block_on(select(timeout_registry.driver(), canceled));
+
+	drop(schedule_handles);
+}
+
+fn independent_timers_fire(n: usize) {
+	let timer = Timer::default();
+
+	let (timers, cancelables): (Vec<_>, Vec<_>) = (0..n).map(|_| {
+		let (cancelation_token, cancelable) = CancelationToken::new();
+		let sleep = timer.sleep(DEADLINE);
+
+		let timer = async move {
+			sleep.await;
+			cancelation_token.cancel();
+		};
+
+		(timer, cancelable)
+	}).unzip();
+
+	block_on(join_all(timers));
+	assert!(cancelables.iter().all(|cancelable| cancelable.is_canceled()));
+}
+
+fn registry_deschedules(n: usize) {
+	let timeout_registry = TimeoutRegistry::new();
+
+	for _ in 0..n {
+		let (cancelation_token, _cancelable) = CancelationToken::new();
+		drop(timeout_registry.schedule_after(cancelation_token, Duration::from_secs(60)));
+	}
+}
+
+fn independent_timers_deschedule(n: usize) {
+	let timer = Timer::default();
+
+	for _ in 0..n {
+		let (_cancelation_token, _cancelable) = CancelationToken::new();
+		drop(timer.sleep(Duration::from_secs(60)));
+	}
+}
+
+fn bench_fire(c: &mut Criterion) {
+	let mut group = c.benchmark_group("fire");
+
+	for n in [100, 1000, 10000].iter() {
+		group.bench_with_input(BenchmarkId::new("timeout_registry", n), n, |b, n| b.iter(|| registry_fires(*n)));
+		group.bench_with_input(BenchmarkId::new("independent_timers", n), n, |b, n| b.iter(|| independent_timers_fire(*n)));
+	}
+
+	group.finish();
+}
+
+fn bench_deschedule(c: &mut Criterion) {
+	let mut group = c.benchmark_group("deschedule");
+
+	for n in [100, 1000, 10000].iter() {
+		group.bench_with_input(BenchmarkId::new("timeout_registry", n), n, |b, n| b.iter(|| registry_deschedules(*n)));
+		group.bench_with_input(BenchmarkId::new("independent_timers", n), n, |b, n| b.iter(|| independent_timers_deschedule(*n)));
+	}
+
+	group.finish();
+}
+
+criterion_group!(benches, bench_fire, bench_deschedule);
+criterion_main!(benches);
@@ -0,0 +1,360 @@
+// https://github.com/GWBasic/sync-tokens
+// (c) Andrew Rondeau
+// Apache 2.0 license
+// See https://github.com/GWBasic/sync-tokens/blob/main/LICENSE
+
+//! Contains conveniences for spawning cancelable tasks on async-std. Requires the `async-std` feature. See
+//! [`spawn_cancelable()`](fn.spawn_cancelable.html), [`bind_to()`](fn.bind_to.html),
+//! [`spawn_coordinated()`](fn.spawn_coordinated.html), [`spawn_task()`](fn.spawn_task.html) and
+//! [`CancelableJoinHandle`](struct.CancelableJoinHandle.html). A
+//! [`Timer`](../timer/struct.Timer.html) that uses async-std's timer is created with
+//! [`Timer::async_std()`](../timer/struct.Timer.html#method.async_std)
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::panic::AssertUnwindSafe;
+use std::task::{Context, Poll};
+
+use async_std::task::JoinHandle;
+use futures::FutureExt;
+use futures::future::{Either, select};
+
+use crate::cancelation_token::{Cancelable, CancelationToken};
+use crate::completion_token::{Completable, CompletionToken};
+use crate::task_handle::{FinishedGuard, run_worker};
+
+/// Spawns future on async-std, so that it stops when cancelable is canceled. The task returns canceled_result when
+/// canceled
+///
+/// ```
+/// use sync_tokens::async_std_runtime::spawn_cancelable;
+/// use sync_tokens::cancelation_token::CancelationToken;
+///
+/// # async_std::task::block_on(async {
+/// let (cancelation_token, cancelable) = CancelationToken::new();
+///
+/// let join_handle = spawn_cancelable(&cancelable, futures::future::pending(), "canceled");
+/// cancelation_token.cancel();
+///
+/// assert_eq!(join_handle.await, "canceled");
+/// # });
+/// ```
+pub fn spawn_cancelable<F, T>(cancelable: &Cancelable, future: F, canceled_result: T) -> JoinHandle<T> where
+F: Future<Output = T> + Send + 'static,
+T: Send + 'static {
+	async_std::task::spawn(cancelable.allow_cancel(Box::pin(future), canceled_result))
+}
+
+/// Cancels an already spawned task when cancelable is canceled. Returns a handle that finishes when the task finishes,
+/// with its result, or once it's canceled, with None
+pub fn bind_to<T>(cancelable: &Cancelable, join_handle: JoinHandle<T>) -> JoinHandle<Option<T>> where
+T: Send + 'static {
+	let canceled = cancelable.future();
+
+	async_std::task::spawn(async move {
+		match select(join_handle, canceled).await {
+			Either::Left((result, _)) => Some(result),
+			Either::Right((_, join_handle)) => join_handle.cancel().await
+		}
+	})
+}
+
+/// Spawns a worker on async-std, with a [`Completable`](../completion_token/struct.Completable.html) to signal when
+/// it's ready and a [`Cancelable`](../cancelation_token/struct.Cancelable.html) to stop it. Returns the task's
+/// handle, and the matching [`CompletionToken`](../completion_token/struct.CompletionToken.html) and
+/// [`CancelationToken`](../cancelation_token/struct.CancelationToken.html).
+/// 
+/// If the worker panics before it's ready, the completion token is abandoned, and the panic is passed on to whoever
+/// awaits the handle
+///
+/// ```
+/// use sync_tokens::async_std_runtime::spawn_coordinated;
+///
+/// # async_std::task::block_on(async {
+/// let (join_handle, completion_token, cancelation_token) = spawn_coordinated(|completable, cancelable| async move {
+///     completable.complete("listening");
+///     cancelable.allow_cancel(futures::future::pending(), "stopped").await
+/// });
+///
+/// assert_eq!(completion_token.await, "listening");
+/// cancelation_token.cancel();
+/// assert_eq!(join_handle.await, "stopped");
+/// # });
+/// ```
+pub fn spawn_coordinated<W, F, T, R>(worker: W) -> (JoinHandle<T>, CompletionToken<R>, CancelationToken) where
+W: FnOnce(Completable<R>, Cancelable) -> F,
+F: Future<Output = T> + Send + 'static,
+T: Send + 'static {
+	let (completion_token, completable) = CompletionToken::new();
+	let (cancelation_token, cancelable) = CancelationToken::new();
+
+	let worker = AssertUnwindSafe(worker(completable, cancelable)).catch_unwind();
+
+	let join_handle = async_std::task::spawn(async move {
+		// The worker, with its completable, is dropped before the panic continues, so the completion token is
+		// abandoned first
+		match worker.await {
+			Ok(result) => result,
+			Err(panic) => std::panic::resume_unwind(panic)
+		}
+	});
+
+	(join_handle, completion_token, cancelation_token)
+}
+
+/// Wraps a task's [`JoinHandle`](https://docs.rs/async-std/latest/async_std/task/struct.JoinHandle.html), and
+/// cancels a [`CancelationToken`](../cancelation_token/struct.CancelationToken.html) if the handle is dropped before
+/// the task finishes. This ties the task's lifetime to whoever holds the handle: abandoning the handle stops the task,
+/// as long as the task stops when its [`Cancelable`](../cancelation_token/struct.Cancelable.html) is canceled.
+///
+/// Awaiting the handle waits for the task, and returns its result. A task that was awaited to completion is never
+/// canceled. Use [`detach()`](struct.CancelableJoinHandle.html#method.detach) to let the task keep running instead
+///
+/// ```
+/// use sync_tokens::async_std_runtime::CancelableJoinHandle;
+/// use sync_tokens::cancelation_token::CancelationToken;
+///
+/// # async_std::task::block_on(async {
+/// let (cancelation_token, cancelable) = CancelationToken::new();
+/// let join_handle = async_std::task::spawn(async move { cancelable.future().await });
+///
+/// drop(CancelableJoinHandle::new(join_handle, cancelation_token.clone()));
+/// assert!(cancelation_token.is_canceled());
+/// # });
+/// ```
+#[derive(Debug)]
+pub struct CancelableJoinHandle<T> {
+	join_handle: JoinHandle<T>,
+	cancelation_token: CancelationToken,
+	cancel_on_drop: bool
+}
+
+impl<T> CancelableJoinHandle<T> {
+	/// Wraps join_handle, so that cancelation_token is canceled if the handle is dropped before the task finishes
+	pub fn new(join_handle: JoinHandle<T>, cancelation_token: CancelationToken) -> CancelableJoinHandle<T> {
+		CancelableJoinHandle {
+			join_handle,
+			cancelation_token,
+			cancel_on_drop: true
+		}
+	}
+
+	/// Lets the task keep running in the background, without canceling the token
+	pub fn detach(mut self) {
+		self.cancel_on_drop = false;
+	}
+
+	/// Returns the [`CancelationToken`](../cancelation_token/struct.CancelationToken.html) that's canceled when the
+	/// handle is dropped
+	pub fn cancelation_token(&self) -> &CancelationToken {
+		&self.cancelation_token
+	}
+}
+
+impl<T> Future for CancelableJoinHandle<T> {
+	type Output = T;
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+		let this = self.get_mut();
+		let result = futures::ready!(Pin::new(&mut this.join_handle).poll(cx));
+
+		// The task finished, so there's nothing to cancel
+		this.cancel_on_drop = false;
+		Poll::Ready(result)
+	}
+}
+
+impl<T> Drop for CancelableJoinHandle<T> {
+	fn drop(&mut self) {
+		if self.cancel_on_drop {
+			self.cancelation_token.cancel();
+		}
+	}
+}
+
+/// A [`TaskHandle`](../task_handle/struct.TaskHandle.html) for a task on async-std, that's ready with R and returns T.
+/// Returned by [`spawn_task()`](fn.spawn_task.html)
+pub type TaskHandle<R, T> = crate::task_handle::TaskHandle<R, JoinHandle<T>>;
+
+/// Spawns a worker on async-std, the same as [`spawn_coordinated()`](fn.spawn_coordinated.html), and returns a
+/// [`TaskHandle`](../task_handle/struct.TaskHandle.html) to wait for it to be ready and to stop it
+///
+/// ```
+/// use sync_tokens::async_std_runtime::spawn_task;
+///
+/// # async_std::task::block_on(async {
+/// let task_handle = spawn_task(|completable, cancelable| async move {
+///     completable.complete("listening");
+///     cancelable.future().await;
+///     "stopped"
+/// });
+///
+/// assert_eq!(task_handle.ready().await, Ok("listening"));
+/// assert_eq!(task_handle.stop().await, "stopped");
+/// # });
+/// ```
+pub fn spawn_task<W, F, T, R>(worker: W) -> TaskHandle<R, T> where
+W: FnOnce(Completable<R>, Cancelable) -> F,
+F: Future<Output = T> + Send + 'static,
+T: Send + 'static,
+R: Send + 'static {
+	let finished = Arc::new(AtomicBool::new(false));
+	let guard = FinishedGuard(finished.clone());
+
+	let (join_handle, completion_token, cancelation_token) = spawn_coordinated(move |completable, cancelable| {
+		// The worker gets its own completable, so that the task is marked finished before the token is abandoned
+		let (ready_token, ready_completable) = CompletionToken::new();
+		run_worker(worker(ready_completable, cancelable), ready_token, completable, guard)
+	});
+
+	crate::task_handle::TaskHandle::new(join_handle, completion_token, cancelation_token, finished)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::cancelation_token::RecvCanceled;
+	use crate::completion_token::Abandoned;
+
+	#[async_std::test]
+	async fn test_spawn_coordinated() {
+		let (join_handle, completion_token, cancelation_token) = spawn_coordinated(|completable, cancelable| async move {
+			async_std::task::sleep(std::time::Duration::from_millis(10)).await;
+			completable.complete(8080);
+
+			cancelable.future().await;
+			"stopped"
+		});
+
+		assert_eq!(completion_token.await, 8080, "Should be ready");
+
+		cancelation_token.cancel();
+		assert_eq!(join_handle.await, "stopped", "Should stop once canceled");
+	}
+
+	#[async_std::test]
+	async fn test_spawn_task() {
+		let task_handle = spawn_task(|completable, cancelable| async move {
+			async_std::task::sleep(std::time::Duration::from_millis(10)).await;
+			completable.complete(8080);
+
+			cancelable.future().await;
+			"stopped"
+		});
+
+		assert_eq!(task_handle.ready().await, Ok(8080), "Should be ready");
+		assert!(!task_handle.is_finished(), "Shouldn't be finished yet");
+		assert_eq!(task_handle.stop().await, "stopped", "Should stop once canceled");
+
+		let task_handle = spawn_task(|completable: Completable<u16>, _cancelable| async move {
+			let _completable = completable;
+			panic!("Worker failed")
+		});
+
+		assert_eq!(task_handle.ready().await, Err(Abandoned), "A panic should abandon");
+
+		// The completable is dropped while the panic unwinds, so the task might not be finished yet
+		for _ in 0..1000 {
+			if task_handle.is_finished() {
+				break;
+			}
+
+			async_std::task::sleep(std::time::Duration::from_millis(1)).await;
+		}
+
+		assert!(task_handle.is_finished(), "A panicked task is finished");
+		assert!(AssertUnwindSafe(task_handle.stop()).catch_unwind().await.is_err(), "The panic should be passed on");
+	}
+
+	#[async_std::test]
+	async fn test_spawn_coordinated_panics() {
+		let (join_handle, completion_token, _cancelation_token) = spawn_coordinated(|_completable: Completable<u16>, _cancelable| async move {
+			panic!("Worker failed")
+		});
+
+		assert_eq!(completion_token.try_wait().await, Err(Abandoned), "A panic should abandon the completion token");
+		assert!(AssertUnwindSafe(join_handle).catch_unwind().await.is_err(), "The panic should be passed on");
+	}
+
+	#[async_std::test]
+	async fn test_spawn_cancelable() {
+		let (cancelation_token, cancelable) = CancelationToken::new();
+
+		let completed = spawn_cancelable(&cancelable, async { "completed" }, "canceled");
+		assert_eq!(completed.await, "completed", "Wrong result");
+
+		let canceled = spawn_cancelable(&cancelable, futures::future::pending(), "canceled");
+		cancelation_token.cancel();
+
+		assert_eq!(canceled.await, "canceled", "Wrong result");
+	}
+
+	#[async_std::test]
+	async fn test_bind_to() {
+		let (cancelation_token, cancelable) = CancelationToken::new();
+
+		let finished = bind_to(&cancelable, async_std::task::spawn(async { 42 }));
+		assert_eq!(finished.await, Some(42), "Wrong result");
+
+		let stuck = bind_to(&cancelable, async_std::task::spawn(futures::future::pending::<()>()));
+		cancelation_token.cancel();
+
+		assert_eq!(stuck.await, None, "The task should be canceled");
+	}
+
+	#[async_std::test]
+	async fn test_cancelable_join_handle_drop_cancels() {
+		let (cancelation_token, cancelable) = CancelationToken::new();
+		let (stopped, stopped_completable) = CompletionToken::new();
+
+		let join_handle = async_std::task::spawn(async move {
+			cancelable.future().await;
+			stopped_completable.complete(());
+		});
+
+		drop(CancelableJoinHandle::new(join_handle, cancelation_token.clone()));
+
+		assert!(cancelation_token.is_canceled(), "Dropping should cancel");
+		assert_eq!(stopped.try_wait().await, Ok(()), "The task should stop");
+	}
+
+	#[async_std::test]
+	async fn test_cancelable_join_handle_await() {
+		let (cancelation_token, _cancelable) = CancelationToken::new();
+
+		let join_handle = CancelableJoinHandle::new(async_std::task::spawn(async { 42 }), cancelation_token.clone());
+		assert_eq!(join_handle.await, 42, "Wrong result");
+		assert!(!cancelation_token.is_canceled(), "Awaiting to completion shouldn't cancel");
+	}
+
+	#[async_std::test]
+	async fn test_cancelable_join_handle_detach() {
+		let (cancelation_token, cancelable) = CancelationToken::new();
+
+		let join_handle = async_std::task::spawn(async move { cancelable.future().await });
+		let cancelable_join_handle = CancelableJoinHandle::new(join_handle, cancelation_token.clone());
+
+		cancelable_join_handle.detach();
+		assert!(!cancelation_token.is_canceled(), "Detaching shouldn't cancel");
+		cancelation_token.cancel();
+	}
+
+	#[async_std::test]
+	async fn test_recv_or_canceled() {
+		let (cancelation_token, cancelable) = CancelationToken::new();
+		let (sender, mut receiver) = async_std::channel::unbounded();
+
+		sender.send(1).await.unwrap();
+		sender.send(2).await.unwrap();
+		assert_eq!(cancelable.recv_or_canceled(&mut receiver).await, Ok(1), "Wrong item");
+
+		cancelation_token.cancel();
+		assert_eq!(cancelable.recv_or_canceled(&mut receiver).await, Err(RecvCanceled::Canceled), "Should be canceled");
+		assert_eq!(receiver.recv().await, Ok(2), "The buffered item should still be received");
+
+		let (_cancelation_token, cancelable) = CancelationToken::new();
+		drop(sender);
+		assert_eq!(cancelable.recv_or_canceled(&mut receiver).await, Err(RecvCanceled::Closed), "Should be closed");
+	}
+}
@@ -0,0 +1,98 @@
+// https://github.com/GWBasic/sync-tokens
+// (c) Andrew Rondeau
+// Apache 2.0 license
+// See https://github.com/GWBasic/sync-tokens/blob/main/LICENSE
+
+//! Internal runner for the futures that the crate drives itself, such as timers that fire whether or not anything
+//! awaits them
+use std::future::Future;
+#[cfg(not(target_arch = "wasm32"))]
+use std::panic::AssertUnwindSafe;
+#[cfg(not(target_arch = "wasm32"))]
+use std::pin::Pin;
+#[cfg(not(target_arch = "wasm32"))]
+use std::sync::OnceLock;
+
+#[cfg(not(target_arch = "wasm32"))]
+use futures::FutureExt;
+#[cfg(not(target_arch = "wasm32"))]
+use futures::channel::mpsc::{UnboundedSender, unbounded};
+#[cfg(not(target_arch = "wasm32"))]
+use futures::stream::StreamExt;
+
+#[cfg(not(target_arch = "wasm32"))]
+type BackgroundFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Runs future in the background, so that the crate works without an async runtime. Every background future shares
+/// one thread, which starts the first time something is spawned, so a timer that nothing awaits doesn't cost a thread
+/// of its own. Browsers don't have threads, so on wasm, the future runs on the page's event loop instead.
+///
+/// A background future must never block, because it would hold up every other background future. It should also
+/// finish once its work can't matter anymore, such as when what it times out completes, so that it doesn't keep
+/// anything alive. Create timers before spawning, on the caller's thread, so that a timer that needs a runtime's
+/// context, such as tokio's, finds it. A panic only stops the future that panicked
+pub(crate) fn spawn<F>(future: F) where
+F: Future<Output = ()> + Send + 'static {
+	#[cfg(not(target_arch = "wasm32"))]
+	{
+		static SENDER: OnceLock<UnboundedSender<BackgroundFuture>> = OnceLock::new();
+
+		let sender = SENDER.get_or_init(|| {
+			let (sender, receiver) = unbounded::<BackgroundFuture>();
+
+			std::thread::Builder::new()
+				.name("sync-tokens".to_string())
+				.spawn(move || futures::executor::block_on(receiver.for_each_concurrent(None, |future| {
+					AssertUnwindSafe(future).catch_unwind().map(drop)
+				})))
+				.expect("Couldn't start the background thread");
+
+			sender
+		});
+
+		// The receiver is never dropped, because the thread runs forever
+		sender.unbounded_send(Box::pin(future)).expect("The background thread stopped");
+	}
+
+	#[cfg(target_arch = "wasm32")]
+	wasm_bindgen_futures::spawn_local(future);
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::mpsc;
+	use std::thread;
+
+	use super::*;
+
+	#[test]
+	fn test_shares_one_thread() {
+		let (sender, receiver) = mpsc::channel();
+
+		for _ in 0..100 {
+			let sender = sender.clone();
+			spawn(async move {
+				sender.send(thread::current().id()).unwrap();
+			});
+		}
+
+		let thread_ids: Vec<_> = receiver.iter().take(100).collect();
+
+		assert!(thread_ids.iter().all(|thread_id| *thread_id == thread_ids[0]), "Every future should run on the same thread");
+		assert_ne!(thread_ids[0], thread::current().id(), "Futures should run in the background");
+	}
+
+	#[test]
+	fn test_panic_only_stops_its_future() {
+		spawn(async {
+			panic!("Background future panicked");
+		});
+
+		let (sender, receiver) = mpsc::channel();
+		spawn(async move {
+			sender.send(()).unwrap();
+		});
+
+		assert_eq!(receiver.recv(), Ok(()), "Futures should still run after a panic");
+	}
+}
@@ -0,0 +1,220 @@
+// https://github.com/GWBasic/sync-tokens
+// (c) Andrew Rondeau
+// Apache 2.0 license
+// See https://github.com/GWBasic/sync-tokens/blob/main/LICENSE
+
+//! Contains a type-erased handle for anything that can be canceled, so that different kinds of cancelation can be
+//! stored together, or in trait objects. See [`BoxCancelable`](struct.BoxCancelable.html)
+use std::fmt;
+use std::sync::Arc;
+
+use futures::future::{BoxFuture, Either, FutureExt, select};
+
+use crate::cancelation_token::{Cancelable, HookedCancelable};
+
+/// Something that can be canceled. This trait is object-safe, so that different sources of cancelation can be
+/// wrapped in a [`BoxCancelable`](struct.BoxCancelable.html)
+pub trait CancelationSource {
+	/// Returns true once canceled
+	fn is_canceled(&self) -> bool;
+
+	/// Returns a future that returns once canceled
+	fn canceled(&self) -> BoxFuture<'static, ()>;
+}
+
+/// A type-erased [`CancelationSource`](trait.CancelationSource.html), such as a
+/// [`Cancelable`](../cancelation_token/struct.Cancelable.html). Returned by
+/// [`Cancelable::boxed()`](../cancelation_token/struct.Cancelable.html#method.boxed), or
+/// [`BoxCancelable::new()`](struct.BoxCancelable.html#method.new) for other sources.
+///
+/// Clones share the same source
+///
+/// ```
+/// use sync_tokens::box_cancelable::BoxCancelable;
+/// use sync_tokens::cancelation_token::CancelationToken;
+///
+/// # async_std::task::block_on(async {
+/// let (cancelation_token, cancelable) = CancelationToken::new();
+/// let (_child_cancelation_token, child_cancelable) = cancelation_token.child();
+/// let hooked_cancelable = CancelationToken::new().1.with_pre_hook(|| {});
+///
+/// let cancelables = vec![cancelable.boxed(), child_cancelable.boxed(), BoxCancelable::new(hooked_cancelable)];
+///
+/// cancelation_token.cancel();
+///
+/// assert!(cancelables[0].is_canceled());
+/// cancelables[1].canceled().await;
+/// assert!(!cancelables[2].is_canceled());
+/// # });
+/// ```
+#[derive(Clone)]
+pub struct BoxCancelable {
+	source: Arc<dyn CancelationSource + Send + Sync>
+}
+
+impl BoxCancelable {
+	/// Wraps source in a [`BoxCancelable`](struct.BoxCancelable.html)
+	pub fn new<S>(source: S) -> BoxCancelable where
+	S: CancelationSource + Send + Sync + 'static {
+		BoxCancelable {
+			source: Arc::new(source)
+		}
+	}
+
+	/// Returns true once canceled
+	pub fn is_canceled(&self) -> bool {
+		self.source.is_canceled()
+	}
+
+	/// Returns a future that returns once canceled
+	pub fn canceled(&self) -> BoxFuture<'static, ()> {
+		self.source.canceled()
+	}
+
+	/// Runs future until it finishes, or until canceled. Returns canceled_result if canceled. The same as
+	/// [`Cancelable::allow_cancel()`](../cancelation_token/struct.Cancelable.html#method.allow_cancel), but takes a
+	/// boxed future
+	pub async fn allow_cancel<'a, T>(&self, future: BoxFuture<'a, T>, canceled_result: T) -> T {
+		match select(future, self.canceled()).await {
+			Either::Left((result, _)) => result,
+			Either::Right(_) => canceled_result
+		}
+	}
+}
+
+impl CancelationSource for BoxCancelable {
+	fn is_canceled(&self) -> bool {
+		self.source.is_canceled()
+	}
+
+	fn canceled(&self) -> BoxFuture<'static, ()> {
+		self.source.canceled()
+	}
+}
+
+impl CancelationSource for Cancelable {
+	fn is_canceled(&self) -> bool {
+		Cancelable::is_canceled(self)
+	}
+
+	fn canceled(&self) -> BoxFuture<'static, ()> {
+		self.future().boxed()
+	}
+}
+
+impl CancelationSource for HookedCancelable {
+	fn is_canceled(&self) -> bool {
+		HookedCancelable::is_canceled(self)
+	}
+
+	fn canceled(&self) -> BoxFuture<'static, ()> {
+		self.future().boxed()
+	}
+}
+
+impl fmt::Debug for BoxCancelable {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("BoxCancelable")
+			.field("canceled", &self.is_canceled())
+			.finish()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::{Duration, Instant};
+
+	use futures::executor::LocalPool;
+	use futures::task::LocalSpawnExt;
+
+	use super::*;
+	use crate::cancelation_token::CancelationToken;
+	use crate::timer::{ManualClock, Timer};
+
+	// Canceled when either cancelable is canceled
+	struct Merged(Cancelable, Cancelable);
+
+	impl CancelationSource for Merged {
+		fn is_canceled(&self) -> bool {
+			self.0.is_canceled() || self.1.is_canceled()
+		}
+
+		fn canceled(&self) -> BoxFuture<'static, ()> {
+			select(self.0.future(), self.1.future()).map(|_| ()).boxed()
+		}
+	}
+
+	// Canceled once a deadline passes
+	struct Deadline {
+		timer: Timer,
+		deadline: Instant
+	}
+
+	impl CancelationSource for Deadline {
+		fn is_canceled(&self) -> bool {
+			self.timer.now() >= self.deadline
+		}
+
+		fn canceled(&self) -> BoxFuture<'static, ()> {
+			self.timer.sleep_until(self.deadline).boxed()
+		}
+	}
+
+	#[test]
+	fn test_mixed_sources() {
+		let clock = ManualClock::new();
+		let timer = Timer::new(clock.clone());
+
+		let (plain_cancelation_token, plain_cancelable) = CancelationToken::new();
+		let (merged_cancelation_token, merged_cancelable) = CancelationToken::new();
+		let (_other_cancelation_token, other_cancelable) = CancelationToken::new();
+
+		let cancelables: Vec<BoxCancelable> = vec![
+			plain_cancelable.boxed(),
+			BoxCancelable::new(Merged(merged_cancelable, other_cancelable)),
+			BoxCancelable::new(Deadline { deadline: timer.now() + Duration::from_secs(10), timer })
+		];
+
+		let mut pool = LocalPool::new();
+		let canceled: Vec<_> = cancelables.iter()
+			.map(|box_cancelable| pool.spawner().spawn_local_with_handle(box_cancelable.canceled()).unwrap())
+			.collect();
+		pool.run_until_stalled();
+
+		assert!(cancelables.iter().all(|box_cancelable| !box_cancelable.is_canceled()), "Nothing should be canceled yet");
+
+		plain_cancelation_token.cancel();
+		merged_cancelation_token.cancel();
+		clock.advance(Duration::from_secs(10));
+
+		for canceled in canceled {
+			pool.run_until(canceled);
+		}
+
+		assert!(cancelables.iter().all(|box_cancelable| box_cancelable.is_canceled()), "Everything should be canceled");
+	}
+
+	#[test]
+	fn test_clones_share_source() {
+		let (cancelation_token, cancelable) = CancelationToken::new();
+		let box_cancelable = cancelable.boxed();
+		let cloned = box_cancelable.clone();
+
+		cancelation_token.cancel();
+		assert!(cloned.is_canceled(), "Clones should share the source");
+		futures::executor::block_on(cloned.canceled());
+	}
+
+	#[test]
+	fn test_allow_cancel() {
+		let (cancelation_token, cancelable) = CancelationToken::new();
+		let box_cancelable = BoxCancelable::new(cancelable.boxed());
+
+		let result = futures::executor::block_on(box_cancelable.allow_cancel(async { "finished" }.boxed(), "canceled"));
+		assert_eq!(result, "finished", "Wrong result");
+
+		cancelation_token.cancel();
+		let result = futures::executor::block_on(box_cancelable.allow_cancel(futures::future::pending().boxed(), "canceled"));
+		assert_eq!(result, "canceled", "Wrong result");
+	}
+}
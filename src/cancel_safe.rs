@@ -0,0 +1,207 @@
+// https://github.com/GWBasic/sync-tokens
+// (c) Andrew Rondeau
+// Apache 2.0 license
+// See https://github.com/GWBasic/sync-tokens/blob/main/LICENSE
+
+//! Marks futures that can be dropped before they finish without losing anything. See
+//! [`CancelSafe`](trait.CancelSafe.html)
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use pin_project_lite::pin_project;
+
+#[cfg(feature = "diagnostics")]
+use crate::cancelation_token::NamedCancelationTokenFuture;
+use crate::cancelation_token::{CancelableFuture, CancelationTokenFuture, CancelationValueFuture};
+use crate::completion_token::{CompletionToken, MemoizedCompletionToken, TryCompletionTokenFuture};
+use crate::epoch_token::EpochFuture;
+use crate::heartbeat_completion_token::HeartbeatCompletionToken;
+use crate::lease_token::LeaseFuture;
+use crate::rate_gate::RateGateAcquireFuture;
+use crate::ready_set::ReadySetFuture;
+use crate::semaphore::SemaphoreAcquireFuture;
+use crate::task_tracker::TaskTrackerFuture;
+use crate::timer::Sleep;
+
+/// Marks a future that can be dropped at any `.await` point without losing anything, so that it can be passed to
+/// [`Cancelable::allow_cancel_safe()`](../cancelation_token/struct.Cancelable.html#method.allow_cancel_safe). For
+/// example, a [`CompletionToken`](../completion_token/struct.CompletionToken.html) only takes its result when it
+/// returns it, so dropping it leaves the result for another clone; but a future that receives from a channel and
+/// then writes the message somewhere loses the message if it's dropped in between.
+///
+/// This is implemented for the crate's futures that are cancel-safe, and for trivially safe futures from std and
+/// futures. [`MultiCompletable::complete_next()`](../multi_completion_token/struct.MultiCompletable.html#method.complete_next)
+/// isn't cancel-safe, because dropping it drops the value. Implement it for your own futures, or wrap a future with
+/// [`assert_cancel_safe()`](fn.assert_cancel_safe.html) to acknowledge, where a reviewer can see it, that losing its
+/// progress is fine
+///
+/// Futures that aren't marked don't compile with allow_cancel_safe:
+///
+/// ```compile_fail
+/// # #![allow(warnings)]
+/// use sync_tokens::cancelation_token::CancelationToken;
+///
+/// # async_std::task::block_on(async {
+/// let (_cancelation_token, cancelable) = CancelationToken::new();
+/// let (sender, receiver) = async_std::channel::unbounded::<u32>();
+///
+/// // Loses the message if it's canceled after receiving, while sending
+/// let forward = Box::pin(async move {
+///     let message = receiver.recv().await.unwrap();
+///     sender.send(message).await.unwrap();
+/// });
+///
+/// cancelable.allow_cancel_safe(forward, ()).await;
+/// # });
+/// ```
+pub trait CancelSafe: Future {}
+
+pin_project! {
+	/// A future that's explicitly acknowledged to be [`CancelSafe`](trait.CancelSafe.html). Returned by
+	/// [`assert_cancel_safe()`](fn.assert_cancel_safe.html)
+	#[derive(Debug)]
+	pub struct AssertCancelSafe<F> {
+		#[pin]
+		future: F
+	}
+}
+
+/// Marks future as [`CancelSafe`](trait.CancelSafe.html), without checking. Use it when losing the future's progress
+/// on cancelation is acceptable, so that the decision is visible where the future is canceled
+///
+/// ```
+/// use sync_tokens::cancel_safe::assert_cancel_safe;
+/// use sync_tokens::cancelation_token::CancelationToken;
+///
+/// # async_std::task::block_on(async {
+/// let (cancelation_token, cancelable) = CancelationToken::new();
+///
+/// // An unfinished download is discarded on cancelation anyway
+/// let download = assert_cancel_safe(Box::pin(async { vec![1u8, 2, 3] }));
+///
+/// assert_eq!(cancelable.allow_cancel_safe(download, Vec::new()).await, vec![1, 2, 3]);
+/// # });
+/// ```
+pub fn assert_cancel_safe<F>(future: F) -> AssertCancelSafe<F> where
+F: Future {
+	AssertCancelSafe { future }
+}
+
+impl<F> AssertCancelSafe<F> {
+	/// Returns the wrapped future
+	pub fn into_inner(self) -> F {
+		self.future
+	}
+}
+
+impl<F> Future for AssertCancelSafe<F> where
+F: Future {
+	type Output = F::Output;
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+		self.project().future.poll(cx)
+	}
+}
+
+impl<F> CancelSafe for AssertCancelSafe<F> where
+F: Future {}
+
+// The crate's futures only change shared state when they return
+impl CancelSafe for CancelationTokenFuture {}
+impl<T> CancelSafe for CancelationValueFuture<T> {}
+#[cfg(feature = "diagnostics")]
+impl CancelSafe for NamedCancelationTokenFuture {}
+impl<F, T> CancelSafe for CancelableFuture<F, T> where
+F: CancelSafe<Output = T> + Unpin {}
+impl<T> CancelSafe for CompletionToken<T> {}
+impl<T> CancelSafe for TryCompletionTokenFuture<T> {}
+impl<T> CancelSafe for MemoizedCompletionToken<T> {}
+impl<T> CancelSafe for HeartbeatCompletionToken<T> {}
+impl CancelSafe for EpochFuture {}
+impl CancelSafe for LeaseFuture {}
+impl CancelSafe for ReadySetFuture {}
+impl CancelSafe for TaskTrackerFuture {}
+impl CancelSafe for Sleep {}
+
+// Dropped while waiting, these give back what they were handed: the semaphore passes a permit on to the next waiter,
+// and the rate gate refunds its slot. Only the place in line is lost
+impl CancelSafe for SemaphoreAcquireFuture {}
+impl CancelSafe for RateGateAcquireFuture {}
+
+impl<T> CancelSafe for std::future::Ready<T> {}
+impl<T> CancelSafe for std::future::Pending<T> {}
+impl<T> CancelSafe for futures::future::Ready<T> {}
+impl<T> CancelSafe for futures::future::Pending<T> {}
+
+impl<F> CancelSafe for Pin<Box<F>> where
+F: CancelSafe + ?Sized {}
+impl<F> CancelSafe for Box<F> where
+F: CancelSafe + Unpin + ?Sized {}
+impl<F> CancelSafe for &mut F where
+F: CancelSafe + Unpin + ?Sized {}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use super::*;
+	use crate::cancelation_token::CancelationToken;
+	use crate::epoch_token::EpochToken;
+	use crate::lease_token::LeaseToken;
+	use crate::rate_gate::RateGate;
+	use crate::ready_set::ReadySet;
+	use crate::semaphore::Semaphore;
+	use crate::task_tracker::TaskTracker;
+	use crate::timer::{ManualClock, Timer};
+
+	fn requires_cancel_safe<F: CancelSafe>(_future: &F) {}
+
+	#[test]
+	fn test_crate_futures_are_cancel_safe() {
+		let (completion_token, _completable) = CompletionToken::<u32>::new();
+		let (_cancelation_token, cancelable) = CancelationToken::new();
+
+		requires_cancel_safe(&completion_token.clone());
+		requires_cancel_safe(&completion_token.clone().try_wait());
+		requires_cancel_safe(&cancelable.future());
+		requires_cancel_safe(&cancelable.allow_cancel_safe(completion_token, 0));
+		requires_cancel_safe(&Timer::new(ManualClock::new()).sleep(Duration::from_secs(1)));
+		requires_cancel_safe(&Box::pin(futures::future::ready(())));
+
+		requires_cancel_safe(&Semaphore::new(1).acquire(None));
+		requires_cancel_safe(&RateGate::with_timer(1, Duration::from_secs(1), Timer::new(ManualClock::new())).acquire(None));
+		requires_cancel_safe(&EpochToken::new(0).wait_for(1));
+		requires_cancel_safe(&TaskTracker::new().wait());
+		requires_cancel_safe(&ReadySet::new().all_ready());
+		requires_cancel_safe(&LeaseToken::with_timer(Duration::from_secs(1), Timer::new(ManualClock::new())).0.expired());
+	}
+
+	runtime_test! {
+		async fn test_canceled_completion_token_keeps_result() {
+			let (cancelation_token, cancelable) = CancelationToken::new();
+			let (completion_token, completable) = CompletionToken::new();
+
+			cancelation_token.cancel();
+			assert_eq!(cancelable.allow_cancel_safe(completion_token.clone(), "canceled").await, "canceled", "Should be canceled");
+
+			// Dropping the canceled clone didn't take the result
+			completable.complete("complete");
+			assert_eq!(completion_token.await, "complete", "The result should still be there");
+		}
+	}
+
+	runtime_test! {
+		async fn test_assert_cancel_safe() {
+			let (cancelation_token, cancelable) = CancelationToken::new();
+
+			let finished = assert_cancel_safe(Box::pin(async { "finished" }));
+			assert_eq!(cancelable.allow_cancel_safe(finished, "canceled").await, "finished", "Should finish");
+
+			cancelation_token.cancel();
+
+			let pending = assert_cancel_safe(Box::pin(futures::future::pending()));
+			assert_eq!(cancelable.allow_cancel_safe(pending, "canceled").await, "canceled", "Should be canceled");
+		}
+	}
+}
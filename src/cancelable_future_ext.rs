@@ -0,0 +1,139 @@
+// https://github.com/GWBasic/sync-tokens
+// (c) Andrew Rondeau
+// Apache 2.0 license
+// See https://github.com/GWBasic/sync-tokens/blob/main/LICENSE
+
+//! Contains an extension trait that allows canceling futures in method position. See
+//! [`CancelableFutureExt`](trait.CancelableFutureExt.html)
+use std::future::Future;
+
+use futures::future::{Either, FutureExt, Map};
+
+use crate::cancelation_token::{Cancelable, CancelableFuture, Canceled};
+
+/// Allows canceling any future in method position, so that it reads naturally at the end of a chain:
+/// `request().with_cancel(&cancelable, default).await` instead of
+/// `cancelable.allow_cancel(request(), default).await`. Every method is the same as calling
+/// [`Cancelable::allow_cancel()`](../cancelation_token/struct.Cancelable.html#method.allow_cancel).
+///
+/// This is implemented for every future, and is exported from [`prelude`](../prelude/index.html). Futures that aren't
+/// `Unpin`, such as the futures returned by `async fn`s, need to be pinned first, for example, with `Box::pin`
+///
+/// ```
+/// use futures::future::Either;
+/// use sync_tokens::cancelation_token::{Canceled, CancelationToken};
+/// use sync_tokens::prelude::*;
+///
+/// # async_std::task::block_on(async {
+/// let (cancelation_token, cancelable) = CancelationToken::new();
+///
+/// assert_eq!(Box::pin(async { "finished" }).with_cancel(&cancelable, "canceled").await, "finished");
+///
+/// cancelation_token.cancel();
+///
+/// let result: Result<(), Canceled> = futures::future::pending().with_cancel_err(&cancelable).await;
+/// assert_eq!(result, Err(Canceled));
+/// assert!(matches!(futures::future::pending::<()>().or_canceled(&cancelable).await, Either::Right(Canceled)));
+/// # });
+/// ```
+pub trait CancelableFutureExt: Future + Sized {
+	/// Runs the future until it finishes, or until canceled. Returns canceled_result if canceled
+	fn with_cancel(self, cancelable: &Cancelable, canceled_result: Self::Output) -> CancelableFuture<Self, Self::Output> where
+	Self: Unpin {
+		cancelable.allow_cancel(self, canceled_result)
+	}
+
+	/// Runs a future that returns a `Result` until it finishes, or until canceled. Returns
+	/// [`Canceled`](../cancelation_token/struct.Canceled.html), converted to the future's error type, if canceled
+	fn with_cancel_err<T, E>(self, cancelable: &Cancelable) -> CancelableFuture<Self, Result<T, E>> where
+	Self: Future<Output = Result<T, E>> + Unpin,
+	E: From<Canceled> {
+		cancelable.allow_cancel(self, Err(E::from(Canceled)))
+	}
+
+	/// Runs the future until it finishes, or until canceled. Returns
+	/// [`Either::Left`](https://docs.rs/futures/latest/futures/future/enum.Either.html) with the future's result, or
+	/// [`Either::Right`](https://docs.rs/futures/latest/futures/future/enum.Either.html) if canceled
+	#[allow(clippy::type_complexity)]
+	fn or_canceled(self, cancelable: &Cancelable) -> CancelableFuture<Map<Self, fn(Self::Output) -> Either<Self::Output, Canceled>>, Either<Self::Output, Canceled>> where
+	Self: Unpin {
+		let left: fn(Self::Output) -> Either<Self::Output, Canceled> = Either::Left;
+		cancelable.allow_cancel(self.map(left), Either::Right(Canceled))
+	}
+}
+
+impl<F> CancelableFutureExt for F where
+F: Future {}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::cancelation_token::CancelationToken;
+
+	#[derive(Debug, PartialEq, Eq)]
+	enum RequestError {
+		Canceled
+	}
+
+	impl From<Canceled> for RequestError {
+		fn from(_: Canceled) -> RequestError {
+			RequestError::Canceled
+		}
+	}
+
+	runtime_test! {
+		async fn test_with_cancel() {
+			let (cancelation_token, cancelable) = CancelationToken::new();
+
+			assert_eq!(futures::future::ready(1).with_cancel(&cancelable, 2).await, 1, "Should finish");
+
+			cancelation_token.cancel();
+			assert_eq!(futures::future::pending().with_cancel(&cancelable, 2).await, 2, "Should be canceled");
+		}
+	}
+
+	runtime_test! {
+		async fn test_with_cancel_err() {
+			let (cancelation_token, cancelable) = CancelationToken::new();
+
+			let result: Result<u32, Canceled> = futures::future::ok(1).with_cancel_err(&cancelable).await;
+			assert_eq!(result, Ok(1), "Should finish");
+
+			cancelation_token.cancel();
+
+			// Any error that can be made from Canceled works
+			let result: Result<u32, RequestError> = futures::future::pending().with_cancel_err(&cancelable).await;
+			assert_eq!(result, Err(RequestError::Canceled), "Should be canceled");
+		}
+	}
+
+	runtime_test! {
+		async fn test_or_canceled() {
+			let (cancelation_token, cancelable) = CancelationToken::new();
+
+			assert!(matches!(futures::future::ready("finished").or_canceled(&cancelable).await, Either::Left("finished")), "Should finish");
+
+			cancelation_token.cancel();
+			assert!(matches!(futures::future::pending::<()>().or_canceled(&cancelable).await, Either::Right(Canceled)), "Should be canceled");
+		}
+	}
+
+	runtime_test! {
+		async fn test_not_unpin() {
+			let (cancelation_token, cancelable) = CancelationToken::new();
+
+			let not_unpin = async {
+				async_std::task::sleep(std::time::Duration::from_secs(60)).await;
+				"finished"
+			};
+
+			let canceling = async_std::task::spawn(async move {
+				async_std::task::sleep(std::time::Duration::from_millis(10)).await;
+				cancelation_token.cancel();
+			});
+
+			assert_eq!(Box::pin(not_unpin).with_cancel(&cancelable, "canceled").await, "canceled", "Should be canceled");
+			canceling.await;
+		}
+	}
+}
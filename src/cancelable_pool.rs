@@ -0,0 +1,295 @@
+// https://github.com/GWBasic/sync-tokens
+// (c) Andrew Rondeau
+// Apache 2.0 license
+// See https://github.com/GWBasic/sync-tokens/blob/main/LICENSE
+
+//! Contains a pool that cancels operations by key. See [`CancelablePool`](struct.CancelablePool.html)
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex, Weak};
+
+use crate::cancelation_token::{Cancelable, CancelationToken};
+
+/// Tracks one [`CancelationToken`](../cancelation_token/struct.CancelationToken.html) per key, such as a session id,
+/// so that operations can be canceled by key or all at once.
+///
+/// A key is removed from the pool when it's canceled, or when every clone of its
+/// [`Cancelable`](../cancelation_token/struct.Cancelable.html) is dropped, so the pool only holds live operations.
+///
+/// Registering a key that's already in the pool replaces it: the previous registration is canceled and removed
+///
+/// ```
+/// use sync_tokens::cancelable_pool::CancelablePool;
+///
+/// let pool = CancelablePool::new();
+///
+/// let first = pool.register("first");
+/// let second = pool.register("second");
+/// assert_eq!(pool.len(), 2);
+///
+/// pool.cancel(&"first");
+/// assert!(first.is_canceled());
+/// assert!(!second.is_canceled());
+///
+/// drop(second);
+/// assert!(pool.is_empty());
+/// ```
+#[derive(Debug)]
+pub struct CancelablePool<K> {
+	shared_state: Arc<Mutex<CancelablePoolState<K>>>
+}
+
+#[derive(Debug)]
+struct CancelablePoolState<K> {
+	entries: HashMap<K, CancelablePoolEntry>,
+	next_registration: u64
+}
+
+#[derive(Debug)]
+struct CancelablePoolEntry {
+	registration: u64,
+	cancelation_token: CancelationToken
+}
+
+impl<K> CancelablePool<K> where
+K: Eq + Hash + Clone + Send + 'static {
+	/// Creates a new, empty, [`CancelablePool`](struct.CancelablePool.html)
+	pub fn new() -> CancelablePool<K> {
+		CancelablePool {
+			shared_state: Arc::new(Mutex::new(CancelablePoolState {
+				entries: HashMap::new(),
+				next_registration: 0
+			}))
+		}
+	}
+
+	/// Registers key and returns its [`Cancelable`](../cancelation_token/struct.Cancelable.html). If key is already
+	/// registered, the previous registration is canceled and replaced
+	pub fn register(&self, key: K) -> Cancelable {
+		let (cancelation_token, cancelable) = CancelationToken::new();
+
+		let replaced = {
+			let mut shared_state = self.shared_state.lock().unwrap();
+
+			let registration = shared_state.next_registration;
+			shared_state.next_registration += 1;
+
+			let pool = Arc::downgrade(&self.shared_state);
+			let hook_key = key.clone();
+			cancelation_token.on_cancelables_dropped(move || remove_registration(pool, hook_key, registration));
+
+			shared_state.entries.insert(key, CancelablePoolEntry {
+				registration,
+				cancelation_token
+			})
+		};
+
+		if let Some(replaced) = replaced {
+			replaced.cancelation_token.cancel();
+		}
+
+		cancelable
+	}
+
+	/// Cancels and removes key. Returns false if key isn't registered
+	pub fn cancel(&self, key: &K) -> bool {
+		let removed = {
+			let mut shared_state = self.shared_state.lock().unwrap();
+			shared_state.entries.remove(key)
+		};
+
+		match removed {
+			Some(entry) => {
+				entry.cancelation_token.cancel();
+				true
+			},
+			None => false
+		}
+	}
+
+	/// Cancels and removes every key. Keys registered while this runs are either canceled, or stay in the pool
+	/// uncanceled; they are never left canceled in the pool
+	pub fn cancel_all(&self) {
+		let removed = {
+			let mut shared_state = self.shared_state.lock().unwrap();
+			std::mem::take(&mut shared_state.entries)
+		};
+
+		for entry in removed.into_values() {
+			entry.cancelation_token.cancel();
+		}
+	}
+
+	/// Returns true if key is registered
+	pub fn contains(&self, key: &K) -> bool {
+		let shared_state = self.shared_state.lock().unwrap();
+		shared_state.entries.contains_key(key)
+	}
+
+	/// The number of registered keys
+	pub fn len(&self) -> usize {
+		let shared_state = self.shared_state.lock().unwrap();
+		shared_state.entries.len()
+	}
+
+	/// Returns true if no keys are registered
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+
+	/// Returns a snapshot of the registered keys
+	pub fn keys(&self) -> std::vec::IntoIter<K> {
+		let shared_state = self.shared_state.lock().unwrap();
+		shared_state.entries.keys().cloned().collect::<Vec<_>>().into_iter()
+	}
+}
+
+impl<K> Default for CancelablePool<K> where
+K: Eq + Hash + Clone + Send + 'static {
+	fn default() -> Self {
+		CancelablePool::new()
+	}
+}
+
+// Removes the key when its Cancelable is dropped, unless the key was since replaced
+fn remove_registration<K>(pool: Weak<Mutex<CancelablePoolState<K>>>, key: K, registration: u64) where
+K: Eq + Hash {
+	if let Some(pool) = pool.upgrade() {
+		let mut shared_state = pool.lock().unwrap();
+
+		let is_current = shared_state.entries.get(&key)
+			.map(|entry| entry.registration == registration)
+			.unwrap_or(false);
+
+		if is_current {
+			shared_state.entries.remove(&key);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::thread;
+
+	use super::*;
+
+	#[test]
+	fn test_register_and_cancel() {
+		let pool = CancelablePool::new();
+
+		let first = pool.register(1);
+		let second = pool.register(2);
+
+		assert_eq!(pool.len(), 2, "Wrong number of keys");
+		assert!(pool.contains(&1), "Key 1 should be registered");
+
+		assert!(pool.cancel(&1), "Key 1 should be canceled");
+		assert!(!pool.cancel(&1), "Key 1 is no longer registered");
+
+		assert!(first.is_canceled(), "First should be canceled");
+		assert!(!second.is_canceled(), "Second shouldn't be canceled");
+		assert_eq!(pool.keys().collect::<Vec<_>>(), vec![2], "Wrong keys");
+	}
+
+	#[test]
+	fn test_cancel_all() {
+		let pool = CancelablePool::new();
+		let cancelables: Vec<_> = (0..10).map(|key| pool.register(key)).collect();
+
+		let mut keys: Vec<_> = pool.keys().collect();
+		keys.sort();
+		assert_eq!(keys, (0..10).collect::<Vec<_>>(), "Wrong keys");
+
+		pool.cancel_all();
+
+		assert!(pool.is_empty(), "Pool should be empty");
+		assert!(cancelables.iter().all(|cancelable| cancelable.is_canceled()), "Everything should be canceled");
+	}
+
+	#[test]
+	fn test_removed_when_cancelable_dropped() {
+		let pool = CancelablePool::new();
+
+		let cancelable = pool.register("session");
+		let clone = cancelable.clone();
+
+		drop(cancelable);
+		assert!(pool.contains(&"session"), "Session should stay while a clone is alive");
+
+		drop(clone);
+		assert!(pool.is_empty(), "Session should be removed when its cancelables are dropped");
+	}
+
+	#[test]
+	fn test_reregister_replaces() {
+		let pool = CancelablePool::new();
+
+		let old = pool.register("session");
+		let new = pool.register("session");
+
+		assert!(old.is_canceled(), "The old registration should be canceled");
+		assert!(!new.is_canceled(), "The new registration shouldn't be canceled");
+		assert_eq!(pool.len(), 1, "Wrong number of keys");
+
+		drop(old);
+		assert!(pool.contains(&"session"), "Dropping the old cancelable shouldn't remove the new registration");
+
+		drop(new);
+		assert!(pool.is_empty(), "Pool should be empty");
+	}
+
+	#[test]
+	fn test_pool_dropped_before_cancelable() {
+		let pool = CancelablePool::new();
+		let cancelable = pool.register("session");
+
+		drop(pool);
+		drop(cancelable);
+	}
+
+	#[test]
+	fn test_cancel_all_while_registering() {
+		let pool = Arc::new(CancelablePool::new());
+
+		let registering_threads: Vec<_> = (0..4).map(|thread_number| {
+			let pool = pool.clone();
+
+			thread::spawn(move || {
+				let mut cancelables = Vec::new();
+
+				for i in 0..1000 {
+					let key = thread_number * 1000 + i;
+					cancelables.push(pool.register(key));
+				}
+
+				cancelables
+			})
+		}).collect();
+
+		let canceling_thread = {
+			let pool = pool.clone();
+			thread::spawn(move || {
+				for _ in 0..100 {
+					pool.cancel_all();
+					thread::yield_now();
+				}
+			})
+		};
+
+		canceling_thread.join().unwrap();
+
+		let mut cancelables = Vec::new();
+		for registering_thread in registering_threads {
+			cancelables.extend(registering_thread.join().unwrap());
+		}
+
+		let live = cancelables.iter().filter(|cancelable| !cancelable.is_canceled()).count();
+		assert_eq!(live, pool.len(), "Every uncanceled registration should be in the pool");
+
+		pool.cancel_all();
+		assert!(cancelables.iter().all(|cancelable| cancelable.is_canceled()), "Everything should be canceled");
+
+		drop(cancelables);
+		assert!(pool.is_empty(), "Pool should be empty");
+	}
+}
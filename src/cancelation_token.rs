@@ -4,247 +4,3958 @@
 // See https://github.com/GWBasic/sync-tokens/blob/main/LICENSE
 
 //! Contains structs to assist in canceling ongoing operations. See [`CancelationToken`](struct.CancelationToken.html) or [`sync-tokens`](../index.html) for an example.
+use std::any::Any;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
 use std::future::Future;
 use std::pin::Pin;
-use std::sync::{Arc, Mutex};
-use std::task::{Context, Poll, Waker};
+use std::sync::{Arc, LockResult, Mutex, MutexGuard, Weak};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+#[cfg(not(target_arch = "wasm32"))]
+use std::sync::atomic::AtomicUsize;
+use std::task::{Context, Poll};
+use std::time::Duration;
 
-use futures::future::{Either, select};
+use futures::FutureExt;
+use futures::future::{AbortHandle, AbortRegistration, Abortable, Aborted, Either, FusedFuture, select};
+use futures::sink::Sink;
+use futures::stream::{FuturesUnordered, Stream, StreamExt};
+use pin_project_lite::pin_project;
+
+#[cfg(feature = "crossbeam")]
+use crossbeam_utils::atomic::AtomicCell;
+
+use crate::box_cancelable::BoxCancelable;
+use crate::cancel_safe::CancelSafe;
+use crate::completion_token::CompletionToken;
+use crate::scheduled_cancel::ScheduledCancel;
+use crate::timer::{Instant, Timer};
+use crate::wakers::{WakePanic, WakerKey, WakerList, Wakers};
 
 /// Allows canceling an asynchronous operation. Whoever has a [`CancelationToken`](struct.CancelationToken.html) can cancel an
 /// operation that uses a [`Cancelable`](struct.Cancelable.html)
 /// 
+/// [`CancelationToken`](struct.CancelationToken.html) is always Send and Sync, so it can be canceled from any thread.
+/// There's no `unsafe impl`; it only shares state that's Send and Sync behind an `Arc<Mutex<..>>`, so the compiler
+/// checks it. A future passed to [`Cancelable::allow_cancel()`](struct.Cancelable.html#method.allow_cancel) is still
+/// only Send if the wrapped future is (error E0277, "cannot be sent between threads safely"):
+/// 
+/// ```compile_fail,E0277
+/// use std::rc::Rc;
+/// 
+/// use sync_tokens::cancelation_token::CancelationToken;
+/// 
+/// let (_cancelation_token, cancelable) = CancelationToken::new();
+/// let future = cancelable.allow_cancel(futures::future::ready(Rc::new(1)), Rc::new(0));
+/// 
+/// std::thread::spawn(move || futures::executor::block_on(future));
+/// ```
+/// 
 /// See example at [`sync-tokens`](../index.html)
-#[derive(Debug)]
 pub struct CancelationToken {
-	shared_state: Arc<Mutex<CancelationTokenState>>
+	shared_state: Arc<SharedState>,
+	// Shared by every clone, so that it's dropped with the last one. Only set by new_cancel_on_drop()
+	cancel_on_drop: Option<Arc<CancelOnDrop>>
 }
 
 /// Assists in canceling an asynchronous operation. Typically, this struct is kept private and
 /// used with either [`allow_cancel()`](struct.CancelationToken.html#method.allow_cancel) or [`Self::future()`](struct.CancelationToken.html#method.future). A [`CancelationToken`](struct.CancelationToken.html) is given to whoever can
 /// cancel operations
 /// 
+/// [`Cancelable`](struct.Cancelable.html) is always Send and Sync
+/// 
 /// See example at [`sync-tokens`](../index.html)
-#[derive(Debug)]
 pub struct Cancelable {
-	shared_state: Arc<Mutex<CancelationTokenState>>
+	shared_state: Arc<SharedState>,
+	// Used by poll_canceled(). Each clone has its own, so that clones polled by different tasks don't replace each
+	// other's wakers
+	waker_key: Mutex<Option<WakerKey>>
+}
+
+/// Whether a [`CancelationToken`](struct.CancelationToken.html) is canceled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CancelationState {
+	/// The token isn't canceled
+	Active,
+	/// The token is canceled
+	Canceled
 }
 
 /// Future for use with [`Cancelable`](struct.Cancelable.html)
+/// 
+/// Any number of these futures can wait on the same [`CancelationToken`](struct.CancelationToken.html) at the
+/// same time; every one of them is woken when it's canceled. Cloning a
+/// [`CancelationTokenFuture`](struct.CancelationTokenFuture.html) creates an independent future that waits for the
+/// same cancelation
+/// 
+/// [`CancelationTokenFuture`](struct.CancelationTokenFuture.html) is always Send, Sync, and Unpin, so it can be stored
+/// in futures that must be Send and Sync
 #[derive(Debug)]
 pub struct CancelationTokenFuture {
-	shared_state: Arc<Mutex<CancelationTokenState>>
+	shared_state: Arc<SharedState>,
+	waker_key: Option<WakerKey>
+}
+
+/// A [`CancelationTokenFuture`](struct.CancelationTokenFuture.html) whose waker is registered with a name, so that
+/// [`CancelationToken::waiting_task_names()`](struct.CancelationToken.html#method.waiting_task_names) can show which
+/// tasks are waiting. Returned by [`Cancelable::future_named()`](struct.Cancelable.html#method.future_named). Requires
+/// the `diagnostics` feature
+#[cfg(feature = "diagnostics")]
+#[cfg_attr(feature = "docs", doc(cfg(feature = "diagnostics")))]
+#[derive(Debug)]
+pub struct NamedCancelationTokenFuture {
+	future: CancelationTokenFuture,
+	name: &'static str
+}
+
+/// A [`CancelationTokenFuture`](struct.CancelationTokenFuture.html) that returns a value once the
+/// [`CancelationToken`](struct.CancelationToken.html) is canceled. Returned by
+/// [`Cancelable::future_with_value()`](struct.Cancelable.html#method.future_with_value).
+/// 
+/// It's Unpin, and implements `FusedFuture`, so it can be used directly in `futures::select!`
+#[derive(Debug)]
+pub struct CancelationValueFuture<T> {
+	future: CancelationTokenFuture,
+	// Taken when the future returns
+	value: Option<T>
+}
+
+/// Future returned by [`Cancelable::allow_cancel()`](struct.Cancelable.html#method.allow_cancel). Unlike an
+/// `async fn`'s future, this can be named, so it can be stored in other futures and structs
+///
+/// It polls the [`Cancelable`](struct.Cancelable.html)'s shared state directly, instead of selecting on a
+/// [`CancelationTokenFuture`](struct.CancelationTokenFuture.html), so calling allow_cancel only costs one reference
+/// count increment, and never allocates once the token has room for the waker
+#[derive(Debug)]
+pub struct CancelableFuture<F, T> {
+	// Set to None as soon as it finishes, so that the inner future is dropped before the result is returned
+	future: Option<F>,
+	canceled_result: Option<T>,
+	shared_state: Arc<SharedState>,
+	waker_key: Option<WakerKey>,
+	// Cancelation is checked before the first poll, so that a future that's already canceled is never polled
+	polled: bool
+}
+
+/// The registration half of [`Cancelable::into_abort_handle()`](struct.Cancelable.html#method.into_abort_handle).
+/// Wrap a future with [`abortable()`](struct.CancelAbortRegistration.html#method.abortable)
+#[derive(Debug)]
+pub struct CancelAbortRegistration {
+	abort_registration: AbortRegistration,
+	// None if the token was already canceled
+	registered: Option<AbortHandleKey>
+}
+
+pin_project! {
+	/// An [`Abortable`](https://docs.rs/futures/latest/futures/future/struct.Abortable.html) that's aborted when a
+	/// [`CancelationToken`](struct.CancelationToken.html) is canceled. Returned by
+	/// [`CancelAbortRegistration::abortable()`](struct.CancelAbortRegistration.html#method.abortable).
+	///
+	/// Its handle is only registered with the token until it finishes, or is dropped
+	#[derive(Debug)]
+	pub struct CancelAbortable<F> {
+		#[pin]
+		abortable: Abortable<F>,
+		registered: Option<AbortHandleKey>
+	}
+}
+
+// Removes an abort handle from its token when dropped
+#[derive(Debug)]
+struct AbortHandleKey {
+	shared_state: Arc<SharedState>,
+	key: u64
+}
+
+/// Stream returned by [`Cancelable::into_stream()`](struct.Cancelable.html#method.into_stream). Yields an item each
+/// time the [`CancelationToken`](struct.CancelationToken.html) is canceled
+#[derive(Debug)]
+pub struct CancelationStream {
+	cancelable: Cancelable,
+	yielded: u64,
+	waker_key: Option<WakerKey>
+}
+
+pin_project! {
+	/// [`Sink`](https://docs.rs/futures/latest/futures/sink/trait.Sink.html) returned by
+	/// [`Cancelable::sink()`](struct.Cancelable.html#method.sink). Once the [`CancelationToken`](struct.CancelationToken.html)
+	/// is canceled, sending and flushing return [`CancelableSinkError::Canceled`](enum.CancelableSinkError.html), even if
+	/// they're waiting on a full sink. Closing is still allowed by default, so that a final close frame can go out
+	#[derive(Debug)]
+	pub struct CancelableSink<S> {
+		#[pin]
+		sink: S,
+		canceled: CancelationTokenFuture,
+		allow_close: bool
+	}
+}
+
+/// Error returned by a [`CancelableSink`](struct.CancelableSink.html). Wraps the inner sink's errors, so that any sink
+/// can be made cancelable
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CancelableSinkError<E> {
+	/// The [`CancelationToken`](struct.CancelationToken.html) was canceled
+	Canceled,
+	/// The inner sink returned an error
+	Sink(E)
+}
+
+/// Error returned by [`Cancelable::recv_or_canceled()`](struct.Cancelable.html#method.recv_or_canceled)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvCanceled {
+	/// The [`CancelationToken`](struct.CancelationToken.html) was canceled. Items that were already sent are still
+	/// in the receiver
+	Canceled,
+	/// The channel is closed, and every item was received
+	Closed
+}
+
+/// A [`Cancelable`](struct.Cancelable.html) that calls a hook every time
+/// [`allow_cancel()`](struct.HookedCancelable.html#method.allow_cancel) is called. Returned by
+/// [`Cancelable::with_pre_hook()`](struct.Cancelable.html#method.with_pre_hook)
+#[derive(Clone)]
+pub struct HookedCancelable {
+	cancelable: Cancelable,
+	pre_hook: Arc<dyn Fn() + Send + Sync>
+}
+
+/// Checks for cancelation from synchronous, CPU-bound code, such as a [rayon](https://docs.rs/rayon) parallel
+/// iterator. Returned by [`Cancelable::sync_checker()`](struct.Cancelable.html#method.sync_checker)
+/// 
+/// Checking is a single atomic load, so it's cheap enough to call for every item
+#[derive(Debug, Clone)]
+pub struct SyncChecker {
+	shared_state: Arc<SharedState>
+}
+
+/// Error returned by operations that stopped because their [`CancelationToken`](struct.CancelationToken.html)
+/// was canceled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Canceled;
+
+// The canceled flag is kept outside of the lock, so that cancel() never waits behind a future that's being polled
+// to set it, and so that checking it never takes the lock.
+//
+// cancel() stores the flag before it takes the lock to wake the futures, and a future that finds the flag unset
+// checks it again while holding the lock before it registers its waker. The lock orders the two: either the future
+// registers its waker first, and cancel() wakes it, or cancel() takes the lock first, and the future sees the flag
+// that was stored before. Every store of true is followed by a wake pass, so a waker that's still registered when a
+// future sees the flag is always removed soon after
+#[derive(Debug)]
+struct SharedState {
+	#[cfg(not(feature = "crossbeam"))]
+	canceled: AtomicBool,
+	// With the crossbeam feature, the flag is the state itself, so that reading the state is a single lock-free load
+	#[cfg(feature = "crossbeam")]
+	canceled: AtomicCell<CancelationState>,
+	state: Mutex<CancelationTokenState>
 }
 
 #[derive(Debug)]
 struct CancelationTokenState {
-	canceled: bool,
-	waker: Option<Waker>
+	id: u64,
+	soft_canceled: bool,
+	// Set by cancel_with_message()
+	reason: Option<String>,
+	// Set by PolicyCancelationToken::cancel()
+	policy: Option<Policy>,
+	cancel_count: u64,
+	wakers: WakerList,
+	cancelable_count: usize,
+	cancelables_dropped_hook: Option<DropHook>,
+	children: Vec<ChildToken>,
+	#[cfg(feature = "opentelemetry")]
+	trace_context: Option<opentelemetry::Context>,
+	// Dropping the sender disconnects every receiver, which wakes every select waiting on one
+	#[cfg(feature = "crossbeam-channel")]
+	crossbeam_channel: Option<(crossbeam_channel::Sender<()>, crossbeam_channel::Receiver<()>)>,
+	// Dropping the source stops every token it produced
+	#[cfg(feature = "stop-token")]
+	stop_source: Option<stop_token::StopSource>,
+	// Aborted, and removed, when canceled. Also removed when the future that they abort finishes or is dropped
+	abort_handles: HashMap<u64, AbortHandle>,
+	next_abort_key: u64
+}
+
+struct DropHook(Box<dyn FnOnce() + Send>);
+
+// Type-erased, so that CancelationToken doesn't need a type parameter for the policy
+pub(crate) type Policy = Arc<dyn Any + Send + Sync>;
+
+// Weak, so that a parent doesn't keep its children alive
+#[derive(Debug)]
+struct ChildToken {
+	shared_state: Weak<SharedState>
+}
+
+// Held by the crate's timers, such as ScheduledCancel's. Weak, so that a timer doesn't keep the token alive, or count as
+// a clone for new_cancel_on_drop()
+#[derive(Debug)]
+pub(crate) struct WeakCancelationToken {
+	child_token: ChildToken,
+	waker_key: Option<WakerKey>
+}
+
+// Cancels when the last CancelationToken that shares it is dropped. Weak, so that it doesn't keep the state alive
+struct CancelOnDrop(ChildToken);
+
+// The waker that Cancelable::from_stop_token() polls the stop token with. Weak, so that it doesn't keep the token alive
+#[cfg(feature = "stop-token")]
+struct StopTokenBridge(ChildToken);
+
+// Counts down the cancelables that Cancelable::block_until_all_canceled() is still waiting for
+#[cfg(not(target_arch = "wasm32"))]
+struct CancelCounter {
+	thread: std::thread::Thread,
+	remaining: AtomicUsize
 }
 
+// Woken when one of the cancelables is canceled. Only counted once, even if it's woken again
+#[cfg(not(target_arch = "wasm32"))]
+struct CountWaker {
+	counter: Arc<CancelCounter>,
+	counted: AtomicBool
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// How often a token created with [`CancelationToken::from_env()`](struct.CancelationToken.html#method.from_env)
+/// checks its environment variable
+#[cfg(all(any(test, feature = "testing"), not(target_arch = "wasm32")))]
+const ENV_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(10);
+
 impl CancelationToken {
 	#[allow(dead_code)]
 	/// Creates a new [`CancelationToken`](struct.CancelationToken.html) and [`Cancelable`](struct.Cancelable.html)
 	pub fn new() -> (CancelationToken, Cancelable) {
-		let shared_state = Arc::new(Mutex::new(CancelationTokenState {
-			canceled: false,
-			waker: None
+		CancelationToken::create(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+	}
+
+	/// Creates a new [`CancelationToken`](struct.CancelationToken.html) and [`Cancelable`](struct.Cancelable.html) with
+	/// the given id, instead of the next id from the process-wide counter. This keeps ids in test output stable across
+	/// runs, for example, `CancelationToken { id: 42, state: Active }`. Ids aren't checked for uniqueness, so this is
+	/// only available with the `testing-unstable` feature
+	#[cfg(any(test, feature = "testing-unstable"))]
+	#[cfg_attr(feature = "docs", doc(cfg(feature = "testing-unstable")))]
+	pub fn new_with_id(id: u64) -> (CancelationToken, Cancelable) {
+		CancelationToken::create(id)
+	}
+
+	fn create(id: u64) -> (CancelationToken, Cancelable) {
+		let shared_state = Arc::new(SharedState::new(CancelationTokenState {
+			id,
+			soft_canceled: false,
+			reason: None,
+			policy: None,
+			cancel_count: 0,
+			wakers: WakerList::new(),
+			cancelable_count: 1,
+			cancelables_dropped_hook: None,
+			children: Vec::new(),
+			#[cfg(feature = "opentelemetry")]
+			trace_context: None,
+			#[cfg(feature = "crossbeam-channel")]
+			crossbeam_channel: None,
+			#[cfg(feature = "stop-token")]
+			stop_source: None,
+			abort_handles: HashMap::new(),
+			next_abort_key: 0
 		}));
 
 		let cancelation_token = CancelationToken {
-			shared_state: shared_state.clone()
+			shared_state: shared_state.clone(),
+			cancel_on_drop: None
 		};
 		
-		let cancelable = Cancelable { shared_state };
+		let cancelable = Cancelable {
+			shared_state,
+			waker_key: Mutex::new(None)
+		};
 
 		(cancelation_token, cancelable)
 	}
 
-	/// Cancels the operation. This can be called multiple times safely
-	#[allow(dead_code)]
-	pub fn cancel(&self) {
+	/// Creates a new [`CancelationToken`](struct.CancelationToken.html) and [`Cancelable`](struct.Cancelable.html) that
+	/// are canceled when the last clone of the [`CancelationToken`](struct.CancelationToken.html) is dropped, so that
+	/// an operation doesn't run forever once nothing can cancel it, such as after its controller crashed. Calling
+	/// [`cancel()`](struct.CancelationToken.html#method.cancel) still cancels earlier.
+	/// 
+	/// Tokens that the crate keeps internally, such as a parent's reference to a [`child()`](struct.CancelationToken.html#method.child),
+	/// and the timer behind a [`ScheduledCancel`](../scheduled_cancel/struct.ScheduledCancel.html), don't count
+	/// 
+	/// ```
+	/// use sync_tokens::cancelation_token::CancelationToken;
+	/// 
+	/// let (cancelation_token, cancelable) = CancelationToken::new_cancel_on_drop();
+	/// let clone = cancelation_token.clone();
+	/// 
+	/// drop(cancelation_token);
+	/// assert!(!cancelable.is_canceled());
+	/// 
+	/// drop(clone);
+	/// assert!(cancelable.is_canceled());
+	/// ```
+	pub fn new_cancel_on_drop() -> (CancelationToken, Cancelable) {
+		let (mut cancelation_token, cancelable) = CancelationToken::new();
+
+		cancelation_token.cancel_on_drop = Some(Arc::new(CancelOnDrop(ChildToken {
+			shared_state: Arc::downgrade(&cancelation_token.shared_state)
+		})));
+
+		(cancelation_token, cancelable)
+	}
+
+	/// Creates a new [`CancelationToken`](struct.CancelationToken.html) and [`Cancelable`](struct.Cancelable.html) that
+	/// are canceled when the var environment variable is set to anything other than an empty string or `0`, such as
+	/// `CANCEL_TOKEN_FORCE_CANCEL=1`. This lets tests inject cancelation from outside of the code under test.
+	/// 
+	/// The variable is checked immediately, and then every 10 milliseconds on a background thread. The thread stops
+	/// once the token is canceled, or once every clone of the token and [`Cancelable`](struct.Cancelable.html) is dropped.
+	/// Not available on wasm, which has neither environment variables nor threads
+	#[cfg(all(any(test, feature = "testing"), not(target_arch = "wasm32")))]
+	#[cfg_attr(feature = "docs", doc(cfg(feature = "testing")))]
+	pub fn from_env(var: &str) -> (CancelationToken, Cancelable) {
+		let (cancelation_token, cancelable) = CancelationToken::new();
+
+		let cancel_requested = {
+			let var = var.to_string();
+			move || std::env::var_os(&var).is_some_and(|value| !value.is_empty() && value != "0")
+		};
+
+		if cancel_requested() {
+			cancelation_token.cancel();
+			return (cancelation_token, cancelable);
+		}
+
+		// The thread only holds a weak reference, so that it doesn't keep the token alive
+		let weak_shared_state = Arc::downgrade(&cancelation_token.shared_state);
+
+		std::thread::spawn(move || loop {
+			std::thread::sleep(ENV_POLL_INTERVAL);
+
+			let shared_state = match weak_shared_state.upgrade() {
+				Some(shared_state) => shared_state,
+				None => return
+			};
+
+			if shared_state.is_canceled() {
+				return;
+			}
+
+			if cancel_requested() {
+				let cancelation_token = CancelationToken {
+					shared_state,
+					cancel_on_drop: None
+				};
+
+				cancelation_token.cancel();
+				return;
+			}
+		});
+
+		(cancelation_token, cancelable)
+	}
+
+	/// Creates a new [`CancelationToken`](struct.CancelationToken.html) and [`Cancelable`](struct.Cancelable.html)
+	/// that are canceled when this token is canceled. Canceling the child doesn't affect this token
+	pub fn child(&self) -> (CancelationToken, Cancelable) {
+		let (child_token, child_cancelable) = CancelationToken::new();
+
 		let mut shared_state = self.shared_state.lock().unwrap();
 
-		shared_state.canceled = true;
-		if let Some(waker) = shared_state.waker.take() {
-			waker.wake()
+		// Checked while holding the lock, so that cancel_self() either sees the child, or the child sees the flag
+		if self.shared_state.is_canceled() {
+			let reason = shared_state.reason.clone();
+			let policy = shared_state.policy.clone();
+			drop(shared_state);
+			child_token.cancel_because(reason.as_deref(), policy.as_ref());
+		} else {
+			shared_state.children.retain(|child| child.shared_state.strong_count() > 0);
+			shared_state.children.push(ChildToken {
+				shared_state: Arc::downgrade(&child_token.shared_state)
+			});
 		}
+
+		(child_token, child_cancelable)
 	}
-}
 
-impl Cancelable {
-	/// Allows canceling the future. canceled_result is what's returned when the [`CancelationToken`](struct.CancelationToken.html)
-	/// is canceled. It is reccomended that the future return a [`Result`](https://doc.rust-lang.org/std/result/) so that canceled_result
-	/// can be an error
+	/// Creates two independent children of this token. Canceling this token cancels both forks, but canceling one fork
+	/// affects neither the other fork nor this token
+	/// 
+	/// ```
+	/// use sync_tokens::cancelation_token::CancelationToken;
+	/// 
+	/// let (cancelation_token, _cancelable) = CancelationToken::new();
+	/// let ((left_token, left_cancelable), (right_token, right_cancelable)) = cancelation_token.fork();
+	/// 
+	/// left_token.cancel();
+	/// assert!(left_cancelable.is_canceled());
+	/// assert!(!right_cancelable.is_canceled());
+	/// 
+	/// cancelation_token.cancel();
+	/// assert!(right_cancelable.is_canceled());
+	/// ```
+	pub fn fork(&self) -> ((CancelationToken, Cancelable), (CancelationToken, Cancelable)) {
+		(self.child(), self.child())
+	}
+
+	/// Cancels the operation. This can be called multiple times safely
+	/// 
+	/// # Panics
+	/// 
+	/// If a waiting task's waker panics, the token and its children are still canceled and every other task is
+	/// still woken, and then the panic is resumed
 	#[allow(dead_code)]
-	pub async fn allow_cancel<TFuture, T>(&self, future: TFuture, canceled_result: T) -> T where
-	TFuture: Future<Output = T> + Unpin {
-		{
-			let shared_state = self.shared_state.lock().unwrap();
-			if shared_state.canceled {
-				return canceled_result;
-			}
+	pub fn cancel(&self) {
+		self.cancel_because(None, None);
+	}
+
+	/// Cancels the operation, with a message that explains why. The message is returned by
+	/// [`cancel_reason()`](struct.CancelationToken.html#method.cancel_reason), and is passed on to child tokens. If
+	/// the operation is already canceled, the message is ignored
+	/// 
+	/// ```
+	/// use sync_tokens::cancelation_token::CancelationToken;
+	/// 
+	/// let (cancelation_token, cancelable) = CancelationToken::new();
+	/// cancelation_token.cancel_with_message("shutdown request");
+	/// 
+	/// assert_eq!(cancelable.cancel_reason(), Some("shutdown request".to_string()));
+	/// ```
+	pub fn cancel_with_message(&self, message: &str) {
+		self.cancel_because(Some(message), None);
+	}
+
+	/// Cancels the operation once duration passes, using the system clock. The returned
+	/// [`ScheduledCancel`](../scheduled_cancel/struct.ScheduledCancel.html) can move the deadline, or abort the
+	/// cancelation
+	pub fn cancel_after(&self, duration: Duration) -> ScheduledCancel {
+		let timer = Timer::default();
+		ScheduledCancel::with_timer(self.clone(), timer.now() + duration, timer)
+	}
+
+	/// Cancels the operation at deadline, using the system clock. The returned
+	/// [`ScheduledCancel`](../scheduled_cancel/struct.ScheduledCancel.html) can move the deadline, or abort the
+	/// cancelation
+	pub fn cancel_at(&self, deadline: Instant) -> ScheduledCancel {
+		ScheduledCancel::with_timer(self.clone(), deadline, Timer::default())
+	}
+
+	/// Cancels every child and their descendants, but not this token, so that new children can be created for the
+	/// next generation of operations
+	/// 
+	/// ```
+	/// use sync_tokens::cancelation_token::CancelationToken;
+	/// 
+	/// let (cancelation_token, cancelable) = CancelationToken::new();
+	/// let (_old_token, old_cancelable) = cancelation_token.child();
+	/// 
+	/// cancelation_token.cancel_subtree();
+	/// assert!(old_cancelable.is_canceled());
+	/// assert!(!cancelable.is_canceled());
+	/// 
+	/// let (_new_token, new_cancelable) = cancelation_token.child();
+	/// assert!(!new_cancelable.is_canceled());
+	/// ```
+	pub fn cancel_subtree(&self) {
+		let children: Vec<CancelationToken> = self.shared_state.lock().unwrap().children.drain(..).filter_map(|child| child.upgrade()).collect();
+
+		for child in children {
+			child.cancel();
 		}
+	}
 
-		let cancelation_token_future = CancelationTokenFuture {
-			shared_state: self.shared_state.clone()
-		};
+	// Used by PolicyCancelationToken, which keeps the policy's type
+	pub(crate) fn cancel_with_policy(&self, policy: Policy) {
+		self.cancel_because(None, Some(&policy));
+	}
 
-		match select(future, cancelation_token_future).await {
-			Either::Left((l, _)) => l,
-			Either::Right(_) => canceled_result
+	fn cancel_because(&self, reason: Option<&str>, policy: Option<&Policy>) {
+		let mut wakers = Wakers::new();
+		let mut abort_handles = Vec::new();
+		self.cancel_tree(reason, policy, &mut wakers, &mut abort_handles);
+
+		// Woken once every token in the tree is canceled and no lock is held, so that a waker that panics can't leave
+		// the tree partly canceled, or poison a lock. Every other waker is still woken before the panic is resumed
+		let mut wake_panic = WakePanic::new();
+		wakers.wake(&mut wake_panic);
+
+		for abort_handle in abort_handles {
+			wake_panic.catch(|| abort_handle.abort());
 		}
+
+		wake_panic.resume();
 	}
 
-	/// Returns a future that returns once the [`CancelationToken`](struct.CancelationToken.html) is canceled. Intended for use
-	/// with select
-	#[allow(dead_code)]
-	pub fn future(&self) -> CancelationTokenFuture {
-		CancelationTokenFuture {
-			shared_state: self.shared_state.clone()
+	fn cancel_tree(&self, reason: Option<&str>, policy: Option<&Policy>, wakers: &mut Wakers, abort_handles: &mut Vec<AbortHandle>) {
+		let children = self.cancel_self(reason, policy, wakers, abort_handles);
+
+		// Canceled without holding this token's lock, so that locks are only ever taken from parent to child
+		for child in children {
+			child.cancel_tree(reason, policy, wakers, abort_handles);
 		}
 	}
-}
 
-impl Future for CancelationTokenFuture {
-	type Output = ();
+	fn cancel_self(&self, reason: Option<&str>, policy: Option<&Policy>, wakers: &mut Wakers, abort_handles: &mut Vec<AbortHandle>) -> Vec<CancelationToken> {
+		// Stored before taking the lock, so that canceling never waits behind a future that's being polled
+		let newly_canceled = self.shared_state.set_canceled();
 
-	fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
 		let mut shared_state = self.shared_state.lock().unwrap();
 
-		if shared_state.canceled {
-            Poll::Ready(())
-		} else {
-            shared_state.waker = Some(cx.waker().clone());
-            Poll::Pending
+		// Stored again, in case reset() took the lock first
+		self.shared_state.set_canceled();
+
+		if newly_canceled {
+			shared_state.cancel_count += 1;
+			shared_state.reason = reason.map(str::to_string);
+			shared_state.policy = policy.cloned();
+
+			#[cfg(feature = "opentelemetry")]
+			record_cancel_event(&shared_state);
 		}
-	}
-}
 
-impl Clone for CancelationToken {
-	fn clone(&self) -> Self {
-		CancelationToken {
-			shared_state: self.shared_state.clone()
+		wakers.take_from(&mut shared_state.wakers);
+
+		#[cfg(feature = "crossbeam-channel")]
+		{
+			shared_state.crossbeam_channel = None;
 		}
-	}
-}
 
-impl Clone for Cancelable {
-	fn clone(&self) -> Self {
-		Cancelable {
-			shared_state: self.shared_state.clone()
+		#[cfg(feature = "stop-token")]
+		{
+			shared_state.stop_source = None;
 		}
+
+		abort_handles.extend(shared_state.abort_handles.drain().map(|(_, abort_handle)| abort_handle));
+
+		shared_state.children.drain(..).filter_map(|child| child.upgrade()).collect()
 	}
-}
 
-#[cfg(test)]
-mod tests {
-    use async_std::prelude::*;
-	use futures::future;
-	use std::task::Context;
+	/// Attaches an OpenTelemetry context. When the token is canceled, a `cancellation_token.canceled` event is added
+	/// to the context's span, so that traces show which span caused the cancelation
+	#[cfg(feature = "opentelemetry")]
+	#[cfg_attr(feature = "docs", doc(cfg(feature = "opentelemetry")))]
+	pub fn with_trace_context(self, cx: opentelemetry::Context) -> CancelationToken {
+		self.shared_state.lock().unwrap().trace_context = Some(cx);
+		self
+	}
 
-    use cooked_waker::IntoWaker;
+	/// Clears the canceled state so that the token can be canceled again. Operations that already observed the
+	/// cancelation are not affected. Does nothing if the token isn't canceled
+	pub fn reset(&self) {
+		let mut shared_state = self.shared_state.lock().unwrap();
+		self.shared_state.clear_canceled();
+		shared_state.soft_canceled = false;
+		shared_state.reason = None;
+		shared_state.policy = None;
+	}
 
-	use super::*;
-	use crate::tests::*;
+	/// Returns true once the operation is canceled
+	pub fn is_canceled(&self) -> bool {
+		self.state() == CancelationState::Canceled
+	}
 
-	fn assert_not_canceled_no_waker(shared_state: &Arc<Mutex<CancelationTokenState>>) {
-		let shared_state = shared_state.lock().unwrap();
-		assert_eq!(shared_state.canceled, false, "Canceled should be false at construction");
-		assert_eq!(shared_state.waker.is_none(), true, "Waker should not be set");
+	/// Returns whether the operation is canceled. Never takes a lock
+	pub fn state(&self) -> CancelationState {
+		self.shared_state.cancelation_state()
 	}
 
-	fn assert_not_canceled_waker_set(shared_state: &Arc<Mutex<CancelationTokenState>>) {
-		let shared_state = shared_state.lock().unwrap();
-		assert_eq!(shared_state.canceled, false, "Canceled should be false");
-		assert_eq!(shared_state.waker.is_some(), true, "Waker should be set");
+	/// Returns the message given to [`cancel_with_message()`](struct.CancelationToken.html#method.cancel_with_message).
+	/// Returns None if the operation isn't canceled, or was canceled without a message
+	pub fn cancel_reason(&self) -> Option<String> {
+		self.shared_state.lock().unwrap().reason.clone()
 	}
 
-	fn assert_canceled(shared_state: &Arc<Mutex<CancelationTokenState>>) {
-		let shared_state = shared_state.lock().unwrap();
-		assert_eq!(shared_state.canceled, true, "Canceled should be true");
-		assert_eq!(shared_state.waker.is_none(), true, "Waker should be set");
+	/// Returns the policy given to
+	/// [`PolicyCancelationToken::cancel()`](../policy_cancelation_token/struct.PolicyCancelationToken.html#method.cancel).
+	/// Returns None if the operation isn't canceled, was canceled without a policy, or the policy isn't a P
+	pub fn cancel_policy<P>(&self) -> Option<P> where
+	P: Clone + 'static {
+		self.shared_state.lock().unwrap().policy.as_ref().and_then(|policy| policy.downcast_ref::<P>()).cloned()
 	}
 
-    #[test]
-    fn test_via_poll() {
+	/// Returns the id shown when this token is displayed. The id is shared with the matching
+	/// [`Cancelable`](struct.Cancelable.html). Ids come from a process-wide counter, so they're distinct unless a
+	/// token was created with `new_with_id()`, which doesn't check for duplicates
+	pub fn fmt_id(&self) -> u64 {
+		self.shared_state.lock().unwrap().id
+	}
 
-		let (cancelation_token, cancelable) = CancelationToken::new();
-		let mut future = cancelable.future();
-		let pinned_future = Pin::new(&mut future);
+	/// Returns a closure, for use with [`poll_fn()`](https://doc.rust-lang.org/std/future/fn.poll_fn.html) or in a
+	/// custom polling loop, that returns `Poll::Ready(())` once canceled. Until then, it keeps the most recent waker,
+	/// the same as a [`CancelationTokenFuture`](struct.CancelationTokenFuture.html). The closure keeps this token, so
+	/// a token from [`new_cancel_on_drop()`](struct.CancelationToken.html#method.new_cancel_on_drop) isn't canceled
+	/// until the closure is dropped
+	/// 
+	/// ```
+	/// use std::future::poll_fn;
+	/// use sync_tokens::cancelation_token::CancelationToken;
+	/// 
+	/// # async_std::task::block_on(async {
+	/// let (cancelation_token, _cancelable) = CancelationToken::new();
+	/// let mut poll_canceled = cancelation_token.clone().into_poll_fn();
+	/// 
+	/// cancelation_token.cancel();
+	/// poll_fn(|cx| poll_canceled(cx)).await;
+	/// # });
+	/// ```
+	pub fn into_poll_fn(self) -> impl FnMut(&mut Context<'_>) -> Poll<()> + Send + 'static {
+		let CancelationToken { shared_state, cancel_on_drop } = self;
 
-		let shared_state = cancelation_token.shared_state.clone();
+		let mut future = CancelationTokenFuture {
+			shared_state,
+			waker_key: None
+		};
 
-		assert_not_canceled_no_waker(&shared_state);
+		move |cx| {
+			let _keep_until_dropped = &cancel_on_drop;
+			Pin::new(&mut future).poll(cx)
+		}
+	}
 
-		let test_waker = TestWaker::new();
-		let waker = test_waker.clone().into_waker();
-		let mut cx = Context::from_waker(&waker);
+	/// Returns the names of the tasks waiting on a [`NamedCancelationTokenFuture`](struct.NamedCancelationTokenFuture.html).
+	/// Tasks that wait without a name aren't included. Requires the `diagnostics` feature
+	/// 
+	/// ```
+	/// use sync_tokens::cancelation_token::CancelationToken;
+	/// 
+	/// # async_std::task::block_on(async {
+	/// let (cancelation_token, cancelable) = CancelationToken::new();
+	/// let listener = async_std::task::spawn(cancelable.future_named("listener"));
+	/// 
+	/// while cancelation_token.waiting_task_names().is_empty() {
+	///     async_std::task::yield_now().await;
+	/// }
+	/// 
+	/// assert_eq!(cancelation_token.waiting_task_names(), vec!["listener"]);
+	/// 
+	/// cancelation_token.cancel();
+	/// listener.await;
+	/// # });
+	/// ```
+	#[cfg(feature = "diagnostics")]
+	#[cfg_attr(feature = "docs", doc(cfg(feature = "diagnostics")))]
+	pub fn waiting_task_names(&self) -> Vec<&'static str> {
+		self.shared_state.lock().unwrap().wakers.names()
+	}
 
-		let poll_result = pinned_future.poll(&mut cx);
-		assert_eq!(poll_result.is_pending(), true, "Cancelation token should be pending");
+	/// Asks the operation to stop once it finishes its current work, without waking anything that waits for
+	/// cancelation. See [`SoftCancelationToken`](../soft_cancelation_token/struct.SoftCancelationToken.html)
+	pub(crate) fn soft_cancel(&self) {
+		self.shared_state.lock().unwrap().soft_canceled = true;
+	}
 
-		assert_not_canceled_waker_set(&shared_state);
+	pub(crate) fn is_soft_canceled(&self) -> bool {
+		self.shared_state.is_canceled() || self.shared_state.lock().unwrap().soft_canceled
+	}
 
-		cancelation_token.cancel();
+	pub(crate) fn downgrade(&self) -> WeakCancelationToken {
+		WeakCancelationToken {
+			child_token: ChildToken {
+				shared_state: Arc::downgrade(&self.shared_state)
+			},
+			waker_key: None
+		}
+	}
 
-		assert_canceled(&shared_state);
+	/// Runs hook once every clone of the matching [`Cancelable`](struct.Cancelable.html) is dropped. The hook runs
+	/// without holding any locks
+	pub(crate) fn on_cancelables_dropped<F>(&self, hook: F) where
+	F: FnOnce() + Send + 'static {
+		let mut shared_state = self.shared_state.lock().unwrap();
+		shared_state.cancelables_dropped_hook = Some(DropHook(Box::new(hook)));
+	}
+}
 
-		let pinned_future = Pin::new(&mut future);
+impl Cancelable {
+	/// Allows canceling the future. canceled_result is what's returned when the [`CancelationToken`](struct.CancelationToken.html)
+	/// is canceled. It is reccomended that the future return a [`Result`](https://doc.rust-lang.org/std/result/) so that canceled_result
+	/// can be an error
+	///
+	/// When canceled, the future is always dropped before canceled_result is returned, so anything that its Drop
+	/// releases, such as a lock, is already released when the caller continues. See
+	/// [`allow_cancel_with_drop_hook()`](struct.Cancelable.html#method.allow_cancel_with_drop_hook) to run cleanup at
+	/// that point
+	#[allow(dead_code)]
+	pub fn allow_cancel<TFuture, T>(&self, future: TFuture, canceled_result: T) -> CancelableFuture<TFuture, T> where
+	TFuture: Future<Output = T> + Unpin {
+		CancelableFuture {
+			future: Some(future),
+			canceled_result: Some(canceled_result),
+			shared_state: self.shared_state.clone(),
+			waker_key: None,
+			polled: false
+		}
+	}
 
-		let poll_result = pinned_future.poll(&mut cx);
-		assert_eq!(poll_result.is_ready(), true, "Cancelation token should be ready");
+	/// Allows canceling a future that's [`CancelSafe`](../cancel_safe/trait.CancelSafe.html), so that it can't lose
+	/// anything when it's dropped. Otherwise, the same as [`allow_cancel()`](struct.Cancelable.html#method.allow_cancel).
+	/// Wrap a future with [`assert_cancel_safe()`](../cancel_safe/fn.assert_cancel_safe.html) to acknowledge that
+	/// canceling it is fine
+	pub fn allow_cancel_safe<TFuture, T>(&self, future: TFuture, canceled_result: T) -> CancelableFuture<TFuture, T> where
+	TFuture: CancelSafe<Output = T> + Unpin {
+		self.allow_cancel(future, canceled_result)
+	}
 
-		assert_canceled(&shared_state);
+	/// Allows canceling a future that returns an `anyhow::Result`. When canceled, returns an error whose message is
+	/// "operation canceled", and that downcasts to [`Canceled`](struct.Canceled.html)
+	#[cfg(feature = "anyhow")]
+	#[cfg_attr(feature = "docs", doc(cfg(feature = "anyhow")))]
+	pub async fn allow_cancel_anyhow<TFuture, T>(&self, future: TFuture) -> anyhow::Result<T> where
+	TFuture: Future<Output = anyhow::Result<T>> + Unpin {
+		// The error is only created when canceled
+		match self.allow_cancel(future.map(Some), None).await {
+			Some(result) => result,
+			None => Err(anyhow::Error::new(Canceled).context("operation canceled"))
+		}
 	}
-	
-	#[async_std::test]
-	async fn test_via_allow_cancel() {
 
-		let (cancelation_token, cancelable) = CancelationToken::new();
-		let shared_state = cancelation_token.shared_state.clone();
+	/// Allows canceling the future, and calls on_drop if it's canceled. on_drop is called after the future is dropped,
+	/// and before canceled_result is returned, so cleanup that depends on the future's Drop can be sequenced reliably.
+	/// on_drop isn't called if the future finishes
+	pub async fn allow_cancel_with_drop_hook<TFuture, T, H>(&self, future: TFuture, canceled_result: T, on_drop: H) -> T where
+	TFuture: Future<Output = T> + Unpin,
+	H: FnOnce() {
+		match self.allow_cancel(future.map(Some), None).await {
+			Some(result) => result,
+			None => {
+				on_drop();
+				canceled_result
+			}
+		}
+	}
 
-		assert_not_canceled_no_waker(&shared_state);
+	/// Allows canceling the future when the canceled result has a different type than the future's result. Returns
+	/// [`Either::Left`](https://docs.rs/futures/latest/futures/future/enum.Either.html) with the future's result, or
+	/// [`Either::Right`](https://docs.rs/futures/latest/futures/future/enum.Either.html) with canceled_value when the
+	/// [`CancelationToken`](struct.CancelationToken.html) is canceled
+	pub async fn allow_cancel_either<TFuture, T, C>(&self, future: TFuture, canceled_value: C) -> Either<T, C> where
+	TFuture: Future<Output = T> + Unpin {
+		self.allow_cancel(future.map(Either::Left), Either::Right(canceled_value)).await
+	}
 
-		let result_future = future::ready("result");
-		let result = cancelable.allow_cancel(result_future, "canceled").await;
+	/// Allows canceling an already boxed future, such as the futures returned by methods defined with
+	/// [`async_trait`](https://docs.rs/async-trait). Otherwise, this is the same as
+	/// [`allow_cancel()`](struct.Cancelable.html#method.allow_cancel)
+	pub async fn allow_cancel_dyn<'a, T>(&self, future: Pin<Box<dyn Future<Output = T> + Send + 'a>>, canceled_result: T) -> T {
+		self.allow_cancel(future, canceled_result).await
+	}
 
-		assert_eq!(result, "result", "Future canceled incorrectly");
+	/// Runs every future from futures until the first one finishes, and returns its result, or returns
+	/// canceled_result if the [`CancelationToken`](struct.CancelationToken.html) is canceled first. The rest of the
+	/// futures are dropped.
+	/// 
+	/// Nothing is taken from futures until the returned future is awaited, and if the operation is already canceled
+	/// by then, none of them are polled. If futures is empty, this waits until canceled
+	/// 
+	/// ```
+	/// use futures::FutureExt;
+	/// use sync_tokens::cancelation_token::CancelationToken;
+	/// 
+	/// # async_std::task::block_on(async {
+	/// let (_cancelation_token, cancelable) = CancelationToken::new();
+	/// let mirrors = ["a", "b", "c"].iter().map(|mirror| async move { *mirror }.boxed());
+	/// 
+	/// let fastest = cancelable.allow_cancel_iter(mirrors, "canceled").await;
+	/// assert_ne!(fastest, "canceled");
+	/// # });
+	/// ```
+	pub async fn allow_cancel_iter<'a, T, I>(&self, futures: I, canceled_result: T) -> T where
+	I: IntoIterator<Item = Pin<Box<dyn Future<Output = T> + Send + 'a>>> {
+		let mut futures: FuturesUnordered<_> = futures.into_iter().collect();
 
-		assert_not_canceled_no_waker(&shared_state);
+		if futures.is_empty() {
+			self.future().await;
+			return canceled_result;
+		}
 
-		cancelation_token.cancel();
+		let first = futures.next().map(|first| first.expect("There's at least one future"));
+		self.allow_cancel(first, canceled_result).await
+	}
 
-		assert_canceled(&shared_state);
+	/// Races the future against both the [`CancelationToken`](struct.CancelationToken.html) and a
+	/// [`CompletionToken`](../completion_token/struct.CompletionToken.html). Returns the future's result if it finishes
+	/// first, the completion token's value if it's completed first, or canceled_result if canceled first.
+	/// 
+	/// The completion token's value is only taken when it wins the race; if the future finishes or the operation is
+	/// canceled first, the completion token can still be awaited. An abandoned completion token never wins
+	pub async fn allow_cancel_or_complete<TFuture, T>(&self, future: TFuture, completion_token: &CompletionToken<T>, canceled_result: T) -> T where
+	TFuture: Future<Output = T> + Unpin {
+		if self.is_canceled() {
+			return canceled_result;
+		}
 
-		let pending_future = future::pending();
-		let result = cancelable.allow_cancel(pending_future, "canceled").await;
+		match select(future, select(completion_token.clone(), self.future())).await {
+			Either::Left((l, _)) => l,
+			Either::Right((Either::Left((completed, _)), _)) => completed,
+			Either::Right((Either::Right(_), _)) => canceled_result
+		}
+	}
 
-		assert_eq!(result, "canceled", "Future not canceled");
+	/// Allows the future to finish its current work when soft-canceled with a
+	/// [`SoftCancelationToken`](../soft_cancelation_token/struct.SoftCancelationToken.html). If the future finishes
+	/// after a soft-cancel, its result is replaced with soft_result. If it's hard-canceled first, it stops immediately
+	/// and hard_result is returned
+	/// 
+	/// ```
+	/// use futures::future::FutureExt;
+	/// use sync_tokens::soft_cancelation_token::SoftCancelationToken;
+	/// 
+	/// # async_std::task::block_on(async {
+	/// let (soft_cancelation_token, cancelable) = SoftCancelationToken::new();
+	/// 
+	/// soft_cancelation_token.soft_cancel();
+	/// let result = cancelable.allow_soft_cancel(async { "finished" }.boxed(), "soft", "hard").await;
+	/// 
+	/// assert_eq!(result, "soft");
+	/// # });
+	/// ```
+	pub async fn allow_soft_cancel<TFuture, T>(&self, future: TFuture, soft_result: T, hard_result: T) -> T where
+	TFuture: Future<Output = T> + Unpin {
+		match self.allow_cancel_either(future, hard_result).await {
+			Either::Left(_) if self.is_soft_canceled() => soft_result,
+			Either::Left(result) => result,
+			Either::Right(hard_result) => hard_result
+		}
 	}
 
-    #[async_std::test]
-    async fn test_via_future() {
+	/// Polls for cancelation, for use in a hand-written [`poll()`](https://doc.rust-lang.org/std/future/trait.Future.html#tymethod.poll).
+	/// Returns `Poll::Ready(())` once the [`CancelationToken`](struct.CancelationToken.html) is canceled. Otherwise,
+	/// cx's waker is woken when it's canceled, the same as with [`future()`](struct.Cancelable.html#method.future).
+	/// 
+	/// Only the waker from the most recent call is kept. Each clone of the [`Cancelable`](struct.Cancelable.html)
+	/// keeps its own waker, so that clones can be polled from different tasks
+	/// 
+	/// ```
+	/// use std::future::Future;
+	/// use std::pin::Pin;
+	/// use std::task::{Context, Poll};
+	/// 
+	/// use sync_tokens::cancelation_token::{Cancelable, CancelationToken};
+	/// 
+	/// // Runs a game's ticks until it's canceled
+	/// struct GameLoop {
+	///     cancelable: Cancelable,
+	///     ticks: u64
+	/// }
+	/// 
+	/// impl Future for GameLoop {
+	///     type Output = u64;
+	/// 
+	///     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<u64> {
+	///         let this = self.get_mut();
+	/// 
+	///         if this.cancelable.poll_canceled(cx).is_ready() {
+	///             return Poll::Ready(this.ticks);
+	///         }
+	/// 
+	///         this.ticks += 1;
+	///         Poll::Pending
+	///     }
+	/// }
+	/// 
+	/// # async_std::task::block_on(async {
+	/// let (cancelation_token, cancelable) = CancelationToken::new();
+	/// let game_loop = async_std::task::spawn(GameLoop { cancelable, ticks: 0 });
+	/// 
+	/// cancelation_token.cancel();
+	/// assert!(game_loop.await <= 1);
+	/// # });
+	/// ```
+	pub fn poll_canceled(&self, cx: &mut Context<'_>) -> Poll<()> {
+		let mut waker_key = self.waker_key.lock().unwrap();
+		self.shared_state.poll_canceled(&mut waker_key, cx)
+	}
 
-		let (cancelation_token, cancelable) = CancelationToken::new();
-		let shared_state = cancelation_token.shared_state.clone();
+	/// Returns a future that returns once the [`CancelationToken`](struct.CancelationToken.html) is canceled. Intended for use
+	/// with select
+	#[allow(dead_code)]
+	pub fn future(&self) -> CancelationTokenFuture {
+		CancelationTokenFuture {
+			shared_state: self.shared_state.clone(),
+			waker_key: None
+		}
+	}
 
-		assert_not_canceled_no_waker(&shared_state);
+	/// Returns a future that returns once the [`CancelationToken`](struct.CancelationToken.html) is canceled, the same as
+	/// [`future()`](struct.Cancelable.html#method.future), that shows up as name in
+	/// [`CancelationToken::waiting_task_names()`](struct.CancelationToken.html#method.waiting_task_names) while it's
+	/// waiting. Requires the `diagnostics` feature
+	#[cfg(feature = "diagnostics")]
+	#[cfg_attr(feature = "docs", doc(cfg(feature = "diagnostics")))]
+	pub fn future_named(&self, name: &'static str) -> NamedCancelationTokenFuture {
+		NamedCancelationTokenFuture {
+			future: self.future(),
+			name
+		}
+	}
 
-		match select(cancelable.future(), future::ready(())).await {
-			Either::Left(_) => panic!("Cancelation token isn't canceled"),
-			Either::Right(_) => {}
+	/// Returns a future that returns value once the [`CancelationToken`](struct.CancelationToken.html) is canceled. The
+	/// same as `cancelable.future().map(|()| value)`, but the returned type can be named, and can be used in
+	/// `futures::select!` without fusing it
+	/// 
+	/// ```
+	/// use sync_tokens::cancelation_token::CancelationToken;
+	/// 
+	/// # async_std::task::block_on(async {
+	/// let (cancelation_token, cancelable) = CancelationToken::new();
+	/// let mut work = futures::future::pending::<u32>();
+	/// let mut canceled = cancelable.future_with_value(u32::MAX);
+	/// 
+	/// cancelation_token.cancel();
+	/// 
+	/// let result = futures::select! {
+	///     result = work => result,
+	///     result = canceled => result
+	/// };
+	/// 
+	/// assert_eq!(result, u32::MAX);
+	/// # });
+	/// ```
+	pub fn future_with_value<T>(&self, value: T) -> CancelationValueFuture<T> {
+		CancelationValueFuture {
+			future: self.future(),
+			value: Some(value)
 		}
+	}
 
-		cancelation_token.cancel();
+	/// Bridges cancelation into completion: returns a [`CompletionToken`](../completion_token/struct.CompletionToken.html),
+	/// and a future that, once spawned, waits for the [`CancelationToken`](struct.CancelationToken.html) to be canceled and
+	/// then completes the token with default. Use this to tell an operation's consumers that it's shutting down with a
+	/// sentinel value, when they only wait on a [`CompletionToken`](../completion_token/struct.CompletionToken.html).
+	/// 
+	/// If the future is dropped before the [`CancelationToken`](struct.CancelationToken.html) is canceled, the
+	/// [`CompletionToken`](../completion_token/struct.CompletionToken.html) is abandoned
+	/// 
+	/// ```
+	/// use sync_tokens::cancelation_token::CancelationToken;
+	/// 
+	/// # async_std::task::block_on(async {
+	/// let (cancelation_token, cancelable) = CancelationToken::new();
+	/// let (shutting_down, bridge) = cancelable.degrade_to_completion("shutting down");
+	/// async_std::task::spawn(bridge);
+	/// 
+	/// cancelation_token.cancel();
+	/// assert_eq!(shutting_down.await, "shutting down");
+	/// # });
+	/// ```
+	pub fn degrade_to_completion<T>(&self, default: T) -> (CompletionToken<T>, impl Future<Output = ()>) {
+		let (completion_token, completable) = CompletionToken::new();
+		let canceled = self.future();
 
-		assert_canceled(&shared_state);
+		let bridge = async move {
+			canceled.await;
+			completable.complete(default);
+		};
 
-		match select(cancelable.future(), future::pending::<()>()).await {
-			Either::Left(_) => {},
-			Either::Right(_) => panic!("Cancelation didn't happen")
-		}
+		(completion_token, bridge)
+	}
+
+	/// Wraps sink, so that sending and flushing stop with an error once the [`CancelationToken`](struct.CancelationToken.html)
+	/// is canceled
+	/// 
+	/// ```
+	/// use futures::channel::mpsc;
+	/// use futures::sink::SinkExt;
+	/// use sync_tokens::cancelation_token::{CancelableSinkError, CancelationToken};
+	/// 
+	/// # async_std::task::block_on(async {
+	/// let (cancelation_token, cancelable) = CancelationToken::new();
+	/// let (sender, _receiver) = mpsc::channel::<u32>(0);
+	/// let mut sink = cancelable.sink(sender);
+	/// 
+	/// // Fills the channel, without waiting for the receiver
+	/// sink.feed(1).await.unwrap();
+	/// cancelation_token.cancel();
+	/// 
+	/// assert_eq!(sink.send(2).await, Err(CancelableSinkError::Canceled));
+	/// # });
+	/// ```
+	pub fn sink<S>(&self, sink: S) -> CancelableSink<S> {
+		CancelableSink {
+			sink,
+			canceled: self.future(),
+			allow_close: true
+		}
+	}
+
+	/// Receives the next item from a channel, or stops when the [`CancelationToken`](struct.CancelationToken.html) is
+	/// canceled. Works with any receiver that's a [`Stream`](https://docs.rs/futures/latest/futures/stream/trait.Stream.html),
+	/// such as futures' mpsc receivers and async-std's channel receivers.
+	/// 
+	/// Once canceled, this returns [`RecvCanceled::Canceled`](enum.RecvCanceled.html) even if items are buffered. The
+	/// receiver isn't consumed, so the caller can still drain them
+	/// 
+	/// ```
+	/// use futures::channel::mpsc;
+	/// use sync_tokens::cancelation_token::{CancelationToken, RecvCanceled};
+	/// 
+	/// # async_std::task::block_on(async {
+	/// let (cancelation_token, cancelable) = CancelationToken::new();
+	/// let (sender, mut receiver) = mpsc::unbounded();
+	/// 
+	/// sender.unbounded_send(1).unwrap();
+	/// assert_eq!(cancelable.recv_or_canceled(&mut receiver).await, Ok(1));
+	/// 
+	/// cancelation_token.cancel();
+	/// assert_eq!(cancelable.recv_or_canceled(&mut receiver).await, Err(RecvCanceled::Canceled));
+	/// # });
+	/// ```
+	pub async fn recv_or_canceled<R>(&self, receiver: &mut R) -> Result<R::Item, RecvCanceled> where
+	R: Stream + Unpin {
+		if self.is_canceled() {
+			return Err(RecvCanceled::Canceled);
+		}
+
+		match select(receiver.next(), self.future()).await {
+			Either::Left((Some(item), _)) => Ok(item),
+			Either::Left((None, _)) => Err(RecvCanceled::Closed),
+			Either::Right(_) => Err(RecvCanceled::Canceled)
+		}
+	}
+
+	/// Blocks the current thread until an item is received from a
+	/// [`std::sync::mpsc::Receiver`](https://doc.rust-lang.org/std/sync/mpsc/struct.Receiver.html), or until the
+	/// [`CancelationToken`](struct.CancelationToken.html) is canceled. Intended for worker threads that aren't async.
+	/// 
+	/// A std receiver can't wait on anything else, so this waits on the receiver in slices of poll_interval, and
+	/// checks for cancelation between them. Cancelation is noticed within poll_interval; a shorter interval notices it
+	/// sooner, at the cost of waking the thread more often while the channel is empty. Items are received as soon as
+	/// they're sent, regardless of poll_interval.
+	/// 
+	/// Once canceled, this returns [`RecvCanceled::Canceled`](enum.RecvCanceled.html) even if items are buffered, so
+	/// that the caller can drain them. Not available on wasm, which doesn't have threads
+	/// 
+	/// ```
+	/// use std::sync::mpsc;
+	/// use std::time::Duration;
+	/// 
+	/// use sync_tokens::cancelation_token::{CancelationToken, RecvCanceled};
+	/// 
+	/// let (cancelation_token, cancelable) = CancelationToken::new();
+	/// let (_sender, receiver) = mpsc::channel::<u32>();
+	/// 
+	/// let worker = std::thread::spawn(move || {
+	///     cancelable.blocking_recv_or_canceled(&receiver, Duration::from_millis(10))
+	/// });
+	/// 
+	/// cancelation_token.cancel();
+	/// assert_eq!(worker.join().unwrap(), Err(RecvCanceled::Canceled));
+	/// ```
+	#[cfg(not(target_arch = "wasm32"))]
+	pub fn blocking_recv_or_canceled<T>(&self, receiver: &std::sync::mpsc::Receiver<T>, poll_interval: std::time::Duration) -> Result<T, RecvCanceled> {
+		use std::sync::mpsc::RecvTimeoutError;
+
+		loop {
+			if self.is_canceled() {
+				return Err(RecvCanceled::Canceled);
+			}
+
+			match receiver.recv_timeout(poll_interval) {
+				Ok(item) => return Ok(item),
+				Err(RecvTimeoutError::Timeout) => continue,
+				Err(RecvTimeoutError::Disconnected) => return Err(RecvCanceled::Closed)
+			}
+		}
+	}
+
+	/// Returns a [crossbeam-channel](https://docs.rs/crossbeam-channel) receiver that disconnects when the
+	/// [`CancelationToken`](struct.CancelationToken.html) is canceled, so that cancelation can be an arm of `select!`
+	/// alongside data channels on threads that aren't async. Receiving returns an error once it's disconnected; no
+	/// message is ever sent. Requires the `crossbeam-channel` feature
+	/// 
+	/// If the token is already canceled, the receiver is already disconnected. After
+	/// [`reset()`](struct.CancelationToken.html#method.reset), call this again to wait for the next cancelation
+	/// 
+	/// ```
+	/// use crossbeam_channel::select;
+	/// use sync_tokens::cancelation_token::CancelationToken;
+	/// 
+	/// let (cancelation_token, cancelable) = CancelationToken::new();
+	/// let (sender, receiver) = crossbeam_channel::unbounded::<u32>();
+	/// 
+	/// let worker = std::thread::spawn(move || {
+	///     let canceled = cancelable.crossbeam_receiver();
+	///     let mut received = 0;
+	/// 
+	///     loop {
+	///         select! {
+	///             recv(receiver) -> _ => received += 1,
+	///             recv(canceled) -> _ => return received
+	///         }
+	///     }
+	/// });
+	/// 
+	/// sender.send(1).unwrap();
+	/// cancelation_token.cancel();
+	/// 
+	/// worker.join().unwrap();
+	/// ```
+	#[cfg(feature = "crossbeam-channel")]
+	#[cfg_attr(feature = "docs", doc(cfg(feature = "crossbeam-channel")))]
+	pub fn crossbeam_receiver(&self) -> crossbeam_channel::Receiver<()> {
+		let mut shared_state = self.shared_state.lock().unwrap();
+
+		if self.shared_state.is_canceled() {
+			let (_, receiver) = crossbeam_channel::bounded(0);
+			return receiver;
+		}
+
+		let (_, receiver) = shared_state.crossbeam_channel.get_or_insert_with(|| crossbeam_channel::bounded(0));
+		receiver.clone()
+	}
+
+	/// Returns a [`Cancelable`](struct.Cancelable.html) that's canceled when stop_token, from the
+	/// [stop-token](https://docs.rs/stop-token) crate, stops. Requires the `stop-token` feature
+	/// 
+	/// stop_token wakes the token directly when it stops, so cancelation is prompt, without a thread or a runtime.
+	/// stop_token is dropped once every clone of the returned [`Cancelable`](struct.Cancelable.html) is dropped
+	/// 
+	/// ```
+	/// use stop_token::StopSource;
+	/// use sync_tokens::cancelation_token::Cancelable;
+	/// 
+	/// # async_std::task::block_on(async {
+	/// let stop_source = StopSource::new();
+	/// let cancelable = Cancelable::from_stop_token(stop_source.token());
+	/// 
+	/// drop(stop_source);
+	/// cancelable.future().await;
+	/// # });
+	/// ```
+	#[cfg(feature = "stop-token")]
+	#[cfg_attr(feature = "docs", doc(cfg(feature = "stop-token")))]
+	pub fn from_stop_token(stop_token: stop_token::StopToken) -> Cancelable {
+		let (cancelation_token, cancelable) = CancelationToken::new();
+
+		let bridge = std::task::Waker::from(Arc::new(StopTokenBridge(ChildToken {
+			shared_state: Arc::downgrade(&cancelation_token.shared_state)
+		})));
+
+		let mut stop_token = stop_token;
+		if Pin::new(&mut stop_token).poll(&mut Context::from_waker(&bridge)).is_ready() {
+			cancelation_token.cancel();
+		} else {
+			// Kept until the cancelables are dropped, so that the bridge stays registered
+			cancelation_token.on_cancelables_dropped(move || drop(stop_token));
+		}
+
+		cancelable
+	}
+
+	/// Returns a [`StopToken`](https://docs.rs/stop-token/latest/stop_token/struct.StopToken.html), from the
+	/// [stop-token](https://docs.rs/stop-token) crate, that stops when the [`CancelationToken`](struct.CancelationToken.html)
+	/// is canceled. This lets code that uses stop-token observe the cancelation. Requires the `stop-token` feature
+	/// 
+	/// If the token is already canceled, the returned token is already stopped. After
+	/// [`reset()`](struct.CancelationToken.html#method.reset), call this again to wait for the next cancelation
+	/// 
+	/// ```
+	/// use sync_tokens::cancelation_token::CancelationToken;
+	/// 
+	/// # async_std::task::block_on(async {
+	/// let (cancelation_token, cancelable) = CancelationToken::new();
+	/// let stop_token = cancelable.stop_token();
+	/// 
+	/// cancelation_token.cancel();
+	/// stop_token.await;
+	/// # });
+	/// ```
+	#[cfg(feature = "stop-token")]
+	#[cfg_attr(feature = "docs", doc(cfg(feature = "stop-token")))]
+	pub fn stop_token(&self) -> stop_token::StopToken {
+		let mut shared_state = self.shared_state.lock().unwrap();
+
+		if self.shared_state.is_canceled() {
+			return stop_token::StopSource::new().token();
+		}
+
+		shared_state.stop_source.get_or_insert_with(stop_token::StopSource::new).token()
+	}
+
+	/// Returns a stream that yields an item each time the [`CancelationToken`](struct.CancelationToken.html) is canceled.
+	/// Unless the token is [`reset()`](struct.CancelationToken.html#method.reset) and canceled again, the stream
+	/// yields exactly one item. The stream never ends
+	pub fn into_stream(self) -> CancelationStream {
+		CancelationStream {
+			cancelable: self,
+			yielded: 0,
+			waker_key: None
+		}
+	}
+
+	/// Blocks the current thread until every one of cancelables is canceled. Intended for orchestration code that
+	/// canceled several subsystems, and waits until each of their tokens confirms the cancelation. The thread is
+	/// parked, instead of polling, until the last one is canceled.
+	/// 
+	/// Not available on wasm, which doesn't have threads
+	/// 
+	/// ```
+	/// use sync_tokens::cancelation_token::{Cancelable, CancelationToken};
+	/// 
+	/// let (cancelation_tokens, cancelables): (Vec<_>, Vec<_>) = (0..3).map(|_| CancelationToken::new()).unzip();
+	/// 
+	/// std::thread::spawn(move || {
+	///     for cancelation_token in cancelation_tokens {
+	///         cancelation_token.cancel();
+	///     }
+	/// });
+	/// 
+	/// Cancelable::block_until_all_canceled(&cancelables);
+	/// assert!(cancelables.iter().all(Cancelable::is_canceled));
+	/// ```
+	#[cfg(not(target_arch = "wasm32"))]
+	pub fn block_until_all_canceled(cancelables: &[Cancelable]) {
+		let counter = Arc::new(CancelCounter {
+			thread: std::thread::current(),
+			remaining: AtomicUsize::new(cancelables.len())
+		});
+
+		// Kept until every cancelable is canceled, so that their wakers stay registered
+		let _futures: Vec<CancelationTokenFuture> = cancelables.iter().map(|cancelable| {
+			let count_waker = Arc::new(CountWaker {
+				counter: counter.clone(),
+				counted: AtomicBool::new(false)
+			});
+
+			let waker = futures::task::waker(count_waker.clone());
+			let mut future = cancelable.future();
+
+			if Pin::new(&mut future).poll(&mut Context::from_waker(&waker)).is_ready() {
+				count_waker.count();
+			}
+
+			future
+		}).collect();
+
+		while counter.remaining.load(Ordering::Acquire) > 0 {
+			std::thread::park();
+		}
+	}
+
+	/// Wraps this [`Cancelable`](struct.Cancelable.html), so that pre_hook is called every time
+	/// [`allow_cancel()`](struct.HookedCancelable.html#method.allow_cancel) is called. pre_hook runs when the
+	/// operation starts, not when it's canceled, so middleware can log entry points, record metrics, or run checks
+	/// 
+	/// ```
+	/// use std::sync::Arc;
+	/// use std::sync::atomic::{AtomicUsize, Ordering};
+	/// 
+	/// use sync_tokens::cancelation_token::CancelationToken;
+	/// 
+	/// # async_std::task::block_on(async {
+	/// let (cancelation_token, cancelable) = CancelationToken::new();
+	/// let operations_started = Arc::new(AtomicUsize::new(0));
+	/// 
+	/// let hooked_cancelable = {
+	///     let operations_started = operations_started.clone();
+	///     cancelable.with_pre_hook(move || { operations_started.fetch_add(1, Ordering::Relaxed); })
+	/// };
+	/// 
+	/// hooked_cancelable.allow_cancel(Box::pin(async { "finished" }), "canceled").await;
+	/// assert_eq!(operations_started.load(Ordering::Relaxed), 1);
+	/// # });
+	/// ```
+	pub fn with_pre_hook<F>(self, pre_hook: F) -> HookedCancelable where
+	F: Fn() + Send + Sync + 'static {
+		HookedCancelable {
+			cancelable: self,
+			pre_hook: Arc::new(pre_hook)
+		}
+	}
+
+	/// Returns [`Canceled`](struct.Canceled.html) if the [`CancelationToken`](struct.CancelationToken.html) is
+	/// canceled. Otherwise, yields to the executor once, so that other tasks can run, and then checks again. Call this
+	/// between units of CPU-heavy work, so that it stops promptly when canceled. See the
+	/// [`#[cancelable]`](../attr.cancelable.html) attribute, with the `macros` feature, to insert these automatically
+	/// 
+	/// ```
+	/// use sync_tokens::cancelation_token::{Canceled, CancelationToken};
+	/// 
+	/// # async_std::task::block_on(async {
+	/// let (cancelation_token, cancelable) = CancelationToken::new();
+	/// 
+	/// assert_eq!(cancelable.checkpoint().await, Ok(()));
+	/// cancelation_token.cancel();
+	/// assert_eq!(cancelable.checkpoint().await, Err(Canceled));
+	/// # });
+	/// ```
+	pub async fn checkpoint(&self) -> Result<(), Canceled> {
+		if self.is_canceled() {
+			return Err(Canceled);
+		}
+
+		let mut yielded = false;
+		futures::future::poll_fn(|cx| {
+			if yielded {
+				Poll::Ready(())
+			} else {
+				yielded = true;
+				cx.waker().wake_by_ref();
+				Poll::Pending
+			}
+		}).await;
+
+		if self.is_canceled() {
+			Err(Canceled)
+		} else {
+			Ok(())
+		}
+	}
+
+	/// Returns a [`SyncChecker`](struct.SyncChecker.html) that checks for cancelation without taking a lock, so that
+	/// CPU-bound code, such as a rayon parallel iterator, can stop early
+	/// 
+	/// ```
+	/// use rayon::prelude::*;
+	/// use sync_tokens::cancelation_token::{Canceled, CancelationToken};
+	/// 
+	/// let (cancelation_token, cancelable) = CancelationToken::new();
+	/// let checker = cancelable.sync_checker();
+	/// 
+	/// let result = (0..1_000_000u64).into_par_iter().try_for_each(|i| {
+	///     checker.bail::<Canceled>()?;
+	/// 
+	///     if i == 1000 {
+	///         cancelation_token.cancel();
+	///     }
+	/// 
+	///     Ok(())
+	/// });
+	/// 
+	/// assert_eq!(result, Err(Canceled));
+	/// ```
+	pub fn sync_checker(&self) -> SyncChecker {
+		SyncChecker {
+			shared_state: self.shared_state.clone()
+		}
+	}
+
+	/// Returns an [`AbortHandle`](https://docs.rs/futures/latest/futures/future/struct.AbortHandle.html) that aborts
+	/// when the [`CancelationToken`](struct.CancelationToken.html) is canceled, and its
+	/// [`CancelAbortRegistration`](struct.CancelAbortRegistration.html), which wraps a future in futures-rs's
+	/// [`Abortable`](https://docs.rs/futures/latest/futures/future/struct.Abortable.html)
+	///
+	/// The handle aborts as soon as the token is canceled, without a background task. If the token is already
+	/// canceled, the handle is already aborted. The handle can still abort on its own. The token only keeps the handle
+	/// until the wrapped future finishes, or is dropped, so a long-lived token doesn't collect handles
+	///
+	/// ```
+	/// use futures::future::Aborted;
+	/// use sync_tokens::cancelation_token::CancelationToken;
+	///
+	/// # async_std::task::block_on(async {
+	/// let (cancelation_token, cancelable) = CancelationToken::new();
+	/// let (_abort_handle, abort_registration) = cancelable.into_abort_handle();
+	///
+	/// let abortable = abort_registration.abortable(futures::future::pending::<()>());
+	///
+	/// cancelation_token.cancel();
+	/// assert_eq!(abortable.await, Err(Aborted));
+	/// # });
+	/// ```
+	pub fn into_abort_handle(self) -> (AbortHandle, CancelAbortRegistration) {
+		let (abort_handle, abort_registration) = AbortHandle::new_pair();
+		let mut shared_state = self.shared_state.lock().unwrap();
+
+		let registered = if self.shared_state.is_canceled() {
+			abort_handle.abort();
+			None
+		} else {
+			let key = shared_state.next_abort_key;
+			shared_state.next_abort_key += 1;
+			shared_state.abort_handles.insert(key, abort_handle.clone());
+
+			Some(AbortHandleKey {
+				shared_state: self.shared_state.clone(),
+				key
+			})
+		};
+
+		(abort_handle, CancelAbortRegistration { abort_registration, registered })
+	}
+
+	/// Returns a [`BoxCancelable`](../box_cancelable/struct.BoxCancelable.html), so that this can be stored with other
+	/// kinds of cancelation
+	pub fn boxed(self) -> BoxCancelable {
+		BoxCancelable::new(self)
+	}
+
+	/// Returns true once the [`CancelationToken`](struct.CancelationToken.html) is canceled
+	pub fn is_canceled(&self) -> bool {
+		self.state() == CancelationState::Canceled
+	}
+
+	/// Returns true once the operation is soft-canceled with a
+	/// [`SoftCancelationToken`](../soft_cancelation_token/struct.SoftCancelationToken.html), or canceled. Long-running
+	/// loops check this between units of work, so that they can stop gracefully
+	pub fn is_soft_canceled(&self) -> bool {
+		self.shared_state.is_canceled() || self.shared_state.lock().unwrap().soft_canceled
+	}
+
+	/// Returns whether the [`CancelationToken`](struct.CancelationToken.html) is canceled. Never takes a lock
+	pub fn state(&self) -> CancelationState {
+		self.shared_state.cancelation_state()
+	}
+
+	/// Returns the message given to
+	/// [`CancelationToken::cancel_with_message()`](struct.CancelationToken.html#method.cancel_with_message). Returns
+	/// None if the operation isn't canceled, or was canceled without a message
+	pub fn cancel_reason(&self) -> Option<String> {
+		self.shared_state.lock().unwrap().reason.clone()
+	}
+
+	/// Returns the policy given to
+	/// [`PolicyCancelationToken::cancel()`](../policy_cancelation_token/struct.PolicyCancelationToken.html#method.cancel).
+	/// Returns None if the operation isn't canceled, was canceled without a policy, or the policy isn't a P
+	pub fn cancel_policy<P>(&self) -> Option<P> where
+	P: Clone + 'static {
+		self.shared_state.lock().unwrap().policy.as_ref().and_then(|policy| policy.downcast_ref::<P>()).cloned()
+	}
+
+	/// Returns the id shown when this cancelable is displayed. The id is the same as the matching
+	/// [`CancelationToken`](struct.CancelationToken.html)'s
+	pub fn fmt_id(&self) -> u64 {
+		self.shared_state.lock().unwrap().id
+	}
+}
+
+impl Future for CancelationTokenFuture {
+	type Output = ();
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+		let this = self.get_mut();
+		this.shared_state.poll_canceled(&mut this.waker_key, cx)
+	}
+}
+
+impl HookedCancelable {
+	/// Calls the hook, and then allows canceling the future. The same as
+	/// [`Cancelable::allow_cancel()`](struct.Cancelable.html#method.allow_cancel)
+	pub fn allow_cancel<TFuture, T>(&self, future: TFuture, canceled_result: T) -> CancelableFuture<TFuture, T> where
+	TFuture: Future<Output = T> + Unpin {
+		(self.pre_hook)();
+		self.cancelable.allow_cancel(future, canceled_result)
+	}
+
+	/// Returns a future that returns once the [`CancelationToken`](struct.CancelationToken.html) is canceled. Doesn't
+	/// call the hook
+	pub fn future(&self) -> CancelationTokenFuture {
+		self.cancelable.future()
+	}
+
+	/// Returns true once the [`CancelationToken`](struct.CancelationToken.html) is canceled
+	pub fn is_canceled(&self) -> bool {
+		self.cancelable.is_canceled()
+	}
+
+	/// Returns the wrapped [`Cancelable`](struct.Cancelable.html)
+	pub fn into_inner(self) -> Cancelable {
+		self.cancelable
+	}
+}
+
+impl SyncChecker {
+	/// Returns true once the [`CancelationToken`](struct.CancelationToken.html) is canceled. Never takes a lock
+	pub fn is_canceled(&self) -> bool {
+		self.shared_state.is_canceled()
+	}
+
+	/// Returns an error once the [`CancelationToken`](struct.CancelationToken.html) is canceled, so that `?` stops
+	/// the work, for example, in rayon's `try_for_each()`
+	pub fn bail<E>(&self) -> Result<(), E> where
+	E: From<Canceled> {
+		if self.is_canceled() {
+			Err(Canceled.into())
+		} else {
+			Ok(())
+		}
+	}
+}
+
+impl CancelAbortRegistration {
+	/// Wraps future, so that it's aborted when the [`CancelationToken`](struct.CancelationToken.html) is canceled, or
+	/// when the [`AbortHandle`](https://docs.rs/futures/latest/futures/future/struct.AbortHandle.html) aborts
+	pub fn abortable<F>(self, future: F) -> CancelAbortable<F> where
+	F: Future {
+		CancelAbortable {
+			abortable: Abortable::new(future, self.abort_registration),
+			registered: self.registered
+		}
+	}
+}
+
+impl<F> Future for CancelAbortable<F> where
+F: Future {
+	type Output = Result<F::Output, Aborted>;
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+		let this = self.project();
+		let result = this.abortable.poll(cx);
+
+		if result.is_ready() {
+			// Nothing left to abort
+			*this.registered = None;
+		}
+
+		result
+	}
+}
+
+impl Drop for AbortHandleKey {
+	fn drop(&mut self) {
+		let mut shared_state = self.shared_state.lock().unwrap();
+		shared_state.abort_handles.remove(&self.key);
+	}
+}
+
+impl<F, T> Future for CancelableFuture<F, T> where
+F: Future<Output = T> + Unpin {
+	type Output = T;
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+		let this = self.get_mut();
+
+		if !this.polled {
+			this.polled = true;
+
+			if this.shared_state.is_canceled() {
+				return Poll::Ready(this.finish(None));
+			}
+		}
+
+		// The lock isn't held while the future is polled, because the future might cancel the token
+		let future = this.future.as_mut().expect("CancelableFuture polled after completion");
+		if let Poll::Ready(result) = Pin::new(future).poll(cx) {
+			return Poll::Ready(this.finish(Some(result)));
+		}
+
+		match this.shared_state.poll_canceled(&mut this.waker_key, cx) {
+			Poll::Ready(()) => Poll::Ready(this.finish(None)),
+			Poll::Pending => Poll::Pending
+		}
+	}
+}
+
+impl<F, T> CancelableFuture<F, T> {
+	// Drops the future, and then returns its result, or canceled_result if there isn't one
+	fn finish(&mut self, result: Option<T>) -> T {
+		self.future = None;
+		let canceled_result = self.canceled_result.take().expect("CancelableFuture polled after completion");
+		result.unwrap_or(canceled_result)
+	}
+}
+
+// Only the inner future is ever polled, so the canceled result doesn't need to be Unpin
+impl<F, T> Unpin for CancelableFuture<F, T> where
+F: Unpin {}
+
+impl<F, T> Drop for CancelableFuture<F, T> {
+	fn drop(&mut self) {
+		if self.waker_key.is_some() {
+			let mut shared_state = self.shared_state.lock().unwrap();
+			shared_state.wakers.remove(self.waker_key);
+		}
+	}
+}
+
+#[cfg(feature = "diagnostics")]
+impl Future for NamedCancelationTokenFuture {
+	type Output = ();
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+		let this = self.get_mut();
+		let poll = Pin::new(&mut this.future).poll(cx);
+
+		// Named after registering; if cancel() woke the waker in between, the stale key names nothing
+		if poll.is_pending() {
+			let mut shared_state = this.future.shared_state.lock().unwrap();
+			shared_state.wakers.set_name(this.future.waker_key, this.name);
+		}
+
+		poll
+	}
+}
+
+impl<T> Future for CancelationValueFuture<T> {
+	type Output = T;
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+		let this = self.get_mut();
+
+		match Pin::new(&mut this.future).poll(cx) {
+			Poll::Ready(()) => Poll::Ready(this.value.take().expect("CancelationValueFuture polled after it returned")),
+			Poll::Pending => Poll::Pending
+		}
+	}
+}
+
+impl<T> FusedFuture for CancelationValueFuture<T> {
+	fn is_terminated(&self) -> bool {
+		self.value.is_none()
+	}
+}
+
+// The value is never pinned
+impl<T> Unpin for CancelationValueFuture<T> {}
+
+impl CancelationTokenFuture {
+	pub(crate) fn is_canceled(&self) -> bool {
+		self.shared_state.is_canceled()
+	}
+}
+
+impl Clone for CancelationTokenFuture {
+	fn clone(&self) -> Self {
+		// The clone registers its own waker the first time it's polled
+		CancelationTokenFuture {
+			shared_state: self.shared_state.clone(),
+			waker_key: None
+		}
+	}
+}
+
+impl Drop for CancelationTokenFuture {
+	fn drop(&mut self) {
+		if self.waker_key.is_some() {
+			let mut shared_state = self.shared_state.lock().unwrap();
+			shared_state.wakers.remove(self.waker_key);
+		}
+	}
+}
+
+// Only shows the id and state, so that debug output is short and, with fixed ids, stable
+impl fmt::Debug for CancelationToken {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("CancelationToken")
+			.field("id", &self.fmt_id())
+			.field("state", &self.state())
+			.finish()
+	}
+}
+
+impl fmt::Debug for Cancelable {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("Cancelable")
+			.field("id", &self.fmt_id())
+			.field("state", &self.state())
+			.finish()
+	}
+}
+
+/// Displays as `CancelationToken(id=42, state=active)` or `CancelationToken(id=42, state=canceled)`
+impl fmt::Display for CancelationToken {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let shared_state = self.shared_state.lock().unwrap();
+		write!(f, "CancelationToken(id={}, state={})", shared_state.id, self.shared_state.state_name())
+	}
+}
+
+/// Displays as `Cancelable(id=42, state=active)` or `Cancelable(id=42, state=canceled)`
+impl fmt::Display for Cancelable {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let shared_state = self.shared_state.lock().unwrap();
+		write!(f, "Cancelable(id={}, state={})", shared_state.id, self.shared_state.state_name())
+	}
+}
+
+#[cfg(feature = "opentelemetry")]
+fn record_cancel_event(shared_state: &CancelationTokenState) {
+	use opentelemetry::KeyValue;
+	use opentelemetry::trace::TraceContextExt;
+
+	if let Some(trace_context) = &shared_state.trace_context {
+		trace_context.span().add_event("cancellation_token.canceled", vec![
+			KeyValue::new("cancellation_token.id", shared_state.id as i64),
+			KeyValue::new("cancellation_token.cancel_count", shared_state.cancel_count as i64)
+		]);
+	}
+}
+
+impl SharedState {
+	fn new(state: CancelationTokenState) -> SharedState {
+		SharedState {
+			#[cfg(not(feature = "crossbeam"))]
+			canceled: AtomicBool::new(false),
+			#[cfg(feature = "crossbeam")]
+			canceled: AtomicCell::new(CancelationState::Active),
+			state: Mutex::new(state)
+		}
+	}
+
+	fn lock(&self) -> LockResult<MutexGuard<'_, CancelationTokenState>> {
+		self.state.lock()
+	}
+
+	// Returns true if the flag wasn't already stored
+	#[cfg(not(feature = "crossbeam"))]
+	fn set_canceled(&self) -> bool {
+		!self.canceled.swap(true, Ordering::AcqRel)
+	}
+
+	#[cfg(feature = "crossbeam")]
+	fn set_canceled(&self) -> bool {
+		self.canceled.swap(CancelationState::Canceled) == CancelationState::Active
+	}
+
+	#[cfg(not(feature = "crossbeam"))]
+	fn clear_canceled(&self) {
+		self.canceled.store(false, Ordering::Release);
+	}
+
+	#[cfg(feature = "crossbeam")]
+	fn clear_canceled(&self) {
+		self.canceled.store(CancelationState::Active);
+	}
+
+	#[cfg(not(feature = "crossbeam"))]
+	fn is_canceled(&self) -> bool {
+		self.canceled.load(Ordering::Acquire)
+	}
+
+	#[cfg(feature = "crossbeam")]
+	fn is_canceled(&self) -> bool {
+		self.canceled.load() == CancelationState::Canceled
+	}
+
+	fn poll_canceled(&self, waker_key: &mut Option<WakerKey>, cx: &mut Context<'_>) -> Poll<()> {
+		if self.is_canceled() {
+			// A waker that's still registered is removed by cancel()'s wake pass, so the lock isn't needed to forget it
+			*waker_key = None;
+			return Poll::Ready(());
+		}
+
+		let mut shared_state = self.lock().unwrap();
+
+		// Checked again while holding the lock, in case cancel() already woke every registered waker
+		if self.is_canceled() {
+			shared_state.wakers.remove(waker_key.take());
+			Poll::Ready(())
+		} else {
+			shared_state.wakers.register(waker_key, cx.waker());
+			Poll::Pending
+		}
+	}
+
+	fn cancelation_state(&self) -> CancelationState {
+		if self.is_canceled() {
+			CancelationState::Canceled
+		} else {
+			CancelationState::Active
+		}
+	}
+
+	fn state_name(&self) -> &'static str {
+		if self.is_canceled() {
+			"canceled"
+		} else {
+			"active"
+		}
+	}
+}
+
+impl Stream for CancelationStream {
+	type Item = ();
+
+	fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+		let this = self.get_mut();
+		let mut shared_state = this.cancelable.shared_state.lock().unwrap();
+
+		if this.yielded < shared_state.cancel_count {
+			this.yielded += 1;
+			Poll::Ready(Some(()))
+		} else {
+			shared_state.wakers.register(&mut this.waker_key, cx.waker());
+			Poll::Pending
+		}
+	}
+}
+
+impl Drop for CancelationStream {
+	fn drop(&mut self) {
+		if self.waker_key.is_some() {
+			let mut shared_state = self.cancelable.shared_state.lock().unwrap();
+			shared_state.wakers.remove(self.waker_key);
+		}
+	}
+}
+
+impl<S> CancelableSink<S> {
+	/// Sets whether closing is allowed after the [`CancelationToken`](struct.CancelationToken.html) is canceled. When
+	/// it isn't, closing returns [`CancelableSinkError::Canceled`](enum.CancelableSinkError.html) too. Defaults to true
+	pub fn allow_close_after_cancel(mut self, allow_close: bool) -> CancelableSink<S> {
+		self.allow_close = allow_close;
+		self
+	}
+
+	/// Returns the inner sink
+	pub fn into_inner(self) -> S {
+		self.sink
+	}
+}
+
+impl<S, Item> Sink<Item> for CancelableSink<S> where
+S: Sink<Item> {
+	type Error = CancelableSinkError<S::Error>;
+
+	fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+		let this = self.project();
+
+		// Polled first, so that waiting on a full sink is woken by cancelation
+		if Pin::new(this.canceled).poll(cx).is_ready() {
+			return Poll::Ready(Err(CancelableSinkError::Canceled));
+		}
+
+		this.sink.poll_ready(cx).map_err(CancelableSinkError::Sink)
+	}
+
+	fn start_send(self: Pin<&mut Self>, item: Item) -> Result<(), Self::Error> {
+		let this = self.project();
+
+		if this.canceled.shared_state.is_canceled() {
+			return Err(CancelableSinkError::Canceled);
+		}
+
+		this.sink.start_send(item).map_err(CancelableSinkError::Sink)
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+		let this = self.project();
+
+		if Pin::new(this.canceled).poll(cx).is_ready() {
+			return Poll::Ready(Err(CancelableSinkError::Canceled));
+		}
+
+		this.sink.poll_flush(cx).map_err(CancelableSinkError::Sink)
+	}
+
+	fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+		let this = self.project();
+
+		if !*this.allow_close && Pin::new(this.canceled).poll(cx).is_ready() {
+			return Poll::Ready(Err(CancelableSinkError::Canceled));
+		}
+
+		this.sink.poll_close(cx).map_err(CancelableSinkError::Sink)
+	}
+}
+
+impl<E> From<Canceled> for CancelableSinkError<E> {
+	fn from(_: Canceled) -> Self {
+		CancelableSinkError::Canceled
+	}
+}
+
+impl<E> fmt::Display for CancelableSinkError<E> where
+E: fmt::Display {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			CancelableSinkError::Canceled => write!(f, "{}", Canceled),
+			CancelableSinkError::Sink(err) => err.fmt(f)
+		}
+	}
+}
+
+impl<E> Error for CancelableSinkError<E> where
+E: Error + 'static {
+	fn source(&self) -> Option<&(dyn Error + 'static)> {
+		match self {
+			CancelableSinkError::Canceled => None,
+			CancelableSinkError::Sink(err) => Some(err)
+		}
+	}
+}
+
+impl fmt::Display for RecvCanceled {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			RecvCanceled::Canceled => write!(f, "Receiving was canceled"),
+			RecvCanceled::Closed => write!(f, "The channel is closed")
+		}
+	}
+}
+
+impl Error for RecvCanceled {}
+
+impl fmt::Display for Canceled {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "The operation was canceled")
+	}
+}
+
+impl Error for Canceled {}
+
+impl Clone for CancelationToken {
+	fn clone(&self) -> Self {
+		CancelationToken {
+			shared_state: self.shared_state.clone(),
+			cancel_on_drop: self.cancel_on_drop.clone()
+		}
+	}
+}
+
+impl Clone for Cancelable {
+	fn clone(&self) -> Self {
+		self.shared_state.lock().unwrap().cancelable_count += 1;
+
+		Cancelable {
+			shared_state: self.shared_state.clone(),
+			waker_key: Mutex::new(None)
+		}
+	}
+}
+
+impl Drop for Cancelable {
+	fn drop(&mut self) {
+		let hook = {
+			let mut shared_state = self.shared_state.lock().unwrap();
+			shared_state.cancelable_count -= 1;
+
+			let waker_key = self.waker_key.get_mut().unwrap();
+			if waker_key.is_some() {
+				shared_state.wakers.remove(*waker_key);
+			}
+
+			if shared_state.cancelable_count == 0 {
+				shared_state.cancelables_dropped_hook.take()
+			} else {
+				None
+			}
+		};
+
+		if let Some(DropHook(hook)) = hook {
+			hook();
+		}
+	}
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl CountWaker {
+	fn count(&self) {
+		if !self.counted.swap(true, Ordering::AcqRel) {
+			self.counter.remaining.fetch_sub(1, Ordering::AcqRel);
+			self.counter.thread.unpark();
+		}
+	}
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl futures::task::ArcWake for CountWaker {
+	fn wake_by_ref(arc_self: &Arc<Self>) {
+		arc_self.count();
+	}
+}
+
+impl WeakCancelationToken {
+	/// Cancels the token, unless every clone of it, and of its [`Cancelable`](struct.Cancelable.html), is gone
+	pub(crate) fn cancel(&self) {
+		if let Some(cancelation_token) = self.child_token.upgrade() {
+			cancelation_token.cancel();
+		}
+	}
+
+	/// Ready once the token is canceled, or once nothing can observe it anymore
+	pub(crate) fn poll_canceled(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+		match self.child_token.shared_state.upgrade() {
+			Some(shared_state) => shared_state.poll_canceled(&mut self.waker_key, cx),
+			None => Poll::Ready(())
+		}
+	}
+}
+
+impl Drop for WeakCancelationToken {
+	fn drop(&mut self) {
+		if self.waker_key.is_some() {
+			if let Some(shared_state) = self.child_token.shared_state.upgrade() {
+				shared_state.lock().unwrap().wakers.remove(self.waker_key);
+			}
+		}
+	}
+}
+
+impl ChildToken {
+	fn upgrade(&self) -> Option<CancelationToken> {
+		Some(CancelationToken {
+			shared_state: self.shared_state.upgrade()?,
+			cancel_on_drop: None
+		})
+	}
+}
+
+#[cfg(feature = "stop-token")]
+impl std::task::Wake for StopTokenBridge {
+	fn wake(self: Arc<Self>) {
+		// Nothing can be sent to a stop token, so it's only woken when its source is dropped. The stop token isn't
+		// polled again to check, because it wakes while holding its own lock
+		if let Some(cancelation_token) = self.0.upgrade() {
+			cancelation_token.cancel();
+		}
+	}
+}
+
+impl Drop for CancelOnDrop {
+	fn drop(&mut self) {
+		// Nothing to cancel if every Cancelable is gone too
+		if let Some(cancelation_token) = self.0.upgrade() {
+			cancelation_token.cancel();
+		}
+	}
+}
+
+impl fmt::Debug for HookedCancelable {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("HookedCancelable")
+			.field("cancelable", &self.cancelable)
+			.finish()
+	}
+}
+
+impl fmt::Debug for DropHook {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "DropHook")
+	}
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::prelude::*;
+	use futures::future;
+	use futures::stream::StreamExt;
+	use std::task::{Context, Waker};
+
+    use cooked_waker::IntoWaker;
+
+	use super::*;
+	use crate::tests::*;
+
+	fn assert_send_sync<T: Send + Sync>() {}
+
+	// Fails to compile if a change to the internals loses Send or Sync
+	#[test]
+	fn test_send_sync() {
+		assert_send_sync::<CancelationToken>();
+		assert_send_sync::<Cancelable>();
+		assert_send_sync::<CancelationTokenFuture>();
+		assert_send_sync::<CancelationStream>();
+		assert_send_sync::<HookedCancelable>();
+		assert_send_sync::<SyncChecker>();
+		assert_send_sync::<Canceled>();
+		assert_send_sync::<CancelableFuture<future::Ready<u32>, u32>>();
+	}
+
+	// CancelableFuture is Send and Sync whenever the future and the canceled result are
+	#[allow(dead_code)]
+	fn cancelable_future_is_send_sync<F, T>() where
+	F: Send + Sync,
+	T: Send + Sync {
+		assert_send_sync::<CancelableFuture<F, T>>();
+	}
+
+	fn assert_not_canceled_no_waker(shared_state: &Arc<SharedState>) {
+		assert!(!shared_state.is_canceled(), "Canceled should be false at construction");
+		let shared_state = shared_state.lock().unwrap();
+		assert!(shared_state.wakers.is_empty(), "Waker should not be set");
+	}
+
+	fn assert_not_canceled_waker_set(shared_state: &Arc<SharedState>) {
+		assert!(!shared_state.is_canceled(), "Canceled should be false");
+		let shared_state = shared_state.lock().unwrap();
+		assert!(!shared_state.wakers.is_empty(), "Waker should be set");
+	}
+
+	fn assert_canceled(shared_state: &Arc<SharedState>) {
+		assert!(shared_state.is_canceled(), "Canceled should be true");
+		let shared_state = shared_state.lock().unwrap();
+		assert!(shared_state.wakers.is_empty(), "Waker should be set");
+	}
+
+    #[test]
+    fn test_via_poll() {
+
+		let (cancelation_token, cancelable) = CancelationToken::new();
+		let mut future = cancelable.future();
+		let pinned_future = Pin::new(&mut future);
+
+		let shared_state = cancelation_token.shared_state.clone();
+
+		assert_not_canceled_no_waker(&shared_state);
+
+		let test_waker = TestWaker::new();
+		let waker = test_waker.clone().into_waker();
+		let mut cx = Context::from_waker(&waker);
+
+		let poll_result = pinned_future.poll(&mut cx);
+		assert!(poll_result.is_pending(), "Cancelation token should be pending");
+
+		assert_not_canceled_waker_set(&shared_state);
+
+		cancelation_token.cancel();
+
+		assert_canceled(&shared_state);
+
+		let pinned_future = Pin::new(&mut future);
+
+		let poll_result = pinned_future.poll(&mut cx);
+		assert!(poll_result.is_ready(), "Cancelation token should be ready");
+
+		assert_canceled(&shared_state);
+	}
+
+	// Futures dropped on other threads while they're being woken shouldn't corrupt the waker list
+	#[test]
+	fn test_drop_during_wake() {
+		for _ in 0..20 {
+			let (cancelation_token, cancelable) = CancelationToken::new();
+
+			let waiting: Vec<_> = (0..8).map(|i| {
+				let cancelable = cancelable.clone();
+				std::thread::spawn(move || {
+					let mut cancelation_token_futures: Vec<_> = (0..100).map(|_| cancelable.future()).collect();
+
+					let test_waker = TestWaker::new();
+					let waker = test_waker.into_waker();
+					let mut cx = Context::from_waker(&waker);
+
+					for future in cancelation_token_futures.iter_mut() {
+						let _ = Pin::new(future).poll(&mut cx);
+					}
+
+					// Half of the threads drop their futures while the token is canceled
+					if i % 2 == 0 {
+						drop(cancelation_token_futures);
+					} else {
+						futures::executor::block_on(futures::future::join_all(cancelation_token_futures));
+					}
+				})
+			}).collect();
+
+			cancelation_token.cancel();
+
+			for waiting in waiting {
+				waiting.join().unwrap();
+			}
+
+			assert!(cancelable.shared_state.lock().unwrap().wakers.is_empty(), "Every waker should be woken or removed");
+		}
+	}
+
+	#[test]
+	fn test_repeated_poll_doesnt_clone_waker() {
+		let (_cancelation_token, cancelable) = CancelationToken::new();
+		let mut future = cancelable.future();
+
+		let counting_waker = Arc::new(CountingWaker);
+		let waker = std::task::Waker::from(counting_waker.clone());
+		let mut cx = Context::from_waker(&waker);
+
+		for _ in 0..10 {
+			assert!(Pin::new(&mut future).poll(&mut cx).is_pending(), "Should be pending");
+		}
+
+		assert_eq!(Arc::strong_count(&counting_waker), 3, "Only one clone of the waker should be stored");
+	}
+	
+	#[test]
+	fn test_multiple_waiters() {
+
+		let (cancelation_token, cancelable) = CancelationToken::new();
+		let shared_state = cancelation_token.shared_state.clone();
+
+		let first_waker = TestWaker::new();
+		let second_waker = TestWaker::new();
+
+		let mut first_future = cancelable.future();
+		let mut second_future = cancelable.clone().future();
+
+		let waker = first_waker.clone().into_waker();
+		let poll_result = Pin::new(&mut first_future).poll(&mut Context::from_waker(&waker));
+		assert!(poll_result.is_pending(), "Cancelation token should be pending");
+
+		let waker = second_waker.clone().into_waker();
+		let poll_result = Pin::new(&mut second_future).poll(&mut Context::from_waker(&waker));
+		assert!(poll_result.is_pending(), "Cancelation token should be pending");
+
+		cancelation_token.cancel();
+
+		assert!(first_waker.woke(), "First waiter should be woken");
+		assert!(second_waker.woke(), "Second waiter should be woken");
+		assert_canceled(&shared_state);
+	}
+
+	runtime_test! {
+		async fn test_cloned_future() {
+			let (cancelation_token, cancelable) = CancelationToken::new();
+			let first_future = cancelable.future();
+			let second_future = first_future.clone();
+
+			let first_task = async_std::task::spawn(first_future);
+			let second_task = async_std::task::spawn(second_future);
+
+			async_std::task::sleep(std::time::Duration::from_millis(10)).await;
+			assert_not_canceled_waker_set(&cancelation_token.shared_state);
+
+			cancelation_token.cancel();
+
+			first_task.await;
+			second_task.await;
+			assert_canceled(&cancelation_token.shared_state);
+		}
+	}
+
+	#[test]
+	fn test_cloned_future_has_its_own_waker() {
+		let (cancelation_token, cancelable) = CancelationToken::new();
+
+		let first_waker = TestWaker::new();
+		let second_waker = TestWaker::new();
+
+		let mut first_future = cancelable.future();
+		let waker = first_waker.clone().into_waker();
+		assert!(Pin::new(&mut first_future).poll(&mut Context::from_waker(&waker)).is_pending(), "Should be pending");
+
+		let mut second_future = first_future.clone();
+		let waker = second_waker.clone().into_waker();
+		assert!(Pin::new(&mut second_future).poll(&mut Context::from_waker(&waker)).is_pending(), "Should be pending");
+
+		drop(first_future);
+		cancelation_token.cancel();
+
+		assert!(!first_waker.woke(), "The dropped future's waker shouldn't be woken");
+		assert!(second_waker.woke(), "The clone's waker should be woken");
+		assert!(Pin::new(&mut second_future).poll(&mut Context::from_waker(&waker)).is_ready(), "Should be ready");
+	}
+
+	#[test]
+	fn test_dropped_future_removes_waker() {
+
+		let (cancelation_token, cancelable) = CancelationToken::new();
+		let shared_state = cancelation_token.shared_state.clone();
+
+		let test_waker = TestWaker::new();
+		let waker = test_waker.into_waker();
+		let mut cx = Context::from_waker(&waker);
+
+		let mut future = cancelable.future();
+		let poll_result = Pin::new(&mut future).poll(&mut cx);
+		assert!(poll_result.is_pending(), "Cancelation token should be pending");
+		assert_not_canceled_waker_set(&shared_state);
+
+		drop(future);
+
+		assert_not_canceled_no_waker(&shared_state);
+	}
+
+	// Many threads poll while the token is canceled. Every waiting future must be woken, no matter how its poll
+	// interleaves with cancel()
+	#[test]
+	fn test_cancel_while_polled_by_many_threads() {
+		const WAITERS: usize = 8;
+		const POLLERS: usize = 8;
+
+		for _ in 0..100 {
+			let (cancelation_token, cancelable) = CancelationToken::new();
+			let (sender, receiver) = std::sync::mpsc::channel();
+
+			for _ in 0..WAITERS {
+				let cancelable = cancelable.clone();
+				let sender = sender.clone();
+
+				std::thread::spawn(move || {
+					futures::executor::block_on(cancelable.future());
+					sender.send(()).unwrap();
+				});
+			}
+
+			for _ in 0..POLLERS {
+				let mut future = cancelable.future();
+				let sender = sender.clone();
+
+				std::thread::spawn(move || {
+					let waker = futures::task::noop_waker();
+					let mut cx = Context::from_waker(&waker);
+
+					while Pin::new(&mut future).poll(&mut cx).is_pending() {}
+					sender.send(()).unwrap();
+				});
+			}
+
+			cancelation_token.cancel();
+
+			for _ in 0..(WAITERS + POLLERS) {
+				receiver.recv_timeout(std::time::Duration::from_secs(10)).expect("A future wasn't woken");
+			}
+
+			assert!(cancelable.shared_state.lock().unwrap().wakers.is_empty(), "Every waker should be woken or removed");
+		}
+	}
+
+	#[test]
+	fn test_ready_future_forgets_waker_key() {
+		let (cancelation_token, cancelable) = CancelationToken::new();
+		let mut future = cancelable.future();
+
+		let waker = TestWaker::new().into_waker();
+		let mut cx = Context::from_waker(&waker);
+
+		assert!(Pin::new(&mut future).poll(&mut cx).is_pending(), "Should be pending");
+		assert!(future.waker_key.is_some(), "The waker should be registered");
+
+		cancelation_token.cancel();
+
+		assert!(Pin::new(&mut future).poll(&mut cx).is_ready(), "Should be ready");
+		assert!(future.waker_key.is_none(), "Dropping the future shouldn't need to take the lock");
+	}
+
+	fn parse_display(display: &str) -> (&str, u64, &str) {
+		let (name, rest) = display.split_once("(id=").expect("Missing id");
+		let (id, rest) = rest.split_once(", state=").expect("Missing state");
+		let state = rest.strip_suffix(')').expect("Missing closing parenthesis");
+
+		(name, id.parse().expect("Id isn't a number"), state)
+	}
+
+	#[test]
+	fn test_display() {
+
+		let (cancelation_token, cancelable) = CancelationToken::new();
+		let id = cancelation_token.fmt_id();
+		assert_eq!(cancelable.fmt_id(), id, "Token and cancelable should share an id");
+
+		let (other_cancelation_token, _) = CancelationToken::new();
+		assert_ne!(other_cancelation_token.fmt_id(), id, "Ids should be unique");
+
+		let display = cancelation_token.to_string();
+		assert_eq!(parse_display(&display), ("CancelationToken", id, "active"), "Wrong display: {}", display);
+		assert!(!cancelation_token.is_canceled(), "Token shouldn't be canceled");
+
+		let display = cancelable.to_string();
+		assert_eq!(parse_display(&display), ("Cancelable", id, "active"), "Wrong display: {}", display);
+		assert!(!cancelable.is_canceled(), "Cancelable shouldn't be canceled");
+
+		cancelation_token.cancel();
+
+		let display = cancelation_token.to_string();
+		assert_eq!(parse_display(&display), ("CancelationToken", id, "canceled"), "Wrong display: {}", display);
+		assert!(cancelation_token.is_canceled(), "Token should be canceled");
+
+		let display = cancelable.to_string();
+		assert_eq!(parse_display(&display), ("Cancelable", id, "canceled"), "Wrong display: {}", display);
+		assert!(cancelable.is_canceled(), "Cancelable should be canceled");
+	}
+
+	runtime_test! {
+		async fn test_via_allow_cancel() {
+
+			let (cancelation_token, cancelable) = CancelationToken::new();
+			let shared_state = cancelation_token.shared_state.clone();
+
+			assert_not_canceled_no_waker(&shared_state);
+
+			let result_future = future::ready("result");
+			let result = cancelable.allow_cancel(result_future, "canceled").await;
+
+			assert_eq!(result, "result", "Future canceled incorrectly");
+
+			assert_not_canceled_no_waker(&shared_state);
+
+			cancelation_token.cancel();
+
+			assert_canceled(&shared_state);
+
+			let pending_future = future::pending();
+			let result = cancelable.allow_cancel(pending_future, "canceled").await;
+
+			assert_eq!(result, "canceled", "Future not canceled");
+		}
+	}
+
+	// Cancelable::allow_cancel used to be an async fn; CancelableFuture must behave the same
+	#[test]
+	fn test_cancelable_future() {
+
+		let test_waker = TestWaker::new();
+		let waker = test_waker.clone().into_waker();
+		let mut cx = Context::from_waker(&waker);
+
+		// Completes
+		let (_cancelation_token, cancelable) = CancelationToken::new();
+		let (completion_token, completable) = CompletionToken::new();
+		let mut cancelable_future = cancelable.allow_cancel(completion_token, "canceled");
+		assert!(Pin::new(&mut cancelable_future).poll(&mut cx).is_pending(), "Should be pending");
+
+		completable.complete("complete");
+		assert_eq!(Pin::new(&mut cancelable_future).poll(&mut cx), Poll::Ready("complete"), "Should complete");
+
+		// Canceled while pending
+		let (cancelation_token, cancelable) = CancelationToken::new();
+		let mut cancelable_future = cancelable.allow_cancel(future::pending(), "canceled");
+		assert!(Pin::new(&mut cancelable_future).poll(&mut cx).is_pending(), "Should be pending");
+
+		cancelation_token.cancel();
+		assert!(test_waker.woke(), "Canceling should wake the future");
+		assert_eq!(Pin::new(&mut cancelable_future).poll(&mut cx), Poll::Ready("canceled"), "Should be canceled");
+
+		// Canceled before the first poll, even though the future is ready
+		let (cancelation_token, cancelable) = CancelationToken::new();
+		let mut cancelable_future = cancelable.allow_cancel(future::ready("result"), "canceled");
+		cancelation_token.cancel();
+		assert_eq!(Pin::new(&mut cancelable_future).poll(&mut cx), Poll::Ready("canceled"), "Should be canceled");
+	}
+
+	runtime_test! {
+		async fn test_cancelable_future_is_nameable() {
+
+			struct Worker {
+				cancelable_future: CancelableFuture<CompletionToken<&'static str>, &'static str>
+			}
+
+			let (cancelation_token, cancelable) = CancelationToken::new();
+			let (completion_token, _completable) = CompletionToken::new();
+
+			let worker = Worker {
+				cancelable_future: cancelable.allow_cancel(completion_token, "canceled")
+			};
+
+			drop(cancelable);
+			cancelation_token.cancel();
+
+			assert_eq!(worker.cancelable_future.await, "canceled", "Should be canceled");
+		}
+	}
+
+	#[test]
+	fn test_cancelable_future_doesnt_allocate() {
+
+		let (_cancelation_token, cancelable) = CancelationToken::new();
+		let waker = TestWaker::new().into_waker();
+		let mut cx = Context::from_waker(&waker);
+
+		let mut poll_pending = || {
+			let mut cancelable_future = cancelable.allow_cancel(future::pending::<u32>(), 0);
+			assert!(Pin::new(&mut cancelable_future).poll(&mut cx).is_pending(), "Should be pending");
+		};
+
+		// Makes room for the waker
+		poll_pending();
+
+		let ((), allocations) = count_allocations(|| {
+			for _ in 0..10 {
+				poll_pending();
+			}
+		});
+
+		assert_eq!(allocations, 0, "allow_cancel shouldn't allocate");
+		assert!(cancelable.shared_state.lock().unwrap().wakers.is_empty(), "Dropping the future should remove its waker");
+	}
+
+	runtime_test! {
+		async fn test_allow_cancel_or_complete_future_wins() {
+
+			let (_cancelation_token, cancelable) = CancelationToken::new();
+			let (completion_token, completable) = CompletionToken::new();
+
+			let result = cancelable.allow_cancel_or_complete(future::ready("result"), &completion_token, "canceled").await;
+			assert_eq!(result, "result", "The future should win");
+
+			// The completion token wasn't consumed
+			completable.complete("complete");
+			assert_eq!(completion_token.await, "complete", "Completion token should still have its value");
+		}
+	}
+
+	runtime_test! {
+		async fn test_allow_cancel_or_complete_completion_wins() {
+
+			let (cancelation_token, cancelable) = CancelationToken::new();
+			let (completion_token, completable) = CompletionToken::new();
+
+			let task = async_std::task::spawn(async move {
+				cancelable.allow_cancel_or_complete(future::pending(), &completion_token, "canceled").await
+			});
+
+			async_std::task::sleep(std::time::Duration::from_millis(10)).await;
+			completable.complete("complete");
+
+			assert_eq!(task.await, "complete", "The completion token should win");
+			assert!(!cancelation_token.is_canceled(), "The cancelation token shouldn't be affected");
+		}
+	}
+
+	runtime_test! {
+		async fn test_allow_cancel_or_complete_cancel_wins() {
+
+			let (cancelation_token, cancelable) = CancelationToken::new();
+			let (completion_token, completable) = CompletionToken::new();
+
+			let task = {
+				let completion_token = completion_token.clone();
+				async_std::task::spawn(async move {
+					cancelable.allow_cancel_or_complete(future::pending(), &completion_token, "canceled").await
+				})
+			};
+
+			async_std::task::sleep(std::time::Duration::from_millis(10)).await;
+			cancelation_token.cancel();
+
+			assert_eq!(task.await, "canceled", "Cancelation should win");
+
+			// The completion token wasn't consumed
+			completable.complete("complete");
+			assert_eq!(completion_token.await, "complete", "Completion token should still have its value");
+		}
+	}
+
+	runtime_test! {
+		async fn test_allow_cancel_or_complete_ignores_abandoned() {
+
+			let (_cancelation_token, cancelable) = CancelationToken::new();
+			let (completion_token, completable) = CompletionToken::new();
+			drop(completable);
+
+			let (result_token, result_completable) = CompletionToken::new();
+			let task = async_std::task::spawn(async move {
+				cancelable.allow_cancel_or_complete(result_token, &completion_token, "canceled").await
+			});
+
+			async_std::task::sleep(std::time::Duration::from_millis(10)).await;
+			result_completable.complete("result");
+
+			assert_eq!(task.await, "result", "An abandoned completion token shouldn't win");
+		}
+	}
+
+	#[async_trait::async_trait]
+	trait Worker: Send + Sync {
+		async fn wait_for(&self, input: CompletionToken<&'static str>) -> &'static str;
+
+		async fn work(&self, cancelable: &Cancelable, input: CompletionToken<&'static str>) -> &'static str {
+			cancelable.allow_cancel_dyn(self.wait_for(input), "canceled").await
+		}
+	}
+
+	struct TestWorker;
+
+	#[async_trait::async_trait]
+	impl Worker for TestWorker {
+		async fn wait_for(&self, input: CompletionToken<&'static str>) -> &'static str {
+			input.await
+		}
+	}
+
+	runtime_test! {
+		async fn test_allow_cancel_dyn() {
+
+			let worker: Box<dyn Worker> = Box::new(TestWorker);
+			let (cancelation_token, cancelable) = CancelationToken::new();
+
+			let (input, input_completable) = CompletionToken::new();
+			input_completable.complete("result");
+			assert_eq!(worker.work(&cancelable, input).await, "result", "Future canceled incorrectly");
+
+			let (input, _input_completable) = CompletionToken::new();
+			let work = worker.work(&cancelable, input);
+
+			cancelation_token.cancel();
+			assert_eq!(work.await, "canceled", "Future not canceled");
+		}
+	}
+
+	#[test]
+	fn test_reset() {
+
+		let (cancelation_token, cancelable) = CancelationToken::new();
+		let shared_state = cancelation_token.shared_state.clone();
+
+		cancelation_token.cancel();
+		assert_canceled(&shared_state);
+
+		cancelation_token.reset();
+		assert_not_canceled_no_waker(&shared_state);
+		assert!(!cancelable.is_canceled(), "Cancelable should be reset");
+
+		let mut future = cancelable.future();
+		let waker = TestWaker::new().into_waker();
+		let poll_result = Pin::new(&mut future).poll(&mut Context::from_waker(&waker));
+		assert!(poll_result.is_pending(), "Cancelation token should be pending after reset");
+
+		cancelation_token.cancel();
+		let poll_result = Pin::new(&mut future).poll(&mut Context::from_waker(&waker));
+		assert!(poll_result.is_ready(), "Cancelation token should be canceled again");
+	}
+
+	runtime_test! {
+		async fn test_into_stream() {
+
+			let (cancelation_token, cancelable) = CancelationToken::new();
+
+			let stream_task = async_std::task::spawn(async move {
+				futures::StreamExt::count(futures::StreamExt::take(cancelable.into_stream(), 5)).await
+			});
+
+			for _ in 0..5 {
+				async_std::task::sleep(std::time::Duration::from_millis(1)).await;
+				cancelation_token.cancel();
+				cancelation_token.reset();
+			}
+
+			assert_eq!(stream_task.await, 5, "Stream should yield once per cancelation");
+		}
+	}
+
+	#[test]
+	fn test_into_stream_without_reset() {
+
+		let (cancelation_token, cancelable) = CancelationToken::new();
+		let mut stream = cancelable.into_stream();
+
+		let waker = TestWaker::new().into_waker();
+		let mut cx = Context::from_waker(&waker);
+
+		assert!(Pin::new(&mut stream).poll_next(&mut cx).is_pending(), "Stream should be pending");
+
+		cancelation_token.cancel();
+		cancelation_token.cancel();
+
+		assert_eq!(Pin::new(&mut stream).poll_next(&mut cx), Poll::Ready(Some(())), "Stream should yield once");
+		assert!(Pin::new(&mut stream).poll_next(&mut cx).is_pending(), "Stream should only yield once");
+	}
+
+	runtime_test! {
+		async fn test_via_future() {
+
+			let (cancelation_token, cancelable) = CancelationToken::new();
+			let shared_state = cancelation_token.shared_state.clone();
+
+			assert_not_canceled_no_waker(&shared_state);
+
+			match select(cancelable.future(), future::ready(())).await {
+				Either::Left(_) => panic!("Cancelation token isn't canceled"),
+				Either::Right(_) => {}
+			}
+
+			cancelation_token.cancel();
+
+			assert_canceled(&shared_state);
+
+			match select(cancelable.future(), future::pending::<()>()).await {
+				Either::Left(_) => {},
+				Either::Right(_) => panic!("Cancelation didn't happen")
+			}
+
+			assert_canceled(&shared_state);
+		}
+	}
+
+	#[cfg(feature = "opentelemetry")]
+	#[test]
+	fn test_with_trace_context() {
+		use opentelemetry::{Context, KeyValue};
+		use opentelemetry::trace::{TraceContextExt, Tracer, TracerProvider};
+		use opentelemetry_sdk::trace::{InMemorySpanExporter, SdkTracerProvider};
+
+		let exporter = InMemorySpanExporter::default();
+		let provider = SdkTracerProvider::builder().with_simple_exporter(exporter.clone()).build();
+		let tracer = provider.tracer("sync-tokens");
+
+		let span = tracer.start("shutdown");
+		let trace_context = Context::current_with_span(span);
+
+		let (cancelation_token, _cancelable) = CancelationToken::new();
+		let cancelation_token = cancelation_token.with_trace_context(trace_context.clone());
+
+		cancelation_token.cancel();
+		cancelation_token.cancel();
+
+		trace_context.span().end();
+
+		let spans = exporter.get_finished_spans().unwrap();
+		assert_eq!(spans.len(), 1, "Wrong number of spans");
+
+		let events = &spans[0].events.events;
+		assert_eq!(events.len(), 1, "Canceling twice should only record one event");
+		assert_eq!(events[0].name, "cancellation_token.canceled", "Wrong event name");
+		assert_eq!(events[0].attributes, vec![
+			KeyValue::new("cancellation_token.id", cancelation_token.fmt_id() as i64),
+			KeyValue::new("cancellation_token.cancel_count", 1)
+		], "Wrong attributes");
+	}
+
+	#[test]
+	fn test_state() {
+		let (cancelation_token, cancelable) = CancelationToken::new();
+		assert_eq!((cancelation_token.state(), cancelable.state()), (CancelationState::Active, CancelationState::Active), "Wrong state");
+
+		cancelation_token.cancel();
+		assert_eq!((cancelation_token.state(), cancelable.clone().state()), (CancelationState::Canceled, CancelationState::Canceled), "Wrong state");
+
+		cancelation_token.reset();
+		assert_eq!((cancelation_token.clone().state(), cancelable.state()), (CancelationState::Active, CancelationState::Active), "Wrong state");
+	}
+
+	#[cfg(feature = "crossbeam")]
+	#[test]
+	fn test_state_is_lock_free() {
+		assert!(AtomicCell::<CancelationState>::is_lock_free(), "State should be lock-free");
+	}
+
+	// Readers that see the cancelation without a lock must agree with the futures, which check under the lock
+	#[test]
+	fn test_state_stress() {
+		let waker = futures::task::noop_waker();
+
+		for _ in 0..100 {
+			let (cancelation_token, cancelable) = CancelationToken::new();
+
+			let readers: Vec<_> = (0..4).map(|_| {
+				let cancelable = cancelable.clone();
+				let waker = waker.clone();
+
+				std::thread::spawn(move || {
+					while !cancelable.is_canceled() {
+						std::hint::spin_loop();
+					}
+
+					let mut future = cancelable.future();
+					assert!(Pin::new(&mut future).poll(&mut Context::from_waker(&waker)).is_ready(), "Future should agree with the state");
+				})
+			}).collect();
+
+			cancelation_token.cancel();
+
+			for reader in readers {
+				reader.join().unwrap();
+			}
+		}
+	}
+
+	// set_var changes the whole process's environment, which races with other threads reading it. Each test uses its
+	// own variable so that tests running in parallel don't see each other's changes
+	runtime_test! {
+		#[cfg(not(target_arch = "wasm32"))]
+		async fn test_from_env() {
+			const VAR: &str = "SYNC_TOKENS_TEST_FROM_ENV";
+
+			let (cancelation_token, cancelable) = CancelationToken::from_env(VAR);
+
+			async_std::task::sleep(ENV_POLL_INTERVAL * 2).await;
+			assert!(!cancelation_token.is_canceled(), "Shouldn't be canceled until the variable is set");
+
+			std::env::set_var(VAR, "1");
+
+			let canceled = async_std::future::timeout(ENV_POLL_INTERVAL * 2, cancelable.future()).await;
+			std::env::remove_var(VAR);
+
+			assert!(canceled.is_ok(), "Should be canceled within 2 poll intervals");
+			assert!(cancelation_token.is_canceled(), "Should be canceled");
+		}
+	}
+
+	#[cfg(not(target_arch = "wasm32"))]
+	#[test]
+	fn test_from_env_already_set() {
+		const VAR: &str = "SYNC_TOKENS_TEST_FROM_ENV_ALREADY_SET";
+
+		std::env::set_var(VAR, "1");
+		let (cancelation_token, _cancelable) = CancelationToken::from_env(VAR);
+		std::env::remove_var(VAR);
+
+		assert!(cancelation_token.is_canceled(), "Should be canceled at construction");
+	}
+
+	#[cfg(not(target_arch = "wasm32"))]
+	#[test]
+	fn test_from_env_zero() {
+		const VAR: &str = "SYNC_TOKENS_TEST_FROM_ENV_ZERO";
+
+		std::env::set_var(VAR, "0");
+		let (cancelation_token, _cancelable) = CancelationToken::from_env(VAR);
+
+		std::thread::sleep(ENV_POLL_INTERVAL * 2);
+		std::env::remove_var(VAR);
+
+		assert!(!cancelation_token.is_canceled(), "0 shouldn't cancel");
+	}
+
+	runtime_test! {
+		async fn test_allow_cancel_either() {
+			let (_cancelation_token, cancelable) = CancelationToken::new();
+
+			match cancelable.allow_cancel_either(future::ready("complete".to_string()), 42u32).await {
+				Either::Left(result) => assert_eq!(result, "complete", "Wrong result"),
+				Either::Right(_) => panic!("The future's result should be on the left")
+			}
+
+			let (cancelation_token, cancelable) = CancelationToken::new();
+			let waiting = async_std::task::spawn(async move {
+				cancelable.allow_cancel_either(future::pending::<String>(), 42u32).await
+			});
+
+			cancelation_token.cancel();
+			match waiting.await {
+				Either::Left(_) => panic!("The canceled value should be on the right"),
+				Either::Right(canceled_value) => assert_eq!(canceled_value, 42, "Wrong canceled value")
+			}
+		}
+	}
+
+	// Records when it's dropped, like a future that holds a lock
+	struct DropTracker {
+		events: Arc<Mutex<Vec<&'static str>>>
+	}
+
+	impl Future for DropTracker {
+		type Output = &'static str;
+
+		fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Self::Output> {
+			Poll::Pending
+		}
+	}
+
+	impl Drop for DropTracker {
+		fn drop(&mut self) {
+			self.events.lock().unwrap().push("future dropped");
+		}
+	}
+
+	runtime_test! {
+		async fn test_allow_cancel_drops_future_before_returning() {
+			let events = Arc::new(Mutex::new(Vec::new()));
+
+			// Canceled while waiting
+			let (cancelation_token, cancelable) = CancelationToken::new();
+			let waiting = {
+				let events = events.clone();
+				async_std::task::spawn(async move {
+					let result = cancelable.allow_cancel(DropTracker { events: events.clone() }, "canceled").await;
+					events.lock().unwrap().push(result);
+				})
+			};
+
+			async_std::task::sleep(std::time::Duration::from_millis(10)).await;
+			cancelation_token.cancel();
+			waiting.await;
+
+			assert_eq!(*events.lock().unwrap(), vec!["future dropped", "canceled"], "The future should be dropped before returning");
+
+			// Canceled before the first poll
+			events.lock().unwrap().clear();
+			let (cancelation_token, cancelable) = CancelationToken::new();
+			cancelation_token.cancel();
+
+			let result = cancelable.allow_cancel(DropTracker { events: events.clone() }, "canceled").await;
+			events.lock().unwrap().push(result);
+
+			assert_eq!(*events.lock().unwrap(), vec!["future dropped", "canceled"], "The future should be dropped before returning");
+		}
+	}
+
+	runtime_test! {
+		async fn test_allow_cancel_with_drop_hook() {
+			let events = Arc::new(Mutex::new(Vec::new()));
+			let (cancelation_token, cancelable) = CancelationToken::new();
+
+			let waiting = {
+				let events = events.clone();
+				async_std::task::spawn(async move {
+					let hook_events = events.clone();
+					let result = cancelable.allow_cancel_with_drop_hook(
+						DropTracker { events: events.clone() },
+						"canceled",
+						move || hook_events.lock().unwrap().push("hook")).await;
+
+					events.lock().unwrap().push(result);
+				})
+			};
+
+			async_std::task::sleep(std::time::Duration::from_millis(10)).await;
+			cancelation_token.cancel();
+			waiting.await;
+
+			assert_eq!(*events.lock().unwrap(), vec!["future dropped", "hook", "canceled"], "The hook should run after the future is dropped");
+
+			// The hook doesn't run when the future finishes
+			let (_cancelation_token, cancelable) = CancelationToken::new();
+			let result = cancelable.allow_cancel_with_drop_hook(future::ready("finished"), "canceled", || panic!("The hook shouldn't run")).await;
+			assert_eq!(result, "finished", "Wrong result");
+		}
+	}
+
+	#[test]
+	fn test_fork_parent_cancels_both() {
+		let (cancelation_token, cancelable) = CancelationToken::new();
+		let ((_left_token, left_cancelable), (_right_token, right_cancelable)) = cancelation_token.fork();
+
+		cancelation_token.cancel();
+
+		assert!(cancelable.is_canceled(), "Parent should be canceled");
+		assert!(left_cancelable.is_canceled(), "Left fork should be canceled");
+		assert!(right_cancelable.is_canceled(), "Right fork should be canceled");
+	}
+
+	#[test]
+	fn test_fork_is_independent() {
+		let (cancelation_token, cancelable) = CancelationToken::new();
+		let ((left_token, left_cancelable), (_right_token, right_cancelable)) = cancelation_token.fork();
+
+		left_token.cancel();
+
+		assert!(left_cancelable.is_canceled(), "Left fork should be canceled");
+		assert!(!right_cancelable.is_canceled(), "Right fork shouldn't be canceled");
+		assert!(!cancelable.is_canceled(), "Parent shouldn't be canceled");
+	}
+
+	runtime_test! {
+		async fn test_fork_awaited_separately() {
+			let (cancelation_token, _cancelable) = CancelationToken::new();
+			let ((left_token, left_cancelable), (_right_token, right_cancelable)) = cancelation_token.fork();
+
+			let left = async_std::task::spawn(async move { left_cancelable.future().await });
+			let right = async_std::task::spawn(async move {
+				right_cancelable.allow_cancel(future::pending(), "canceled").await
+			});
+
+			left_token.cancel();
+			left.await;
+
+			async_std::task::sleep(std::time::Duration::from_millis(10)).await;
+			cancelation_token.cancel();
+			assert_eq!(right.await, "canceled", "The right fork should only be canceled by the parent");
+		}
+	}
+
+	runtime_test! {
+		async fn test_new_cancel_on_drop() {
+
+			let (cancelation_token, cancelable) = CancelationToken::new_cancel_on_drop();
+			let clones: Vec<_> = (0..3).map(|_| cancelation_token.clone()).collect();
+
+			let worker = {
+				let cancelable = cancelable.clone();
+				async_std::task::spawn(async move { cancelable.future().await })
+			};
+
+			drop(cancelation_token);
+
+			for clone in clones {
+				async_std::task::sleep(Duration::from_millis(10)).await;
+				assert!(!cancelable.is_canceled(), "Shouldn't be canceled while a clone is held");
+				drop(clone);
+			}
+
+			assert!(cancelable.is_canceled(), "Dropping the last clone should cancel");
+			worker.await;
+		}
+	}
+
+	#[test]
+	fn test_new_cancel_on_drop_explicit_cancel() {
+
+		let (cancelation_token, cancelable) = CancelationToken::new_cancel_on_drop();
+
+		cancelation_token.cancel_with_message("explicit");
+		assert!(cancelable.is_canceled(), "Canceling should still work before the token is dropped");
+
+		drop(cancelation_token);
+		assert_eq!(cancelable.cancel_reason().as_deref(), Some("explicit"), "Dropping shouldn't replace the reason");
+	}
+
+	#[test]
+	fn test_new_cancel_on_drop_ignores_internal_tokens() {
+
+		let (cancelation_token, cancelable) = CancelationToken::new_cancel_on_drop();
+		let (child_token, child_cancelable) = cancelation_token.child();
+
+		drop(child_token);
+		assert!(!child_cancelable.is_canceled(), "Children aren't canceled on drop");
+
+		// Canceling the subtree creates, and drops, tokens for the children
+		cancelation_token.cancel_subtree();
+		assert!(child_cancelable.is_canceled(), "The child should be canceled");
+		assert!(!cancelable.is_canceled(), "The parent's internal tokens shouldn't count as a clone");
+
+		drop(cancelation_token);
+		assert!(cancelable.is_canceled(), "Dropping the only token should cancel");
+
+		let (cancelation_token, cancelable) = CancelationToken::new();
+		drop(cancelation_token);
+		assert!(!cancelable.is_canceled(), "Ordinary tokens aren't canceled on drop");
+	}
+
+	#[test]
+	fn test_cancel_wakes_past_a_panicking_waker() {
+
+		let (cancelation_token, cancelable) = CancelationToken::new();
+		let (_child_token, child_cancelable) = cancelation_token.child();
+
+		let panicking_waker = Waker::from(Arc::new(PanickingWaker));
+		let test_waker = TestWaker::new();
+		let child_test_waker = TestWaker::new();
+
+		let mut panicking_future = cancelable.future();
+		let mut future = cancelable.future();
+		let mut child_future = child_cancelable.future();
+		assert!(Pin::new(&mut panicking_future).poll(&mut Context::from_waker(&panicking_waker)).is_pending(), "Shouldn't be canceled yet");
+		assert!(Pin::new(&mut future).poll(&mut Context::from_waker(&test_waker.clone().into_waker())).is_pending(), "Shouldn't be canceled yet");
+		assert!(Pin::new(&mut child_future).poll(&mut Context::from_waker(&child_test_waker.clone().into_waker())).is_pending(), "Shouldn't be canceled yet");
+
+		let result = std::panic::catch_unwind(|| cancelation_token.cancel_with_message("shutdown"));
+
+		assert!(result.is_err(), "The waker's panic should be resumed");
+		assert!(test_waker.woke(), "The other waker should still be woken");
+		assert!(child_test_waker.woke(), "The child's waker should still be woken");
+		assert!(cancelable.is_canceled(), "Should be canceled");
+		assert!(child_cancelable.is_canceled(), "The child should be canceled");
+
+		// The lock wasn't poisoned
+		assert_eq!(cancelable.cancel_reason().as_deref(), Some("shutdown"), "Wrong reason");
+		assert!(futures::FutureExt::now_or_never(future).is_some(), "The woken future should return");
+	}
+
+	#[test]
+	fn test_cancel_reason() {
+		let (cancelation_token, cancelable) = CancelationToken::new();
+		let (child_token, child_cancelable) = cancelation_token.child();
+
+		assert_eq!(cancelation_token.cancel_reason(), None, "Shouldn't have a reason before canceling");
+
+		cancelation_token.cancel_with_message("shutdown request");
+		cancelation_token.cancel_with_message("ignored");
+
+		assert!(cancelable.is_canceled(), "Should be canceled");
+		assert_eq!(cancelation_token.cancel_reason().as_deref(), Some("shutdown request"), "Wrong reason");
+		assert_eq!(cancelable.cancel_reason().as_deref(), Some("shutdown request"), "Wrong reason");
+		assert_eq!(child_cancelable.cancel_reason().as_deref(), Some("shutdown request"), "Children should get the reason");
+		assert_eq!(cancelation_token.child().1.cancel_reason().as_deref(), Some("shutdown request"), "Late children should get the reason");
+
+		cancelation_token.reset();
+		assert_eq!(cancelable.cancel_reason(), None, "Resetting should clear the reason");
+
+		cancelation_token.cancel();
+		assert!(cancelable.is_canceled(), "Should be canceled");
+		assert_eq!(cancelable.cancel_reason(), None, "Canceling without a message shouldn't have a reason");
+		assert_eq!(child_token.cancel_reason().as_deref(), Some("shutdown request"), "The child wasn't reset");
+	}
+
+	runtime_test! {
+		#[cfg(feature = "anyhow")]
+		async fn test_allow_cancel_anyhow() {
+
+			async fn fetch(cancelable: &Cancelable) -> anyhow::Result<u32> {
+				let value = cancelable.allow_cancel_anyhow(future::pending::<anyhow::Result<u32>>()).await?;
+				Ok(value + 1)
+			}
+
+			let (cancelation_token, cancelable) = CancelationToken::new();
+
+			let result = cancelable.allow_cancel_anyhow(future::ready(Ok(1))).await;
+			assert_eq!(result.unwrap(), 1, "Wrong result");
+
+			cancelation_token.cancel();
+
+			let err = fetch(&cancelable).await.unwrap_err();
+			assert_eq!(err.to_string(), "operation canceled", "Wrong message");
+			assert_eq!(err.root_cause().to_string(), Canceled.to_string(), "? should keep the chain");
+			assert_eq!(err.downcast_ref::<Canceled>(), Some(&Canceled), "Should downcast to Canceled");
+		}
+	}
+
+	#[test]
+	fn test_cancel_subtree() {
+		let (root_token, root_cancelable) = CancelationToken::new();
+		let (mid_token, mid_cancelable) = root_token.child();
+		let (_leaf_token, leaf_cancelable) = mid_token.child();
+
+		root_token.cancel_subtree();
+
+		assert!(mid_cancelable.is_canceled(), "Mid should be canceled");
+		assert!(leaf_cancelable.is_canceled(), "Leaf should be canceled");
+		assert!(!root_cancelable.is_canceled(), "Root shouldn't be canceled");
+
+		let (new_mid_token, new_mid_cancelable) = root_token.child();
+		let (_new_leaf_token, new_leaf_cancelable) = new_mid_token.child();
+
+		assert!(!new_mid_cancelable.is_canceled(), "The new mid shouldn't be canceled");
+		assert!(!new_leaf_cancelable.is_canceled(), "The new leaf shouldn't be canceled");
+
+		root_token.cancel();
+		assert!(new_leaf_cancelable.is_canceled(), "Canceling the root should still cancel the new children");
+	}
+
+	runtime_test! {
+		async fn test_degrade_to_completion() {
+			let (cancelation_token, cancelable) = CancelationToken::new();
+			let (completion_token, bridge) = cancelable.degrade_to_completion(-1);
+			let bridge = async_std::task::spawn(bridge);
+
+			assert!(futures::FutureExt::now_or_never(completion_token.clone()).is_none(), "Shouldn't complete before canceling");
+
+			cancelation_token.cancel();
+			assert_eq!(completion_token.await, -1, "Should complete with the default");
+			bridge.await;
+		}
+	}
+
+	#[test]
+	fn test_degrade_to_completion_dropped() {
+		let (_cancelation_token, cancelable) = CancelationToken::new();
+		let (completion_token, bridge) = cancelable.degrade_to_completion(());
+
+		drop(bridge);
+		assert_eq!(futures::executor::block_on(completion_token.try_wait()), Err(crate::completion_token::Abandoned), "Dropping the bridge should abandon the token");
+	}
+
+	#[test]
+	fn test_future_with_value_already_canceled() {
+
+		let (cancelation_token, cancelable) = CancelationToken::new();
+		cancelation_token.cancel();
+
+		let mut future = cancelable.future_with_value("canceled");
+		assert!(!future.is_terminated(), "Shouldn't be terminated before it returns");
+		assert_eq!(futures::FutureExt::now_or_never(&mut future), Some("canceled"), "Should return the value immediately");
+		assert!(future.is_terminated(), "Should be terminated once it returns");
+	}
+
+	runtime_test! {
+		async fn test_future_with_value_canceled_later() {
+
+			let (cancelation_token, cancelable) = CancelationToken::new();
+			let mut future = cancelable.future_with_value(vec![1, 2, 3]);
+
+			assert!(futures::FutureExt::now_or_never(&mut future).is_none(), "Shouldn't be canceled yet");
+
+			let waiting = async_std::task::spawn(future);
+			async_std::task::sleep(Duration::from_millis(10)).await;
+			cancelation_token.cancel();
+
+			assert_eq!(waiting.await, vec![1, 2, 3], "Should return the value once canceled");
+		}
+	}
+
+	runtime_test! {
+		async fn test_future_with_value_in_select() {
+
+			let (cancelation_token, cancelable) = CancelationToken::new();
+			let (completion_token, completable) = CompletionToken::new();
+
+			completable.complete(Some(1));
+			let mut work = futures::FutureExt::fuse(completion_token);
+			let mut canceled = cancelable.future_with_value(None);
+
+			let result = futures::select! {
+				result = work => result,
+				result = canceled => result
+			};
+			assert_eq!(result, Some(1), "The work should win before canceling");
+
+			cancelation_token.cancel();
+			let mut work = futures::future::pending();
+			let mut canceled = cancelable.future_with_value(None);
+
+			let result: Option<u32> = futures::select! {
+				result = work => result,
+				result = canceled => result
+			};
+			assert_eq!(result, None, "Should return the sentinel once canceled");
+		}
+	}
+
+	runtime_test! {
+		#[cfg(feature = "diagnostics")]
+		async fn test_waiting_task_names() {
+			let (cancelation_token, cancelable) = CancelationToken::new();
+
+			let waiting: Vec<_> = vec!["listener", "reaper", "metrics"].into_iter()
+				.map(|name| async_std::task::spawn(cancelable.future_named(name)))
+				.collect();
+
+			// An unnamed waiter isn't listed
+			let unnamed = async_std::task::spawn(cancelable.future());
+
+			while cancelation_token.waiting_task_names().len() < 3 {
+				async_std::task::yield_now().await;
+			}
+
+			let mut names = cancelation_token.waiting_task_names();
+			names.sort_unstable();
+			assert_eq!(names, vec!["listener", "metrics", "reaper"], "Every named waiter should be listed");
+
+			cancelation_token.cancel();
+			assert!(cancelation_token.waiting_task_names().is_empty(), "Canceling should wake every waiter");
+
+			for waiting in waiting {
+				waiting.await;
+			}
 
-		assert_canceled(&shared_state);
+			unnamed.await;
+		}
+	}
+
+	#[test]
+	fn test_child_of_canceled() {
+		let (cancelation_token, _cancelable) = CancelationToken::new();
+		cancelation_token.cancel();
+
+		let (_child_token, child_cancelable) = cancelation_token.child();
+		assert!(child_cancelable.is_canceled(), "A child of a canceled token should be canceled");
+	}
+
+	#[test]
+	fn test_dropped_children_are_pruned() {
+		let (cancelation_token, _cancelable) = CancelationToken::new();
+
+		for _ in 0..10 {
+			drop(cancelation_token.child());
+		}
+
+		assert_eq!(cancelation_token.shared_state.lock().unwrap().children.len(), 1, "Dropped children should be pruned");
+	}
+
+	runtime_test! {
+		async fn test_sink_canceled_while_full() {
+			use futures::channel::mpsc;
+			use futures::sink::SinkExt;
+
+			let (cancelation_token, cancelable) = CancelationToken::new();
+			let (sender, mut receiver) = mpsc::channel::<u32>(0);
+			let mut sink = cancelable.sink(sender);
+
+			sink.feed(1).await.unwrap();
+
+			// The channel is full, so this waits until it's canceled
+			let sending = async_std::task::spawn(async move {
+				let result = sink.send(2).await;
+				(result, sink)
+			});
+
+			async_std::task::sleep(std::time::Duration::from_millis(10)).await;
+			cancelation_token.cancel();
+
+			let (result, mut sink) = sending.await;
+			assert_eq!(result, Err(CancelableSinkError::Canceled), "Sending to a full sink should stop when canceled");
+			assert_eq!(sink.flush().await, Err(CancelableSinkError::Canceled), "Flushing should fail once canceled");
+
+			assert_eq!(sink.close().await, Ok(()), "Closing should be allowed after cancelation");
+			assert_eq!(receiver.next().await, Some(1), "The first item should be delivered");
+			assert_eq!(receiver.next().await, None, "The sink should be closed");
+		}
+	}
+
+	runtime_test! {
+		async fn test_sink_close_rejected() {
+			use futures::channel::mpsc;
+			use futures::sink::SinkExt;
+
+			let (cancelation_token, cancelable) = CancelationToken::new();
+			let (sender, _receiver) = mpsc::channel::<u32>(1);
+			let mut sink = cancelable.sink(sender).allow_close_after_cancel(false);
+
+			sink.feed(1).await.unwrap();
+			cancelation_token.cancel();
+
+			assert_eq!(sink.close().await, Err(CancelableSinkError::Canceled), "Closing should be rejected");
+		}
+	}
+
+	runtime_test! {
+		async fn test_sink_error() {
+			use futures::channel::mpsc;
+			use futures::sink::SinkExt;
+
+			let (_cancelation_token, cancelable) = CancelationToken::new();
+			let (sender, receiver) = mpsc::channel::<u32>(1);
+			let mut sink = cancelable.sink(sender);
+
+			drop(receiver);
+
+			match sink.send(1).await {
+				Err(CancelableSinkError::Sink(err)) => assert!(err.is_disconnected(), "Wrong error"),
+				_ => panic!("The inner sink's error should be returned")
+			}
+		}
+	}
+
+	runtime_test! {
+		async fn test_recv_canceled_while_empty() {
+			use futures::channel::mpsc;
+
+			let (cancelation_token, cancelable) = CancelationToken::new();
+			let (_sender, mut receiver) = mpsc::channel::<u32>(1);
+
+			let receiving = async_std::task::spawn(async move {
+				cancelable.recv_or_canceled(&mut receiver).await
+			});
+
+			async_std::task::sleep(std::time::Duration::from_millis(10)).await;
+			cancelation_token.cancel();
+
+			assert_eq!(receiving.await, Err(RecvCanceled::Canceled), "Waiting on an empty channel should stop when canceled");
+		}
+	}
+
+	runtime_test! {
+		async fn test_recv_canceled_with_buffered_items() {
+			use futures::channel::mpsc;
+
+			let (cancelation_token, cancelable) = CancelationToken::new();
+			let (sender, mut receiver) = mpsc::unbounded();
+
+			for i in 0..3 {
+				sender.unbounded_send(i).unwrap();
+			}
+
+			assert_eq!(cancelable.recv_or_canceled(&mut receiver).await, Ok(0), "Wrong item");
+
+			cancelation_token.cancel();
+			drop(sender);
+
+			assert_eq!(cancelable.recv_or_canceled(&mut receiver).await, Err(RecvCanceled::Canceled), "Should be canceled");
+			assert_eq!(receiver.collect::<Vec<_>>().await, vec![1, 2], "Buffered items should still be drained");
+		}
+	}
+
+	runtime_test! {
+		async fn test_recv_closed() {
+			use futures::channel::mpsc;
+
+			let (_cancelation_token, cancelable) = CancelationToken::new();
+			let (sender, mut receiver) = mpsc::unbounded::<u32>();
+
+			drop(sender);
+
+			assert_eq!(cancelable.recv_or_canceled(&mut receiver).await, Err(RecvCanceled::Closed), "Should be closed");
+		}
+	}
+
+	#[test]
+	fn test_blocking_recv_canceled_while_empty() {
+		use std::sync::mpsc;
+		use std::time::{Duration, Instant};
+
+		let (cancelation_token, cancelable) = CancelationToken::new();
+		let (sender, receiver) = mpsc::channel::<u32>();
+
+		let worker = std::thread::spawn(move || {
+			let first = cancelable.blocking_recv_or_canceled(&receiver, Duration::from_millis(10));
+			let second = cancelable.blocking_recv_or_canceled(&receiver, Duration::from_millis(10));
+			(first, second, receiver)
+		});
+
+		sender.send(1).unwrap();
+		std::thread::sleep(Duration::from_millis(50));
+
+		let canceled_at = Instant::now();
+		cancelation_token.cancel();
+
+		let (first, second, receiver) = worker.join().unwrap();
+		assert!(canceled_at.elapsed() < Duration::from_secs(1), "Cancelation should unblock the thread promptly");
+		assert_eq!(first, Ok(1), "Wrong item");
+		assert_eq!(second, Err(RecvCanceled::Canceled), "Waiting on an empty channel should stop when canceled");
+
+		sender.send(2).unwrap();
+		assert_eq!(receiver.try_recv(), Ok(2), "The receiver should still be usable");
+	}
+
+	#[cfg(feature = "crossbeam-channel")]
+	#[test]
+	fn test_crossbeam_receiver_select() {
+		use crossbeam_channel::select;
+
+		let (cancelation_token, cancelable) = CancelationToken::new();
+		let (sender, receiver) = crossbeam_channel::unbounded();
+
+		let worker = std::thread::spawn(move || {
+			let canceled = cancelable.crossbeam_receiver();
+			let mut received = Vec::new();
+
+			loop {
+				select! {
+					recv(receiver) -> item => received.push(item.unwrap()),
+					recv(canceled) -> _ => return received
+				}
+			}
+		});
+
+		for i in 0..3 {
+			sender.send(i).unwrap();
+		}
+
+		std::thread::sleep(std::time::Duration::from_millis(50));
+
+		// Canceled from the async side
+		futures::executor::block_on(async move {
+			cancelation_token.cancel();
+		});
+
+		assert_eq!(worker.join().unwrap(), vec![0, 1, 2], "The select loop should exit once canceled");
+	}
+
+	#[cfg(feature = "crossbeam-channel")]
+	#[test]
+	fn test_crossbeam_receiver_after_cancel_and_reset() {
+		use crossbeam_channel::TryRecvError;
+
+		let (cancelation_token, cancelable) = CancelationToken::new();
+
+		cancelation_token.cancel();
+		assert_eq!(cancelable.crossbeam_receiver().try_recv(), Err(TryRecvError::Disconnected), "Should already be disconnected");
+
+		cancelation_token.reset();
+		let canceled = cancelable.crossbeam_receiver();
+		assert_eq!(canceled.try_recv(), Err(TryRecvError::Empty), "Should wait for the next cancelation");
+
+		cancelation_token.cancel();
+		assert_eq!(canceled.try_recv(), Err(TryRecvError::Disconnected), "Should disconnect when canceled again");
+	}
+
+	runtime_test! {
+		#[cfg(feature = "stop-token")]
+		async fn test_from_stop_token() {
+			let stop_source = stop_token::StopSource::new();
+			let cancelable = Cancelable::from_stop_token(stop_source.token());
+
+			assert!(!cancelable.is_canceled(), "Shouldn't be canceled until the source stops");
+
+			let waiting = async_std::task::spawn(cancelable.future());
+			drop(stop_source);
+
+			async_std::future::timeout(std::time::Duration::from_secs(1), waiting).await.expect("Stopping should cancel promptly");
+			assert!(cancelable.is_canceled(), "Should be canceled");
+		}
+	}
+
+	#[cfg(feature = "stop-token")]
+	#[test]
+	fn test_from_stop_token_stops_waiting_when_dropped() {
+		let stop_source = stop_token::StopSource::new();
+		let cancelable = Cancelable::from_stop_token(stop_source.token());
+
+		let weak_shared_state = Arc::downgrade(&cancelable.shared_state);
+		drop(cancelable);
+
+		assert!(weak_shared_state.upgrade().is_none(), "The stop token shouldn't keep the token alive once the cancelable is dropped");
+	}
+
+	#[cfg(feature = "stop-token")]
+	#[test]
+	fn test_from_stop_token_without_a_runtime() {
+		let stop_source = stop_token::StopSource::new();
+		let cancelable = Cancelable::from_stop_token(stop_source.token());
+
+		drop(stop_source);
+		assert!(cancelable.is_canceled(), "Dropping the source should cancel right away");
+
+		let stopped = Cancelable::from_stop_token(stop_token::StopSource::new().token());
+		assert!(stopped.is_canceled(), "A stopped token should cancel right away");
+	}
+
+	runtime_test! {
+		#[cfg(feature = "stop-token")]
+		async fn test_stop_token() {
+			use futures::future::FutureExt;
+
+			let (cancelation_token, cancelable) = CancelationToken::new();
+			let mut stop_token = cancelable.stop_token();
+
+			assert!((&mut stop_token).now_or_never().is_none(), "Shouldn't stop until canceled");
+
+			let waiting = async_std::task::spawn(stop_token);
+			cancelation_token.cancel();
+
+			async_std::future::timeout(std::time::Duration::from_secs(1), waiting).await.expect("Canceling should stop promptly");
+			assert!(cancelable.stop_token().now_or_never().is_some(), "Should already be stopped");
+
+			cancelation_token.reset();
+			assert!(cancelable.stop_token().now_or_never().is_none(), "Should wait for the next cancelation after reset");
+		}
+	}
+
+	#[test]
+	fn test_block_until_all_canceled() {
+		let (cancelation_tokens, cancelables): (Vec<_>, Vec<_>) = (0..5).map(|_| CancelationToken::new()).unzip();
+		let canceled_count = Arc::new(AtomicUsize::new(0));
+
+		// Canceled from different threads, in an order that varies from run to run
+		let cancelers: Vec<_> = cancelation_tokens.into_iter().enumerate().map(|(i, cancelation_token)| {
+			let canceled_count = canceled_count.clone();
+			std::thread::spawn(move || {
+				let jitter = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().subsec_nanos() as u64 % 20;
+				std::thread::sleep(std::time::Duration::from_millis(10 + jitter + (i as u64 * 7) % 11));
+
+				canceled_count.fetch_add(1, Ordering::SeqCst);
+				cancelation_token.cancel();
+			})
+		}).collect();
+
+		Cancelable::block_until_all_canceled(&cancelables);
+
+		assert_eq!(canceled_count.load(Ordering::SeqCst), 5, "Should only return after all 5 are canceled");
+		assert!(cancelables.iter().all(Cancelable::is_canceled), "Every cancelable should be canceled");
+
+		for canceler in cancelers {
+			canceler.join().unwrap();
+		}
+	}
+
+	#[test]
+	fn test_block_until_all_canceled_already_canceled() {
+		let (cancelation_token, cancelable) = CancelationToken::new();
+		cancelation_token.cancel();
+
+		Cancelable::block_until_all_canceled(&[cancelable]);
+		Cancelable::block_until_all_canceled(&[]);
+	}
+
+	#[test]
+	fn test_poll_canceled() {
+		let (cancelation_token, cancelable) = CancelationToken::new();
+		let clone = cancelable.clone();
+
+		let test_waker = TestWaker::new();
+		let waker = test_waker.clone().into_waker();
+		let other_test_waker = TestWaker::new();
+		let other_waker = other_test_waker.clone().into_waker();
+
+		assert!(cancelable.poll_canceled(&mut Context::from_waker(&waker)).is_pending(), "Shouldn't be canceled");
+		assert!(cancelable.poll_canceled(&mut Context::from_waker(&waker)).is_pending(), "Shouldn't be canceled");
+		assert!(clone.poll_canceled(&mut Context::from_waker(&other_waker)).is_pending(), "Shouldn't be canceled");
+		assert_eq!(cancelable.shared_state.lock().unwrap().wakers.len(), 2, "Each clone should keep one waker");
+
+		cancelation_token.cancel();
+
+		assert!(test_waker.woke(), "Canceling should wake");
+		assert!(other_test_waker.woke(), "Canceling should wake every clone");
+		assert!(cancelable.poll_canceled(&mut Context::from_waker(&waker)).is_ready(), "Should be canceled");
+	}
+
+	runtime_test! {
+		async fn test_allow_cancel_iter() {
+			let (cancelation_token, cancelable) = CancelationToken::new();
+
+			// The futures finish in reverse order
+			let futures = (0..5u64).map(|i| futures::FutureExt::boxed(async move {
+				async_std::task::sleep(std::time::Duration::from_millis(50 - 10 * i)).await;
+				i
+			}));
+
+			assert_eq!(cancelable.allow_cancel_iter(futures, 42).await, 4, "The first future to finish should win");
+
+			let canceling = async_std::task::spawn(async move {
+				async_std::task::sleep(std::time::Duration::from_millis(10)).await;
+				cancelation_token.cancel();
+			});
+
+			let futures = (0..5).map(|_| futures::FutureExt::boxed(futures::future::pending()));
+			assert_eq!(cancelable.allow_cancel_iter(futures, 42).await, 42, "Should be canceled");
+			canceling.await;
+		}
+	}
+
+	#[test]
+	fn test_allow_cancel_iter_is_lazy() {
+		let (cancelation_token, cancelable) = CancelationToken::new();
+		let created = Arc::new(AtomicUsize::new(0));
+		let polled = Arc::new(AtomicUsize::new(0));
+
+		let futures = {
+			let created = created.clone();
+			let polled = polled.clone();
+			(0..5).map(move |i| {
+				created.fetch_add(1, Ordering::SeqCst);
+				let polled = polled.clone();
+				futures::FutureExt::boxed(async move {
+					polled.fetch_add(1, Ordering::SeqCst);
+					i
+				})
+			})
+		};
+
+		let allow_cancel_iter = cancelable.allow_cancel_iter(futures, 42);
+		assert_eq!(created.load(Ordering::SeqCst), 0, "Futures shouldn't be taken before awaiting");
+
+		cancelation_token.cancel();
+		assert_eq!(futures::executor::block_on(allow_cancel_iter), 42, "Should be canceled");
+		assert_eq!(polled.load(Ordering::SeqCst), 0, "No future should be polled after cancelation");
+	}
+
+	runtime_test! {
+		async fn test_checkpoint() {
+			let (cancelation_token, cancelable) = CancelationToken::new();
+
+			assert_eq!(cancelable.checkpoint().await, Ok(()), "Shouldn't be canceled");
+
+			// Cancels while the checkpoint yields
+			let checkpoint = cancelable.checkpoint();
+			futures::pin_mut!(checkpoint);
+			assert!(futures::poll!(checkpoint.as_mut()).is_pending(), "Should yield");
+			cancelation_token.cancel();
+			assert_eq!(checkpoint.await, Err(Canceled), "Should be canceled");
+		}
+	}
+
+	runtime_test! {
+		#[cfg(feature = "macros")]
+		async fn test_cancelable_macro() {
+			#[crate::cancelable(loops)]
+			async fn count(cancelable: &Cancelable, counted: &AtomicUsize) -> usize {
+				for _ in 0..usize::MAX {
+					counted.fetch_add(1, Ordering::Relaxed);
+				}
+
+				counted.load(Ordering::Relaxed)
+			}
+
+			let (cancelation_token, cancelable) = CancelationToken::new();
+			let counted = Arc::new(AtomicUsize::new(0));
+
+			let counting = async_std::task::spawn({
+				let counted = counted.clone();
+				async move {
+					count(&cancelable, &counted).await
+				}
+			});
+
+			async_std::task::sleep(std::time::Duration::from_millis(10)).await;
+			assert!(counted.load(Ordering::Relaxed) > 0, "Should be counting");
+
+			let canceled_at = std::time::Instant::now();
+			cancelation_token.cancel();
+
+			assert_eq!(counting.await, Err(Canceled), "Should stop when canceled");
+			assert!(canceled_at.elapsed() < std::time::Duration::from_secs(1), "Should stop promptly");
+		}
+	}
+
+	#[test]
+	fn test_into_poll_fn() {
+		let (cancelation_token, cancelable) = CancelationToken::new();
+		let mut poll_fn_future = std::future::poll_fn(cancelation_token.clone().into_poll_fn());
+		let mut future = cancelable.future();
+
+		let test_waker = TestWaker::new();
+		let waker = test_waker.clone().into_waker();
+		let mut cx = Context::from_waker(&waker);
+
+		for _ in 0..2 {
+			assert!(Pin::new(&mut poll_fn_future).poll(&mut cx).is_pending(), "Shouldn't be canceled");
+			assert!(Pin::new(&mut future).poll(&mut cx).is_pending(), "Shouldn't be canceled");
+		}
+
+		assert_eq!(cancelable.shared_state.lock().unwrap().wakers.len(), 2, "Each should keep one waker");
+
+		cancelation_token.cancel();
+		assert!(test_waker.woke(), "Canceling should wake");
+		assert!(Pin::new(&mut poll_fn_future).poll(&mut cx).is_ready(), "Should be canceled");
+		assert!(Pin::new(&mut future).poll(&mut cx).is_ready(), "Should be canceled");
+
+		cancelation_token.reset();
+		assert!(Pin::new(&mut poll_fn_future).poll(&mut cx).is_pending(), "Shouldn't be canceled after reset");
+		drop(poll_fn_future);
+		drop(future);
+		assert!(cancelable.shared_state.lock().unwrap().wakers.is_empty(), "Dropping should remove the wakers");
+	}
+
+	#[test]
+	fn test_into_poll_fn_keeps_cancel_on_drop() {
+		let (cancelation_token, cancelable) = CancelationToken::new_cancel_on_drop();
+		let mut poll_canceled = cancelation_token.into_poll_fn();
+
+		let waker = TestWaker::new().into_waker();
+		assert!(poll_canceled(&mut Context::from_waker(&waker)).is_pending(), "Converting the last clone shouldn't cancel");
+		assert!(!cancelable.is_canceled(), "Converting the last clone shouldn't cancel");
+
+		drop(poll_canceled);
+		assert!(cancelable.is_canceled(), "Dropping the closure should cancel");
+	}
+
+	#[test]
+	fn test_dropped_cancelable_removes_waker() {
+		let (_cancelation_token, cancelable) = CancelationToken::new();
+		let clone = cancelable.clone();
+
+		let waker = TestWaker::new().into_waker();
+		assert!(clone.poll_canceled(&mut Context::from_waker(&waker)).is_pending(), "Shouldn't be canceled");
+
+		drop(clone);
+		assert!(cancelable.shared_state.lock().unwrap().wakers.is_empty(), "Dropping should remove the waker");
+	}
+
+	runtime_test! {
+		async fn test_with_pre_hook() {
+			let (cancelation_token, cancelable) = CancelationToken::new();
+			let calls = Arc::new(AtomicUsize::new(0));
+
+			let hooked_cancelable = {
+				let calls = calls.clone();
+				cancelable.with_pre_hook(move || { calls.fetch_add(1, Ordering::SeqCst); })
+			};
+
+			let finished = hooked_cancelable.allow_cancel(Box::pin(async { "finished" }), "canceled");
+			assert_eq!(calls.load(Ordering::SeqCst), 1, "The hook should run when allow_cancel is called");
+			assert_eq!(finished.await, "finished", "Wrong result");
+			assert_eq!(calls.load(Ordering::SeqCst), 1, "Completing shouldn't call the hook");
+
+			let clone = hooked_cancelable.clone();
+			let pending = clone.allow_cancel(Box::pin(future::pending()), "canceled");
+			assert_eq!(calls.load(Ordering::SeqCst), 2, "Clones should share the hook");
+
+			cancelation_token.cancel();
+			assert_eq!(pending.await, "canceled", "Wrong result");
+
+			hooked_cancelable.future().await;
+			assert!(hooked_cancelable.is_canceled(), "Should be canceled");
+			assert_eq!(hooked_cancelable.allow_cancel(Box::pin(async { "finished" }), "canceled").await, "canceled", "Wrong result");
+			assert_eq!(calls.load(Ordering::SeqCst), 3, "The hook should run once per allow_cancel, even when canceled");
+		}
+	}
+
+	#[test]
+	fn test_sync_checker_stops_parallel_work() {
+		use rayon::prelude::*;
+
+		const TOTAL: u64 = 10_000_000;
+
+		let (cancelation_token, cancelable) = CancelationToken::new();
+		let checker = cancelable.sync_checker();
+		let processed = AtomicUsize::new(0);
+
+		let result = (0..TOTAL).into_par_iter().map(|i| i.wrapping_mul(i)).try_for_each(|_| {
+			checker.bail::<Canceled>()?;
+
+			if processed.fetch_add(1, Ordering::Relaxed) == 1000 {
+				cancelation_token.cancel();
+			}
+
+			Ok(())
+		});
+
+		assert_eq!(result, Err(Canceled), "The work should stop with an error");
+		assert!((processed.load(Ordering::Relaxed) as u64) < TOTAL, "The work should stop early");
+	}
+
+	#[test]
+	fn test_sync_checker_reset() {
+		let (cancelation_token, cancelable) = CancelationToken::new();
+		cancelation_token.cancel();
+
+		let checker = cancelable.sync_checker();
+		assert!(checker.is_canceled(), "Should start canceled");
+		assert_eq!(checker.bail::<Canceled>(), Err(Canceled), "Should bail");
+
+		cancelation_token.reset();
+		assert!(!checker.is_canceled(), "Reset should clear the flag");
+		assert!(!cancelable.sync_checker().is_canceled(), "New checkers should share the flag");
+
+		cancelation_token.cancel();
+		assert!(checker.is_canceled(), "Should be canceled again");
+	}
+
+	runtime_test! {
+		async fn test_into_abort_handle() {
+			let (cancelation_token, cancelable) = CancelationToken::new();
+			let (abort_handle, abort_registration) = cancelable.into_abort_handle();
+
+			let abortable = async_std::task::spawn(abort_registration.abortable(future::pending::<()>()));
+
+			async_std::task::sleep(std::time::Duration::from_millis(10)).await;
+			assert!(!abort_handle.is_aborted(), "Shouldn't be aborted yet");
+
+			cancelation_token.cancel();
+			assert!(abort_handle.is_aborted(), "Canceling should abort");
+			assert_eq!(abortable.await, Err(Aborted), "The abortable future should be aborted");
+		}
+	}
+
+	runtime_test! {
+		async fn test_into_abort_handle_already_canceled() {
+			let (cancelation_token, cancelable) = CancelationToken::new();
+			cancelation_token.cancel();
+
+			let (abort_handle, abort_registration) = cancelable.into_abort_handle();
+			assert!(abort_handle.is_aborted(), "Should start aborted");
+			assert_eq!(abort_registration.abortable(future::ready(())).await, Err(Aborted), "Should be aborted");
+
+			// Aborting on its own doesn't cancel
+			let (cancelation_token, cancelable) = CancelationToken::new();
+			let (abort_handle, _abort_registration) = cancelable.into_abort_handle();
+
+			abort_handle.abort();
+			assert!(!cancelation_token.is_canceled(), "Aborting shouldn't cancel");
+		}
+	}
+
+	#[test]
+	fn test_abort_handles_are_removed_when_done() {
+		let (cancelation_token, cancelable) = CancelationToken::new();
+
+		let test_waker = TestWaker::new();
+		let waker = test_waker.into_waker();
+		let mut cx = Context::from_waker(&waker);
+
+		for _ in 0..100 {
+			let (_abort_handle, abort_registration) = cancelable.clone().into_abort_handle();
+			let mut finished = abort_registration.abortable(future::ready(()));
+			assert_eq!(Pin::new(&mut finished).poll(&mut cx), Poll::Ready(Ok(())), "Should finish");
+
+			let (_abort_handle, abort_registration) = cancelable.clone().into_abort_handle();
+			let mut dropped = abort_registration.abortable(future::pending::<()>());
+			assert!(Pin::new(&mut dropped).poll(&mut cx).is_pending(), "Should be pending");
+		}
+
+		let (abort_handle, abort_registration) = cancelable.into_abort_handle();
+		assert_eq!(cancelation_token.shared_state.lock().unwrap().abort_handles.len(), 1, "Only the live handle should be kept");
+
+		cancelation_token.cancel();
+		assert!(abort_handle.is_aborted(), "Canceling should abort");
+		assert_eq!(cancelation_token.shared_state.lock().unwrap().abort_handles.len(), 0, "Canceling should remove the handle");
+
+		// Dropped after the token removed it
+		drop(abort_registration);
+	}
+
+	#[test]
+	fn test_new_with_id() {
+		let (cancelation_token, cancelable) = CancelationToken::new_with_id(42);
+
+		assert_eq!(format!("{:?}", cancelation_token), "CancelationToken { id: 42, state: Active }", "Wrong debug output");
+		assert_eq!(format!("{:?}", cancelable), "Cancelable { id: 42, state: Active }", "Wrong debug output");
+
+		cancelation_token.cancel();
+		assert_eq!(format!("{:?}", cancelation_token), "CancelationToken { id: 42, state: Canceled }", "Wrong debug output");
+	}
+
+	#[test]
+	fn test_blocking_recv_closed() {
+		let (_cancelation_token, cancelable) = CancelationToken::new();
+		let (sender, receiver) = std::sync::mpsc::channel::<u32>();
+
+		drop(sender);
+
+		assert_eq!(cancelable.blocking_recv_or_canceled(&receiver, std::time::Duration::from_millis(10)), Err(RecvCanceled::Closed), "Should be closed");
+	}
+}
+
+
+#[cfg(all(test, target_arch = "wasm32"))]
+mod wasm_tests {
+	use futures::future;
+	use wasm_bindgen_test::wasm_bindgen_test;
+
+	use super::*;
+	use crate::completion_token::CompletionToken;
+
+	#[wasm_bindgen_test]
+	async fn test_cancel_round_trip() {
+		let (cancelation_token, cancelable) = CancelationToken::new();
+		let (completion_token, completable) = CompletionToken::new();
+
+		wasm_bindgen_futures::spawn_local(async move {
+			let result = cancelable.allow_cancel(future::pending(), "canceled").await;
+			completable.complete(result);
+		});
+
+		cancelation_token.cancel();
+		assert_eq!(completion_token.await, "canceled", "The spawned task should be canceled");
 	}
 }
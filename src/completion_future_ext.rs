@@ -0,0 +1,140 @@
+// https://github.com/GWBasic/sync-tokens
+// (c) Andrew Rondeau
+// Apache 2.0 license
+// See https://github.com/GWBasic/sync-tokens/blob/main/LICENSE
+
+//! Contains an extension trait that completes a [`Completable`](../completion_token/struct.Completable.html) when a
+//! future finishes. See [`CompletionFutureExt`](trait.CompletionFutureExt.html)
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use pin_project_lite::pin_project;
+
+use crate::completion_token::Completable;
+
+/// Completes a [`Completable`](../completion_token/struct.Completable.html) when a future finishes, so that signaling
+/// when a future is done doesn't need an async block that moves the completable.
+///
+/// If the returned future is dropped before it finishes, the completable is dropped with it, so the
+/// [`CompletionToken`](../completion_token/struct.CompletionToken.html) is abandoned.
+///
+/// This is implemented for every future, and is exported from [`prelude`](../prelude/index.html)
+///
+/// ```
+/// use sync_tokens::completion_token::CompletionToken;
+/// use sync_tokens::prelude::*;
+///
+/// # async_std::task::block_on(async {
+/// let (completion_token, completable) = CompletionToken::new();
+/// let (ready_signal, ready_signaler) = CompletionToken::new();
+///
+/// let loading = async { "loaded" }
+///     .notify_completion(completable)
+///     .signal_completion(ready_signaler);
+///
+/// assert_eq!(loading.await, "loaded");
+/// assert_eq!(completion_token.await, "loaded");
+/// ready_signal.await;
+/// # });
+/// ```
+pub trait CompletionFutureExt: Future + Sized {
+	/// Completes completable with a clone of the future's output when it finishes
+	fn notify_completion(self, completable: Completable<Self::Output>) -> NotifyCompletion<Self, Self::Output> where
+	Self::Output: Clone {
+		NotifyCompletion {
+			future: self,
+			completable: Some(completable),
+			result: Self::Output::clone
+		}
+	}
+
+	/// Signals ready_signaler when the future finishes. The output doesn't need to implement Clone
+	fn signal_completion(self, ready_signaler: Completable<()>) -> NotifyCompletion<Self, ()> {
+		NotifyCompletion {
+			future: self,
+			completable: Some(ready_signaler),
+			result: |_| ()
+		}
+	}
+}
+
+impl<F> CompletionFutureExt for F where
+F: Future {}
+
+pin_project! {
+	/// Future returned by [`CompletionFutureExt::notify_completion()`](trait.CompletionFutureExt.html#method.notify_completion)
+	/// and [`CompletionFutureExt::signal_completion()`](trait.CompletionFutureExt.html#method.signal_completion). Returns
+	/// the same output as the future that it wraps
+	#[derive(Debug)]
+	pub struct NotifyCompletion<F: Future, T> {
+		#[pin]
+		future: F,
+		completable: Option<Completable<T>>,
+		result: fn(&F::Output) -> T
+	}
+}
+
+impl<F, T> Future for NotifyCompletion<F, T> where
+F: Future {
+	type Output = F::Output;
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+		let this = self.project();
+		let output = futures::ready!(this.future.poll(cx));
+
+		if let Some(completable) = this.completable.take() {
+			completable.complete((this.result)(&output));
+		}
+
+		Poll::Ready(output)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::completion_token::{Abandoned, CompletionToken};
+
+	runtime_test! {
+		async fn test_notify_completion() {
+			let (completion_token, completable) = CompletionToken::new();
+
+			let waiting = async_std::task::spawn(completion_token);
+			async_std::task::sleep(std::time::Duration::from_millis(10)).await;
+
+			assert_eq!(async { vec![1, 2, 3] }.notify_completion(completable).await, vec![1, 2, 3], "Wrong output");
+			assert_eq!(waiting.await, vec![1, 2, 3], "The clone of the output should complete the token");
+		}
+	}
+
+	runtime_test! {
+		async fn test_signal_completion() {
+			// Doesn't implement Clone
+			struct Connection;
+
+			let (ready_signal, ready_signaler) = CompletionToken::new();
+
+			let Connection = async { Connection }.signal_completion(ready_signaler).await;
+			assert_eq!(ready_signal.try_wait().await, Ok(()), "Should be signaled");
+		}
+	}
+
+	runtime_test! {
+		async fn test_dropped_before_completion() {
+			let (completion_token, completable) = CompletionToken::<u32>::new();
+			let (ready_signal, ready_signaler) = CompletionToken::new();
+
+			let notify_completion = futures::future::pending().notify_completion(completable);
+			let signal_completion = futures::future::pending::<()>().signal_completion(ready_signaler);
+
+			assert!(futures::FutureExt::now_or_never(completion_token.clone()).is_none(), "Shouldn't be complete yet");
+
+			drop(notify_completion);
+			drop(signal_completion);
+
+			assert_eq!(completion_token.try_wait().await, Err(Abandoned), "Dropping should abandon");
+			assert_eq!(ready_signal.try_wait().await, Err(Abandoned), "Dropping should abandon");
+		}
+	}
+}
@@ -4,10 +4,29 @@
 // See https://github.com/GWBasic/sync-tokens/blob/main/LICENSE
 
 //! Contains structs to assist in waiting for a task to reach a certain state. See [`CompletionToken`](struct.CompletionToken.html) or [`sync-tokens`](../index.html) for an example.
+#[cfg(feature = "serde")]
+use std::any::Any;
+#[cfg(feature = "serde")]
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
 use std::future::Future;
+use std::marker::PhantomData;
 use std::pin::Pin;
-use std::sync::{Arc, Mutex};
-use std::task::{Context, Poll, Waker};
+use std::sync::{Arc, Mutex, Weak};
+#[cfg(feature = "serde")]
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Context, Poll, Wake, Waker};
+use std::time::Duration;
+
+use futures::FutureExt;
+use futures::future::{Either, Shared, select};
+use futures::stream::{FuturesUnordered, StreamExt};
+
+use crate::background;
+use crate::timer::{Sleep, Timer};
+use crate::wakers::{WakerKey, WakerList, wake_each};
 
 #[derive(Debug)]
 /// Allows waiting for a task to reach a certain state. When calling await, the task
@@ -15,11 +34,47 @@ use std::task::{Context, Poll, Waker};
 /// 
 /// See example at [`sync-tokens`](../index.html)
 /// 
+/// If the [`Completable`](struct.Completable.html) is dropped without calling complete, awaiting the
+/// [`CompletionToken`](struct.CompletionToken.html) never returns. Use [`try_wait()`](struct.CompletionToken.html#method.try_wait)
+/// to find out when this happens.
+/// 
+/// [`CompletionToken<T>`](struct.CompletionToken.html) and [`Completable<T>`](struct.Completable.html) are Send and
+/// Sync when T is Send. T doesn't need to be Sync, because the result is only moved, or cloned, while holding a lock.
+/// There's no `unsafe impl`; the bounds come from the `Arc<Mutex<..>>` that holds the result, so the compiler checks
+/// them. A token for a result that can't be sent, such as an `Rc`, can't be moved to another thread (error E0277,
+/// "`Rc<i32>` cannot be sent between threads safely"):
+/// 
+/// ```compile_fail,E0277
+/// use std::rc::Rc;
+/// 
+/// use sync_tokens::completion_token::CompletionToken;
+/// 
+/// let (completion_token, completable) = CompletionToken::<Rc<i32>>::new();
+/// 
+/// std::thread::spawn(move || futures::executor::block_on(completion_token));
+/// completable.complete(Rc::new(1));
+/// ```
+/// 
+/// Neither can its [`Completable`](struct.Completable.html):
+/// 
+/// ```compile_fail,E0277
+/// use std::rc::Rc;
+/// 
+/// use sync_tokens::completion_token::CompletionToken;
+/// 
+/// let (_completion_token, completable) = CompletionToken::<Rc<i32>>::new();
+/// 
+/// std::thread::spawn(move || completable.complete(Rc::new(1)));
+/// ```
+/// 
 /// # Panics
 /// 
 /// A [`CompletionToken`](struct.CompletionToken.html) will panic if it's awaited multiple times
 pub struct CompletionToken<T> {
-	shared_state: Arc<Mutex<CompletionTokenState<T>>>
+	shared_state: Arc<Mutex<CompletionTokenState<T>>>,
+	waker_key: Option<WakerKey>,
+	// Set for tokens that clone the result instead of taking it
+	clone_result: Option<fn(&T) -> T>
 }
 
 /// Allows unblocking a task that called await on a [`CompletionToken`](struct.CompletionToken.html)
@@ -30,11 +85,336 @@ pub struct Completable<T> {
 	shared_state: Arc<Mutex<CompletionTokenState<T>>>
 }
 
+/// Future returned by [`CompletionToken::try_wait()`](struct.CompletionToken.html#method.try_wait)
+#[derive(Debug)]
+pub struct TryCompletionTokenFuture<T> {
+	completion_token: CompletionToken<T>
+}
+
+/// A [`CompletionToken`](struct.CompletionToken.html) whose result is shared, in an [`Arc`](https://doc.rust-lang.org/std/sync/struct.Arc.html),
+/// instead of taken. Every clone of the token, and every await, returns the same result, even if it doesn't implement
+/// [`Clone`](https://doc.rust-lang.org/std/clone/trait.Clone.html)
+/// 
+/// ```
+/// use std::sync::Arc;
+/// 
+/// use sync_tokens::completion_token::MemoizedCompletionToken;
+/// 
+/// # async_std::task::block_on(async {
+/// let (memoized_completion_token, memoized_completable) = MemoizedCompletionToken::new();
+/// let clone = memoized_completion_token.clone();
+/// 
+/// memoized_completable.complete(vec![1, 2, 3]);
+/// 
+/// let first = memoized_completion_token.await;
+/// let second = clone.await;
+/// assert!(Arc::ptr_eq(&first, &second));
+/// # });
+/// ```
+#[derive(Debug)]
+pub struct MemoizedCompletionToken<T> {
+	completion_token: CompletionToken<Arc<T>>
+}
+
+/// Completes a [`MemoizedCompletionToken`](struct.MemoizedCompletionToken.html)
+#[derive(Debug)]
+pub struct MemoizedCompletable<T> {
+	completable: Completable<Arc<T>>
+}
+
+/// A [`Completable`](struct.Completable.html) that can be completed more than once without panicking, up to a limit.
+/// The [`CompletionToken`](struct.CompletionToken.html) resolves with the first value; later values are dropped.
+/// Once the limit is reached, [`complete()`](struct.BoundedCompletable.html#method.complete) returns
+/// [`CompletionOverflowError`](struct.CompletionOverflowError.html), so that a bug that completes in a loop is
+/// reported instead of crashing the task. Returned by
+/// [`CompletionToken::new_bounded()`](struct.CompletionToken.html#method.new_bounded)
+/// 
+/// ```
+/// use sync_tokens::completion_token::{CompletionOverflowError, CompletionToken};
+/// 
+/// # async_std::task::block_on(async {
+/// let (completion_token, bounded_completable) = CompletionToken::new_bounded(2);
+/// 
+/// assert_eq!(bounded_completable.complete("first"), Ok(()));
+/// assert_eq!(bounded_completable.complete("second"), Ok(()));
+/// assert_eq!(bounded_completable.complete("third"), Err(CompletionOverflowError));
+/// 
+/// assert_eq!(completion_token.await, "first");
+/// # });
+/// ```
+#[derive(Debug)]
+pub struct BoundedCompletable<T> {
+	completable: Completable<T>,
+	completions: AtomicUsize,
+	max_completions: usize
+}
+
+/// Error returned by [`BoundedCompletable::complete()`](struct.BoundedCompletable.html#method.complete) once it's
+/// completed max_completions times
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompletionOverflowError;
+
+/// A [`Completable`](struct.Completable.html) that checks each result with a validator before completing. A result
+/// that the validator rejects is dropped, and the [`CompletionToken`](struct.CompletionToken.html) stays incomplete,
+/// so that an incorrect value never reaches whoever waits for it. Returned by
+/// [`CompletionToken::new_with_validator()`](struct.CompletionToken.html#method.new_with_validator)
+/// 
+/// ```
+/// use sync_tokens::completion_token::{CompletionToken, ValidationError};
+/// 
+/// # async_std::task::block_on(async {
+/// let (completion_token, validating_completable) = CompletionToken::new_with_validator(|port: &u16| *port != 0);
+/// 
+/// assert_eq!(validating_completable.complete(0), Err(ValidationError));
+/// assert_eq!(validating_completable.complete(8080), Ok(()));
+/// 
+/// assert_eq!(completion_token.await, 8080);
+/// # });
+/// ```
+pub struct ValidatingCompletable<T, V> {
+	completable: Completable<T>,
+	validator: V
+}
+
+/// Error returned by [`ValidatingCompletable::complete()`](struct.ValidatingCompletable.html#method.complete) when the
+/// validator rejects the result
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ValidationError;
+
+/// A [`Completable`](struct.Completable.html) for an operation that's retried when it fails. Each failure passed to
+/// [`complete_or_retry()`](struct.RetryableCompletable.html#method.complete_or_retry) is recorded, and the
+/// [`CompletionToken`](struct.CompletionToken.html) keeps waiting for the next attempt. Once the operation fails more
+/// than max_retries times, the token is abandoned. Returned by
+/// [`CompletionToken::new_retryable()`](struct.CompletionToken.html#method.new_retryable)
+/// 
+/// ```
+/// use sync_tokens::completion_token::CompletionToken;
+/// 
+/// # async_std::task::block_on(async {
+/// let (completion_token, mut retryable_completable) = CompletionToken::new_retryable(1);
+/// 
+/// assert_eq!(retryable_completable.complete_or_retry(Err("connection refused")), Ok(()));
+/// assert_eq!(retryable_completable.last_error(), Some(&"connection refused"));
+/// assert_eq!(retryable_completable.complete_or_retry(Ok("connected")), Ok(()));
+/// 
+/// assert_eq!(completion_token.retry_count(), 1);
+/// assert_eq!(completion_token.await, "connected");
+/// # });
+/// ```
+#[derive(Debug)]
+pub struct RetryableCompletable<T, E> {
+	// Dropped once the retries are exhausted, which abandons the token
+	completable: Option<Completable<T>>,
+	last_error: Option<E>,
+	max_retries: u32
+}
+
+/// Error returned by [`RetryableCompletable::complete_or_retry()`](struct.RetryableCompletable.html#method.complete_or_retry)
+/// once the operation failed more than max_retries times
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetriesExhaustedError;
+
+/// Wraps a [`Completable`](struct.Completable.html), and transforms each result before completing it. A lower layer
+/// completes with its internal type, while the [`CompletionToken`](struct.CompletionToken.html) only sees the
+/// transformed type
+/// 
+/// Dropping the [`CompletionAdapter`](struct.CompletionAdapter.html) without calling complete abandons the
+/// [`CompletionToken`](struct.CompletionToken.html)
+/// 
+/// ```
+/// use sync_tokens::completion_token::CompletionToken;
+/// 
+/// # async_std::task::block_on(async {
+/// let (completion_token, completion_adapter) = CompletionToken::new_adapted(|bytes: Vec<u8>| String::from_utf8(bytes).unwrap());
+/// 
+/// completion_adapter.complete(b"ready".to_vec());
+/// assert_eq!(completion_token.await, "ready");
+/// # });
+/// ```
+pub struct CompletionAdapter<T, U, F> where
+F: Fn(T) -> U {
+	completable: Completable<U>,
+	adapt: F,
+	_input: PhantomData<fn(T)>
+}
+
+/// A [`CompletionToken`](struct.CompletionToken.html) that only signals that something happened, without a value
+/// 
+/// ```
+/// use sync_tokens::completion_token::ReadySignal;
+/// 
+/// # async_std::task::block_on(async {
+/// let (ready_signal, ready_signaler) = ReadySignal::new();
+/// 
+/// ready_signaler.signal();
+/// ready_signal.await;
+/// # });
+/// ```
+/// 
+/// Waiting doesn't cost anything extra: `()` takes no space, and the flag that tells whether it's set fits in the
+/// shared state's padding
+pub type ReadySignal = CompletionToken<()>;
+
+/// Sets the matching [`ReadySignal`](type.ReadySignal.html)
+pub type ReadySignaler = Completable<()>;
+
+/// Configures a [`CompletionToken`](struct.CompletionToken.html) and [`Completable`](struct.Completable.html) before
+/// creating them. Returned by [`CompletionToken::builder()`](struct.CompletionToken.html#method.builder)
+/// 
+/// The builder's second type parameter tracks whether a timeout is set, so that options that only apply to a
+/// timeout, such as [`with_timer()`](struct.CompletionTokenBuilder.html#method.with_timer), are only available after
+/// calling [`with_timeout()`](struct.CompletionTokenBuilder.html#method.with_timeout)
+/// 
+/// ```
+/// use std::time::Duration;
+/// 
+/// use sync_tokens::completion_token::{Abandoned, CompletionToken};
+/// 
+/// # async_std::task::block_on(async {
+/// let (completion_token, _completable) = CompletionToken::<()>::builder()
+///     .with_name("startup")
+///     .with_capacity(4)
+///     .with_timeout(Duration::from_millis(10))
+///     .build();
+/// 
+/// assert_eq!(completion_token.name(), Some("startup".to_string()));
+/// assert_eq!(completion_token.try_wait().await, Err(Abandoned));
+/// # });
+/// ```
+#[derive(Debug)]
+pub struct CompletionTokenBuilder<T, S = NoTimeout> {
+	name: Option<String>,
+	capacity: usize,
+	timeout: S,
+	_result: PhantomData<fn(T)>
+}
+
+/// Builder state for a [`CompletionTokenBuilder`](struct.CompletionTokenBuilder.html) without a timeout
+#[derive(Debug, Clone, Copy)]
+pub struct NoTimeout;
+
+/// Builder state for a [`CompletionTokenBuilder`](struct.CompletionTokenBuilder.html) with a timeout
+#[derive(Debug, Clone)]
+pub struct WithTimeout {
+	timeout: Duration,
+	timer: Timer
+}
+
+/// Serializable reference to a [`CompletionToken`](struct.CompletionToken.html), so that it can be stored on disk, or
+/// sent to another part of the process over a network connection. Returned by
+/// [`CompletionToken::handle()`](struct.CompletionToken.html#method.handle). Requires the `serde` feature
+/// 
+/// The handle only contains an id. [`CompletionToken::from_handle()`](struct.CompletionToken.html#method.from_handle)
+/// looks up the token in a registry for the current process; once every
+/// [`CompletionToken`](struct.CompletionToken.html) and [`Completable`](struct.Completable.html) is dropped, the handle
+/// is stale
+/// 
+/// ```
+/// use sync_tokens::completion_token::{CompletionToken, CompletionTokenHandle};
+/// 
+/// # async_std::task::block_on(async {
+/// let (completion_token, completable) = CompletionToken::new();
+/// let handle: CompletionTokenHandle = completion_token.handle();
+/// 
+/// completable.complete("done");
+/// 
+/// let completion_token = CompletionToken::<&str>::from_handle(handle).unwrap();
+/// assert_eq!(completion_token.await, "done");
+/// # });
+/// ```
+#[cfg(feature = "serde")]
+#[cfg_attr(feature = "docs", doc(cfg(feature = "serde")))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub struct CompletionTokenHandle {
+	id: u64
+}
+
+// Weak, so that the registry doesn't keep tokens alive. Entries are removed when the token's state is dropped
+#[cfg(feature = "serde")]
+static HANDLE_REGISTRY: Mutex<BTreeMap<u64, Weak<dyn Any + Send + Sync>>> = Mutex::new(BTreeMap::new());
+
+#[cfg(feature = "serde")]
+static NEXT_HANDLE_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Error returned when a [`Completable`](struct.Completable.html) is dropped without calling complete, or when a
+/// token built with a timeout times out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Abandoned;
+
+/// Whether a [`CompletionToken`](struct.CompletionToken.html) is complete, and what completed it. Returned by
+/// [`CompletionToken::state()`](struct.CompletionToken.html#method.state)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompletionState {
+	/// The [`Completable`](struct.Completable.html) hasn't completed yet
+	Pending,
+	/// The [`Completable`](struct.Completable.html) completed
+	Complete,
+	/// The [`Completable`](struct.Completable.html) was dropped without completing, so the token completed with
+	/// `T::default()`. See [`CompletionToken::new_with_default_on_drop()`](struct.CompletionToken.html#method.new_with_default_on_drop)
+	CompletedWithDefault,
+	/// The fallback from [`Completable::complete_within()`](struct.Completable.html#method.complete_within) completed
+	/// the token
+	CompletedByFallback,
+	/// The [`Completable`](struct.Completable.html) was dropped without completing, or the token timed out
+	Abandoned
+}
+
 #[derive(Debug)]
 struct CompletionTokenState<T> {
+	name: Option<String>,
 	complete: bool,
+	abandoned: bool,
+	// Fallback when complete_within() completed, so that the late real completion is dropped, or Default when
+	// new_with_default_on_drop() completed
+	completed_by: CompletedBy,
+	// Set by new_with_default_on_drop(), and taken when the completable is dropped without completing
+	default_on_drop: Option<fn() -> T>,
+	// Failures recorded by RetryableCompletable
+	retries: u32,
 	result: Option<T>,
-	waker: Option<Waker>
+	// Set once a token subscribes, so that the result is retained for every subscriber
+	retain_result: Option<fn(&T) -> T>,
+	// Set by handle(), and removed from the registry when the state is dropped
+	#[cfg(feature = "serde")]
+	handle_id: Option<u64>,
+	// Set by from_shared(), instead of a completable
+	source: SourceState<T>,
+	wakers: WakerList
+}
+
+#[derive(Debug)]
+enum SourceState<T> {
+	None,
+	Idle(SharedSource<T>),
+	// Taken out by the task that's polling it. missed is set if another task polled the token in the meantime, because
+	// that task may have been woken for the source, and returned without polling it
+	Polling { missed: bool }
+}
+
+// The future that from_shared() completes the token with. Whichever task polls the token polls it, with a waker that
+// wakes every waiting task, so that a task that stops waiting can't leave the others asleep
+struct SharedSource<T> {
+	future: Pin<Box<dyn Future<Output = T> + Send>>,
+	waker: Waker
+}
+
+// Weak, so that the future doesn't keep the token alive
+struct WakeWaiting<T>(Weak<Mutex<CompletionTokenState<T>>>);
+
+// Returns once the token completes or is abandoned, or once nothing refers to it. Raced against a timer, so that the
+// timer is disarmed as soon as it can't matter anymore
+#[derive(Debug)]
+struct Settled<T> {
+	shared_state: Weak<Mutex<CompletionTokenState<T>>>,
+	waker_key: Option<WakerKey>
+}
+
+// Only read once the token is complete
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CompletedBy {
+	Completable,
+	Fallback,
+	Default
 }
 
 /// Future that allows gracefully shutting down the server
@@ -42,157 +422,2126 @@ impl<T> CompletionToken<T> {
 	#[allow(dead_code)]
 	/// Creates a new [`CompletionToken`](struct.CompletionToken.html) and [`Completable`](struct.Completable.html)
 	pub fn new() -> (CompletionToken<T>, Completable<T>) {
-		let shared_state = Arc::new(Mutex::new(CompletionTokenState {
-			complete: false,
-			result: None,
-			waker: None
-		}));
+		CompletionToken::with_capacity(1)
+	}
 
-		let completion_token = CompletionToken {
-			shared_state: shared_state.clone()
+	/// Creates a new [`CompletionToken`](struct.CompletionToken.html) and [`Completable`](struct.Completable.html) with
+	/// room for capacity waiting clones of the token. Use this when the number of waiters is known up front, so that
+	/// awaiting the clones never reallocates
+	pub fn with_capacity(capacity: usize) -> (CompletionToken<T>, Completable<T>) {
+		CompletionToken::create(None, capacity)
+	}
+
+	/// Creates a new [`CompletionToken`](struct.CompletionToken.html) and
+	/// [`BoundedCompletable`](struct.BoundedCompletable.html), that can be completed up to max_completions times. With
+	/// max_completions of 0, completing always fails, and the token is abandoned once the completable is dropped
+	pub fn new_bounded(max_completions: usize) -> (CompletionToken<T>, BoundedCompletable<T>) {
+		let (completion_token, completable) = CompletionToken::new();
+
+		let bounded_completable = BoundedCompletable {
+			completable,
+			completions: AtomicUsize::new(0),
+			max_completions
 		};
 
-		let completable = Completable { shared_state };
+		(completion_token, bounded_completable)
+	}
 
-		(completion_token, completable)
+	/// Creates a new [`CompletionToken`](struct.CompletionToken.html) and
+	/// [`ValidatingCompletable`](struct.ValidatingCompletable.html), that only completes with results that validator
+	/// accepts
+	pub fn new_with_validator<V>(validator: V) -> (CompletionToken<T>, ValidatingCompletable<T, V>) where
+	V: Fn(&T) -> bool {
+		let (completion_token, completable) = CompletionToken::new();
+
+		let validating_completable = ValidatingCompletable {
+			completable,
+			validator
+		};
+
+		(completion_token, validating_completable)
 	}
-}
 
-impl<T> Completable<T> {
-	/// Call to indicate that the operation is complete, and unblock any calls to await on the [`CompletionToken`](struct.CompletionToken.html)
+	/// Creates a new [`CompletionToken`](struct.CompletionToken.html) and [`Completable`](struct.Completable.html) that,
+	/// if the [`Completable`](struct.Completable.html) is dropped without calling complete, completes with
+	/// `T::default()` instead of being abandoned. Completing before the drop wins.
+	/// [`state()`](struct.CompletionToken.html#method.state) tells the default apart from a real result
 	/// 
-	/// # Panics
+	/// ```
+	/// use sync_tokens::completion_token::{CompletionState, CompletionToken};
 	/// 
-	/// Complete will panic if it is called multiple times
-	#[allow(dead_code)]
-	pub fn complete(&self, result: T) {
-		let mut shared_state = self.shared_state.lock().unwrap();
+	/// # async_std::task::block_on(async {
+	/// let (completion_token, completable) = CompletionToken::<usize>::new_with_default_on_drop();
+	/// 
+	/// // The cache warmer went away without warming any entries
+	/// drop(completable);
+	/// 
+	/// assert_eq!(completion_token.state(), CompletionState::CompletedWithDefault);
+	/// assert_eq!(completion_token.await, 0);
+	/// # });
+	/// ```
+	pub fn new_with_default_on_drop() -> (CompletionToken<T>, Completable<T>) where
+	T: Default {
+		let (completion_token, completable) = CompletionToken::new();
+		completion_token.shared_state.lock().unwrap().default_on_drop = Some(T::default);
 
-		if shared_state.complete {
-			panic!("Completion token is already complete")
-		}
+		(completion_token, completable)
+	}
 
-		shared_state.complete = true;
-		shared_state.result = Some(result);
+	/// Creates a new [`CompletionToken`](struct.CompletionToken.html) and
+	/// [`RetryableCompletable`](struct.RetryableCompletable.html), that keeps waiting through up to max_retries failed
+	/// attempts. With max_retries of 0, the first failure abandons the token
+	pub fn new_retryable<E>(max_retries: u32) -> (CompletionToken<T>, RetryableCompletable<T, E>) {
+		let (completion_token, completable) = CompletionToken::new();
+
+		let retryable_completable = RetryableCompletable {
+			completable: Some(completable),
+			last_error: None,
+			max_retries
+		};
+
+		(completion_token, retryable_completable)
+	}
 
-		if let Some(waker) = shared_state.waker.take() {
-			waker.wake()
+	/// Returns a [`CompletionTokenBuilder`](struct.CompletionTokenBuilder.html), to configure the token before creating
+	/// it
+	pub fn builder() -> CompletionTokenBuilder<T> {
+		CompletionTokenBuilder {
+			name: None,
+			capacity: 1,
+			timeout: NoTimeout,
+			_result: PhantomData
 		}
 	}
-}
 
-impl<T> Future for CompletionToken<T> {
-	type Output = T;
+	/// Returns the name given with [`CompletionTokenBuilder::with_name()`](struct.CompletionTokenBuilder.html#method.with_name)
+	pub fn name(&self) -> Option<String> {
+		self.shared_state.lock().unwrap().name.clone()
+	}
 
-	fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-		let mut shared_state = self.shared_state.lock().unwrap();
+	/// Returns how many failed attempts were passed to
+	/// [`RetryableCompletable::complete_or_retry()`](struct.RetryableCompletable.html#method.complete_or_retry). Always
+	/// 0 for other completables
+	pub fn retry_count(&self) -> u32 {
+		self.shared_state.lock().unwrap().retries
+	}
 
-		if shared_state.complete {
-			let result = shared_state.result.take().expect("result already consumed");
-            Poll::Ready(result)
-		} else {
-            shared_state.waker = Some(cx.waker().clone());
-            Poll::Pending
-		}
+	fn create(name: Option<String>, capacity: usize) -> (CompletionToken<T>, Completable<T>) {
+		let shared_state = CompletionToken::new_state(name, capacity);
+
+		let completion_token = CompletionToken {
+			shared_state: shared_state.clone(),
+			waker_key: None,
+			clone_result: None
+		};
+
+		let completable = Completable { shared_state };
+
+		(completion_token, completable)
 	}
-}
 
-impl<T> Clone for CompletionToken<T> {
-	fn clone(&self) -> Self {
-		CompletionToken {
-			shared_state: self.shared_state.clone()
+	fn new_state(name: Option<String>, capacity: usize) -> Arc<Mutex<CompletionTokenState<T>>> {
+		Arc::new(Mutex::new(CompletionTokenState {
+			name,
+			complete: false,
+			abandoned: false,
+			completed_by: CompletedBy::Completable,
+			default_on_drop: None,
+			retries: 0,
+			result: None,
+			retain_result: None,
+			#[cfg(feature = "serde")]
+			handle_id: None,
+			source: SourceState::None,
+			wakers: WakerList::with_capacity(capacity)
+		}))
+	}
+
+	/// Waits for the [`Completable`](struct.Completable.html) to complete, or returns [`Abandoned`](struct.Abandoned.html)
+	/// if the [`Completable`](struct.Completable.html) is dropped without calling complete
+	pub fn try_wait(self) -> TryCompletionTokenFuture<T> {
+		TryCompletionTokenFuture {
+			completion_token: self
 		}
 	}
-}
 
+	/// Waits for the result, transforms it with f, and completes completable with it. This chains tokens into a
+	/// pipeline, where each stage waits for the one before it. The returned future must be awaited, or spawned, for
+	/// the pipeline to run.
+	/// 
+	/// If this token is abandoned, completable is dropped without completing, so the next stage is abandoned too
+	/// 
+	/// ```
+	/// use sync_tokens::completion_token::CompletionToken;
+	/// 
+	/// # async_std::task::block_on(async {
+	/// let (body, body_completable) = CompletionToken::new();
+	/// let (length, length_completable) = CompletionToken::new();
+	/// 
+	/// let pipeline = async_std::task::spawn(body.pipe_to(length_completable, |body: String| body.len()));
+	/// 
+	/// body_completable.complete("hello".to_string());
+	/// assert_eq!(length.await, 5);
+	/// pipeline.await;
+	/// # });
+	/// ```
+	pub async fn pipe_to<U, F>(self, completable: Completable<U>, f: F) where
+	F: FnOnce(T) -> U {
+		if let Ok(result) = self.try_wait().await {
+			completable.complete(f(result));
+		}
+	}
 
-#[cfg(test)]
-mod tests {
-    use async_std::prelude::*;
-	use futures::future;
-	use futures::future::{Either, select};
-	use std::task::Context;
+	/// Polls for the result, for use in a hand-written [`poll()`](https://doc.rust-lang.org/std/future/trait.Future.html#tymethod.poll).
+	/// This is what awaiting the token does: it returns `Poll::Ready` with the result once the
+	/// [`Completable`](struct.Completable.html) completes. Otherwise, cx's waker is woken when it completes. If the
+	/// [`Completable`](struct.Completable.html) is dropped without calling complete, this never returns `Poll::Ready`
+	/// 
+	/// ```
+	/// use std::future::Future;
+	/// use std::pin::Pin;
+	/// use std::task::{Context, Poll};
+	/// 
+	/// use sync_tokens::completion_token::CompletionToken;
+	/// 
+	/// // Waits for assets to load, while counting frames
+	/// struct LoadingScreen {
+	///     assets: CompletionToken<Vec<String>>,
+	///     frames: u64
+	/// }
+	/// 
+	/// impl Future for LoadingScreen {
+	///     type Output = (Vec<String>, u64);
+	/// 
+	///     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+	///         let this = self.get_mut();
+	///         this.frames += 1;
+	///         this.assets.poll_complete(cx).map(|assets| (assets, this.frames))
+	///     }
+	/// }
+	/// 
+	/// # async_std::task::block_on(async {
+	/// let (assets, completable) = CompletionToken::new();
+	/// completable.complete(vec!["map".to_string()]);
+	/// 
+	/// let (assets, frames) = LoadingScreen { assets, frames: 0 }.await;
+	/// assert_eq!(assets, vec!["map".to_string()]);
+	/// # });
+	/// ```
+	/// 
+	/// # Panics
+	/// 
+	/// Panics if polled after the result was already taken
+	pub fn poll_complete(&mut self, cx: &mut Context<'_>) -> Poll<T> {
+		match self.poll_result(cx) {
+			Poll::Ready(Ok(result)) => Poll::Ready(result),
+			// An abandoned token never completes
+			_ => Poll::Pending
+		}
+	}
 
-    use cooked_waker::IntoWaker;
+	/// Returns how many tasks are waiting on this token, or its clones. Once the
+	/// [`Completable`](struct.Completable.html) completes, or is dropped, the waiting tasks are woken and this returns
+	/// 0. The same as [`Completable::waiter_count()`](struct.Completable.html#method.waiter_count). Requires the
+	/// `diagnostics` feature
+	#[cfg(feature = "diagnostics")]
+	#[cfg_attr(feature = "docs", doc(cfg(feature = "diagnostics")))]
+	pub fn pending_waker_count(&self) -> usize {
+		self.shared_state.lock().unwrap().wakers.len()
+	}
 
-	use super::*;
-	use crate::tests::*;
+	/// Returns whether the token is complete, and what completed it
+	pub fn state(&self) -> CompletionState {
+		let shared_state = self.shared_state.lock().unwrap();
 
-	fn assert_not_completed_no_waker<T>(shared_state: &Arc<Mutex<CompletionTokenState<T>>>) {
-		let shared_state = shared_state.lock().unwrap();
-		assert_eq!(shared_state.complete, false, "Complete should be false at construction");
-		assert_eq!(shared_state.waker.is_none(), true, "Waker should not be set");
+		if shared_state.complete {
+			match shared_state.completed_by {
+				CompletedBy::Completable => CompletionState::Complete,
+				CompletedBy::Fallback => CompletionState::CompletedByFallback,
+				CompletedBy::Default => CompletionState::CompletedWithDefault
+			}
+		} else if shared_state.abandoned {
+			CompletionState::Abandoned
+		} else {
+			CompletionState::Pending
+		}
 	}
 
-	fn assert_not_completed_waker_set<T>(shared_state: &Arc<Mutex<CompletionTokenState<T>>>) {
-		let shared_state = shared_state.lock().unwrap();
-		assert_eq!(shared_state.complete, false, "Complete should be false");
-		assert_eq!(shared_state.waker.is_some(), true, "Waker should be set");
+	/// Returns true once the [`Completable`](struct.Completable.html) completed
+	pub(crate) fn is_complete(&self) -> bool {
+		self.shared_state.lock().unwrap().complete
 	}
 
-	fn assert_completed<T>(shared_state: &Arc<Mutex<CompletionTokenState<T>>>) {
-		let shared_state = shared_state.lock().unwrap();
-		assert_eq!(shared_state.complete, true, "Complete should be true");
-		assert_eq!(shared_state.waker.is_none(), true, "Waker should be set");
-	}
+	pub(crate) fn poll_result(&mut self, cx: &mut Context<'_>) -> Poll<Result<T, Abandoned>> {
+		let mut shared_state = self.shared_state.lock().unwrap();
 
-    #[test]
-    fn test_via_poll() {
+		if shared_state.complete {
+			let result = match self.clone_result.or(shared_state.retain_result) {
+				Some(clone_result) => shared_state.result.as_ref().map(clone_result),
+				None => shared_state.result.take()
+			};
 
-		let (mut completion_token, completable) = CompletionToken::new();
-		let shared_state = completion_token.shared_state.clone();
+			match result {
+				Some(result) => Poll::Ready(Ok(result)),
+				// A subscriber that came too late, after a token that didn't subscribe took the result
+				None if self.clone_result.is_some() => Poll::Ready(Err(Abandoned)),
+				None => {
+					drop(shared_state);
+					panic!("result already consumed")
+				}
+			}
+		} else if shared_state.abandoned {
+			Poll::Ready(Err(Abandoned))
+		} else {
+            shared_state.wakers.register(&mut self.waker_key, cx.waker());
 
-		let pinned_completion_token = Pin::new(&mut completion_token);
+			// Polled without holding the lock, because the future's waker takes it
+			if let Some(mut source) = shared_state.take_source() {
+				drop(shared_state);
 
-		assert_not_completed_no_waker(&shared_state);
+				match source.future.as_mut().poll(&mut Context::from_waker(&source.waker)) {
+					Poll::Ready(result) => {
+						let mut shared_state = self.shared_state.lock().unwrap();
+						shared_state.complete = true;
+						shared_state.result = Some(result);
+						shared_state.source = SourceState::None;
 
-		let test_waker = TestWaker::new();
-		let waker = test_waker.clone().into_waker();
-		let mut cx = Context::from_waker(&waker);
+						let wakers = shared_state.wakers.take_all();
+						drop(shared_state);
+						wake_each(wakers);
 
-		let poll_result = pinned_completion_token.poll(&mut cx);
-		assert_eq!(poll_result.is_pending(), true, "Completion token should be pending");
+						return self.poll_result(cx);
+					},
+					Poll::Pending => {
+						let mut shared_state = self.shared_state.lock().unwrap();
 
-		assert_not_completed_waker_set(&shared_state);
+						// Another task might be waiting for this one to poll the source again, but this one might stop
+						// waiting, so every waiting task is woken to poll it
+						if shared_state.restore_source(source) {
+							let wakers = shared_state.wakers.take_all();
+							drop(shared_state);
+							wake_each(wakers);
+						}
+					}
+				}
+			}
 
-		completable.complete("complete");
+            Poll::Pending
+		}
+	}
+}
 
-		assert_completed(&shared_state);
+impl<U> CompletionToken<U> {
+	/// Creates a new [`CompletionToken`](struct.CompletionToken.html) and a [`CompletionAdapter`](struct.CompletionAdapter.html)
+	/// that completes it with the results of adapt
+	pub fn new_adapted<T, F>(adapt: F) -> (CompletionToken<U>, CompletionAdapter<T, U, F>) where
+	F: Fn(T) -> U {
+		let (completion_token, completable) = CompletionToken::new();
+		(completion_token, CompletionAdapter::new(completable, adapt))
+	}
+}
 
-		let pinned_completion_token = Pin::new(&mut completion_token);
+impl<T, E> CompletionToken<Result<T, E>> {
+	/// Waits for the first token that completes with `Ok`, and returns its index and value. Tokens that complete with
+	/// `Err` are skipped, and abandoned tokens count as an [`Abandoned`](struct.Abandoned.html) error. If every token
+	/// fails, returns every error, in the same order as the tokens
+	/// 
+	/// ```
+	/// use sync_tokens::completion_token::{Abandoned, CompletionToken};
+	/// 
+	/// #[derive(Debug, PartialEq)]
+	/// enum BackendError {
+	///     Down,
+	///     Abandoned
+	/// }
+	/// 
+	/// impl From<Abandoned> for BackendError {
+	///     fn from(_: Abandoned) -> BackendError {
+	///         BackendError::Abandoned
+	///     }
+	/// }
+	/// 
+	/// # async_std::task::block_on(async {
+	/// let (primary, primary_completable) = CompletionToken::new();
+	/// let (backup, backup_completable) = CompletionToken::new();
+	/// 
+	/// primary_completable.complete(Err(BackendError::Down));
+	/// backup_completable.complete(Ok("response"));
+	/// 
+	/// assert_eq!(CompletionToken::race_ok(vec![primary, backup]).await, Ok((1, "response")));
+	/// # });
+	/// ```
+	pub async fn race_ok(completion_tokens: Vec<CompletionToken<Result<T, E>>>) -> Result<(usize, T), Vec<E>> where
+	E: From<Abandoned> {
+		let mut errors: Vec<Option<E>> = completion_tokens.iter().map(|_| None).collect();
 
-		let poll_result = pinned_completion_token.poll(&mut cx);
+		let mut results: FuturesUnordered<_> = completion_tokens.into_iter()
+			.enumerate()
+			.map(|(index, completion_token)| completion_token.try_wait().map(move |result| (index, result)))
+			.collect();
 
-		match poll_result {
-			Poll::Ready(result) => assert_eq!(result, "complete", "Wrong result"),
-			_ => panic!("Completion token should be ready")
+		while let Some((index, result)) = results.next().await {
+			match result {
+				Ok(Ok(value)) => return Ok((index, value)),
+				Ok(Err(err)) => errors[index] = Some(err),
+				Err(abandoned) => errors[index] = Some(E::from(abandoned))
+			}
 		}
 
-		assert_completed(&shared_state);
+		Err(errors.into_iter().map(|err| err.expect("Every token failed")).collect())
 	}
+}
 
-    #[async_std::test]
-    async fn test_via_future() {
+impl<T> CompletionToken<T> where
+T: Clone {
+	/// Splits this token into n tokens that each resolve, independently, to a clone of the result. Each of the
+	/// returned tokens can be awaited any number of times.
+	/// 
+	/// Like [`subscribe()`](struct.CompletionToken.html#method.subscribe), this retains the result, so clones of
+	/// this token that were made before calling split also resolve to a clone of the result, instead of taking it
+	/// from the split tokens
+	pub fn split(self, n: usize) -> Vec<CompletionToken<T>> {
+		self.shared_state.lock().unwrap().retain_result = Some(T::clone);
 
-		let (mut completion_token, completable) = CompletionToken::new();
-		let shared_state = completion_token.shared_state.clone();
+		(0..n).map(|_| CompletionToken {
+			shared_state: self.shared_state.clone(),
+			waker_key: None,
+			clone_result: Some(T::clone)
+		}).collect()
+	}
 
-		assert_not_completed_no_waker(&shared_state);
+	/// Creates a new, independent, token that resolves to a clone of the result. If the
+	/// [`Completable`](struct.Completable.html) already completed, the new token resolves immediately.
+	/// 
+	/// Once a token subscribes, the result is retained: this token, and every clone of it, also resolve to a clone
+	/// of the result instead of taking it. To unsubscribe, drop the returned token
+	/// 
+	/// If a token that didn't subscribe already took the result, there's nothing left to clone, so the returned token
+	/// is treated as abandoned: [`try_wait()`](struct.CompletionToken.html#method.try_wait) returns
+	/// [`Abandoned`](struct.Abandoned.html), and awaiting it never returns
+	pub fn subscribe(&self) -> CompletionToken<T> {
+		let mut shared_state = self.shared_state.lock().unwrap();
+		shared_state.retain_result = Some(T::clone);
 
-		match select(completion_token, future::ready(())).await {
-			Either::Left(_) => panic!("Cancelation token isn't canceled"),
-			Either::Right((_, c)) => completion_token = c
+		CompletionToken {
+			shared_state: self.shared_state.clone(),
+			waker_key: None,
+			clone_result: Some(T::clone)
 		}
+	}
 
-		completable.complete("complete");
+	/// Creates a token that completes when shared, a futures-rs
+	/// [`Shared`](https://docs.rs/futures/latest/futures/future/struct.Shared.html) future, resolves. Like shared, the
+	/// token, and every clone of it, can be awaited any number of times, and each resolves to a clone of the result.
+	///
+	/// shared is polled whenever the token, or a clone of it, is polled, so the token only completes once something
+	/// awaits it. Until then, [`state()`](struct.CompletionToken.html#method.state) returns
+	/// [`CompletionState::Pending`](enum.CompletionState.html#variant.Pending), even if shared already resolved
+	///
+	/// ```
+	/// use futures::FutureExt;
+	/// use sync_tokens::completion_token::CompletionToken;
+	///
+	/// # async_std::task::block_on(async {
+	/// let shared = async { "loaded" }.shared();
+	/// let completion_token = CompletionToken::from_shared(shared.clone());
+	///
+	/// assert_eq!(completion_token.clone().await, "loaded");
+	/// assert_eq!(completion_token.await, "loaded");
+	/// assert_eq!(shared.await, "loaded");
+	/// # });
+	/// ```
+	pub fn from_shared<F>(shared: Shared<F>) -> CompletionToken<T> where
+	F: Future<Output = T> + Send + 'static,
+	T: Send + Sync + 'static {
+		let shared_state = CompletionToken::new_state(None, 1);
 
-		assert_completed(&shared_state);
+		{
+			let mut state = shared_state.lock().unwrap();
+			state.retain_result = Some(T::clone);
+			state.source = SourceState::Idle(SharedSource {
+				future: Box::pin(shared),
+				waker: Waker::from(Arc::new(WakeWaiting(Arc::downgrade(&shared_state))))
+			});
+		}
 
-		match select(completion_token, future::pending::<()>()).await {
-			Either::Left((result, _)) => assert_eq!(result, "complete", "Wrong result"),
-			Either::Right(_) => panic!("Cancelation didn't happen")
+		CompletionToken {
+			shared_state,
+			waker_key: None,
+			clone_result: None
 		}
+	}
+}
 
-		assert_completed(&shared_state);
+#[cfg(feature = "serde")]
+#[cfg_attr(feature = "docs", doc(cfg(feature = "serde")))]
+impl<T> CompletionToken<T> where
+T: Send + 'static {
+	/// Returns a serializable [`CompletionTokenHandle`](struct.CompletionTokenHandle.html) for this token. Every clone of
+	/// the token returns the same handle
+	pub fn handle(&self) -> CompletionTokenHandle {
+		let mut shared_state = self.shared_state.lock().unwrap();
+
+		let id = *shared_state.handle_id.get_or_insert_with(|| {
+			let id = NEXT_HANDLE_ID.fetch_add(1, Ordering::Relaxed);
+			let weak_shared_state = Arc::downgrade(&self.shared_state) as Weak<dyn Any + Send + Sync>;
+			HANDLE_REGISTRY.lock().unwrap().insert(id, weak_shared_state);
+			id
+		});
+
+		CompletionTokenHandle { id }
+	}
+
+	/// Returns the token that handle refers to, the same as cloning it. Returns None if the handle is stale, or if the
+	/// token's result isn't a T
+	pub fn from_handle(handle: CompletionTokenHandle) -> Option<CompletionToken<T>> {
+		let shared_state = HANDLE_REGISTRY.lock().unwrap().get(&handle.id)?.upgrade()?;
+
+		Some(CompletionToken {
+			shared_state: shared_state.downcast::<Mutex<CompletionTokenState<T>>>().ok()?,
+			waker_key: None,
+			clone_result: None
+		})
+	}
+}
+
+impl<T> Completable<T> {
+	/// Call to indicate that the operation is complete, and unblock any calls to await on the [`CompletionToken`](struct.CompletionToken.html)
+	/// 
+	/// # Panics
+	/// 
+	/// Complete will panic if it is called multiple times. Calling it after the fallback from
+	/// [`complete_within()`](struct.Completable.html#method.complete_within) completed the token drops result instead
+	/// 
+	/// If a waiting task's waker panics, the token is still complete and every other task is still woken, and then
+	/// the panic is resumed
+	#[allow(dead_code)]
+	pub fn complete(&self, result: T) {
+		let mut shared_state = self.shared_state.lock().unwrap();
+
+		if shared_state.complete {
+			// The fallback won the race, so the waiting tasks already have a result
+			if shared_state.completed_by == CompletedBy::Fallback {
+				return;
+			}
+
+			panic!("Completion token is already complete")
+		}
+
+		// Timed out, so the waiting tasks already stopped waiting
+		if shared_state.abandoned {
+			return;
+		}
+
+		shared_state.complete = true;
+		shared_state.result = Some(result);
+
+		// Woken without holding the lock, so that a waker that panics can't poison it, or keep other tasks asleep
+		let wakers = shared_state.wakers.take_all();
+		drop(shared_state);
+		wake_each(wakers);
+	}
+}
+
+impl<T, S> CompletionTokenBuilder<T, S> {
+	/// Names the token, for logging and debugging. See [`CompletionToken::name()`](struct.CompletionToken.html#method.name)
+	pub fn with_name(mut self, name: &str) -> CompletionTokenBuilder<T, S> {
+		self.name = Some(name.to_string());
+		self
+	}
+
+	/// Makes room for capacity waiting clones of the token. See
+	/// [`CompletionToken::with_capacity()`](struct.CompletionToken.html#method.with_capacity)
+	pub fn with_capacity(mut self, capacity: usize) -> CompletionTokenBuilder<T, S> {
+		self.capacity = capacity;
+		self
+	}
+}
+
+impl<T> CompletionTokenBuilder<T, NoTimeout> {
+	/// Abandons the token if it isn't completed within timeout, so that
+	/// [`try_wait()`](struct.CompletionToken.html#method.try_wait) returns [`Abandoned`](struct.Abandoned.html).
+	/// Completing the token after it times out does nothing
+	pub fn with_timeout(self, timeout: Duration) -> CompletionTokenBuilder<T, WithTimeout> {
+		CompletionTokenBuilder {
+			name: self.name,
+			capacity: self.capacity,
+			timeout: WithTimeout {
+				timeout,
+				timer: Timer::default()
+			},
+			_result: PhantomData
+		}
+	}
+
+	/// Creates the [`CompletionToken`](struct.CompletionToken.html) and [`Completable`](struct.Completable.html)
+	pub fn build(self) -> (CompletionToken<T>, Completable<T>) {
+		CompletionToken::create(self.name, self.capacity)
+	}
+}
+
+impl<T> CompletionTokenBuilder<T, WithTimeout> where
+T: Send + 'static {
+	/// Uses the given [`Timer`](../timer/struct.Timer.html) for the timeout, instead of the system clock
+	pub fn with_timer(mut self, timer: Timer) -> CompletionTokenBuilder<T, WithTimeout> {
+		self.timeout.timer = timer;
+		self
+	}
+
+	/// Creates the [`CompletionToken`](struct.CompletionToken.html) and [`Completable`](struct.Completable.html). The
+	/// timeout is disarmed once the token completes, or once the [`Completable`](struct.Completable.html) is dropped
+	pub fn build(self) -> (CompletionToken<T>, Completable<T>) {
+		// One more slot, for the timeout's waker
+		let (completion_token, completable) = CompletionToken::create(self.name, self.capacity + 1);
+
+		let timed_out = Settled::new(&completion_token.shared_state).timed_out(self.timeout.timer.sleep(self.timeout.timeout));
+
+		background::spawn(async move {
+			if let Some(shared_state) = timed_out.await {
+				let mut shared_state = shared_state.lock().unwrap();
+
+				if !shared_state.complete && !shared_state.abandoned {
+					shared_state.abandoned = true;
+
+					let wakers = shared_state.wakers.take_all();
+					drop(shared_state);
+					wake_each(wakers);
+				}
+			}
+		});
+
+		(completion_token, completable)
+	}
+}
+
+impl<T> Completable<T> where
+T: Send + 'static {
+	/// Completes the [`CompletionToken`](struct.CompletionToken.html) with fallback if
+	/// [`complete()`](struct.Completable.html#method.complete) isn't called within duration.
+	/// 
+	/// Whichever completes first, under the token's lock, wins: if the real result arrives first, the timer is disarmed
+	/// and the fallback is dropped right away; if the fallback fires first, the later call to complete drops its
+	/// result instead of panicking. Unlike a timeout that a waiting task puts on its own await, this is the producer's
+	/// policy, so every waiting task sees the same result; and unlike
+	/// [`CompletionTokenBuilder::with_timeout()`](struct.CompletionTokenBuilder.html#method.with_timeout), the token
+	/// completes instead of being abandoned. If the [`Completable`](struct.Completable.html) is dropped first, the token
+	/// is abandoned, as usual, and the timer is disarmed
+	/// 
+	/// ```
+	/// use std::time::Duration;
+	/// 
+	/// use sync_tokens::completion_token::CompletionToken;
+	/// 
+	/// # async_std::task::block_on(async {
+	/// let (completion_token, completable) = CompletionToken::new();
+	/// completable.complete_within(Duration::from_millis(10), "cached config");
+	/// 
+	/// assert_eq!(completion_token.await, "cached config");
+	/// 
+	/// // The real result arrived too late, so it's dropped
+	/// completable.complete("fresh config");
+	/// # });
+	/// ```
+	pub fn complete_within(&self, duration: Duration, fallback: T) {
+		background::spawn(self.fallback_after(Timer::default(), duration, fallback));
+	}
+
+	// Returns the future that completes with fallback once duration passes, so that tests can run it with a ManualClock
+	fn fallback_after(&self, timer: Timer, duration: Duration, fallback: T) -> impl Future<Output = ()> + Send + 'static {
+		let timed_out = Settled::new(&self.shared_state).timed_out(timer.sleep(duration));
+
+		async move {
+			if let Some(shared_state) = timed_out.await {
+				let mut shared_state = shared_state.lock().unwrap();
+
+				if !shared_state.complete && !shared_state.abandoned {
+					shared_state.complete = true;
+					shared_state.completed_by = CompletedBy::Fallback;
+					shared_state.result = Some(fallback);
+
+					let wakers = shared_state.wakers.take_all();
+					drop(shared_state);
+					wake_each(wakers);
+				}
+			}
+		}
+	}
+}
+
+impl<T> Completable<T> {
+	/// Returns how many tasks are waiting on the [`CompletionToken`](struct.CompletionToken.html), or its clones. Use
+	/// this to avoid starting an operation when nobody is waiting for it. The same as
+	/// [`CompletionToken::pending_waker_count()`](struct.CompletionToken.html#method.pending_waker_count). Requires the
+	/// `diagnostics` feature
+	#[cfg(feature = "diagnostics")]
+	#[cfg_attr(feature = "docs", doc(cfg(feature = "diagnostics")))]
+	pub fn waiter_count(&self) -> usize {
+		self.shared_state.lock().unwrap().wakers.len()
+	}
+}
+
+impl Completable<()> {
+	/// Signals the [`ReadySignal`](type.ReadySignal.html). The same as calling complete with `()`
+	/// 
+	/// # Panics
+	/// 
+	/// Signal will panic if it is called multiple times
+	pub fn signal(&self) {
+		self.complete(());
+	}
+}
+
+impl<T> BoundedCompletable<T> {
+	/// Completes the [`CompletionToken`](struct.CompletionToken.html) the first time it's called. Later calls drop
+	/// result. Returns [`CompletionOverflowError`](struct.CompletionOverflowError.html), without completing, once this
+	/// was already called max_completions times
+	pub fn complete(&self, result: T) -> Result<(), CompletionOverflowError> {
+		let max_completions = self.max_completions;
+		let completions = self.completions
+			.fetch_update(Ordering::AcqRel, Ordering::Acquire, |completions| {
+				if completions < max_completions {
+					Some(completions + 1)
+				} else {
+					None
+				}
+			})
+			.map_err(|_| CompletionOverflowError)?;
+
+		// Only the first call completes, so the completable never panics
+		if completions == 0 {
+			self.completable.complete(result);
+		}
+
+		Ok(())
+	}
+
+	/// Returns how many times [`complete()`](struct.BoundedCompletable.html#method.complete) succeeded
+	pub fn completions(&self) -> usize {
+		self.completions.load(Ordering::Acquire)
+	}
+}
+
+impl<T, V> ValidatingCompletable<T, V> where
+V: Fn(&T) -> bool {
+	/// Completes the [`CompletionToken`](struct.CompletionToken.html) if the validator accepts result. Returns
+	/// [`ValidationError`](struct.ValidationError.html), without completing, if the validator rejects it; complete can
+	/// be called again with another result
+	/// 
+	/// # Panics
+	/// 
+	/// Complete will panic if it's called again after a result was accepted
+	pub fn complete(&self, result: T) -> Result<(), ValidationError> {
+		if !(self.validator)(&result) {
+			return Err(ValidationError);
+		}
+
+		self.completable.complete(result);
+		Ok(())
+	}
+}
+
+impl<T, E> RetryableCompletable<T, E> {
+	/// Completes the [`CompletionToken`](struct.CompletionToken.html) with an `Ok` result. An `Err` is kept as the
+	/// [`last_error()`](struct.RetryableCompletable.html#method.last_error), and the token keeps waiting for the next
+	/// attempt. Returns [`RetriesExhaustedError`](struct.RetriesExhaustedError.html) once the operation failed more than
+	/// max_retries times; the token is abandoned then, and later results are dropped
+	/// 
+	/// # Panics
+	/// 
+	/// Panics if it's called again after an `Ok` result
+	pub fn complete_or_retry(&mut self, result: Result<T, E>) -> Result<(), RetriesExhaustedError> {
+		let completable = self.completable.as_ref().ok_or(RetriesExhaustedError)?;
+
+		match result {
+			Ok(result) => {
+				completable.complete(result);
+				Ok(())
+			},
+			Err(error) => {
+				let retries = {
+					let mut shared_state = completable.shared_state.lock().unwrap();
+					shared_state.retries += 1;
+					shared_state.retries
+				};
+
+				self.last_error = Some(error);
+
+				if retries > self.max_retries {
+					self.completable = None;
+					Err(RetriesExhaustedError)
+				} else {
+					Ok(())
+				}
+			}
+		}
+	}
+
+	/// Returns the error from the most recent failed attempt
+	pub fn last_error(&self) -> Option<&E> {
+		self.last_error.as_ref()
+	}
+}
+
+impl<T> Drop for Completable<T> {
+	fn drop(&mut self) {
+		let mut shared_state = self.shared_state.lock().unwrap();
+
+		if !shared_state.complete {
+			match shared_state.default_on_drop.take() {
+				Some(default) => {
+					shared_state.complete = true;
+					shared_state.completed_by = CompletedBy::Default;
+					shared_state.result = Some(default());
+				},
+				None => shared_state.abandoned = true
+			}
+
+			let wakers = shared_state.wakers.take_all();
+			drop(shared_state);
+			wake_each(wakers);
+		}
+	}
+}
+
+impl<T> MemoizedCompletionToken<T> {
+	/// Creates a new [`MemoizedCompletionToken`](struct.MemoizedCompletionToken.html) and
+	/// [`MemoizedCompletable`](struct.MemoizedCompletable.html)
+	pub fn new() -> (MemoizedCompletionToken<T>, MemoizedCompletable<T>) {
+		let (mut completion_token, completable) = CompletionToken::new();
+		completion_token.clone_result = Some(Arc::clone);
+
+		let memoized_completion_token = MemoizedCompletionToken { completion_token };
+		let memoized_completable = MemoizedCompletable { completable };
+
+		(memoized_completion_token, memoized_completable)
+	}
+
+	/// Waits for the [`MemoizedCompletable`](struct.MemoizedCompletable.html) to complete, or returns
+	/// [`Abandoned`](struct.Abandoned.html) if it's dropped without calling complete
+	pub fn try_wait(self) -> TryCompletionTokenFuture<Arc<T>> {
+		self.completion_token.try_wait()
+	}
+}
+
+impl<T> MemoizedCompletable<T> {
+	/// Call to indicate that the operation is complete. Every [`MemoizedCompletionToken`](struct.MemoizedCompletionToken.html)
+	/// returns the same result
+	/// 
+	/// # Panics
+	/// 
+	/// Complete will panic if it is called multiple times
+	pub fn complete(&self, result: T) {
+		self.completable.complete(Arc::new(result));
+	}
+}
+
+impl<T, U, F> CompletionAdapter<T, U, F> where
+F: Fn(T) -> U {
+	/// Wraps completable, so that it's completed with the results of adapt
+	pub fn new(completable: Completable<U>, adapt: F) -> CompletionAdapter<T, U, F> {
+		CompletionAdapter {
+			completable,
+			adapt,
+			_input: PhantomData
+		}
+	}
+
+	/// Transforms result, and then completes the wrapped [`Completable`](struct.Completable.html)
+	/// 
+	/// # Panics
+	/// 
+	/// Complete will panic if it is called multiple times
+	pub fn complete(&self, result: T) {
+		self.completable.complete((self.adapt)(result));
+	}
+
+	/// Returns the wrapped [`Completable`](struct.Completable.html)
+	pub fn into_inner(self) -> Completable<U> {
+		self.completable
+	}
+}
+
+impl<T, U, F> fmt::Debug for CompletionAdapter<T, U, F> where
+U: fmt::Debug,
+F: Fn(T) -> U {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("CompletionAdapter")
+			.field("completable", &self.completable)
+			.finish()
+	}
+}
+
+impl<T, V> fmt::Debug for ValidatingCompletable<T, V> where
+T: fmt::Debug {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("ValidatingCompletable")
+			.field("completable", &self.completable)
+			.finish()
+	}
+}
+
+impl<T> Clone for MemoizedCompletionToken<T> {
+	fn clone(&self) -> Self {
+		MemoizedCompletionToken {
+			completion_token: self.completion_token.clone()
+		}
+	}
+}
+
+impl<T> Future for MemoizedCompletionToken<T> {
+	type Output = Arc<T>;
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+		Pin::new(&mut self.get_mut().completion_token).poll(cx)
+	}
+}
+
+impl<T> Future for CompletionToken<T> {
+	type Output = T;
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+		self.get_mut().poll_complete(cx)
+	}
+}
+
+impl<T> fmt::Debug for SharedSource<T> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("SharedSource").finish_non_exhaustive()
+	}
+}
+
+impl<T> Wake for WakeWaiting<T> where
+T: Send + 'static {
+	fn wake(self: Arc<Self>) {
+		if let Some(shared_state) = self.0.upgrade() {
+			let wakers = shared_state.lock().unwrap().wakers.take_all();
+			wake_each(wakers);
+		}
+	}
+}
+
+impl<T> Settled<T> {
+	fn new(shared_state: &Arc<Mutex<CompletionTokenState<T>>>) -> Settled<T> {
+		Settled {
+			// Weak, so that the timer doesn't keep a token that's no longer used alive
+			shared_state: Arc::downgrade(shared_state),
+			waker_key: None
+		}
+	}
+
+	// Waits for sleep, unless the token settles first. Returns the token's state if sleep finished first
+	async fn timed_out(mut self, sleep: Sleep) -> Option<Arc<Mutex<CompletionTokenState<T>>>> {
+		match select(sleep, &mut self).await {
+			Either::Left(_) => self.shared_state.upgrade(),
+			Either::Right(_) => None
+		}
+	}
+}
+
+impl<T> Future for Settled<T> {
+	type Output = ();
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+		let this = self.get_mut();
+
+		let shared_state = match this.shared_state.upgrade() {
+			Some(shared_state) => shared_state,
+			None => return Poll::Ready(())
+		};
+
+		let mut shared_state = shared_state.lock().unwrap();
+
+		if shared_state.complete || shared_state.abandoned {
+			Poll::Ready(())
+		} else {
+			shared_state.wakers.register(&mut this.waker_key, cx.waker());
+			Poll::Pending
+		}
+	}
+}
+
+impl<T> Drop for Settled<T> {
+	fn drop(&mut self) {
+		if self.waker_key.is_some() {
+			if let Some(shared_state) = self.shared_state.upgrade() {
+				shared_state.lock().unwrap().wakers.remove(self.waker_key);
+			}
+		}
+	}
+}
+
+impl<T> Future for TryCompletionTokenFuture<T> {
+	type Output = Result<T, Abandoned>;
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+		self.get_mut().completion_token.poll_result(cx)
+	}
+}
+
+impl fmt::Display for Abandoned {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "The completable was dropped without completing")
+	}
+}
+
+impl Error for Abandoned {}
+
+impl fmt::Display for CompletionOverflowError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "The completable was completed too many times")
+	}
+}
+
+impl Error for CompletionOverflowError {}
+
+impl fmt::Display for RetriesExhaustedError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "The operation failed more times than it can be retried")
+	}
+}
+
+impl Error for RetriesExhaustedError {}
+
+impl fmt::Display for ValidationError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "The validator rejected the result")
+	}
+}
+
+impl Error for ValidationError {}
+
+// Shows the shared state's address, so that log lines can match a token with its completable
+impl<T> fmt::Pointer for CompletionToken<T> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		fmt::Pointer::fmt(&Arc::as_ptr(&self.shared_state), f)
+	}
+}
+
+impl<T> fmt::Pointer for Completable<T> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		fmt::Pointer::fmt(&Arc::as_ptr(&self.shared_state), f)
+	}
+}
+
+impl<T> Clone for CompletionToken<T> {
+	fn clone(&self) -> Self {
+		CompletionToken {
+			shared_state: self.shared_state.clone(),
+			waker_key: None,
+			clone_result: self.clone_result
+		}
+	}
+}
+
+impl<T> CompletionTokenState<T> {
+	// Takes the source out, so that it can be polled without holding the lock. Returns None if there isn't one, or if
+	// another task is already polling it
+	fn take_source(&mut self) -> Option<SharedSource<T>> {
+		match std::mem::replace(&mut self.source, SourceState::None) {
+			SourceState::Idle(source) => {
+				self.source = SourceState::Polling { missed: false };
+				Some(source)
+			},
+			SourceState::Polling { .. } => {
+				self.source = SourceState::Polling { missed: true };
+				None
+			},
+			SourceState::None => None
+		}
+	}
+
+	// Puts the source back once it's pending. Returns true if another task polled the token in the meantime
+	fn restore_source(&mut self, source: SharedSource<T>) -> bool {
+		let missed = matches!(self.source, SourceState::Polling { missed: true });
+		self.source = SourceState::Idle(source);
+		missed
+	}
+}
+
+#[cfg(feature = "serde")]
+impl<T> Drop for CompletionTokenState<T> {
+	fn drop(&mut self) {
+		if let Some(handle_id) = self.handle_id {
+			// Ignores a poisoned registry, because the entry is stale anyway
+			if let Ok(mut registry) = HANDLE_REGISTRY.lock() {
+				registry.remove(&handle_id);
+			}
+		}
+	}
+}
+
+impl<T> Drop for CompletionToken<T> {
+	fn drop(&mut self) {
+		if self.waker_key.is_some() {
+			let mut shared_state = self.shared_state.lock().unwrap();
+			shared_state.wakers.remove(self.waker_key);
+		}
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+    use async_std::prelude::*;
+	use futures::executor::LocalPool;
+	use futures::future;
+	use futures::future::{Either, select};
+	use futures::task::LocalSpawnExt;
+	use std::task::{Context, Waker};
+
+    use cooked_waker::IntoWaker;
+
+	use super::*;
+	use crate::tests::*;
+
+	fn assert_send_sync<T: Send + Sync>() {}
+
+	// Fails to compile if a change to the internals loses Send or Sync, or starts requiring T: Sync
+	#[allow(dead_code)]
+	fn completion_token_is_send_sync<T: Send>() {
+		assert_send_sync::<CompletionToken<T>>();
+		assert_send_sync::<Completable<T>>();
+		assert_send_sync::<TryCompletionTokenFuture<T>>();
+		assert_send_sync::<BoundedCompletable<T>>();
+	}
+
+	// Memoized results are shared in an Arc, so they also need to be Sync
+	#[allow(dead_code)]
+	fn memoized_completion_token_is_send_sync<T: Send + Sync>() {
+		assert_send_sync::<MemoizedCompletionToken<T>>();
+		assert_send_sync::<MemoizedCompletable<T>>();
+	}
+
+	#[test]
+	fn test_send_sync() {
+		// Cell isn't Sync
+		completion_token_is_send_sync::<std::cell::Cell<u32>>();
+		assert_send_sync::<Abandoned>();
+	}
+
+	fn assert_not_completed_no_waker<T>(shared_state: &Arc<Mutex<CompletionTokenState<T>>>) {
+		let shared_state = shared_state.lock().unwrap();
+		assert!(!shared_state.complete, "Complete should be false at construction");
+		assert!(shared_state.wakers.is_empty(), "Waker should not be set");
+	}
+
+	fn assert_not_completed_waker_set<T>(shared_state: &Arc<Mutex<CompletionTokenState<T>>>) {
+		let shared_state = shared_state.lock().unwrap();
+		assert!(!shared_state.complete, "Complete should be false");
+		assert!(!shared_state.wakers.is_empty(), "Waker should be set");
+	}
+
+	fn assert_completed<T>(shared_state: &Arc<Mutex<CompletionTokenState<T>>>) {
+		let shared_state = shared_state.lock().unwrap();
+		assert!(shared_state.complete, "Complete should be true");
+		assert!(shared_state.wakers.is_empty(), "Waker should be set");
+	}
+
+    #[test]
+    fn test_via_poll() {
+
+		let (mut completion_token, completable) = CompletionToken::new();
+		let shared_state = completion_token.shared_state.clone();
+
+		let pinned_completion_token = Pin::new(&mut completion_token);
+
+		assert_not_completed_no_waker(&shared_state);
+
+		let test_waker = TestWaker::new();
+		let waker = test_waker.clone().into_waker();
+		let mut cx = Context::from_waker(&waker);
+
+		let poll_result = pinned_completion_token.poll(&mut cx);
+		assert!(poll_result.is_pending(), "Completion token should be pending");
+
+		assert_not_completed_waker_set(&shared_state);
+
+		completable.complete("complete");
+
+		assert_completed(&shared_state);
+
+		let pinned_completion_token = Pin::new(&mut completion_token);
+
+		let poll_result = pinned_completion_token.poll(&mut cx);
+
+		match poll_result {
+			Poll::Ready(result) => assert_eq!(result, "complete", "Wrong result"),
+			_ => panic!("Completion token should be ready")
+		}
+
+		assert_completed(&shared_state);
+	}
+
+	runtime_test! {
+		async fn test_via_future() {
+
+			let (mut completion_token, completable) = CompletionToken::new();
+			let shared_state = completion_token.shared_state.clone();
+
+			assert_not_completed_no_waker(&shared_state);
+
+			match select(completion_token, future::ready(())).await {
+				Either::Left(_) => panic!("Cancelation token isn't canceled"),
+				Either::Right((_, c)) => completion_token = c
+			}
+
+			completable.complete("complete");
+
+			assert_completed(&shared_state);
+
+			match select(completion_token, future::pending::<()>()).await {
+				Either::Left((result, _)) => assert_eq!(result, "complete", "Wrong result"),
+				Either::Right(_) => panic!("Cancelation didn't happen")
+			}
+
+			assert_completed(&shared_state);
+		}
+	}
+
+	runtime_test! {
+		async fn test_try_wait() {
+
+			let (completion_token, completable) = CompletionToken::new();
+			completable.complete("complete");
+
+			assert_eq!(completion_token.try_wait().await, Ok("complete"), "Wrong result");
+		}
+	}
+
+    #[test]
+    fn test_abandoned() {
+
+		let (completion_token, completable) = CompletionToken::<&str>::new();
+		let shared_state = completion_token.shared_state.clone();
+
+		let mut try_wait_future = completion_token.clone().try_wait();
+		let mut completion_token = completion_token;
+
+		let test_waker = TestWaker::new();
+		let waker = test_waker.clone().into_waker();
+		let mut cx = Context::from_waker(&waker);
+
+		let poll_result = Pin::new(&mut try_wait_future).poll(&mut cx);
+		assert!(poll_result.is_pending(), "Completion token should be pending");
+
+		drop(completable);
+
+		assert!(test_waker.woke(), "Waiter should be woken when the completable is dropped");
+		assert_not_completed_no_waker(&shared_state);
+
+		match Pin::new(&mut try_wait_future).poll(&mut cx) {
+			Poll::Ready(Err(Abandoned)) => {},
+			_ => panic!("Completion token should be abandoned")
+		}
+
+		let poll_result = Pin::new(&mut completion_token).poll(&mut cx);
+		assert!(poll_result.is_pending(), "Awaiting an abandoned completion token should never return");
+	}
+
+    #[test]
+    fn test_drop_after_complete_is_not_abandoned() {
+
+		let (completion_token, completable) = CompletionToken::new();
+
+		completable.complete("complete");
+		drop(completable);
+
+		assert_eq!(futures::executor::block_on(completion_token.try_wait()), Ok("complete"), "Wrong result");
+	}
+
+	runtime_test! {
+		async fn test_split() {
+
+			let (completion_token, completable) = CompletionToken::new();
+			let split_tokens = completion_token.split(5);
+			assert_eq!(split_tokens.len(), 5, "Wrong number of tokens");
+
+			let tasks: Vec<_> = split_tokens.into_iter()
+				.map(async_std::task::spawn)
+				.collect();
+
+			completable.complete("complete".to_string());
+
+			for task in tasks {
+				assert_eq!(task.await, "complete", "Wrong result");
+			}
+		}
+	}
+
+    #[test]
+    fn test_split_awaited_multiple_times() {
+
+		let (completion_token, completable) = CompletionToken::new();
+		let mut split_tokens = completion_token.split(2);
+
+		let test_waker = TestWaker::new();
+		let waker = test_waker.into_waker();
+		let mut cx = Context::from_waker(&waker);
+
+		completable.complete(42);
+
+		for split_token in split_tokens.iter_mut() {
+			for _ in 0..3 {
+				match Pin::new(&mut *split_token).poll(&mut cx) {
+					Poll::Ready(result) => assert_eq!(result, 42, "Wrong result"),
+					_ => panic!("Split token should be ready")
+				}
+			}
+		}
+	}
+
+    #[test]
+    fn test_split_drops_result_once() {
+
+		let value = Arc::new("complete");
+
+		{
+			let (completion_token, completable) = CompletionToken::new();
+			let split_tokens = completion_token.split(5);
+
+			completable.complete(value.clone());
+
+			let results: Vec<_> = split_tokens.into_iter().take(3).map(futures::executor::block_on).collect();
+			assert_eq!(Arc::strong_count(&value), 5, "Each result, and the stored result, should hold a reference");
+
+			drop(results);
+		}
+
+		assert_eq!(Arc::strong_count(&value), 1, "Every clone of the result should be dropped exactly once");
+	}
+
+	#[test]
+	fn test_split_after_clone() {
+		let (completion_token, completable) = CompletionToken::new();
+		let clone = completion_token.clone();
+		let split_tokens = completion_token.split(2);
+
+		completable.complete(42);
+
+		assert_eq!(futures::executor::block_on(clone), 42, "Wrong result");
+
+		for split_token in split_tokens {
+			assert_eq!(futures::executor::block_on(split_token), 42, "A clone from before the split shouldn't take the result");
+		}
+	}
+
+	runtime_test! {
+		async fn test_subscribe() {
+
+			let (completion_token, completable) = CompletionToken::new();
+
+			let tasks: Vec<_> = (0..5)
+				.map(|_| async_std::task::spawn(completion_token.subscribe()))
+				.collect();
+
+			completable.complete("complete".to_string());
+
+			for task in tasks {
+				assert_eq!(task.await, "complete", "Wrong result");
+			}
+
+			assert_eq!(completion_token.await, "complete", "The original token should still resolve");
+		}
+	}
+
+	runtime_test! {
+		async fn test_pipe_to() {
+
+			let (text, text_completable) = CompletionToken::<String>::new();
+			let (length, length_completable) = CompletionToken::new();
+			let (is_long, is_long_completable) = CompletionToken::new();
+
+			let first_stage = async_std::task::spawn(text.pipe_to(length_completable, |text| text.len()));
+			let second_stage = async_std::task::spawn(length.pipe_to(is_long_completable, |length| length > 10));
+
+			text_completable.complete("a long enough string".to_string());
+
+			assert!(is_long.await, "Wrong result");
+			first_stage.await;
+			second_stage.await;
+		}
+	}
+
+	runtime_test! {
+		async fn test_pipe_to_abandoned() {
+
+			let (text, text_completable) = CompletionToken::<String>::new();
+			let (length, length_completable) = CompletionToken::new();
+			let (is_long, is_long_completable) = CompletionToken::<bool>::new();
+
+			let pipeline = async_std::task::spawn(async move {
+				text.pipe_to(length_completable, |text| text.len()).await;
+				length.pipe_to(is_long_completable, |length| length > 10).await;
+			});
+
+			drop(text_completable);
+
+			assert_eq!(is_long.try_wait().await, Err(Abandoned), "Abandoning the first stage should abandon the last");
+			pipeline.await;
+		}
+	}
+
+	#[derive(Debug, PartialEq, Eq)]
+	enum BackendError {
+		Down(usize),
+		Abandoned
+	}
+
+	impl From<Abandoned> for BackendError {
+		fn from(_: Abandoned) -> BackendError {
+			BackendError::Abandoned
+		}
+	}
+
+	type Backends = (Vec<CompletionToken<Result<&'static str, BackendError>>>, Vec<Completable<Result<&'static str, BackendError>>>);
+
+	fn backends(count: usize) -> Backends {
+		(0..count).map(|_| CompletionToken::new()).unzip()
+	}
+
+	#[test]
+	fn test_race_ok_first_ok() {
+		let test_waker = TestWaker::new();
+		let waker = test_waker.into_waker();
+		let mut cx = Context::from_waker(&waker);
+
+		let (completion_tokens, completables) = backends(3);
+		let mut race = Box::pin(CompletionToken::race_ok(completion_tokens));
+		assert!(race.as_mut().poll(&mut cx).is_pending(), "Should wait for a result");
+
+		completables[2].complete(Ok("third"));
+		assert_eq!(race.as_mut().poll(&mut cx), Poll::Ready(Ok((2, "third"))), "Shouldn't wait for the other backends");
+	}
+
+	#[test]
+	fn test_race_ok_skips_errors() {
+		let test_waker = TestWaker::new();
+		let waker = test_waker.into_waker();
+		let mut cx = Context::from_waker(&waker);
+
+		let (completion_tokens, completables) = backends(3);
+		let mut race = Box::pin(CompletionToken::race_ok(completion_tokens));
+
+		completables[0].complete(Err(BackendError::Down(0)));
+		assert!(race.as_mut().poll(&mut cx).is_pending(), "An error shouldn't end the race");
+
+		completables[2].complete(Err(BackendError::Down(2)));
+		assert!(race.as_mut().poll(&mut cx).is_pending(), "An error shouldn't end the race");
+
+		completables[1].complete(Ok("second"));
+		assert_eq!(race.as_mut().poll(&mut cx), Poll::Ready(Ok((1, "second"))), "The only Ok should win");
+	}
+
+	runtime_test! {
+		async fn test_race_ok_ok_before_errors() {
+			let (completion_tokens, completables) = backends(2);
+
+			completables[1].complete(Ok("second"));
+			completables[0].complete(Err(BackendError::Down(0)));
+
+			assert_eq!(CompletionToken::race_ok(completion_tokens).await, Ok((1, "second")), "The Ok should win");
+		}
+	}
+
+	runtime_test! {
+		async fn test_race_ok_all_fail() {
+			let (completion_tokens, mut completables) = backends(3);
+
+			// Failures arrive out of order, but are returned in the same order as the tokens
+			completables[2].complete(Err(BackendError::Down(2)));
+			drop(completables.remove(1));
+			completables[0].complete(Err(BackendError::Down(0)));
+
+			assert_eq!(
+				CompletionToken::race_ok(completion_tokens).await,
+				Err(vec![BackendError::Down(0), BackendError::Abandoned, BackendError::Down(2)]),
+				"Should return every error");
+		}
+	}
+
+	runtime_test! {
+		async fn test_race_ok_empty() {
+			assert_eq!(CompletionToken::race_ok(Vec::<CompletionToken<Result<(), BackendError>>>::new()).await, Err(Vec::new()), "Nothing can succeed");
+		}
+	}
+
+    #[test]
+    fn test_repeated_poll_doesnt_clone_waker() {
+
+		let (mut completion_token, _completable) = CompletionToken::<u32>::new();
+
+		let counting_waker = Arc::new(CountingWaker);
+		let waker = std::task::Waker::from(counting_waker.clone());
+		let mut cx = Context::from_waker(&waker);
+
+		for _ in 0..10 {
+			assert!(Pin::new(&mut completion_token).poll(&mut cx).is_pending(), "Should be pending");
+		}
+
+		assert_eq!(Arc::strong_count(&counting_waker), 3, "Only one clone of the waker should be stored");
+	}
+
+    #[test]
+    fn test_late_subscribe() {
+
+		let (completion_token, completable) = CompletionToken::new();
+		let early_subscriber = completion_token.subscribe();
+
+		completable.complete(42);
+		assert_eq!(futures::executor::block_on(completion_token.clone()), 42, "Wrong result");
+
+		let late_subscriber = completion_token.subscribe();
+
+		let test_waker = TestWaker::new();
+		let waker = test_waker.into_waker();
+		let mut cx = Context::from_waker(&waker);
+
+		for mut subscriber in [early_subscriber, late_subscriber] {
+			match Pin::new(&mut subscriber).poll(&mut cx) {
+				Poll::Ready(result) => assert_eq!(result, 42, "Wrong result"),
+				_ => panic!("Subscriber should resolve immediately")
+			}
+		}
+	}
+
+	#[test]
+	fn test_subscribe_after_result_taken() {
+		let (completion_token, completable) = CompletionToken::new();
+
+		completable.complete(42);
+		assert_eq!(futures::executor::block_on(completion_token.clone()), 42, "Wrong result");
+
+		// The only clone of the result was taken, so there's nothing left to subscribe to
+		let mut subscriber = completion_token.subscribe();
+		assert_eq!(futures::executor::block_on(subscriber.clone().try_wait()), Err(Abandoned), "A late subscriber should be abandoned");
+
+		let test_waker = TestWaker::new();
+		let waker = test_waker.into_waker();
+		assert!(Pin::new(&mut subscriber).poll(&mut Context::from_waker(&waker)).is_pending(), "Awaiting a late subscriber should never return");
+		assert!(!completion_token.shared_state.is_poisoned(), "The lock shouldn't be poisoned");
+	}
+
+	runtime_test! {
+		async fn test_from_shared() {
+
+			let (sender, receiver) = futures::channel::oneshot::channel();
+			let completion_token = CompletionToken::from_shared(future::FutureExt::shared(receiver));
+
+			let first = async_std::task::spawn(completion_token.clone());
+			let second = async_std::task::spawn(completion_token.clone());
+
+			async_std::task::sleep(std::time::Duration::from_millis(10)).await;
+			sender.send("sent".to_string()).unwrap();
+
+			assert_eq!(first.await, Ok("sent".to_string()), "Wrong result");
+			assert_eq!(second.await, Ok("sent".to_string()), "Wrong result");
+			assert_eq!(completion_token.await, Ok("sent".to_string()), "The token can be awaited after its clones");
+		}
+	}
+
+	#[test]
+	fn test_from_shared_wakes_a_task_that_polled_mid_flight() {
+		type Received = Result<i32, futures::channel::oneshot::Canceled>;
+
+		let (sender, mut receiver) = futures::channel::oneshot::channel::<i32>();
+		let second: Arc<Mutex<Option<CompletionToken<Received>>>> = Arc::new(Mutex::new(None));
+		let second_waker = TestWaker::new();
+
+		let source = {
+			let second = second.clone();
+			let second_waker = second_waker.clone();
+
+			future::poll_fn(move |cx| {
+				// The second task polls while the first one is polling the source
+				if let Some(second) = second.lock().unwrap().as_mut() {
+					assert!(Pin::new(second).poll(&mut Context::from_waker(&second_waker.clone().into_waker())).is_pending(), "Shouldn't be complete yet");
+				}
+
+				Pin::new(&mut receiver).poll(cx)
+			})
+		};
+
+		let completion_token = CompletionToken::from_shared(future::FutureExt::shared(source));
+		*second.lock().unwrap() = Some(completion_token.clone());
+
+		let mut first = completion_token.clone();
+		let first_waker = TestWaker::new();
+		assert!(Pin::new(&mut first).poll(&mut Context::from_waker(&first_waker.into_waker())).is_pending(), "Shouldn't be complete yet");
+
+		// The first task stops waiting, so the second one has to poll the source from now on
+		drop(first);
+		assert!(second_waker.woke(), "The second task should be woken to poll the source");
+
+		let second = second.lock().unwrap().take().unwrap();
+		sender.send(42).unwrap();
+		assert_eq!(future::FutureExt::now_or_never(second), Some(Ok(42)), "Wrong result");
+		assert_eq!(future::FutureExt::now_or_never(completion_token), Some(Ok(42)), "Wrong result");
+	}
+
+	#[test]
+	fn test_from_shared_wakes_every_waiting_task() {
+		let (sender, receiver) = futures::channel::oneshot::channel();
+		let completion_token = CompletionToken::from_shared(future::FutureExt::shared(receiver));
+
+		let first_waker = TestWaker::new();
+		let second_waker = TestWaker::new();
+
+		let mut first = completion_token.clone();
+		let mut second = completion_token.clone();
+		assert!(Pin::new(&mut second).poll(&mut Context::from_waker(&second_waker.clone().into_waker())).is_pending(), "Shouldn't be complete yet");
+		assert!(Pin::new(&mut first).poll(&mut Context::from_waker(&first_waker.clone().into_waker())).is_pending(), "Shouldn't be complete yet");
+
+		// The last task to poll stops waiting
+		drop(first);
+		sender.send(42).unwrap();
+
+		assert!(second_waker.woke(), "The other task should still be woken");
+		assert_eq!(future::FutureExt::now_or_never(second), Some(Ok(42)), "Wrong result");
+		assert_eq!(future::FutureExt::now_or_never(completion_token), Some(Ok(42)), "The token can be awaited after its clones");
+	}
+
+	runtime_test! {
+		async fn test_memoized() {
+
+			let (memoized_completion_token, memoized_completable) = MemoizedCompletionToken::<Vec<u8>>::new();
+			let clone = memoized_completion_token.clone();
+
+			let task = async_std::task::spawn(clone);
+
+			memoized_completable.complete(vec![1, 2, 3]);
+
+			let first = task.await;
+			let second = memoized_completion_token.await;
+
+			assert_eq!(*first, vec![1, 2, 3], "Wrong result");
+			assert!(Arc::ptr_eq(&first, &second), "Both awaits should share the same vec");
+		}
+	}
+
+	runtime_test! {
+		async fn test_adapter() {
+
+			let (completion_token, completable) = CompletionToken::<String>::new();
+			let completion_adapter = CompletionAdapter::new(completable, |bytes: Vec<u8>| String::from_utf8(bytes).unwrap());
+
+			let task = async_std::task::spawn(completion_token);
+
+			completion_adapter.complete(b"db-pool".to_vec());
+
+			assert_eq!(task.await, "db-pool", "Wrong transformed result");
+		}
+	}
+
+	runtime_test! {
+		async fn test_adapter_abandoned() {
+
+			let (completion_token, completion_adapter) = CompletionToken::new_adapted(|bytes: Vec<u8>| bytes.len());
+
+			drop(completion_adapter);
+
+			assert_eq!(completion_token.try_wait().await, Err(Abandoned), "Dropping the adapter should abandon the token");
+		}
+	}
+
+    #[test]
+    fn test_memoized_polled_multiple_times() {
+
+		let (mut memoized_completion_token, memoized_completable) = MemoizedCompletionToken::new();
+
+		let test_waker = TestWaker::new();
+		let waker = test_waker.into_waker();
+		let mut cx = Context::from_waker(&waker);
+
+		memoized_completable.complete(vec![42u8]);
+
+		let first = match Pin::new(&mut memoized_completion_token).poll(&mut cx) {
+			Poll::Ready(result) => result,
+			_ => panic!("Should be ready")
+		};
+
+		let second = match Pin::new(&mut memoized_completion_token).poll(&mut cx) {
+			Poll::Ready(result) => result,
+			_ => panic!("Should be ready")
+		};
+
+		assert!(Arc::ptr_eq(&first, &second), "Both polls should share the same vec");
+	}
+
+	runtime_test! {
+		async fn test_memoized_abandoned() {
+
+			let (memoized_completion_token, memoized_completable) = MemoizedCompletionToken::<Vec<u8>>::new();
+			drop(memoized_completable);
+
+			assert_eq!(memoized_completion_token.try_wait().await, Err(Abandoned), "Should be abandoned");
+		}
+	}
+
+    fn count_waiter_allocations(completion_token: CompletionToken<()>, waiters: usize) -> usize {
+
+		let waker = futures::task::noop_waker();
+		let mut cx = Context::from_waker(&waker);
+
+		let mut clones: Vec<_> = (0..waiters).map(|_| completion_token.clone()).collect();
+
+		let (_, allocations) = count_allocations(|| {
+			for clone in clones.iter_mut() {
+				assert!(Pin::new(clone).poll(&mut cx).is_pending(), "Token should be pending");
+			}
+		});
+
+		allocations
+	}
+
+    #[test]
+    fn test_with_capacity() {
+
+		let (completion_token, _completable) = CompletionToken::with_capacity(10);
+		assert_eq!(count_waiter_allocations(completion_token, 10), 0, "Waiting shouldn't allocate");
+
+		let (completion_token, _completable) = CompletionToken::new();
+		assert!(count_waiter_allocations(completion_token, 10) > 0, "Waiting should reallocate");
+	}
+
+	runtime_test! {
+		async fn test_ready_signal() {
+			let (ready_signal, ready_signaler) = ReadySignal::new();
+			let subscribers: Vec<_> = (0..3).map(|_| ready_signal.subscribe()).collect();
+
+			assert_not_completed_no_waker(&ready_signal.shared_state);
+
+			let waiting: Vec<_> = subscribers.into_iter().map(async_std::task::spawn).collect();
+			ready_signaler.signal();
+
+			for waiting in waiting {
+				waiting.await;
+			}
+
+			assert!(ready_signal.is_complete(), "Should be signaled");
+
+			let (ready_signal, ready_signaler) = ReadySignal::new();
+			drop(ready_signaler);
+
+			assert_eq!(ready_signal.try_wait().await, Err(Abandoned), "Should be abandoned");
+		}
+	}
+
+	#[test]
+	fn test_ready_signal_state_size() {
+		// The same state without a result
+		#[allow(dead_code)]
+		struct WithoutResult {
+			name: Option<String>,
+			complete: bool,
+			abandoned: bool,
+			completed_by: CompletedBy,
+			default_on_drop: Option<fn() -> ()>,
+			retries: u32,
+			retain_result: Option<fn(&()) -> ()>,
+			#[cfg(feature = "serde")]
+			handle_id: Option<u64>,
+			source: SourceState<()>,
+			wakers: WakerList
+		}
+
+		assert_eq!(std::mem::size_of::<CompletionTokenState<()>>(), std::mem::size_of::<WithoutResult>(), "The unit result shouldn't take space");
+	}
+
+	#[test]
+	fn test_poll_complete() {
+		let (mut completion_token, completable) = CompletionToken::new();
+
+		let test_waker = TestWaker::new();
+		let waker = test_waker.clone().into_waker();
+		let mut cx = Context::from_waker(&waker);
+
+		assert_eq!(completion_token.poll_complete(&mut cx), Poll::Pending, "Shouldn't be complete");
+
+		completable.complete(42);
+		assert!(test_waker.woke(), "Completing should wake");
+		assert_eq!(completion_token.poll_complete(&mut cx), Poll::Ready(42), "Wrong result");
+
+		let (mut completion_token, completable) = CompletionToken::<()>::new();
+		drop(completable);
+		assert_eq!(completion_token.poll_complete(&mut cx), Poll::Pending, "An abandoned token never completes");
+	}
+
+	runtime_test! {
+		#[cfg(feature = "diagnostics")]
+		async fn test_waiter_count() {
+			let (completion_token, completable) = CompletionToken::new();
+
+			assert_eq!(completion_token.pending_waker_count(), 0, "Nobody should be waiting yet");
+			assert_eq!(completable.waiter_count(), 0, "Nobody should be waiting yet");
+
+			let waiting: Vec<_> = (0..3).map(|_| async_std::task::spawn(completion_token.subscribe())).collect();
+
+			while completable.waiter_count() < 3 {
+				async_std::task::yield_now().await;
+			}
+
+			assert_eq!(completion_token.pending_waker_count(), 3, "Every waiting task should be counted");
+			assert_eq!(completable.waiter_count(), 3, "Both sides should see the same count");
+
+			completable.complete("done");
+
+			assert_eq!(completion_token.pending_waker_count(), 0, "Completing should wake every waiting task");
+			assert_eq!(completable.waiter_count(), 0, "Both sides should see the same count");
+
+			for waiting in waiting {
+				assert_eq!(waiting.await, "done", "Wrong result");
+			}
+		}
+	}
+
+	#[test]
+	fn test_builder() {
+		use crate::timer::ManualClock;
+
+		let clock = ManualClock::new();
+		let (completion_token, completable) = CompletionToken::<()>::builder()
+			.with_name("startup")
+			.with_capacity(10)
+			.with_timeout(Duration::from_secs(5))
+			.with_timer(Timer::new(clock.clone()))
+			.build();
+
+		assert_eq!(completion_token.name(), Some("startup".to_string()), "Wrong name");
+		assert_eq!(count_waiter_allocations(completion_token.clone(), 10), 0, "Waiting shouldn't allocate");
+
+		let timed_out = completion_token.clone();
+		std::thread::sleep(Duration::from_millis(10));
+		assert!(!completion_token.is_complete() && !completion_token.shared_state.lock().unwrap().abandoned, "Shouldn't time out yet");
+
+		clock.advance(Duration::from_secs(5));
+		assert_eq!(futures::executor::block_on(timed_out.try_wait()), Err(Abandoned), "Should time out");
+
+		completable.complete(());
+		assert!(!completion_token.is_complete(), "Completing after the timeout should do nothing");
+	}
+
+	// Returns true once nothing holds a weak reference to the token's state, which means that the timer let go of it
+	fn wait_for_timer_to_disarm<T>(completion_token: &CompletionToken<T>) -> bool {
+		for _ in 0..100 {
+			if Arc::weak_count(&completion_token.shared_state) == 0 {
+				return true;
+			}
+
+			std::thread::sleep(Duration::from_millis(10));
+		}
+
+		false
+	}
+
+	#[test]
+	fn test_builder_timeout_is_disarmed() {
+		use crate::timer::ManualClock;
+
+		let clock = ManualClock::new();
+		let build = || CompletionToken::<()>::builder()
+			.with_timeout(Duration::from_secs(5))
+			.with_timer(Timer::new(clock.clone()))
+			.build();
+
+		let (completion_token, completable) = build();
+		completable.complete(());
+		assert!(wait_for_timer_to_disarm(&completion_token), "Completing should disarm the timeout");
+
+		let (completion_token, completable) = build();
+		drop(completable);
+		assert!(wait_for_timer_to_disarm(&completion_token), "Dropping the completable should disarm the timeout");
+	}
+
+	#[test]
+	fn test_builder_options_are_independent() {
+		let (completion_token, completable) = CompletionToken::builder().with_name("named").build();
+		assert_eq!(completion_token.name(), Some("named".to_string()), "Wrong name");
+		completable.complete(1);
+		assert_eq!(futures::executor::block_on(completion_token), 1, "Wrong result");
+
+		let (completion_token, _completable) = CompletionToken::<()>::builder().with_capacity(10).build();
+		assert_eq!(completion_token.name(), None, "Shouldn't have a name");
+		assert_eq!(count_waiter_allocations(completion_token, 10), 0, "Waiting shouldn't allocate");
+
+		let (completion_token, completable) = CompletionToken::builder().with_timeout(Duration::from_secs(60)).build();
+		completable.complete("before the timeout");
+		assert_eq!(futures::executor::block_on(completion_token.try_wait()), Ok("before the timeout"), "Should complete before the timeout");
+	}
+
+	#[cfg(feature = "serde")]
+	#[test]
+	fn test_handle_round_trip() {
+		let (completion_token, completable) = CompletionToken::new();
+
+		let serialized = serde_json::to_string(&completion_token.handle()).unwrap();
+		let handle: CompletionTokenHandle = serde_json::from_str(&serialized).unwrap();
+		assert_eq!(handle, completion_token.clone().handle(), "Clones should have the same handle");
+
+		completable.complete(42);
+
+		let from_handle = CompletionToken::<i32>::from_handle(handle).expect("The handle should resolve the token");
+		assert_eq!(futures::executor::block_on(from_handle), 42, "Wrong result");
+	}
+
+	#[cfg(feature = "serde")]
+	#[test]
+	fn test_stale_handle() {
+		let (completion_token, completable) = CompletionToken::<i32>::new();
+		let handle = completion_token.handle();
+
+		assert!(CompletionToken::<String>::from_handle(handle).is_none(), "The wrong type shouldn't resolve");
+
+		drop(completion_token);
+		drop(completable);
+		assert!(CompletionToken::<i32>::from_handle(handle).is_none(), "The handle should be stale");
+		assert!(!HANDLE_REGISTRY.lock().unwrap().contains_key(&handle.id), "Dropping the token should remove the registry entry");
+	}
+
+	#[test]
+	fn test_bounded_completable() {
+		let (completion_token, bounded_completable) = CompletionToken::new_bounded(3);
+
+		let results: Vec<_> = (1..=5).map(|i| bounded_completable.complete(i)).collect();
+
+		assert_eq!(results, vec![Ok(()), Ok(()), Ok(()), Err(CompletionOverflowError), Err(CompletionOverflowError)], "Only 3 completions should succeed");
+		assert_eq!(bounded_completable.completions(), 3, "Wrong number of completions");
+		assert_eq!(futures::executor::block_on(completion_token), 1, "Should resolve with the first value");
+	}
+
+	#[test]
+	fn test_bounded_completable_zero() {
+		let (completion_token, bounded_completable) = CompletionToken::new_bounded(0);
+
+		assert_eq!(bounded_completable.complete(()), Err(CompletionOverflowError), "Completing should fail");
+		drop(bounded_completable);
+		assert_eq!(futures::executor::block_on(completion_token.try_wait()), Err(Abandoned), "Should be abandoned");
+	}
+
+	#[test]
+	fn test_validating_completable() {
+		let (completion_token, validating_completable) = CompletionToken::new_with_validator(|value: &i32| *value >= 0);
+
+		assert_eq!(validating_completable.complete(-1), Err(ValidationError), "Negative values should be rejected");
+		assert!(futures::FutureExt::now_or_never(completion_token.clone()).is_none(), "A rejected value shouldn't complete");
+
+		assert_eq!(validating_completable.complete(42), Ok(()), "Positive values should be accepted");
+		assert_eq!(futures::executor::block_on(completion_token), 42, "Should resolve with the accepted value");
+	}
+
+	#[test]
+	fn test_validating_completable_abandoned() {
+		let (completion_token, validating_completable) = CompletionToken::new_with_validator(|_: &u32| false);
+
+		assert_eq!(validating_completable.complete(1), Err(ValidationError), "Should be rejected");
+		drop(validating_completable);
+		assert_eq!(futures::executor::block_on(completion_token.try_wait()), Err(Abandoned), "Dropping without a valid result should abandon");
+	}
+
+	runtime_test! {
+		async fn test_new_with_default_on_drop() {
+			let (completion_token, completable) = CompletionToken::<Vec<u32>>::new_with_default_on_drop();
+			let waiting = async_std::task::spawn(completion_token.clone());
+
+			async_std::task::sleep(Duration::from_millis(10)).await;
+			assert_eq!(completion_token.state(), CompletionState::Pending, "Shouldn't be complete yet");
+
+			drop(completable);
+
+			assert_eq!(completion_token.state(), CompletionState::CompletedWithDefault, "Should be completed with the default");
+			assert_eq!(waiting.await, Vec::<u32>::new(), "The waiting task should get the default");
+		}
+	}
+
+	#[test]
+	fn test_new_with_default_on_drop_complete_wins() {
+		let (completion_token, completable) = CompletionToken::<u32>::new_with_default_on_drop();
+
+		completable.complete(42);
+		drop(completable);
+
+		assert_eq!(completion_token.state(), CompletionState::Complete, "A real result shouldn't look like a default");
+		assert_eq!(futures::executor::block_on(completion_token), 42, "The real result should win");
+
+		let (completion_token, completable) = CompletionToken::<u32>::new();
+		drop(completable);
+		assert_eq!(completion_token.state(), CompletionState::Abandoned, "Other tokens should still be abandoned");
+	}
+
+	#[test]
+	fn test_retryable_completable() {
+		let (completion_token, mut retryable_completable) = CompletionToken::new_retryable(3);
+		let waiting = completion_token.clone();
+
+		for attempt in 1..=3 {
+			assert_eq!(retryable_completable.complete_or_retry(Err(format!("attempt {} failed", attempt))), Ok(()), "Should retry");
+			assert_eq!(completion_token.retry_count(), attempt, "Wrong retry count");
+			assert!(futures::FutureExt::now_or_never(completion_token.clone()).is_none(), "A failure shouldn't complete");
+		}
+
+		assert_eq!(retryable_completable.last_error().map(String::as_str), Some("attempt 3 failed"), "Wrong last error");
+
+		assert_eq!(retryable_completable.complete_or_retry(Ok(42)), Ok(()), "Should complete");
+		assert_eq!(completion_token.retry_count(), 3, "Succeeding isn't a retry");
+		assert_eq!(futures::executor::block_on(waiting), 42, "Should resolve with the successful value");
+	}
+
+	#[test]
+	fn test_retryable_completable_exhausted() {
+		let (completion_token, mut retryable_completable) = CompletionToken::<u32>::new_retryable(1);
+
+		assert_eq!(retryable_completable.complete_or_retry(Err("first")), Ok(()), "Should retry");
+		assert_eq!(retryable_completable.complete_or_retry(Err("second")), Err(RetriesExhaustedError), "Retries should be exhausted");
+		assert_eq!(retryable_completable.last_error(), Some(&"second"), "Wrong last error");
+		assert_eq!(futures::executor::block_on(completion_token.clone().try_wait()), Err(Abandoned), "Should be abandoned");
+
+		assert_eq!(retryable_completable.complete_or_retry(Ok(1)), Err(RetriesExhaustedError), "The failure should be permanent");
+		assert_eq!(completion_token.retry_count(), 2, "Wrong retry count");
+	}
+
+	#[test]
+	fn test_complete_wakes_past_a_panicking_waker() {
+		let (completion_token, completable) = CompletionToken::new();
+
+		let panicking_waker = Waker::from(Arc::new(PanickingWaker));
+		let test_waker = TestWaker::new();
+
+		let mut panicking_clone = completion_token.clone();
+		let mut clone = completion_token.clone();
+		assert!(Pin::new(&mut panicking_clone).poll(&mut Context::from_waker(&panicking_waker)).is_pending(), "Shouldn't be complete yet");
+		assert!(Pin::new(&mut clone).poll(&mut Context::from_waker(&test_waker.clone().into_waker())).is_pending(), "Shouldn't be complete yet");
+
+		let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| completable.complete("complete")));
+
+		assert!(result.is_err(), "The waker's panic should be resumed");
+		assert!(test_waker.woke(), "The other waker should still be woken");
+		assert!(completion_token.is_complete(), "Should be complete");
+
+		// The lock wasn't poisoned
+		assert_eq!(futures::FutureExt::now_or_never(clone), Some("complete"), "The woken clone should return the result");
+	}
+
+	#[test]
+	fn test_complete_within_fallback() {
+		use crate::timer::ManualClock;
+
+		let clock = ManualClock::new();
+		let (completion_token, completable) = CompletionToken::new();
+		let fallback_after = completable.fallback_after(Timer::new(clock.clone()), Duration::from_secs(5), "fallback");
+
+		let mut pool = LocalPool::new();
+		pool.spawner().spawn_local(fallback_after).unwrap();
+		let result = pool.spawner().spawn_local_with_handle(completion_token.clone()).unwrap();
+		pool.run_until_stalled();
+
+		clock.advance(Duration::from_secs(5) - Duration::from_millis(1));
+		pool.run_until_stalled();
+		assert!(!completion_token.is_complete(), "The fallback shouldn't fire early");
+
+		clock.advance(Duration::from_millis(1));
+		assert_eq!(pool.run_until(result), "fallback", "Should complete with the fallback");
+		assert_eq!(completion_token.state(), CompletionState::CompletedByFallback, "The state should show the fallback");
+
+		// Doesn't panic, and the late result is dropped
+		completable.complete("late");
+		assert!(completion_token.shared_state.lock().unwrap().result.is_none(), "The late result should be dropped");
+	}
+
+	#[test]
+	fn test_complete_within_real_result_wins() {
+		use crate::timer::ManualClock;
+
+		let clock = ManualClock::new();
+		let (completion_token, completable) = CompletionToken::new();
+		let fallback_after = completable.fallback_after(Timer::new(clock.clone()), Duration::from_secs(5), "fallback");
+
+		let mut pool = LocalPool::new();
+		let fallback_after = pool.spawner().spawn_local_with_handle(fallback_after).unwrap();
+		pool.run_until_stalled();
+
+		completable.complete("real");
+
+		clock.advance(Duration::from_secs(5));
+		pool.run_until(fallback_after);
+		assert_eq!(futures::executor::block_on(completion_token), "real", "The fallback should be disarmed");
+	}
+
+	#[test]
+	fn test_complete_within_abandoned() {
+		use crate::timer::ManualClock;
+
+		let clock = ManualClock::new();
+		let (completion_token, completable) = CompletionToken::new();
+		let fallback_after = completable.fallback_after(Timer::new(clock.clone()), Duration::from_secs(5), 1);
+
+		drop(completable);
+		clock.advance(Duration::from_secs(5));
+		futures::executor::block_on(fallback_after);
+
+		assert_eq!(futures::executor::block_on(completion_token.try_wait()), Err(Abandoned), "Dropping the completable should still abandon");
+	}
+
+	#[test]
+	fn test_complete_within_is_disarmed() {
+		let (completion_token, completable) = CompletionToken::new();
+		completable.complete_within(Duration::from_secs(3600), "fallback");
+		completable.complete("real");
+		assert!(wait_for_timer_to_disarm(&completion_token), "Completing should disarm the fallback");
+
+		let (completion_token, completable) = CompletionToken::new();
+		completable.complete_within(Duration::from_secs(3600), "fallback");
+		drop(completable);
+		assert!(wait_for_timer_to_disarm(&completion_token), "Dropping the completable should disarm the fallback");
+	}
+
+	runtime_test! {
+		async fn test_complete_within_system_timer() {
+			let (completion_token, completable) = CompletionToken::new();
+			let start = crate::timer::Instant::now();
+
+			completable.complete_within(Duration::from_millis(20), 0);
+
+			assert_eq!(completion_token.await, 0, "Should complete with the fallback");
+			assert!(start.elapsed() >= Duration::from_millis(20), "Completed early");
+		}
+	}
+
+	#[test]
+	fn test_pointer() {
+		let (completion_token, completable) = CompletionToken::<()>::new();
+		let (other_completion_token, other_completable) = CompletionToken::<()>::new();
+
+		assert_eq!(format!("{:p}", completion_token), format!("{:p}", completable), "A matched pair should print the same pointer");
+		assert_eq!(format!("{:p}", completion_token.clone()), format!("{:p}", completion_token), "Clones should print the same pointer");
+		assert_eq!(format!("{:p}", other_completion_token), format!("{:p}", other_completable), "A matched pair should print the same pointer");
+		assert_ne!(format!("{:p}", completion_token), format!("{:p}", other_completion_token), "Different pairs should print different pointers");
+	}
+}
+
+#[cfg(all(test, target_arch = "wasm32"))]
+mod wasm_tests {
+	use wasm_bindgen_test::wasm_bindgen_test;
+
+	use super::*;
+
+	#[wasm_bindgen_test]
+	async fn test_complete_round_trip() {
+		let (completion_token, completable) = CompletionToken::new();
+
+		wasm_bindgen_futures::spawn_local(async move {
+			completable.complete("complete");
+		});
+
+		assert_eq!(completion_token.await, "complete", "Wrong result");
+	}
+
+	#[wasm_bindgen_test]
+	async fn test_abandoned_round_trip() {
+		let (completion_token, completable) = CompletionToken::<()>::new();
+
+		wasm_bindgen_futures::spawn_local(async move {
+			drop(completable);
+		});
+
+		assert_eq!(completion_token.try_wait().await, Err(Abandoned), "Should be abandoned");
 	}
 }
@@ -0,0 +1,459 @@
+// https://github.com/GWBasic/sync-tokens
+// (c) Andrew Rondeau
+// Apache 2.0 license
+// See https://github.com/GWBasic/sync-tokens/blob/main/LICENSE
+
+//! Contains a combined cancelation and completion state, for operations that need both. See
+//! [`Coordinator`](struct.Coordinator.html)
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use futures::future::{Either, select};
+
+use crate::completion_token::Abandoned;
+use crate::wakers::{WakerKey, WakerList, wake_each};
+
+/// Holds the state of a [`CancelationToken`](../cancelation_token/struct.CancelationToken.html) and a
+/// [`CompletionToken`](../completion_token/struct.CompletionToken.html) in a single allocation, behind a single lock.
+///
+/// The usual pattern of creating both tokens for each operation allocates two shared states. Servers that start an
+/// operation per request can use a [`Coordinator`](struct.Coordinator.html) instead, and hand out the same four
+/// handles as thin views of the shared state: a
+/// [`CoordinatedCancelationToken`](struct.CoordinatedCancelationToken.html) and a
+/// [`CoordinatedCompletionToken`](struct.CoordinatedCompletionToken.html) for the caller, and a
+/// [`CoordinatedCancelable`](struct.CoordinatedCancelable.html) and a
+/// [`CoordinatedCompletable`](struct.CoordinatedCompletable.html) for the operation. Creating a handle never allocates.
+///
+/// The completion token is abandoned once every [`CoordinatedCompletable`](struct.CoordinatedCompletable.html) is
+/// dropped without completing
+///
+/// ```
+/// use sync_tokens::coordinator::Coordinator;
+///
+/// # async_std::task::block_on(async {
+/// let coordinator = Coordinator::new();
+/// let (completable, cancelable) = (coordinator.completable(), coordinator.cancelable());
+///
+/// let server = async_std::task::spawn(async move {
+///     completable.complete("listening");
+///     cancelable.allow_cancel(futures::future::pending(), "stopped").await
+/// });
+///
+/// assert_eq!(coordinator.completion_token().await, "listening");
+///
+/// coordinator.cancel_token().cancel();
+/// assert_eq!(server.await, "stopped");
+/// # });
+/// ```
+#[derive(Debug)]
+pub struct Coordinator<T> {
+	shared_state: Arc<Mutex<CoordinatorState<T>>>
+}
+
+/// Cancels the operation that holds the matching [`CoordinatedCancelable`](struct.CoordinatedCancelable.html).
+/// Created by [`Coordinator::cancel_token()`](struct.Coordinator.html#method.cancel_token)
+#[derive(Debug)]
+pub struct CoordinatedCancelationToken<T> {
+	shared_state: Arc<Mutex<CoordinatorState<T>>>
+}
+
+/// Allows an operation to be canceled by a [`CoordinatedCancelationToken`](struct.CoordinatedCancelationToken.html).
+/// Created by [`Coordinator::cancelable()`](struct.Coordinator.html#method.cancelable)
+#[derive(Debug)]
+pub struct CoordinatedCancelable<T> {
+	shared_state: Arc<Mutex<CoordinatorState<T>>>
+}
+
+/// Future returned by [`CoordinatedCancelable::future()`](struct.CoordinatedCancelable.html#method.future). Resolves
+/// when the [`Coordinator`](struct.Coordinator.html) is canceled
+#[derive(Debug)]
+pub struct CoordinatedCancelationFuture<T> {
+	shared_state: Arc<Mutex<CoordinatorState<T>>>,
+	waker_key: Option<WakerKey>
+}
+
+/// Waits for the matching [`CoordinatedCompletable`](struct.CoordinatedCompletable.html) to complete. Created by
+/// [`Coordinator::completion_token()`](struct.Coordinator.html#method.completion_token)
+///
+/// If every [`CoordinatedCompletable`](struct.CoordinatedCompletable.html) is dropped without calling complete,
+/// awaiting never returns. Use [`try_wait()`](struct.CoordinatedCompletionToken.html#method.try_wait) to find out when
+/// this happens.
+///
+/// # Panics
+///
+/// Panics if it, or another completion token from the same [`Coordinator`](struct.Coordinator.html), is awaited after
+/// the result was taken
+#[derive(Debug)]
+pub struct CoordinatedCompletionToken<T> {
+	shared_state: Arc<Mutex<CoordinatorState<T>>>,
+	waker_key: Option<WakerKey>
+}
+
+/// Unblocks whoever waits on a [`CoordinatedCompletionToken`](struct.CoordinatedCompletionToken.html). Created by
+/// [`Coordinator::completable()`](struct.Coordinator.html#method.completable)
+#[derive(Debug)]
+pub struct CoordinatedCompletable<T> {
+	shared_state: Arc<Mutex<CoordinatorState<T>>>
+}
+
+#[derive(Debug)]
+struct CoordinatorState<T> {
+	canceled: bool,
+	cancel_wakers: WakerList,
+	complete: bool,
+	abandoned: bool,
+	result: Option<T>,
+	completion_wakers: WakerList,
+	completable_count: usize
+}
+
+impl<T> Coordinator<T> {
+	/// Creates a new [`Coordinator`](struct.Coordinator.html). This is the only allocation; the handles share it
+	pub fn new() -> Coordinator<T> {
+		Coordinator {
+			shared_state: Arc::new(Mutex::new(CoordinatorState {
+				canceled: false,
+				cancel_wakers: WakerList::new(),
+				complete: false,
+				abandoned: false,
+				result: None,
+				completion_wakers: WakerList::new(),
+				completable_count: 0
+			}))
+		}
+	}
+
+	/// Returns a handle that cancels the operation
+	pub fn cancel_token(&self) -> CoordinatedCancelationToken<T> {
+		CoordinatedCancelationToken {
+			shared_state: self.shared_state.clone()
+		}
+	}
+
+	/// Returns a handle that the operation uses to find out when it's canceled
+	pub fn cancelable(&self) -> CoordinatedCancelable<T> {
+		CoordinatedCancelable {
+			shared_state: self.shared_state.clone()
+		}
+	}
+
+	/// Returns a handle that waits for the operation to complete
+	pub fn completion_token(&self) -> CoordinatedCompletionToken<T> {
+		CoordinatedCompletionToken {
+			shared_state: self.shared_state.clone(),
+			waker_key: None
+		}
+	}
+
+	/// Returns a handle that the operation uses to complete
+	pub fn completable(&self) -> CoordinatedCompletable<T> {
+		self.shared_state.lock().unwrap().completable_count += 1;
+
+		CoordinatedCompletable {
+			shared_state: self.shared_state.clone()
+		}
+	}
+}
+
+impl<T> Default for Coordinator<T> {
+	fn default() -> Self {
+		Coordinator::new()
+	}
+}
+
+impl<T> CoordinatedCancelationToken<T> {
+	/// Cancels, and wakes every future that waits on a [`CoordinatedCancelable`](struct.CoordinatedCancelable.html).
+	/// This can be called multiple times safely
+	pub fn cancel(&self) {
+		let mut shared_state = self.shared_state.lock().unwrap();
+
+		shared_state.canceled = true;
+		let wakers = shared_state.cancel_wakers.take_all();
+		drop(shared_state);
+		wake_each(wakers);
+	}
+
+	/// Returns true if canceled
+	pub fn is_canceled(&self) -> bool {
+		self.shared_state.lock().unwrap().canceled
+	}
+}
+
+impl<T> CoordinatedCancelable<T> {
+	/// Runs future until it finishes, or until canceled. Returns canceled_result if canceled
+	pub async fn allow_cancel<TFuture, R>(&self, future: TFuture, canceled_result: R) -> R where
+	TFuture: Future<Output = R> + Unpin {
+		match select(future, self.future()).await {
+			Either::Left((result, _)) => result,
+			Either::Right(_) => canceled_result
+		}
+	}
+
+	/// Returns a future that resolves when canceled
+	pub fn future(&self) -> CoordinatedCancelationFuture<T> {
+		CoordinatedCancelationFuture {
+			shared_state: self.shared_state.clone(),
+			waker_key: None
+		}
+	}
+
+	/// Returns true if canceled
+	pub fn is_canceled(&self) -> bool {
+		self.shared_state.lock().unwrap().canceled
+	}
+}
+
+impl<T> CoordinatedCompletionToken<T> {
+	/// Waits for a [`CoordinatedCompletable`](struct.CoordinatedCompletable.html) to complete, or returns
+	/// [`Abandoned`](../completion_token/struct.Abandoned.html) if every one of them is dropped without calling
+	/// complete
+	pub async fn try_wait(mut self) -> Result<T, Abandoned> {
+		futures::future::poll_fn(|cx| self.poll_result(cx)).await
+	}
+
+	fn poll_result(&mut self, cx: &mut Context<'_>) -> Poll<Result<T, Abandoned>> {
+		let mut shared_state = self.shared_state.lock().unwrap();
+
+		if shared_state.complete {
+			Poll::Ready(Ok(shared_state.result.take().expect("result already consumed")))
+		} else if shared_state.abandoned {
+			Poll::Ready(Err(Abandoned))
+		} else {
+			shared_state.completion_wakers.register(&mut self.waker_key, cx.waker());
+			Poll::Pending
+		}
+	}
+}
+
+impl<T> CoordinatedCompletable<T> {
+	/// Call to indicate that the operation is complete, and wake whoever waits on a
+	/// [`CoordinatedCompletionToken`](struct.CoordinatedCompletionToken.html)
+	///
+	/// # Panics
+	///
+	/// Complete will panic if it's called multiple times, including on different completables from the same
+	/// [`Coordinator`](struct.Coordinator.html)
+	pub fn complete(&self, result: T) {
+		let mut shared_state = self.shared_state.lock().unwrap();
+
+		if shared_state.complete {
+			drop(shared_state);
+			panic!("Completion token is already complete")
+		}
+
+		shared_state.complete = true;
+		shared_state.result = Some(result);
+		let wakers = shared_state.completion_wakers.take_all();
+		drop(shared_state);
+		wake_each(wakers);
+	}
+}
+
+impl<T> Clone for CoordinatedCancelationToken<T> {
+	fn clone(&self) -> Self {
+		CoordinatedCancelationToken {
+			shared_state: self.shared_state.clone()
+		}
+	}
+}
+
+impl<T> Clone for CoordinatedCancelable<T> {
+	fn clone(&self) -> Self {
+		CoordinatedCancelable {
+			shared_state: self.shared_state.clone()
+		}
+	}
+}
+
+impl<T> Future for CoordinatedCancelationFuture<T> {
+	type Output = ();
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+		let this = self.get_mut();
+		let mut shared_state = this.shared_state.lock().unwrap();
+
+		if shared_state.canceled {
+			Poll::Ready(())
+		} else {
+			shared_state.cancel_wakers.register(&mut this.waker_key, cx.waker());
+			Poll::Pending
+		}
+	}
+}
+
+impl<T> Drop for CoordinatedCancelationFuture<T> {
+	fn drop(&mut self) {
+		if self.waker_key.is_some() {
+			self.shared_state.lock().unwrap().cancel_wakers.remove(self.waker_key);
+		}
+	}
+}
+
+impl<T> Future for CoordinatedCompletionToken<T> {
+	type Output = T;
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+		match self.get_mut().poll_result(cx) {
+			Poll::Ready(Ok(result)) => Poll::Ready(result),
+			// Like CompletionToken, never returns when abandoned
+			Poll::Ready(Err(Abandoned)) | Poll::Pending => Poll::Pending
+		}
+	}
+}
+
+impl<T> Clone for CoordinatedCompletionToken<T> {
+	fn clone(&self) -> Self {
+		CoordinatedCompletionToken {
+			shared_state: self.shared_state.clone(),
+			waker_key: None
+		}
+	}
+}
+
+impl<T> Drop for CoordinatedCompletionToken<T> {
+	fn drop(&mut self) {
+		if self.waker_key.is_some() {
+			self.shared_state.lock().unwrap().completion_wakers.remove(self.waker_key);
+		}
+	}
+}
+
+impl<T> Drop for CoordinatedCompletable<T> {
+	fn drop(&mut self) {
+		let mut shared_state = self.shared_state.lock().unwrap();
+		shared_state.completable_count -= 1;
+
+		if shared_state.completable_count == 0 && !shared_state.complete {
+			shared_state.abandoned = true;
+			let wakers = shared_state.completion_wakers.take_all();
+			drop(shared_state);
+			wake_each(wakers);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::panic::{AssertUnwindSafe, catch_unwind};
+	use std::task::Waker;
+	use std::time::Duration;
+
+	use cooked_waker::IntoWaker;
+
+	use super::*;
+	use crate::cancelation_token::CancelationToken;
+	use crate::completion_token::CompletionToken;
+	use crate::tests::*;
+
+	runtime_test! {
+		async fn test_complete() {
+			let coordinator = Coordinator::new();
+			let completable = coordinator.completable();
+
+			let waiting = async_std::task::spawn(coordinator.completion_token());
+
+			async_std::task::sleep(Duration::from_millis(10)).await;
+			completable.complete(8080);
+
+			assert_eq!(waiting.await, 8080, "Wrong result");
+		}
+	}
+
+	runtime_test! {
+		async fn test_cancel() {
+			let coordinator = Coordinator::<()>::new();
+			let cancelable = coordinator.cancelable();
+
+			let waiting = async_std::task::spawn(async move {
+				cancelable.allow_cancel(futures::future::pending(), "canceled").await
+			});
+
+			async_std::task::sleep(Duration::from_millis(10)).await;
+
+			let cancel_token = coordinator.cancel_token();
+			assert!(!cancel_token.is_canceled(), "Shouldn't be canceled yet");
+
+			cancel_token.cancel();
+			cancel_token.cancel();
+
+			assert!(coordinator.cancelable().is_canceled(), "Should be canceled");
+			assert_eq!(waiting.await, "canceled", "Wrong result");
+
+			// Canceling doesn't affect completion
+			let completable = coordinator.completable();
+			completable.complete(());
+			assert_eq!(coordinator.completion_token().try_wait().await, Ok(()), "Should still complete");
+		}
+	}
+
+	#[test]
+	fn test_wakes_past_a_panicking_waker() {
+		let coordinator = Coordinator::new();
+		let panicking_waker = Waker::from(Arc::new(PanickingWaker));
+		let test_waker = TestWaker::new();
+
+		let mut panicking_future = coordinator.cancelable().future();
+		let mut future = coordinator.cancelable().future();
+		assert!(Pin::new(&mut panicking_future).poll(&mut Context::from_waker(&panicking_waker)).is_pending(), "Shouldn't be canceled yet");
+		assert!(Pin::new(&mut future).poll(&mut Context::from_waker(&test_waker.clone().into_waker())).is_pending(), "Shouldn't be canceled yet");
+
+		let result = catch_unwind(AssertUnwindSafe(|| coordinator.cancel_token().cancel()));
+
+		assert!(result.is_err(), "The waker's panic should be resumed");
+		assert!(test_waker.woke(), "The other waker should still be woken");
+		assert!(coordinator.cancelable().is_canceled(), "The lock shouldn't be poisoned");
+
+		let test_waker = TestWaker::new();
+		let mut panicking_token = coordinator.completion_token();
+		let mut completion_token = coordinator.completion_token();
+		assert!(Pin::new(&mut panicking_token).poll(&mut Context::from_waker(&panicking_waker)).is_pending(), "Shouldn't be complete yet");
+		assert!(Pin::new(&mut completion_token).poll(&mut Context::from_waker(&test_waker.clone().into_waker())).is_pending(), "Shouldn't be complete yet");
+
+		let result = catch_unwind(AssertUnwindSafe(|| coordinator.completable().complete(8080)));
+
+		assert!(result.is_err(), "The waker's panic should be resumed");
+		assert!(test_waker.woke(), "The other waker should still be woken");
+		assert_eq!(futures::FutureExt::now_or_never(completion_token), Some(8080), "The lock shouldn't be poisoned");
+	}
+
+	runtime_test! {
+		async fn test_abandoned() {
+			let coordinator = Coordinator::<u32>::new();
+			let first = coordinator.completable();
+			let second = coordinator.completable();
+
+			drop(first);
+			assert!(futures::FutureExt::now_or_never(coordinator.completion_token().try_wait()).is_none(), "A completable is still alive");
+
+			drop(second);
+			assert_eq!(coordinator.completion_token().try_wait().await, Err(Abandoned), "Dropping every completable should abandon");
+		}
+	}
+
+	#[test]
+	fn test_single_allocation() {
+		let (handles, allocations) = count_allocations(|| {
+			let coordinator = Coordinator::<u32>::new();
+
+			(coordinator.cancel_token(), coordinator.cancelable(), coordinator.completion_token(), coordinator.completable())
+		});
+
+		assert_eq!(allocations, 1, "The handles should share one allocation");
+		drop(handles);
+
+		let (tokens, separate_allocations) = count_allocations(|| (CancelationToken::new(), CompletionToken::<u32>::new()));
+		assert!(separate_allocations > allocations, "Separate tokens should allocate more");
+		drop(tokens);
+	}
+
+	#[test]
+	#[should_panic(expected = "Completion token is already complete")]
+	fn test_complete_twice() {
+		let coordinator = Coordinator::new();
+
+		coordinator.completable().complete(1);
+		coordinator.completable().complete(2);
+	}
+}
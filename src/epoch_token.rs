@@ -0,0 +1,272 @@
+// https://github.com/GWBasic/sync-tokens
+// (c) Andrew Rondeau
+// Apache 2.0 license
+// See https://github.com/GWBasic/sync-tokens/blob/main/LICENSE
+
+//! Contains a counter that tasks can wait on until it reaches a value. See [`EpochToken`](struct.EpochToken.html)
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+/// A monotonically increasing counter, such as a replication log's applied index, that tasks can wait on.
+///
+/// The producer calls [`advance_to()`](struct.EpochToken.html#method.advance_to), and consumers call
+/// [`wait_for()`](struct.EpochToken.html#method.wait_for). Waiters are ordered by threshold, so advancing only wakes
+/// the waiters whose threshold is reached. Clones share the same counter
+///
+/// ```
+/// use async_std::task;
+/// use sync_tokens::epoch_token::EpochToken;
+///
+/// # task::block_on(async {
+/// let epoch_token = EpochToken::new(0);
+///
+/// let waiter = task::spawn({
+///     let epoch_token = epoch_token.clone();
+///     async move {
+///         epoch_token.wait_for(10).await
+///     }
+/// });
+///
+/// epoch_token.advance_to(5).unwrap();
+/// epoch_token.advance_to(12).unwrap();
+///
+/// assert_eq!(waiter.await, 12);
+/// assert!(epoch_token.advance_to(11).is_err());
+/// # });
+/// ```
+#[derive(Debug, Clone)]
+pub struct EpochToken {
+	shared_state: Arc<Mutex<EpochTokenState>>
+}
+
+/// Future returned by [`EpochToken::wait_for()`](struct.EpochToken.html#method.wait_for). Returns the counter's value
+/// when the threshold was reached
+#[derive(Debug)]
+pub struct EpochFuture {
+	shared_state: Arc<Mutex<EpochTokenState>>,
+	threshold: u64,
+	waiter_key: Option<(u64, u64)>
+}
+
+/// Error returned when [`EpochToken::advance_to()`](struct.EpochToken.html#method.advance_to) would move the counter
+/// backwards
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EpochRegression {
+	/// The counter's value
+	pub current: u64,
+	/// The value that was rejected
+	pub requested: u64
+}
+
+#[derive(Debug)]
+struct EpochTokenState {
+	current: u64,
+	// Keyed by threshold, and then by a unique id so that waiters can share a threshold
+	waiters: BTreeMap<(u64, u64), Waker>,
+	next_waiter_id: u64
+}
+
+impl EpochToken {
+	/// Creates a new [`EpochToken`](struct.EpochToken.html) that starts at initial
+	pub fn new(initial: u64) -> EpochToken {
+		EpochToken {
+			shared_state: Arc::new(Mutex::new(EpochTokenState {
+				current: initial,
+				waiters: BTreeMap::new(),
+				next_waiter_id: 0
+			}))
+		}
+	}
+
+	/// Moves the counter to value, waking every waiter whose threshold is now reached. Advancing to the current value
+	/// does nothing; advancing to a lower value returns [`EpochRegression`](struct.EpochRegression.html)
+	pub fn advance_to(&self, value: u64) -> Result<(), EpochRegression> {
+		let reached = {
+			let mut shared_state = self.shared_state.lock().unwrap();
+
+			if value < shared_state.current {
+				return Err(EpochRegression {
+					current: shared_state.current,
+					requested: value
+				});
+			}
+
+			shared_state.current = value;
+
+			// Everything at or above value + 1 keeps waiting
+			let waiting = match value.checked_add(1) {
+				Some(next) => shared_state.waiters.split_off(&(next, 0)),
+				None => BTreeMap::new()
+			};
+
+			std::mem::replace(&mut shared_state.waiters, waiting)
+		};
+
+		for waker in reached.into_values() {
+			waker.wake();
+		}
+
+		Ok(())
+	}
+
+	/// Returns a future that returns once the counter reaches at least threshold. The future returns immediately if
+	/// the threshold is already reached
+	pub fn wait_for(&self, threshold: u64) -> EpochFuture {
+		EpochFuture {
+			shared_state: self.shared_state.clone(),
+			threshold,
+			waiter_key: None
+		}
+	}
+
+	/// The counter's latest value
+	pub fn current(&self) -> u64 {
+		self.shared_state.lock().unwrap().current
+	}
+}
+
+impl Default for EpochToken {
+	fn default() -> Self {
+		EpochToken::new(0)
+	}
+}
+
+impl Future for EpochFuture {
+	type Output = u64;
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+		let this = self.get_mut();
+		let mut shared_state = this.shared_state.lock().unwrap();
+
+		if shared_state.current >= this.threshold {
+			if let Some(waiter_key) = this.waiter_key.take() {
+				shared_state.waiters.remove(&waiter_key);
+			}
+
+			return Poll::Ready(shared_state.current);
+		}
+
+		let waiter_key = match this.waiter_key {
+			Some(waiter_key) => waiter_key,
+			None => {
+				let waiter_key = (this.threshold, shared_state.next_waiter_id);
+				shared_state.next_waiter_id += 1;
+				this.waiter_key = Some(waiter_key);
+				waiter_key
+			}
+		};
+
+		shared_state.waiters.insert(waiter_key, cx.waker().clone());
+		Poll::Pending
+	}
+}
+
+impl Drop for EpochFuture {
+	fn drop(&mut self) {
+		if let Some(waiter_key) = self.waiter_key {
+			let mut shared_state = self.shared_state.lock().unwrap();
+			shared_state.waiters.remove(&waiter_key);
+		}
+	}
+}
+
+impl fmt::Display for EpochRegression {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "Can not move the epoch from {} back to {}", self.current, self.requested)
+	}
+}
+
+impl Error for EpochRegression {}
+
+#[cfg(test)]
+mod tests {
+	use cooked_waker::IntoWaker;
+
+	use super::*;
+	use crate::tests::*;
+
+	#[test]
+	fn test_only_reached_waiters_are_woken() {
+		let epoch_token = EpochToken::new(0);
+
+		let test_wakers: Vec<_> = (0..4).map(|_| TestWaker::new()).collect();
+		let mut futures: Vec<_> = [5, 10, 10, 20].iter().map(|threshold| epoch_token.wait_for(*threshold)).collect();
+
+		for (future, test_waker) in futures.iter_mut().zip(test_wakers.iter()) {
+			let waker = test_waker.clone().into_waker();
+			assert!(Pin::new(future).poll(&mut Context::from_waker(&waker)).is_pending(), "Should be pending");
+		}
+
+		epoch_token.advance_to(10).unwrap();
+
+		let woke: Vec<_> = test_wakers.iter().map(|test_waker| test_waker.woke()).collect();
+		assert_eq!(woke, vec![true, true, true, false], "Only reached waiters should be woken");
+		assert_eq!(epoch_token.shared_state.lock().unwrap().waiters.len(), 1, "Only one waiter should remain");
+
+		let waker = test_wakers[0].clone().into_waker();
+		assert_eq!(Pin::new(&mut futures[0]).poll(&mut Context::from_waker(&waker)), Poll::Ready(10), "Wrong value");
+	}
+
+	#[test]
+	fn test_already_reached() {
+		let epoch_token = EpochToken::new(7);
+
+		assert_eq!(epoch_token.current(), 7, "Wrong value");
+		assert_eq!(futures::executor::block_on(epoch_token.wait_for(7)), 7, "Should return immediately");
+		assert_eq!(futures::executor::block_on(epoch_token.wait_for(3)), 7, "Should return immediately");
+	}
+
+	#[test]
+	fn test_regression() {
+		let epoch_token = EpochToken::default();
+
+		epoch_token.advance_to(5).unwrap();
+		epoch_token.advance_to(5).unwrap();
+
+		assert_eq!(epoch_token.advance_to(4), Err(EpochRegression { current: 5, requested: 4 }), "Should reject regression");
+		assert_eq!(epoch_token.current(), 5, "Wrong value");
+	}
+
+	#[test]
+	fn test_dropped_future_removes_waiter() {
+		let epoch_token = EpochToken::new(0);
+
+		let test_waker = TestWaker::new();
+		let waker = test_waker.into_waker();
+
+		let mut future = epoch_token.wait_for(3);
+		assert!(Pin::new(&mut future).poll(&mut Context::from_waker(&waker)).is_pending(), "Should be pending");
+		assert_eq!(epoch_token.shared_state.lock().unwrap().waiters.len(), 1, "Waiter should be registered");
+
+		drop(future);
+		assert!(epoch_token.shared_state.lock().unwrap().waiters.is_empty(), "Waiter should be removed");
+	}
+
+	runtime_test! {
+		async fn test_many_waiters() {
+			let epoch_token = EpochToken::new(0);
+
+			let tasks: Vec<_> = (1..=100).rev().map(|threshold| {
+				let epoch_token = epoch_token.clone();
+				async_std::task::spawn(async move {
+					let value = epoch_token.wait_for(threshold).await;
+					assert!(value >= threshold, "Returned before the threshold");
+				})
+			}).collect();
+
+			for value in 1..=100 {
+				epoch_token.advance_to(value).unwrap();
+				async_std::task::yield_now().await;
+			}
+
+			for task in tasks {
+				task.await;
+			}
+		}
+	}
+}
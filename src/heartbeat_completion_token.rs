@@ -0,0 +1,305 @@
+// https://github.com/GWBasic/sync-tokens
+// (c) Andrew Rondeau
+// Apache 2.0 license
+// See https://github.com/GWBasic/sync-tokens/blob/main/LICENSE
+
+//! Contains a completion token that fails if the operation stops sending heartbeats. See
+//! [`HeartbeatCompletionToken`](struct.HeartbeatCompletionToken.html)
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use crate::timer::{DeadlineSleep, Instant, Timer};
+use crate::wakers::{WakerKey, WakerList, wake_each};
+
+/// Waits for an operation that must call [`HeartbeatCompletable::heartbeat()`](struct.HeartbeatCompletable.html#method.heartbeat)
+/// at least once per timeout until it completes. Awaiting the token returns `Ok` with the operation's result, or
+/// [`HeartbeatTimeoutError`](struct.HeartbeatTimeoutError.html) if a heartbeat didn't arrive in time.
+///
+/// The timeout is only detected while the token is awaited. If the
+/// [`HeartbeatCompletable`](struct.HeartbeatCompletable.html) is dropped without completing, heartbeats stop, so the
+/// token times out
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use async_std::task;
+/// use sync_tokens::heartbeat_completion_token::HeartbeatCompletionToken;
+///
+/// # task::block_on(async {
+/// let (heartbeat_completion_token, heartbeat_completable) = HeartbeatCompletionToken::new(Duration::from_millis(50));
+///
+/// task::spawn(async move {
+///     for _ in 0..3 {
+///         task::sleep(Duration::from_millis(10)).await;
+///         heartbeat_completable.heartbeat().unwrap();
+///     }
+///
+///     heartbeat_completable.complete("done").unwrap();
+/// });
+///
+/// assert_eq!(heartbeat_completion_token.await, Ok("done"));
+/// # });
+/// ```
+#[derive(Debug)]
+pub struct HeartbeatCompletionToken<T> {
+	shared_state: Arc<Mutex<HeartbeatCompletionState<T>>>,
+	sleep: DeadlineSleep,
+	waker_key: Option<WakerKey>
+}
+
+/// Sends heartbeats to, and completes, a [`HeartbeatCompletionToken`](struct.HeartbeatCompletionToken.html)
+#[derive(Debug)]
+pub struct HeartbeatCompletable<T> {
+	shared_state: Arc<Mutex<HeartbeatCompletionState<T>>>,
+	timer: Timer
+}
+
+/// Returned when the operation didn't send a heartbeat in time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeartbeatTimeoutError;
+
+#[derive(Debug)]
+struct HeartbeatCompletionState<T> {
+	timeout: Duration,
+	last_heartbeat: Instant,
+	result: Option<T>,
+	complete: bool,
+	timed_out: bool,
+	wakers: WakerList
+}
+
+impl<T> HeartbeatCompletionToken<T> {
+	/// Creates a new [`HeartbeatCompletionToken`](struct.HeartbeatCompletionToken.html) and
+	/// [`HeartbeatCompletable`](struct.HeartbeatCompletable.html) that use the system clock. The first heartbeat is
+	/// due one timeout from now
+	pub fn new(timeout: Duration) -> (HeartbeatCompletionToken<T>, HeartbeatCompletable<T>) {
+		HeartbeatCompletionToken::with_timer(timeout, Timer::default())
+	}
+
+	/// Creates a new [`HeartbeatCompletionToken`](struct.HeartbeatCompletionToken.html) and
+	/// [`HeartbeatCompletable`](struct.HeartbeatCompletable.html) that use the given [`Timer`](../timer/struct.Timer.html)
+	pub fn with_timer(timeout: Duration, timer: Timer) -> (HeartbeatCompletionToken<T>, HeartbeatCompletable<T>) {
+		let shared_state = Arc::new(Mutex::new(HeartbeatCompletionState {
+			timeout,
+			last_heartbeat: timer.now(),
+			result: None,
+			complete: false,
+			timed_out: false,
+			wakers: WakerList::new()
+		}));
+
+		let heartbeat_completable = HeartbeatCompletable {
+			shared_state: shared_state.clone(),
+			timer: timer.clone()
+		};
+
+		let heartbeat_completion_token = HeartbeatCompletionToken {
+			shared_state,
+			sleep: DeadlineSleep::new(timer),
+			waker_key: None
+		};
+
+		(heartbeat_completion_token, heartbeat_completable)
+	}
+
+	/// Returns true if a heartbeat didn't arrive in time
+	pub fn is_timed_out(&self) -> bool {
+		self.shared_state.lock().unwrap().timed_out
+	}
+}
+
+impl<T> HeartbeatCompletable<T> {
+	/// Tells the [`HeartbeatCompletionToken`](struct.HeartbeatCompletionToken.html) that the operation is still
+	/// working. Returns [`HeartbeatTimeoutError`](struct.HeartbeatTimeoutError.html) if a heartbeat was already missed
+	pub fn heartbeat(&self) -> Result<(), HeartbeatTimeoutError> {
+		let mut shared_state = self.shared_state.lock().unwrap();
+
+		if shared_state.timed_out {
+			return Err(HeartbeatTimeoutError);
+		}
+
+		shared_state.last_heartbeat = self.timer.now();
+		Ok(())
+	}
+
+	/// Completes the [`HeartbeatCompletionToken`](struct.HeartbeatCompletionToken.html) with result. Returns
+	/// [`HeartbeatTimeoutError`](struct.HeartbeatTimeoutError.html), and drops result, if a heartbeat was already missed
+	///
+	/// # Panics
+	///
+	/// Complete will panic if it is called multiple times
+	pub fn complete(&self, result: T) -> Result<(), HeartbeatTimeoutError> {
+		let mut shared_state = self.shared_state.lock().unwrap();
+
+		if shared_state.complete {
+			// Released first, so that the panic doesn't poison the lock for the token
+			drop(shared_state);
+			panic!("Heartbeat completion token is already complete")
+		}
+
+		if shared_state.timed_out {
+			return Err(HeartbeatTimeoutError);
+		}
+
+		shared_state.complete = true;
+		shared_state.result = Some(result);
+		let wakers = shared_state.wakers.take_all();
+		drop(shared_state);
+		wake_each(wakers);
+
+		Ok(())
+	}
+}
+
+impl<T> Future for HeartbeatCompletionToken<T> {
+	type Output = Result<T, HeartbeatTimeoutError>;
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+		let this = self.get_mut();
+
+		loop {
+			let deadline = {
+				let mut shared_state = this.shared_state.lock().unwrap();
+
+				if shared_state.complete {
+					let result = shared_state.result.take().expect("HeartbeatCompletionToken polled after completion");
+					return Poll::Ready(Ok(result));
+				}
+
+				if shared_state.timed_out {
+					return Poll::Ready(Err(HeartbeatTimeoutError));
+				}
+
+				let deadline = shared_state.last_heartbeat + shared_state.timeout;
+				if this.sleep.now() >= deadline {
+					shared_state.timed_out = true;
+					this.sleep.clear();
+
+					return Poll::Ready(Err(HeartbeatTimeoutError));
+				}
+
+				shared_state.wakers.register(&mut this.waker_key, cx.waker());
+				deadline
+			};
+
+			// Heartbeats move the deadline forward; the sleep is replaced once it wakes for an old deadline
+			if this.sleep.poll_until(deadline, cx).is_pending() {
+				return Poll::Pending;
+			}
+		}
+	}
+}
+
+impl<T> Drop for HeartbeatCompletionToken<T> {
+	fn drop(&mut self) {
+		if self.waker_key.is_some() {
+			let mut shared_state = self.shared_state.lock().unwrap();
+			shared_state.wakers.remove(self.waker_key);
+		}
+	}
+}
+
+impl fmt::Display for HeartbeatTimeoutError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "The operation didn't send a heartbeat in time")
+	}
+}
+
+impl Error for HeartbeatTimeoutError {}
+
+#[cfg(test)]
+mod tests {
+	use futures::executor::LocalPool;
+	use futures::task::LocalSpawnExt;
+
+	use super::*;
+	use crate::timer::ManualClock;
+
+	#[test]
+	fn test_missed_heartbeat() {
+		let clock = ManualClock::new();
+		let (heartbeat_completion_token, heartbeat_completable) = HeartbeatCompletionToken::<u32>::with_timer(Duration::from_secs(10), Timer::new(clock.clone()));
+
+		let mut pool = LocalPool::new();
+		let result = pool.spawner().spawn_local_with_handle(heartbeat_completion_token).unwrap();
+		pool.run_until_stalled();
+
+		for _ in 0..5 {
+			clock.advance(Duration::from_secs(8));
+			pool.run_until_stalled();
+
+			heartbeat_completable.heartbeat().unwrap();
+			pool.run_until_stalled();
+		}
+
+		// The 6th heartbeat never arrives, so the deadline is 10 seconds after the 5th
+		clock.advance(Duration::from_secs(10) - Duration::from_millis(1));
+		pool.run_until_stalled();
+
+		clock.advance(Duration::from_millis(1));
+		assert_eq!(pool.run_until(result), Err(HeartbeatTimeoutError), "Should time out at the deadline");
+
+		assert_eq!(heartbeat_completable.heartbeat(), Err(HeartbeatTimeoutError), "Late heartbeats should fail");
+		assert_eq!(heartbeat_completable.complete(1), Err(HeartbeatTimeoutError), "Completing late should fail");
+	}
+
+	#[test]
+	fn test_completes() {
+		let clock = ManualClock::new();
+		let (heartbeat_completion_token, heartbeat_completable) = HeartbeatCompletionToken::with_timer(Duration::from_secs(10), Timer::new(clock.clone()));
+
+		let mut pool = LocalPool::new();
+		let result = pool.spawner().spawn_local_with_handle(heartbeat_completion_token).unwrap();
+		pool.run_until_stalled();
+
+		clock.advance(Duration::from_secs(9));
+		heartbeat_completable.heartbeat().unwrap();
+		pool.run_until_stalled();
+
+		heartbeat_completable.complete("done").unwrap();
+		assert_eq!(pool.run_until(result), Ok("done"), "Should complete");
+	}
+
+	#[test]
+	fn test_complete_twice_doesnt_poison() {
+		let clock = ManualClock::new();
+		let (heartbeat_completion_token, heartbeat_completable) = HeartbeatCompletionToken::with_timer(Duration::from_secs(10), Timer::new(clock));
+
+		heartbeat_completable.complete("first").unwrap();
+
+		let completed_again = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| heartbeat_completable.complete("second")));
+		assert!(completed_again.is_err(), "Completing twice should panic");
+
+		// The lock isn't poisoned, so the token still returns the first result
+		assert!(!heartbeat_completion_token.is_timed_out(), "Shouldn't be timed out");
+		assert_eq!(LocalPool::new().run_until(heartbeat_completion_token), Ok("first"), "Should return the first result");
+	}
+
+	#[test]
+	fn test_dropped_completable_times_out() {
+		let clock = ManualClock::new();
+		let (mut heartbeat_completion_token, heartbeat_completable) = HeartbeatCompletionToken::<u32>::with_timer(Duration::from_secs(10), Timer::new(clock.clone()));
+
+		drop(heartbeat_completable);
+		clock.advance(Duration::from_secs(10));
+
+		let mut pool = LocalPool::new();
+		assert_eq!(pool.run_until(&mut heartbeat_completion_token), Err(HeartbeatTimeoutError), "Should time out");
+		assert!(heartbeat_completion_token.is_timed_out(), "Should be timed out");
+	}
+
+	runtime_test! {
+		async fn test_system_timer() {
+			let start = Instant::now();
+			let (heartbeat_completion_token, _heartbeat_completable) = HeartbeatCompletionToken::<u32>::new(Duration::from_millis(20));
+
+			assert_eq!(heartbeat_completion_token.await, Err(HeartbeatTimeoutError), "Should time out");
+			assert!(start.elapsed() >= Duration::from_millis(20), "Timed out early");
+		}
+	}
+}
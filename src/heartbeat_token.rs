@@ -0,0 +1,274 @@
+// https://github.com/GWBasic/sync-tokens
+// (c) Andrew Rondeau
+// Apache 2.0 license
+// See https://github.com/GWBasic/sync-tokens/blob/main/LICENSE
+
+//! Contains a watchdog that cancels a task when it stops sending heartbeats. See
+//! [`HeartbeatToken`](struct.HeartbeatToken.html)
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use crate::cancelation_token::{Cancelable, CancelationToken};
+use crate::scheduled_cancel::ScheduledCancel;
+use crate::timer::Timer;
+
+/// Watches a task that calls [`HeartbeatFeeder::heartbeat()`](struct.HeartbeatFeeder.html#method.heartbeat)
+/// periodically. If no heartbeat arrives within the interval, the task is starved: awaiting the
+/// [`HeartbeatToken`](struct.HeartbeatToken.html) returns [`Starved`](struct.Starved.html), and the
+/// [`Cancelable`](../cancelation_token/struct.Cancelable.html) returned by
+/// [`cancelable()`](struct.HeartbeatToken.html#method.cancelable) is canceled.
+///
+/// The deadline is kept by a [`ScheduledCancel`](../scheduled_cancel/struct.ScheduledCancel.html) that each heartbeat
+/// moves, so the cancelable is canceled once the task is starved, whether or not anything awaits the
+/// [`HeartbeatToken`](struct.HeartbeatToken.html).
+///
+/// By default, starvation is permanent. If [`allow_revival()`](struct.HeartbeatToken.html#method.allow_revival) is
+/// called, a heartbeat after starvation resets the cancelable, and the token can be awaited again
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use async_std::task;
+/// use sync_tokens::heartbeat_token::HeartbeatToken;
+///
+/// # task::block_on(async {
+/// let (heartbeat_token, heartbeat_feeder) = HeartbeatToken::new(Duration::from_millis(50));
+/// let cancelable = heartbeat_token.cancelable();
+///
+/// task::spawn(async move {
+///     for _ in 0..3 {
+///         task::sleep(Duration::from_millis(10)).await;
+///         heartbeat_feeder.heartbeat().unwrap();
+///     }
+///
+///     // The worker is now wedged
+///     cancelable.future().await;
+/// });
+///
+/// heartbeat_token.await;
+/// # });
+/// ```
+#[derive(Debug)]
+pub struct HeartbeatToken {
+	shared_state: Arc<Mutex<HeartbeatState>>,
+	cancelable: Cancelable
+}
+
+/// Sends heartbeats to a [`HeartbeatToken`](struct.HeartbeatToken.html)
+#[derive(Debug, Clone)]
+pub struct HeartbeatFeeder {
+	shared_state: Arc<Mutex<HeartbeatState>>,
+	timer: Timer
+}
+
+/// Returned when a task didn't send a heartbeat in time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Starved;
+
+#[derive(Debug)]
+struct HeartbeatState {
+	interval: Duration,
+	revivable: bool,
+	// Only canceled when the task is starved
+	cancelation_token: CancelationToken,
+	// Fires when the task is starved. Replaced when the task is revived
+	scheduled_cancel: ScheduledCancel
+}
+
+impl HeartbeatToken {
+	/// Creates a new [`HeartbeatToken`](struct.HeartbeatToken.html) and [`HeartbeatFeeder`](struct.HeartbeatFeeder.html)
+	/// that use the system clock. The first heartbeat is due one interval from now
+	pub fn new(interval: Duration) -> (HeartbeatToken, HeartbeatFeeder) {
+		HeartbeatToken::with_timer(interval, Timer::default())
+	}
+
+	/// Creates a new [`HeartbeatToken`](struct.HeartbeatToken.html) and [`HeartbeatFeeder`](struct.HeartbeatFeeder.html)
+	/// that use the given [`Timer`](../timer/struct.Timer.html)
+	pub fn with_timer(interval: Duration, timer: Timer) -> (HeartbeatToken, HeartbeatFeeder) {
+		let (cancelation_token, cancelable) = CancelationToken::new();
+		let scheduled_cancel = ScheduledCancel::with_timer(cancelation_token.clone(), timer.now() + interval, timer.clone());
+
+		let shared_state = Arc::new(Mutex::new(HeartbeatState {
+			interval,
+			revivable: false,
+			cancelation_token,
+			scheduled_cancel
+		}));
+
+		let heartbeat_feeder = HeartbeatFeeder {
+			shared_state: shared_state.clone(),
+			timer
+		};
+
+		let heartbeat_token = HeartbeatToken {
+			shared_state,
+			cancelable
+		};
+
+		(heartbeat_token, heartbeat_feeder)
+	}
+
+	/// Allows a heartbeat that arrives after starvation to revive the task: the cancelable is
+	/// [`reset`](../cancelation_token/struct.CancelationToken.html#method.reset), and the token can be awaited again
+	pub fn allow_revival(&self) {
+		self.shared_state.lock().unwrap().revivable = true;
+	}
+
+	/// Returns a [`Cancelable`](../cancelation_token/struct.Cancelable.html) that's canceled when the task is starved
+	pub fn cancelable(&self) -> Cancelable {
+		self.cancelable.clone()
+	}
+
+	/// Returns true if the task is starved
+	pub fn is_starved(&self) -> bool {
+		self.cancelable.is_canceled()
+	}
+}
+
+impl HeartbeatFeeder {
+	/// Tells the [`HeartbeatToken`](struct.HeartbeatToken.html) that the task is alive. Returns
+	/// [`Starved`](struct.Starved.html) if a heartbeat was missed, unless revival is allowed
+	pub fn heartbeat(&self) -> Result<(), Starved> {
+		let mut shared_state = self.shared_state.lock().unwrap();
+		let interval = shared_state.interval;
+
+		// Fails once the deadline passed
+		if !shared_state.scheduled_cancel.reschedule_after(interval) {
+			if !shared_state.revivable {
+				return Err(Starved);
+			}
+
+			shared_state.cancelation_token.reset();
+			shared_state.scheduled_cancel = ScheduledCancel::with_timer(shared_state.cancelation_token.clone(), self.timer.now() + interval, self.timer.clone());
+		}
+
+		Ok(())
+	}
+}
+
+impl Future for HeartbeatToken {
+	type Output = Starved;
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+		self.cancelable.poll_canceled(cx).map(|()| Starved)
+	}
+}
+
+impl fmt::Display for Starved {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "No heartbeat arrived in time")
+	}
+}
+
+impl Error for Starved {}
+
+#[cfg(test)]
+mod tests {
+	use futures::executor::LocalPool;
+	use futures::task::LocalSpawnExt;
+
+	use super::*;
+	use crate::timer::ManualClock;
+
+	#[test]
+	fn test_exact_expiry() {
+		let clock = ManualClock::new();
+		let (heartbeat_token, heartbeat_feeder) = HeartbeatToken::with_timer(Duration::from_secs(10), Timer::new(clock.clone()));
+		let cancelable = heartbeat_token.cancelable();
+
+		let mut pool = LocalPool::new();
+		let starved = pool.spawner().spawn_local_with_handle(heartbeat_token).unwrap();
+
+		pool.run_until_stalled();
+
+		clock.advance(Duration::from_secs(5));
+		heartbeat_feeder.heartbeat().unwrap();
+		pool.run_until_stalled();
+
+		// The deadline is now 15 seconds
+		clock.advance(Duration::from_secs(10) - Duration::from_millis(1));
+		pool.run_until_stalled();
+		assert!(!cancelable.is_canceled(), "Shouldn't be starved before the deadline");
+
+		clock.advance(Duration::from_millis(1));
+		assert_eq!(pool.run_until(starved), Starved, "Should be starved at the deadline");
+		assert!(cancelable.is_canceled(), "Cancelable should be canceled");
+
+		assert_eq!(heartbeat_feeder.heartbeat(), Err(Starved), "Late heartbeats shouldn't revive the task");
+	}
+
+	#[test]
+	fn test_timely_heartbeats() {
+		let clock = ManualClock::new();
+		let (heartbeat_token, heartbeat_feeder) = HeartbeatToken::with_timer(Duration::from_secs(10), Timer::new(clock.clone()));
+		let cancelable = heartbeat_token.cancelable();
+
+		let mut pool = LocalPool::new();
+		pool.spawner().spawn_local(async move {
+			heartbeat_token.await;
+		}).unwrap();
+
+		for _ in 0..1000 {
+			clock.advance(Duration::from_secs(9));
+			pool.run_until_stalled();
+
+			heartbeat_feeder.heartbeat().unwrap();
+			pool.run_until_stalled();
+		}
+
+		assert!(!cancelable.is_canceled(), "Timely heartbeats should keep the task alive");
+	}
+
+	#[test]
+	fn test_revival() {
+		let clock = ManualClock::new();
+		let (mut heartbeat_token, heartbeat_feeder) = HeartbeatToken::with_timer(Duration::from_secs(10), Timer::new(clock.clone()));
+		let cancelable = heartbeat_token.cancelable();
+		heartbeat_token.allow_revival();
+
+		let mut pool = LocalPool::new();
+
+		clock.advance(Duration::from_secs(10));
+		assert_eq!(pool.run_until(&mut heartbeat_token), Starved, "Should be starved");
+		assert!(cancelable.is_canceled(), "Cancelable should be canceled");
+
+		heartbeat_feeder.heartbeat().unwrap();
+		assert!(!heartbeat_token.is_starved(), "Should be revived");
+		assert!(!cancelable.is_canceled(), "Cancelable should be reset");
+
+		let starved = pool.spawner().spawn_local_with_handle(heartbeat_token).unwrap();
+		pool.run_until_stalled();
+
+		clock.advance(Duration::from_secs(10));
+		assert_eq!(pool.run_until(starved), Starved, "Should be starved again");
+		assert!(cancelable.is_canceled(), "Cancelable should be canceled again");
+	}
+
+	#[test]
+	fn test_starves_without_being_awaited() {
+		let clock = ManualClock::new();
+		let (heartbeat_token, _heartbeat_feeder) = HeartbeatToken::with_timer(Duration::from_secs(10), Timer::new(clock.clone()));
+		let cancelable = heartbeat_token.cancelable();
+
+		clock.advance(Duration::from_secs(10));
+
+		// Nothing polls the heartbeat token
+		futures::executor::block_on(cancelable.future());
+		assert!(heartbeat_token.is_starved(), "Should be starved");
+	}
+
+	runtime_test! {
+		async fn test_system_timer() {
+			let start = std::time::Instant::now();
+			let (heartbeat_token, _heartbeat_feeder) = HeartbeatToken::new(Duration::from_millis(20));
+
+			assert_eq!(heartbeat_token.await, Starved, "Should be starved");
+			assert!(start.elapsed() >= Duration::from_millis(20), "Starved early");
+		}
+	}
+}
@@ -0,0 +1,412 @@
+// https://github.com/GWBasic/sync-tokens
+// (c) Andrew Rondeau
+// Apache 2.0 license
+// See https://github.com/GWBasic/sync-tokens/blob/main/LICENSE
+
+//! Contains a lease that must be renewed to keep doing work. See [`LeaseToken`](struct.LeaseToken.html)
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use crate::cancelation_token::{Cancelable, CancelationToken};
+use crate::scheduled_cancel::ScheduledCancel;
+use crate::timer::{DeadlineSleep, Instant, Timer};
+use crate::wakers::{WakePanic, WakerKey, WakerList, Wakers};
+
+/// The controller's side of a lease. The controller grants a [`Lease`](struct.Lease.html) for a duration; the holder
+/// must call [`renew()`](struct.Lease.html#method.renew) before the lease expires to keep it.
+///
+/// When the lease expires, the holder's [`Cancelable`](../cancelation_token/struct.Cancelable.html) is canceled and
+/// [`expired()`](struct.LeaseToken.html#method.expired) returns [`LeaseEnd::Expired`](enum.LeaseEnd.html). The
+/// cancelable is canceled by a [`ScheduledCancel`](../scheduled_cancel/struct.ScheduledCancel.html) that each renewal
+/// moves, so it's canceled on time even if nothing awaits [`expired()`](struct.LeaseToken.html#method.expired).
+///
+/// The holder can give the lease back early with [`release()`](struct.Lease.html#method.release), or by dropping it
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use async_std::task;
+/// use sync_tokens::lease_token::{LeaseEnd, LeaseToken};
+///
+/// # task::block_on(async {
+/// let (lease_token, lease) = LeaseToken::new(Duration::from_millis(50));
+///
+/// task::spawn(async move {
+///     for _ in 0..3 {
+///         task::sleep(Duration::from_millis(10)).await;
+///         lease.renew().unwrap();
+///     }
+///
+///     lease.release();
+/// });
+///
+/// assert_eq!(lease_token.expired().await, LeaseEnd::Released);
+/// # });
+/// ```
+#[derive(Debug, Clone)]
+pub struct LeaseToken {
+	shared_state: Arc<Mutex<LeaseState>>,
+	timer: Timer
+}
+
+/// The holder's side of a lease. See [`LeaseToken`](struct.LeaseToken.html)
+#[derive(Debug)]
+pub struct Lease {
+	shared_state: Arc<Mutex<LeaseState>>,
+	cancelable: Cancelable,
+	timer: Timer
+}
+
+/// Future returned by [`LeaseToken::expired()`](struct.LeaseToken.html#method.expired)
+#[derive(Debug)]
+pub struct LeaseFuture {
+	shared_state: Arc<Mutex<LeaseState>>,
+	sleep: DeadlineSleep,
+	waker_key: Option<WakerKey>
+}
+
+/// How a lease ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LeaseEnd {
+	/// The holder didn't renew the lease in time
+	Expired,
+	/// The holder released the lease
+	Released
+}
+
+/// Error returned when renewing a lease that already expired
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LeaseExpired;
+
+#[derive(Debug)]
+struct LeaseState {
+	duration: Duration,
+	expires_at: Instant,
+	ended: Option<LeaseEnd>,
+	cancelation_token: CancelationToken,
+	// Cancels the holder's token at expires_at, whether or not anything polls
+	scheduled_cancel: ScheduledCancel,
+	wakers: WakerList
+}
+
+// What's canceled and woken when the lease ends, once the lock is released
+struct LeaseEnding {
+	cancelation_token: CancelationToken,
+	wakers: Wakers
+}
+
+impl LeaseToken {
+	/// Grants a [`Lease`](struct.Lease.html) for duration, using the system clock
+	pub fn new(duration: Duration) -> (LeaseToken, Lease) {
+		LeaseToken::with_timer(duration, Timer::default())
+	}
+
+	/// Grants a [`Lease`](struct.Lease.html) for duration, using the given [`Timer`](../timer/struct.Timer.html)
+	pub fn with_timer(duration: Duration, timer: Timer) -> (LeaseToken, Lease) {
+		let (cancelation_token, cancelable) = CancelationToken::new();
+		let expires_at = timer.now() + duration;
+		let scheduled_cancel = ScheduledCancel::with_timer(cancelation_token.clone(), expires_at, timer.clone());
+
+		let shared_state = Arc::new(Mutex::new(LeaseState {
+			duration,
+			expires_at,
+			ended: None,
+			cancelation_token,
+			scheduled_cancel,
+			wakers: WakerList::new()
+		}));
+
+		let lease_token = LeaseToken {
+			shared_state: shared_state.clone(),
+			timer: timer.clone()
+		};
+
+		let lease = Lease {
+			shared_state,
+			cancelable,
+			timer
+		};
+
+		(lease_token, lease)
+	}
+
+	/// Returns a future that returns when the lease expires or is released
+	pub fn expired(&self) -> LeaseFuture {
+		LeaseFuture {
+			shared_state: self.shared_state.clone(),
+			sleep: DeadlineSleep::new(self.timer.clone()),
+			waker_key: None
+		}
+	}
+
+	/// Returns how the lease ended, or None if it's still held
+	pub fn ended(&self) -> Option<LeaseEnd> {
+		let mut shared_state = self.shared_state.lock().unwrap();
+		let ending = shared_state.expire(self.timer.now());
+		let ended = shared_state.ended;
+
+		drop(shared_state);
+		LeaseEnding::finish(ending);
+
+		ended
+	}
+}
+
+impl Lease {
+	/// Extends the lease by its duration, starting now. Returns [`LeaseExpired`](struct.LeaseExpired.html) if it's
+	/// too late
+	pub fn renew(&self) -> Result<(), LeaseExpired> {
+		let now = self.timer.now();
+		let mut shared_state = self.shared_state.lock().unwrap();
+		let ending = shared_state.expire(now);
+
+		let expires_at = now + shared_state.duration;
+
+		let (result, ending) = if shared_state.ended.is_some() {
+			(Err(LeaseExpired), ending)
+		} else if !shared_state.scheduled_cancel.reschedule(expires_at) {
+			// The scheduled cancel fired after now was read
+			(Err(LeaseExpired), Some(shared_state.end(LeaseEnd::Expired)))
+		} else {
+			shared_state.expires_at = expires_at;
+			(Ok(()), None)
+		};
+
+		drop(shared_state);
+		LeaseEnding::finish(ending);
+
+		result
+	}
+
+	/// How long until the lease expires. Returns zero once the lease ended
+	pub fn remaining(&self) -> Duration {
+		let now = self.timer.now();
+		let mut shared_state = self.shared_state.lock().unwrap();
+		let ending = shared_state.expire(now);
+
+		let remaining = if shared_state.ended.is_some() {
+			Duration::ZERO
+		} else {
+			shared_state.expires_at - now
+		};
+
+		drop(shared_state);
+		LeaseEnding::finish(ending);
+
+		remaining
+	}
+
+	/// Returns a [`Cancelable`](../cancelation_token/struct.Cancelable.html) that's canceled when the lease ends
+	pub fn cancelable(&self) -> Cancelable {
+		self.cancelable.clone()
+	}
+
+	/// Gives the lease back before it expires
+	pub fn release(self) {
+		// Dropping releases
+	}
+}
+
+impl Drop for Lease {
+	fn drop(&mut self) {
+		let now = self.timer.now();
+		let mut shared_state = self.shared_state.lock().unwrap();
+
+		let ending = match shared_state.expire(now) {
+			None if shared_state.ended.is_none() => Some(shared_state.end(LeaseEnd::Released)),
+			ending => ending
+		};
+
+		drop(shared_state);
+		LeaseEnding::finish(ending);
+	}
+}
+
+impl LeaseState {
+	// Marks the lease as expired if its time ran out
+	fn expire(&mut self, now: Instant) -> Option<LeaseEnding> {
+		if self.ended.is_none() && now >= self.expires_at {
+			Some(self.end(LeaseEnd::Expired))
+		} else {
+			None
+		}
+	}
+
+	fn end(&mut self, lease_end: LeaseEnd) -> LeaseEnding {
+		self.ended = Some(lease_end);
+
+		LeaseEnding {
+			cancelation_token: self.cancelation_token.clone(),
+			wakers: self.wakers.take_all()
+		}
+	}
+}
+
+impl LeaseEnding {
+	// Cancels and wakes without holding the lock, so that a waker that panics can't poison it
+	fn finish(ending: Option<LeaseEnding>) {
+		if let Some(ending) = ending {
+			let mut wake_panic = WakePanic::new();
+			wake_panic.catch(|| ending.cancelation_token.cancel());
+			ending.wakers.wake(&mut wake_panic);
+			wake_panic.resume();
+		}
+	}
+}
+
+impl Future for LeaseFuture {
+	type Output = LeaseEnd;
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+		let this = self.get_mut();
+
+		loop {
+			let mut shared_state = this.shared_state.lock().unwrap();
+			let ending = shared_state.expire(this.sleep.now());
+			let ended = shared_state.ended;
+
+			if ended.is_none() {
+				// Woken when the lease is released
+				shared_state.wakers.register(&mut this.waker_key, cx.waker());
+			}
+
+			let deadline = shared_state.expires_at;
+
+			drop(shared_state);
+			LeaseEnding::finish(ending);
+
+			if let Some(lease_end) = ended {
+				return Poll::Ready(lease_end);
+			}
+
+			// Renewals move the deadline forward; the sleep is replaced once it wakes for an old deadline
+			if this.sleep.poll_until(deadline, cx).is_pending() {
+				return Poll::Pending;
+			}
+		}
+	}
+}
+
+impl Drop for LeaseFuture {
+	fn drop(&mut self) {
+		if self.waker_key.is_some() {
+			let mut shared_state = self.shared_state.lock().unwrap();
+			shared_state.wakers.remove(self.waker_key);
+		}
+	}
+}
+
+impl fmt::Display for LeaseExpired {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "The lease expired")
+	}
+}
+
+impl Error for LeaseExpired {}
+
+#[cfg(test)]
+mod tests {
+	use futures::executor::LocalPool;
+	use futures::task::LocalSpawnExt;
+
+	use super::*;
+	use crate::timer::ManualClock;
+
+	#[test]
+	fn test_renew_just_in_time() {
+		let clock = ManualClock::new();
+		let (lease_token, lease) = LeaseToken::with_timer(Duration::from_secs(10), Timer::new(clock.clone()));
+		let cancelable = lease.cancelable();
+
+		let mut pool = LocalPool::new();
+		let expired = pool.spawner().spawn_local_with_handle(lease_token.expired()).unwrap();
+		pool.run_until_stalled();
+
+		for _ in 0..100 {
+			clock.advance(Duration::from_secs(10) - Duration::from_millis(1));
+			pool.run_until_stalled();
+
+			assert_eq!(lease.remaining(), Duration::from_millis(1), "Wrong remaining time");
+			lease.renew().unwrap();
+			assert_eq!(lease.remaining(), Duration::from_secs(10), "Renewing should restart the lease");
+			pool.run_until_stalled();
+		}
+
+		assert!(!cancelable.is_canceled(), "Renewed lease shouldn't be canceled");
+		assert_eq!(lease_token.ended(), None, "Lease should be held");
+
+		clock.advance(Duration::from_secs(10));
+		assert_eq!(pool.run_until(expired), LeaseEnd::Expired, "Lease should expire");
+		assert!(cancelable.is_canceled(), "Expired lease should be canceled");
+	}
+
+	#[test]
+	fn test_renew_too_late() {
+		let clock = ManualClock::new();
+		let (lease_token, lease) = LeaseToken::with_timer(Duration::from_secs(10), Timer::new(clock.clone()));
+		let cancelable = lease.cancelable();
+
+		clock.advance(Duration::from_secs(10));
+		assert_eq!(lease.renew(), Err(LeaseExpired), "Renewing should fail");
+		assert!(cancelable.is_canceled(), "Expired lease should be canceled");
+		assert_eq!(lease.remaining(), Duration::ZERO, "Nothing should remain");
+
+		let mut pool = LocalPool::new();
+		assert_eq!(pool.run_until(lease_token.expired()), LeaseEnd::Expired, "Lease should be expired");
+
+		drop(lease);
+		assert_eq!(lease_token.ended(), Some(LeaseEnd::Expired), "Dropping an expired lease shouldn't release it");
+	}
+
+	#[test]
+	fn test_expires_without_being_awaited() {
+		let clock = ManualClock::new();
+		let (lease_token, lease) = LeaseToken::with_timer(Duration::from_secs(10), Timer::new(clock.clone()));
+		let cancelable = lease.cancelable();
+
+		clock.advance(Duration::from_secs(5));
+		lease.renew().unwrap();
+
+		clock.advance(Duration::from_secs(10));
+
+		// Nothing polls expired(), renews, or checks the remaining time
+		futures::executor::block_on(cancelable.future());
+		assert_eq!(lease_token.ended(), Some(LeaseEnd::Expired), "Lease should be expired");
+	}
+
+	#[test]
+	fn test_release() {
+		let clock = ManualClock::new();
+		let (lease_token, lease) = LeaseToken::with_timer(Duration::from_secs(10), Timer::new(clock.clone()));
+		let cancelable = lease.cancelable();
+
+		let mut pool = LocalPool::new();
+		let expired = pool.spawner().spawn_local_with_handle(lease_token.expired()).unwrap();
+		pool.run_until_stalled();
+
+		clock.advance(Duration::from_secs(5));
+		lease.release();
+
+		assert_eq!(pool.run_until(expired), LeaseEnd::Released, "Lease should be released");
+		assert!(cancelable.is_canceled(), "Released lease should be canceled");
+
+		clock.advance(Duration::from_secs(5));
+		assert_eq!(lease_token.ended(), Some(LeaseEnd::Released), "Lease should stay released");
+	}
+
+	runtime_test! {
+		async fn test_system_timer() {
+			let start = Instant::now();
+			let (lease_token, lease) = LeaseToken::new(Duration::from_millis(20));
+
+			assert_eq!(lease_token.expired().await, LeaseEnd::Expired, "Lease should expire");
+			assert!(start.elapsed() >= Duration::from_millis(20), "Expired early");
+			assert!(lease.cancelable().is_canceled(), "Expired lease should be canceled");
+		}
+	}
+}
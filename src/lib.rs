@@ -110,16 +110,193 @@
 #![doc(test(attr(deny(rust_2018_idioms, warnings))))]
 #![doc(test(attr(allow(unused_extern_crates, unused_variables))))]
 
+// Runs an async test on both async-std and tokio. The test becomes a module, named after it, with one test per
+// runtime
+#[cfg(test)]
+macro_rules! runtime_test {
+	($(#[$attr:meta])* async fn $name:ident() $body:block) => {
+		mod $name {
+			#[allow(unused_imports)]
+			use super::*;
+
+			$(#[$attr])*
+			#[async_std::test]
+			async fn on_async_std() $body
+
+			$(#[$attr])*
+			#[cfg(not(target_arch = "wasm32"))]
+			#[tokio::test(flavor = "multi_thread")]
+			async fn on_tokio() $body
+		}
+	};
+}
+
+#[cfg(feature = "async-std")]
+#[cfg_attr(feature = "docs", doc(cfg(feature = "async-std")))]
+pub mod async_std_runtime;
+pub mod box_cancelable;
+pub mod cancel_safe;
+pub mod cancelable_future_ext;
+pub mod cancelable_pool;
 pub mod cancelation_token;
+pub mod completion_future_ext;
 pub mod completion_token;
+pub mod coordinator;
+pub mod epoch_token;
+pub mod heartbeat_completion_token;
+pub mod heartbeat_token;
+pub mod lease_token;
+pub mod managed_task;
+pub mod multi_completion_token;
+#[cfg(feature = "tokio")]
+#[cfg_attr(feature = "docs", doc(cfg(feature = "tokio")))]
+pub mod notify_cancelation_token;
+pub mod observable;
+pub mod once_token;
+pub mod panic_aware_cancelable;
+pub mod policy_cancelation_token;
+pub mod prelude;
+pub mod progress_token;
+pub mod rate_gate;
+pub mod rate_limited_completable;
+pub mod ready_set;
+#[cfg(feature = "remote")]
+#[cfg_attr(feature = "docs", doc(cfg(feature = "remote")))]
+pub mod remote;
+pub mod rendezvous_token;
+pub mod scheduled_cancel;
+pub mod semaphore;
+pub mod service;
+pub mod shutdown_controller;
+pub mod soft_cancelation_token;
+pub mod supervisor;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod sync_primitives;
+pub mod task_handle;
+pub mod task_tracker;
+pub mod throttled_cancelable;
+pub mod timeout_registry;
+pub mod timer;
+#[cfg(feature = "tokio")]
+#[cfg_attr(feature = "docs", doc(cfg(feature = "tokio")))]
+pub mod tokio_runtime;
+pub mod turnstile;
+#[cfg(feature = "tokio")]
+#[cfg_attr(feature = "docs", doc(cfg(feature = "tokio")))]
+pub mod watch_completion_token;
+
+mod background;
+mod wakers;
+
+/// Inserts [`Cancelable::checkpoint()`](cancelation_token/struct.Cancelable.html#method.checkpoint) before every
+/// `.await` in an async fn. Requires the `macros` feature
+/// 
+/// ```
+/// use sync_tokens::cancelable;
+/// use sync_tokens::cancelation_token::{Cancelable, Canceled, CancelationToken};
+/// 
+/// #[cancelable(loops)]
+/// async fn sum(cancelable: &Cancelable, numbers: &[u64]) -> u64 {
+///     let mut sum = 0;
+///     for number in numbers {
+///         sum += number;
+///     }
+///     sum
+/// }
+/// 
+/// # async_std::task::block_on(async {
+/// let (cancelation_token, cancelable) = CancelationToken::new();
+/// assert_eq!(sum(&cancelable, &[1, 2, 3]).await, Ok(6));
+/// 
+/// cancelation_token.cancel();
+/// assert_eq!(sum(&cancelable, &[1, 2, 3]).await, Err(Canceled));
+/// # });
+/// ```
+#[cfg(feature = "macros")]
+#[cfg_attr(feature = "docs", doc(cfg(feature = "macros")))]
+pub use sync_tokens_macros::cancelable;
+
+// The macros refer to this crate by name, including in this crate's tests
+#[cfg(all(test, feature = "macros"))]
+extern crate self as sync_tokens;
+
+// Run with `wasm-pack test --headless --firefox`
+#[cfg(all(test, target_arch = "wasm32"))]
+wasm_bindgen_test::wasm_bindgen_test_configure!(run_in_browser);
 
 #[cfg(test)]
 mod tests {
 
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
     use std::sync::{Arc, Mutex};
 
     use cooked_waker::{Wake, WakeRef, ViaRawPointer};
 
+	// Counts allocations on the current thread, so that tests can verify that something doesn't allocate
+	struct CountingAllocator;
+
+	#[global_allocator]
+	static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+	thread_local! {
+		static COUNTING: Cell<bool> = const { Cell::new(false) };
+		static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+	}
+
+	fn count_allocation() {
+		let _ = COUNTING.try_with(|counting| {
+			if counting.get() {
+				let _ = ALLOCATIONS.try_with(|allocations| allocations.set(allocations.get() + 1));
+			}
+		});
+	}
+
+	unsafe impl GlobalAlloc for CountingAllocator {
+		unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+			count_allocation();
+			System.alloc(layout)
+		}
+
+		unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+			System.dealloc(ptr, layout)
+		}
+
+		unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+			count_allocation();
+			System.realloc(ptr, layout, new_size)
+		}
+	}
+
+	/// Runs f, and returns how many times it allocated or reallocated on the current thread
+	pub fn count_allocations<F, R>(f: F) -> (R, usize) where
+	F: FnOnce() -> R {
+		ALLOCATIONS.with(|allocations| allocations.set(0));
+		COUNTING.with(|counting| counting.set(true));
+
+		let result = f();
+
+		COUNTING.with(|counting| counting.set(false));
+		(result, ALLOCATIONS.with(|allocations| allocations.get()))
+	}
+
+	/// Each clone of a waker made from an `Arc<CountingWaker>` adds to the Arc's strong count, so tests can count
+	/// how many clones of a waker are held
+	pub struct CountingWaker;
+
+	impl std::task::Wake for CountingWaker {
+		fn wake(self: Arc<Self>) {}
+	}
+
+	/// Panics when it's woken, like a buggy executor's waker
+	pub struct PanickingWaker;
+
+	impl std::task::Wake for PanickingWaker {
+		fn wake(self: Arc<Self>) {
+			panic!("Waker panicked");
+		}
+	}
+
 	#[derive(Debug, Clone)]
 	pub struct TestWaker {
 		shared_state: Arc<Mutex<TestWakerState>>
@@ -138,6 +315,10 @@ mod tests {
 				}))
 			}
 		}
+
+		pub fn woke(&self) -> bool {
+			self.shared_state.lock().unwrap().woke
+		}
 	}
 
 	impl WakeRef for TestWaker {
@@ -153,7 +334,7 @@ mod tests {
 		}
 	}
 
-	impl ViaRawPointer for TestWaker {
+	unsafe impl ViaRawPointer for TestWaker {
 		type Target = ();
 	
 		fn into_raw(self) -> *mut () {
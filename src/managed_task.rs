@@ -0,0 +1,288 @@
+// https://github.com/GWBasic/sync-tokens
+// (c) Andrew Rondeau
+// Apache 2.0 license
+// See https://github.com/GWBasic/sync-tokens/blob/main/LICENSE
+
+//! Runs a background task that signals when it's ready, and stops when canceled, on any executor. See
+//! [`run_managed_task()`](fn.run_managed_task.html) and [`run_managed_task_on()`](fn.run_managed_task_on.html)
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+
+use futures::FutureExt;
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+use futures::task::FutureObj;
+use futures::task::{Spawn, SpawnError, SpawnExt};
+
+use crate::cancelation_token::{Cancelable, CancelationToken};
+use crate::completion_token::{Abandoned, Completable, CompletionToken};
+
+/// Handle returned by [`run_managed_task()`](fn.run_managed_task.html) and
+/// [`run_managed_task_on()`](fn.run_managed_task_on.html). Waits for the task to be ready, stops it,
+/// and waits for it to finish.
+///
+/// Dropping the handle doesn't stop the task
+#[derive(Debug)]
+pub struct ManagedTask<T> {
+	ready: CompletionToken<T>,
+	finished: CompletionToken<()>,
+	cancelation_token: CancelationToken
+}
+
+// Spawns on async-std if it's enabled, otherwise on tokio
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+struct RuntimeSpawner;
+
+/// Runs the future that f returns on the async runtime, and returns a [`ManagedTask`](struct.ManagedTask.html) to
+/// control it. f is given a [`Cancelable`](../cancelation_token/struct.Cancelable.html), which is canceled by
+/// [`ManagedTask::cancel()`](struct.ManagedTask.html#method.cancel), and a
+/// [`Completable`](../completion_token/struct.Completable.html) that it completes once it's ready.
+///
+/// This is the pattern shown in the [crate's example](../index.html), in one call. The task is spawned on async-std
+/// if the `async-std` feature is enabled, otherwise on tokio, which must be called from within a tokio runtime. To
+/// run the task on another executor, use [`run_managed_task_on()`](fn.run_managed_task_on.html)
+///
+/// ```
+/// use sync_tokens::managed_task::run_managed_task;
+///
+/// # #[tokio::main]
+/// # async fn main() {
+/// let managed_task = run_managed_task(|cancelable, completable| async move {
+///     completable.complete("listening");
+///     cancelable.future().await;
+/// });
+///
+/// assert_eq!(managed_task.wait_ready().await, "listening");
+///
+/// managed_task.cancel();
+/// managed_task.join().await.unwrap();
+/// # }
+/// ```
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+#[cfg_attr(feature = "docs", doc(cfg(any(feature = "async-std", feature = "tokio"))))]
+pub fn run_managed_task<T, F, TFuture>(f: F) -> ManagedTask<T> where
+F: FnOnce(Cancelable, Completable<T>) -> TFuture,
+TFuture: Future<Output = ()> + Send + 'static {
+	run_managed_task_on(&RuntimeSpawner, f).expect("The runtime always takes the task")
+}
+
+/// Runs the future that f returns on spawner, and returns a [`ManagedTask`](struct.ManagedTask.html) to
+/// control it. f is given a [`Cancelable`](../cancelation_token/struct.Cancelable.html), which is canceled by
+/// [`ManagedTask::cancel()`](struct.ManagedTask.html#method.cancel), and a
+/// [`Completable`](../completion_token/struct.Completable.html) that it completes once it's ready.
+///
+/// This is the pattern shown in the [crate's example](../index.html), in one call. spawner is any futures-rs
+/// [`Spawn`](https://docs.rs/futures/latest/futures/task/trait.Spawn.html), such as a `LocalPool`'s spawner or a
+/// `ThreadPool`, so the task runs wherever the caller's other tasks run. Returns the spawner's error if it can't take
+/// the task, such as after it shut down
+///
+/// ```
+/// use futures::executor::LocalPool;
+///
+/// use sync_tokens::managed_task::run_managed_task_on;
+///
+/// let mut pool = LocalPool::new();
+/// let managed_task = run_managed_task_on(&pool.spawner(), |cancelable, completable| async move {
+///     completable.complete("listening");
+///     cancelable.future().await;
+/// }).unwrap();
+///
+/// assert_eq!(pool.run_until(managed_task.wait_ready()), "listening");
+///
+/// managed_task.cancel();
+/// pool.run_until(managed_task.join()).unwrap();
+/// ```
+pub fn run_managed_task_on<T, S, F, TFuture>(spawner: &S, f: F) -> Result<ManagedTask<T>, SpawnError> where
+S: Spawn + ?Sized,
+F: FnOnce(Cancelable, Completable<T>) -> TFuture,
+TFuture: Future<Output = ()> + Send + 'static {
+	let (ready, ready_completable) = CompletionToken::new();
+	let (finished, finished_completable) = CompletionToken::new();
+	let (cancelation_token, cancelable) = CancelationToken::new();
+
+	let task = f(cancelable, ready_completable);
+
+	// If the task panics, finished_completable is dropped without completing, so join() returns Abandoned. The panic
+	// is caught, so that it doesn't take down the spawner's executor
+	spawner.spawn(async move {
+		if AssertUnwindSafe(task).catch_unwind().await.is_ok() {
+			finished_completable.complete(());
+		}
+	})?;
+
+	Ok(ManagedTask {
+		ready,
+		finished,
+		cancelation_token
+	})
+}
+
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+impl Spawn for RuntimeSpawner {
+	fn spawn_obj(&self, future: FutureObj<'static, ()>) -> Result<(), SpawnError> {
+		#[cfg(feature = "async-std")]
+		async_std::task::spawn(future);
+
+		#[cfg(all(feature = "tokio", not(feature = "async-std")))]
+		tokio::spawn(future);
+
+		Ok(())
+	}
+}
+
+impl<T> ManagedTask<T> {
+	/// Waits until the task is ready, and returns a clone of the value that it completed its
+	/// [`Completable`](../completion_token/struct.Completable.html) with. Never returns if the task finishes without
+	/// becoming ready, the same as awaiting a [`CompletionToken`](../completion_token/struct.CompletionToken.html).
+	/// The value is retained, so this can be called any number of times
+	pub fn wait_ready(&self) -> impl Future<Output = T> where
+	T: Clone {
+		self.ready.subscribe()
+	}
+
+	/// Asks the task to stop, without waiting for it. This can be called multiple times safely
+	pub fn cancel(&self) {
+		self.cancelation_token.cancel();
+	}
+
+	/// Waits until the task finishes. Returns [`Abandoned`](../completion_token/struct.Abandoned.html) if it panicked
+	pub fn join(self) -> impl Future<Output = Result<(), Abandoned>> {
+		self.finished.try_wait()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::net::SocketAddr;
+
+	use async_std::net::{TcpListener, TcpStream};
+	use futures::executor::LocalPool;
+	use futures::task::FutureObj;
+	use futures::{AsyncReadExt, AsyncWriteExt};
+
+	use super::*;
+
+	struct AsyncStdSpawner;
+
+	impl Spawn for AsyncStdSpawner {
+		fn spawn_obj(&self, future: FutureObj<'static, ()>) -> Result<(), SpawnError> {
+			async_std::task::spawn(future);
+			Ok(())
+		}
+	}
+
+	runtime_test! {
+		async fn test_echo_server() {
+			let managed_task = run_managed_task_on(&AsyncStdSpawner, |cancelable, completable: Completable<SocketAddr>| async move {
+				let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+				completable.complete(listener.local_addr().unwrap());
+
+				while let Some((mut stream, _)) = cancelable.allow_cancel(Box::pin(async { listener.accept().await.ok() }), None).await {
+					let mut buffer = [0; 64];
+
+					loop {
+						match stream.read(&mut buffer).await.unwrap() {
+							0 => break,
+							read => stream.write_all(&buffer[..read]).await.unwrap()
+						}
+					}
+				}
+			}).unwrap();
+
+			let addr = managed_task.wait_ready().await;
+
+			let mut stream = TcpStream::connect(addr).await.unwrap();
+			stream.write_all(b"hello").await.unwrap();
+
+			let mut echoed = [0; 5];
+			stream.read_exact(&mut echoed).await.unwrap();
+			assert_eq!(&echoed, b"hello", "Should echo what was sent");
+
+			drop(stream);
+
+			managed_task.cancel();
+			assert_eq!(managed_task.join().await, Ok(()), "The server should stop when canceled");
+		}
+	}
+
+	runtime_test! {
+		async fn test_panic() {
+			let managed_task = run_managed_task_on(&AsyncStdSpawner, |_cancelable, _completable: Completable<()>| async move {
+				panic!("The task failed");
+			}).unwrap();
+
+			assert_eq!(managed_task.join().await, Err(Abandoned), "A panic should abandon the task");
+		}
+	}
+
+	#[cfg(feature = "async-std")]
+	runtime_test! {
+		async fn test_default_runtime() {
+			let managed_task = run_managed_task(|cancelable, completable| async move {
+				completable.complete("ready");
+				cancelable.future().await;
+			});
+
+			assert_eq!(managed_task.wait_ready().await, "ready", "Wrong ready value");
+
+			managed_task.cancel();
+			assert_eq!(managed_task.join().await, Ok(()), "The task should stop when canceled");
+		}
+	}
+
+	#[cfg(all(feature = "tokio", not(feature = "async-std")))]
+	#[tokio::test(flavor = "multi_thread")]
+	async fn test_default_runtime() {
+		let managed_task = run_managed_task(|cancelable, completable| async move {
+			completable.complete("ready");
+			cancelable.future().await;
+		});
+
+		assert_eq!(managed_task.wait_ready().await, "ready", "Wrong ready value");
+
+		managed_task.cancel();
+		assert_eq!(managed_task.join().await, Ok(()), "The task should stop when canceled");
+	}
+
+	#[test]
+	fn test_wait_ready_again() {
+		let mut pool = LocalPool::new();
+
+		let managed_task = run_managed_task_on(&pool.spawner(), |cancelable, completable| async move {
+			completable.complete("ready");
+			cancelable.future().await;
+		}).unwrap();
+
+		assert_eq!(pool.run_until(managed_task.wait_ready()), "ready", "Wrong ready value");
+		assert_eq!(pool.run_until(managed_task.wait_ready()), "ready", "Waiting again should return the value again");
+
+		managed_task.cancel();
+		assert_eq!(pool.run_until(managed_task.join()), Ok(()), "The task should stop when canceled");
+	}
+
+	#[test]
+	fn test_runs_on_the_callers_executor() {
+		let mut pool = LocalPool::new();
+		let caller = std::thread::current().id();
+
+		let managed_task = run_managed_task_on(&pool.spawner(), |cancelable, completable| async move {
+			completable.complete(std::thread::current().id());
+			cancelable.future().await;
+		}).unwrap();
+
+		assert_eq!(pool.run_until(managed_task.wait_ready()), caller, "The task should run on the spawner");
+
+		managed_task.cancel();
+		assert_eq!(pool.run_until(managed_task.join()), Ok(()), "The task should stop when canceled");
+	}
+
+	#[test]
+	fn test_panic_doesnt_stop_the_executor() {
+		let mut pool = LocalPool::new();
+
+		let managed_task = run_managed_task_on(&pool.spawner(), |_cancelable, _completable: Completable<()>| async move {
+			panic!("The task failed");
+		}).unwrap();
+
+		assert_eq!(pool.run_until(managed_task.join()), Err(Abandoned), "A panic should abandon the task");
+	}
+}
@@ -0,0 +1,351 @@
+// https://github.com/GWBasic/sync-tokens
+// (c) Andrew Rondeau
+// Apache 2.0 license
+// See https://github.com/GWBasic/sync-tokens/blob/main/LICENSE
+
+//! Contains a completion token that completes more than once, as a stream. See
+//! [`MultiCompletionToken`](struct.MultiCompletionToken.html)
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use futures::stream::Stream;
+
+use crate::wakers::{WakerKey, WakerList, wake_each};
+
+/// Receives a short sequence of values, such as milestones, from a task. The task sends each value with
+/// [`MultiCompletable::complete_next()`](struct.MultiCompletable.html#method.complete_next), and ends the sequence
+/// with [`MultiCompletable::finish()`](struct.MultiCompletable.html#method.finish). The token is a
+/// [`Stream`](https://docs.rs/futures/latest/futures/stream/trait.Stream.html) of the values, in order.
+///
+/// Only one value is buffered: complete_next waits until the previous value is received, so a task never gets
+/// ahead of the consumer by more than one value. Values that were sent before finishing are still received, and
+/// then the stream ends. If the [`MultiCompletable`](struct.MultiCompletable.html) is dropped without finishing,
+/// the stream ends the same way. If the token is dropped, complete_next returns
+/// [`StreamClosedError`](struct.StreamClosedError.html)
+///
+/// ```
+/// use futures::stream::StreamExt;
+/// use sync_tokens::multi_completion_token::MultiCompletionToken;
+///
+/// # async_std::task::block_on(async {
+/// let (multi_completion_token, multi_completable) = MultiCompletionToken::new();
+///
+/// async_std::task::spawn(async move {
+///     for milestone in vec!["connected", "authenticated", "synced"] {
+///         multi_completable.complete_next(milestone).await.unwrap();
+///     }
+///
+///     multi_completable.finish();
+/// });
+///
+/// let milestones: Vec<_> = multi_completion_token.collect().await;
+/// assert_eq!(milestones, vec!["connected", "authenticated", "synced"]);
+/// # });
+/// ```
+#[derive(Debug)]
+pub struct MultiCompletionToken<T> {
+	shared_state: Arc<Mutex<MultiCompletionState<T>>>,
+	waker_key: Option<WakerKey>
+}
+
+/// Sends values to, and then finishes, the corresponding [`MultiCompletionToken`](struct.MultiCompletionToken.html)
+#[derive(Debug)]
+pub struct MultiCompletable<T> {
+	shared_state: Arc<Mutex<MultiCompletionState<T>>>
+}
+
+/// Future returned by [`MultiCompletable::complete_next()`](struct.MultiCompletable.html#method.complete_next).
+/// Dropping it before it returns drops the value without sending it
+#[derive(Debug)]
+pub struct CompleteNextFuture<'a, T> {
+	multi_completable: &'a MultiCompletable<T>,
+	value: Option<T>,
+	waker_key: Option<WakerKey>
+}
+
+/// Returned by [`MultiCompletable::complete_next()`](struct.MultiCompletable.html#method.complete_next) when the
+/// [`MultiCompletionToken`](struct.MultiCompletionToken.html) was dropped, so nothing will receive the value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamClosedError;
+
+#[derive(Debug)]
+struct MultiCompletionState<T> {
+	// The single-slot buffer
+	next: Option<T>,
+	// Set by finish(), or when the completable is dropped
+	finished: bool,
+	// Set when the token is dropped
+	closed: bool,
+	token_waker: WakerList,
+	// Every complete_next() that waits for the buffer to empty
+	completable_wakers: WakerList
+}
+
+impl<T> MultiCompletionToken<T> {
+	/// Creates a new [`MultiCompletionToken`](struct.MultiCompletionToken.html) and
+	/// [`MultiCompletable`](struct.MultiCompletable.html)
+	pub fn new() -> (MultiCompletionToken<T>, MultiCompletable<T>) {
+		let shared_state = Arc::new(Mutex::new(MultiCompletionState {
+			next: None,
+			finished: false,
+			closed: false,
+			token_waker: WakerList::with_capacity(1),
+			completable_wakers: WakerList::with_capacity(1)
+		}));
+
+		let multi_completion_token = MultiCompletionToken {
+			shared_state: shared_state.clone(),
+			waker_key: None
+		};
+
+		(multi_completion_token, MultiCompletable { shared_state })
+	}
+}
+
+impl<T> MultiCompletable<T> {
+	/// Sends value to the [`MultiCompletionToken`](struct.MultiCompletionToken.html). The returned future waits until
+	/// the previous value was received, then buffers value and returns without waiting for it to be received. Returns
+	/// [`StreamClosedError`](struct.StreamClosedError.html), and drops value, if the token was dropped
+	pub fn complete_next(&self, value: T) -> CompleteNextFuture<'_, T> {
+		CompleteNextFuture {
+			multi_completable: self,
+			value: Some(value),
+			waker_key: None
+		}
+	}
+
+	/// Ends the stream. A value that wasn't received yet is still received before the stream ends
+	pub fn finish(self) {
+		// Drop finishes the stream
+	}
+}
+
+impl<T> Future for CompleteNextFuture<'_, T> {
+	type Output = Result<(), StreamClosedError>;
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+		let this = self.get_mut();
+		let mut shared_state = this.multi_completable.shared_state.lock().unwrap();
+
+		if shared_state.closed {
+			shared_state.completable_wakers.remove(this.waker_key.take());
+			this.value = None;
+
+			return Poll::Ready(Err(StreamClosedError));
+		}
+
+		if shared_state.next.is_some() {
+			shared_state.completable_wakers.register(&mut this.waker_key, cx.waker());
+			return Poll::Pending;
+		}
+
+		shared_state.completable_wakers.remove(this.waker_key.take());
+		shared_state.next = Some(this.value.take().expect("CompleteNextFuture polled after it returned"));
+		let wakers = shared_state.token_waker.take_all();
+		drop(shared_state);
+		wake_each(wakers);
+
+		Poll::Ready(Ok(()))
+	}
+}
+
+// The value is only moved, never pinned
+impl<T> Unpin for CompleteNextFuture<'_, T> {}
+
+impl<T> Drop for CompleteNextFuture<'_, T> {
+	fn drop(&mut self) {
+		if self.waker_key.is_some() {
+			let mut shared_state = self.multi_completable.shared_state.lock().unwrap();
+			shared_state.completable_wakers.remove(self.waker_key);
+		}
+	}
+}
+
+impl<T> Drop for MultiCompletable<T> {
+	fn drop(&mut self) {
+		let mut shared_state = self.shared_state.lock().unwrap();
+
+		shared_state.finished = true;
+		let wakers = shared_state.token_waker.take_all();
+		drop(shared_state);
+		wake_each(wakers);
+	}
+}
+
+impl<T> Stream for MultiCompletionToken<T> {
+	type Item = T;
+
+	fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+		let this = self.get_mut();
+		let mut shared_state = this.shared_state.lock().unwrap();
+
+		if let Some(next) = shared_state.next.take() {
+			// The buffer has room again
+			let wakers = shared_state.completable_wakers.take_all();
+			drop(shared_state);
+			wake_each(wakers);
+			Poll::Ready(Some(next))
+		} else if shared_state.finished {
+			Poll::Ready(None)
+		} else {
+			shared_state.token_waker.register(&mut this.waker_key, cx.waker());
+			Poll::Pending
+		}
+	}
+}
+
+impl<T> Drop for MultiCompletionToken<T> {
+	fn drop(&mut self) {
+		let mut shared_state = self.shared_state.lock().unwrap();
+
+		shared_state.token_waker.remove(self.waker_key);
+		shared_state.closed = true;
+		shared_state.next = None;
+		let wakers = shared_state.completable_wakers.take_all();
+		drop(shared_state);
+		wake_each(wakers);
+	}
+}
+
+impl fmt::Display for StreamClosedError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "The multi completion token was dropped")
+	}
+}
+
+impl Error for StreamClosedError {}
+
+#[cfg(test)]
+mod tests {
+	use cooked_waker::IntoWaker;
+	use futures::stream::StreamExt;
+
+	use super::*;
+	use crate::tests::*;
+
+	runtime_test! {
+		async fn test_sequence_then_finish() {
+			let (multi_completion_token, multi_completable) = MultiCompletionToken::new();
+
+			let producer = async_std::task::spawn(async move {
+				for value in 1..=100 {
+					multi_completable.complete_next(value).await.unwrap();
+				}
+
+				multi_completable.finish();
+			});
+
+			let received: Vec<_> = multi_completion_token.collect().await;
+			assert_eq!(received, (1..=100).collect::<Vec<_>>(), "Every value should be received in order");
+
+			producer.await;
+		}
+	}
+
+	#[test]
+	fn test_backpressure() {
+		let (mut multi_completion_token, multi_completable) = MultiCompletionToken::new();
+
+		let producer_waker = TestWaker::new();
+		let waker = producer_waker.clone().into_waker();
+		let mut producer_cx = Context::from_waker(&waker);
+
+		let consumer_waker = TestWaker::new();
+		let waker = consumer_waker.clone().into_waker();
+		let mut consumer_cx = Context::from_waker(&waker);
+
+		assert_eq!(Pin::new(&mut multi_completion_token).poll_next(&mut consumer_cx), Poll::Pending, "Nothing was sent yet");
+
+		let mut first = multi_completable.complete_next(1);
+		assert_eq!(Pin::new(&mut first).poll(&mut producer_cx), Poll::Ready(Ok(())), "The buffer is empty, so the first value shouldn't wait");
+		assert!(consumer_waker.woke(), "Sending should wake the consumer");
+
+		let mut second = multi_completable.complete_next(2);
+		assert_eq!(Pin::new(&mut second).poll(&mut producer_cx), Poll::Pending, "The buffer is full, so the second value should wait");
+		assert!(!producer_waker.woke(), "Shouldn't wake before the first value is received");
+
+		assert_eq!(Pin::new(&mut multi_completion_token).poll_next(&mut consumer_cx), Poll::Ready(Some(1)), "Wrong value");
+		assert!(producer_waker.woke(), "Receiving should wake the waiting producer");
+
+		assert_eq!(Pin::new(&mut second).poll(&mut producer_cx), Poll::Ready(Ok(())), "The buffer has room again");
+		assert_eq!(Pin::new(&mut multi_completion_token).poll_next(&mut consumer_cx), Poll::Ready(Some(2)), "Wrong value");
+	}
+
+	#[test]
+	fn test_finish_delivers_buffered_value() {
+		let (mut multi_completion_token, multi_completable) = MultiCompletionToken::new();
+
+		futures::executor::block_on(multi_completable.complete_next("last")).unwrap();
+		multi_completable.finish();
+
+		assert_eq!(futures::executor::block_on(multi_completion_token.next()), Some("last"), "The buffered value should be received");
+		assert_eq!(futures::executor::block_on(multi_completion_token.next()), None, "The stream should end");
+		assert_eq!(futures::executor::block_on(multi_completion_token.next()), None, "The stream should stay ended");
+	}
+
+	#[test]
+	fn test_finish_wakes_consumer() {
+		let (mut multi_completion_token, multi_completable) = MultiCompletionToken::<()>::new();
+
+		let test_waker = TestWaker::new();
+		let waker = test_waker.clone().into_waker();
+		let mut cx = Context::from_waker(&waker);
+
+		assert_eq!(Pin::new(&mut multi_completion_token).poll_next(&mut cx), Poll::Pending, "Nothing was sent yet");
+
+		multi_completable.finish();
+		assert!(test_waker.woke(), "Finishing should wake the consumer");
+		assert_eq!(Pin::new(&mut multi_completion_token).poll_next(&mut cx), Poll::Ready(None), "The stream should end");
+	}
+
+	#[test]
+	fn test_dropped_completable_ends_stream() {
+		let (mut multi_completion_token, multi_completable) = MultiCompletionToken::new();
+
+		futures::executor::block_on(multi_completable.complete_next(1)).unwrap();
+		drop(multi_completable);
+
+		assert_eq!(futures::executor::block_on(multi_completion_token.next()), Some(1), "The buffered value should be received");
+		assert_eq!(futures::executor::block_on(multi_completion_token.next()), None, "Dropping the completable should end the stream");
+	}
+
+	#[test]
+	fn test_dropped_token_closes() {
+		let (multi_completion_token, multi_completable) = MultiCompletionToken::new();
+
+		let test_waker = TestWaker::new();
+		let waker = test_waker.clone().into_waker();
+		let mut cx = Context::from_waker(&waker);
+
+		futures::executor::block_on(multi_completable.complete_next(1)).unwrap();
+
+		let mut waiting = multi_completable.complete_next(2);
+		assert_eq!(Pin::new(&mut waiting).poll(&mut cx), Poll::Pending, "The buffer is full");
+
+		drop(multi_completion_token);
+		assert!(test_waker.woke(), "Dropping the token should wake the waiting producer");
+		assert_eq!(Pin::new(&mut waiting).poll(&mut cx), Poll::Ready(Err(StreamClosedError)), "The waiting value can't be sent");
+		assert_eq!(futures::executor::block_on(multi_completable.complete_next(3)), Err(StreamClosedError), "Later values can't be sent");
+	}
+
+	#[test]
+	fn test_dropped_complete_next_removes_waker() {
+		let (_multi_completion_token, multi_completable) = MultiCompletionToken::new();
+
+		let test_waker = TestWaker::new();
+		let waker = test_waker.into_waker();
+		let mut cx = Context::from_waker(&waker);
+
+		futures::executor::block_on(multi_completable.complete_next(1)).unwrap();
+
+		let mut waiting = multi_completable.complete_next(2);
+		assert_eq!(Pin::new(&mut waiting).poll(&mut cx), Poll::Pending, "The buffer is full");
+		drop(waiting);
+
+		assert!(multi_completable.shared_state.lock().unwrap().completable_wakers.is_empty(), "Dropping the future should remove its waker");
+	}
+}
@@ -0,0 +1,196 @@
+// https://github.com/GWBasic/sync-tokens
+// (c) Andrew Rondeau
+// Apache 2.0 license
+// See https://github.com/GWBasic/sync-tokens/blob/main/LICENSE
+
+//! Contains a cancelation token that's backed by tokio's `Notify`. Requires the `tokio` feature. See
+//! [`NotifyCancelationToken`](struct.NotifyCancelationToken.html)
+use std::future::Future;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use futures::future::{Either, select};
+use tokio::sync::Notify;
+
+/// A [`CancelationToken`](../cancelation_token/struct.CancelationToken.html) that wakes waiting tasks with
+/// [`tokio::sync::Notify`](https://docs.rs/tokio/latest/tokio/sync/struct.Notify.html), instead of keeping their
+/// wakers behind a `Mutex`. Checking whether it's canceled only reads an atomic.
+///
+/// It only supports canceling and waiting; use a [`CancelationToken`](../cancelation_token/struct.CancelationToken.html)
+/// for children, reasons, hooks, and resetting. It isn't faster than a
+/// [`CancelationToken`](../cancelation_token/struct.CancelationToken.html) in `benches/notify_cancelation.rs`, except
+/// when canceling with a single waiting task, so prefer it only to keep cancelation on tokio's primitives
+///
+/// ```
+/// use sync_tokens::notify_cancelation_token::NotifyCancelationToken;
+///
+/// # tokio::runtime::Runtime::new().unwrap().block_on(async {
+/// let (notify_cancelation_token, notify_cancelable) = NotifyCancelationToken::new();
+///
+/// let waiting = tokio::spawn(notify_cancelable.allow_cancel(futures::future::pending(), "canceled"));
+/// notify_cancelation_token.cancel();
+///
+/// assert_eq!(waiting.await.unwrap(), "canceled");
+/// # });
+/// ```
+#[derive(Debug, Clone)]
+pub struct NotifyCancelationToken {
+	shared_state: Arc<NotifyState>
+}
+
+/// Allows an operation to be canceled by the corresponding [`NotifyCancelationToken`](struct.NotifyCancelationToken.html)
+#[derive(Debug, Clone)]
+pub struct NotifyCancelable {
+	shared_state: Arc<NotifyState>
+}
+
+#[derive(Debug)]
+struct NotifyState {
+	canceled: AtomicBool,
+	notify: Notify
+}
+
+impl NotifyCancelationToken {
+	/// Creates a new [`NotifyCancelationToken`](struct.NotifyCancelationToken.html) and
+	/// [`NotifyCancelable`](struct.NotifyCancelable.html)
+	pub fn new() -> (NotifyCancelationToken, NotifyCancelable) {
+		let shared_state = Arc::new(NotifyState {
+			canceled: AtomicBool::new(false),
+			notify: Notify::new()
+		});
+
+		(NotifyCancelationToken { shared_state: shared_state.clone() }, NotifyCancelable { shared_state })
+	}
+
+	/// Cancels the operation, and wakes every task that waits for cancelation. This can be called multiple times safely
+	pub fn cancel(&self) {
+		// Set before notifying, so that a future that misses the notification sees the flag when it checks again
+		if !self.shared_state.canceled.swap(true, Ordering::AcqRel) {
+			self.shared_state.notify.notify_waiters();
+		}
+	}
+
+	/// Returns true once the token is canceled
+	pub fn is_canceled(&self) -> bool {
+		self.shared_state.canceled.load(Ordering::Acquire)
+	}
+}
+
+impl NotifyCancelable {
+	/// Returns a future that returns once the [`NotifyCancelationToken`](struct.NotifyCancelationToken.html) is canceled
+	pub fn future(&self) -> impl Future<Output = ()> + Send + 'static {
+		let shared_state = self.shared_state.clone();
+
+		async move {
+			if shared_state.canceled.load(Ordering::Acquire) {
+				return;
+			}
+
+			// notify_waiters() wakes every Notified that was created before it's called, even if it wasn't polled
+			// yet, so checking again after creating it can't miss cancel()
+			let notified = shared_state.notify.notified();
+
+			if !shared_state.canceled.load(Ordering::Acquire) {
+				notified.await;
+			}
+		}
+	}
+
+	/// Allows canceling the future. Returns canceled_result if the
+	/// [`NotifyCancelationToken`](struct.NotifyCancelationToken.html) is canceled first. The same as
+	/// [`Cancelable::allow_cancel()`](../cancelation_token/struct.Cancelable.html#method.allow_cancel)
+	pub fn allow_cancel<TFuture, T>(&self, future: TFuture, canceled_result: T) -> impl Future<Output = T> where
+	TFuture: Future<Output = T> + Unpin {
+		let shared_state = self.shared_state.clone();
+		let canceled = Box::pin(self.future());
+
+		async move {
+			if shared_state.canceled.load(Ordering::Acquire) {
+				return canceled_result;
+			}
+
+			match select(future, canceled).await {
+				Either::Left((result, _)) => result,
+				Either::Right(_) => canceled_result
+			}
+		}
+	}
+
+	/// Returns true once the [`NotifyCancelationToken`](struct.NotifyCancelationToken.html) is canceled
+	pub fn is_canceled(&self) -> bool {
+		self.shared_state.canceled.load(Ordering::Acquire)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use futures::FutureExt;
+
+	use super::*;
+
+	#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+	async fn test_cancel_wakes_every_waiter() {
+		let (notify_cancelation_token, notify_cancelable) = NotifyCancelationToken::new();
+		let waiting: Vec<_> = (0..100).map(|_| tokio::spawn(notify_cancelable.future())).collect();
+
+		tokio::time::sleep(Duration::from_millis(10)).await;
+		assert!(!notify_cancelable.is_canceled(), "Shouldn't be canceled yet");
+
+		notify_cancelation_token.cancel();
+		assert!(notify_cancelable.is_canceled(), "Should be canceled");
+
+		for waiting in waiting {
+			waiting.await.unwrap();
+		}
+	}
+
+	#[tokio::test]
+	async fn test_future_created_before_cancel() {
+		let (notify_cancelation_token, notify_cancelable) = NotifyCancelationToken::new();
+		let mut future = Box::pin(notify_cancelable.future());
+
+		// Polled once, so that it's waiting on the Notify
+		assert!((&mut future).now_or_never().is_none(), "Shouldn't be canceled yet");
+
+		notify_cancelation_token.cancel();
+		notify_cancelation_token.cancel();
+		assert!(future.now_or_never().is_some(), "Canceling should wake the waiting future");
+		assert!(notify_cancelable.future().now_or_never().is_some(), "A new future should return immediately");
+	}
+
+	#[tokio::test]
+	async fn test_allow_cancel() {
+		let (notify_cancelation_token, notify_cancelable) = NotifyCancelationToken::new();
+
+		assert_eq!(notify_cancelable.allow_cancel(futures::future::ready("finished"), "canceled").await, "finished", "Should finish");
+
+		let canceled = tokio::spawn(notify_cancelable.allow_cancel(futures::future::pending(), "canceled"));
+
+		tokio::time::sleep(Duration::from_millis(10)).await;
+		notify_cancelation_token.cancel();
+
+		assert_eq!(canceled.await.unwrap(), "canceled", "Should be canceled");
+		assert_eq!(notify_cancelable.allow_cancel(futures::future::ready("finished"), "canceled").await, "canceled", "A canceled token shouldn't run the future");
+	}
+
+	#[test]
+	fn test_cancel_races_with_waiting() {
+		for _ in 0..100 {
+			let (notify_cancelation_token, notify_cancelable) = NotifyCancelationToken::new();
+			let waiting: Vec<_> = (0..4)
+				.map(|_| {
+					let future = notify_cancelable.future();
+					std::thread::spawn(move || futures::executor::block_on(future))
+				})
+				.collect();
+
+			notify_cancelation_token.cancel();
+
+			for waiting in waiting {
+				waiting.join().unwrap();
+			}
+		}
+	}
+}
@@ -0,0 +1,358 @@
+// https://github.com/GWBasic/sync-tokens
+// (c) Andrew Rondeau
+// Apache 2.0 license
+// See https://github.com/GWBasic/sync-tokens/blob/main/LICENSE
+
+//! Waits on a mix of tokens, such as a completion, a cancelation and a deadline, at once. See
+//! [`Observable`](trait.Observable.html), [`wait_any()`](fn.wait_any.html) and [`wait_all()`](fn.wait_all.html)
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use crate::cancelation_token::{Cancelable, CancelationTokenFuture};
+use crate::completion_token::CompletionToken;
+use crate::heartbeat_token::HeartbeatToken;
+use crate::timer::{Instant, Sleep, Timer};
+
+/// An event that can be waited on, and checked, without consuming it: a cancelation, a completion without a result,
+/// a deadline, or a starved heartbeat. Different kinds of events can be waited on together, as
+/// `Box<dyn Observable>`, with [`wait_any()`](fn.wait_any.html) and [`wait_all()`](fn.wait_all.html), without boxing
+/// them as `dyn Future` and losing [`is_signaled()`](trait.Observable.html#tymethod.is_signaled)
+pub trait Observable: Send {
+	/// Returns `Poll::Ready(())` once the event happened. Until then, wakes the task in cx when it happens
+	fn poll_signaled(&mut self, cx: &mut Context<'_>) -> Poll<()>;
+
+	/// Returns true once the event happened
+	fn is_signaled(&self) -> bool;
+}
+
+/// An [`Observable`](trait.Observable.html) deadline that's signaled once the [`Timer`](../timer/struct.Timer.html)
+/// reaches it
+#[derive(Debug)]
+pub struct DeadlineToken {
+	deadline: Instant,
+	timer: Timer,
+	sleep: Option<Sleep>
+}
+
+/// Future returned by [`wait_any()`](fn.wait_any.html)
+pub struct WaitAny {
+	observables: Vec<Box<dyn Observable>>
+}
+
+/// Future returned by [`wait_all()`](fn.wait_all.html)
+pub struct WaitAll {
+	observables: Vec<Box<dyn Observable>>,
+	signaled: Vec<bool>
+}
+
+/// Waits until any of observables is signaled, and returns its index. If more than one is signaled, returns the
+/// lowest index
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use sync_tokens::cancelation_token::CancelationToken;
+/// use sync_tokens::completion_token::CompletionToken;
+/// use sync_tokens::observable::{DeadlineToken, Observable, wait_any};
+///
+/// # async_std::task::block_on(async {
+/// let (ready, _completable) = CompletionToken::<()>::new();
+/// let (_cancelation_token, cancelable) = CancelationToken::new();
+///
+/// let observables: Vec<Box<dyn Observable>> = vec![
+///     Box::new(ready),
+///     Box::new(cancelable),
+///     Box::new(DeadlineToken::after(Duration::from_millis(10)))
+/// ];
+///
+/// assert_eq!(wait_any(observables).await, 2);
+/// # });
+/// ```
+///
+/// # Panics
+///
+/// Panics if observables is empty, because the future would never return
+pub fn wait_any(observables: Vec<Box<dyn Observable>>) -> WaitAny {
+	assert!(!observables.is_empty(), "wait_any needs at least one observable");
+
+	WaitAny { observables }
+}
+
+/// Waits until every one of observables is signaled. Returns immediately if observables is empty
+pub fn wait_all(observables: Vec<Box<dyn Observable>>) -> WaitAll {
+	let signaled = vec![false; observables.len()];
+
+	WaitAll {
+		observables,
+		signaled
+	}
+}
+
+impl DeadlineToken {
+	/// Creates a [`DeadlineToken`](struct.DeadlineToken.html) that's signaled once timer reaches deadline
+	pub fn with_timer(deadline: Instant, timer: Timer) -> DeadlineToken {
+		DeadlineToken {
+			deadline,
+			timer,
+			sleep: None
+		}
+	}
+
+	/// Creates a [`DeadlineToken`](struct.DeadlineToken.html) that's signaled once duration passes on the system clock
+	pub fn after(duration: Duration) -> DeadlineToken {
+		let timer = Timer::default();
+		DeadlineToken::with_timer(timer.now() + duration, timer)
+	}
+
+	/// The deadline
+	pub fn deadline(&self) -> Instant {
+		self.deadline
+	}
+}
+
+impl WaitAll {
+	/// Returns the indexes of the observables that weren't signaled the last time this was polled
+	pub fn remaining(&self) -> Vec<usize> {
+		self.signaled.iter()
+			.enumerate()
+			.filter(|(_, signaled)| !**signaled)
+			.map(|(index, _)| index)
+			.collect()
+	}
+}
+
+impl Observable for CancelationTokenFuture {
+	fn poll_signaled(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+		Pin::new(self).poll(cx)
+	}
+
+	fn is_signaled(&self) -> bool {
+		CancelationTokenFuture::is_canceled(self)
+	}
+}
+
+impl Observable for Cancelable {
+	fn poll_signaled(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+		self.poll_canceled(cx)
+	}
+
+	fn is_signaled(&self) -> bool {
+		self.is_canceled()
+	}
+}
+
+impl Observable for CompletionToken<()> {
+	fn poll_signaled(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+		// Once complete, the result was already taken, so the token isn't polled again
+		if self.is_complete() {
+			return Poll::Ready(());
+		}
+
+		Pin::new(self).poll(cx)
+	}
+
+	fn is_signaled(&self) -> bool {
+		self.is_complete()
+	}
+}
+
+impl Observable for HeartbeatToken {
+	fn poll_signaled(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+		Pin::new(self).poll(cx).map(|_| ())
+	}
+
+	fn is_signaled(&self) -> bool {
+		self.is_starved()
+	}
+}
+
+impl Observable for DeadlineToken {
+	fn poll_signaled(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+		if self.is_signaled() {
+			self.sleep = None;
+			return Poll::Ready(());
+		}
+
+		let deadline = self.deadline;
+		let timer = &self.timer;
+		let sleep = self.sleep.get_or_insert_with(|| timer.sleep_until(deadline));
+
+		match Pin::new(sleep).poll(cx) {
+			Poll::Ready(()) => {
+				self.sleep = None;
+				Poll::Ready(())
+			},
+			Poll::Pending => Poll::Pending
+		}
+	}
+
+	fn is_signaled(&self) -> bool {
+		self.timer.now() >= self.deadline
+	}
+}
+
+impl Future for WaitAny {
+	type Output = usize;
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+		// Every observable that isn't signaled keeps the task's waker, so whichever is signaled first wakes it
+		for (index, observable) in self.get_mut().observables.iter_mut().enumerate() {
+			if observable.poll_signaled(cx).is_ready() {
+				return Poll::Ready(index);
+			}
+		}
+
+		Poll::Pending
+	}
+}
+
+impl Future for WaitAll {
+	type Output = ();
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+		let this = self.get_mut();
+		let mut all_signaled = true;
+
+		for (observable, signaled) in this.observables.iter_mut().zip(this.signaled.iter_mut()) {
+			// Observables that were already signaled aren't polled again
+			if !*signaled {
+				*signaled = observable.poll_signaled(cx).is_ready();
+				all_signaled &= *signaled;
+			}
+		}
+
+		if all_signaled {
+			Poll::Ready(())
+		} else {
+			Poll::Pending
+		}
+	}
+}
+
+impl fmt::Debug for WaitAny {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("WaitAny")
+			.field("observables", &self.observables.len())
+			.finish()
+	}
+}
+
+impl fmt::Debug for WaitAll {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("WaitAll")
+			.field("observables", &self.observables.len())
+			.field("remaining", &self.remaining())
+			.finish()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use cooked_waker::IntoWaker;
+	use futures::executor::LocalPool;
+	use futures::task::LocalSpawnExt;
+
+	use super::*;
+	use crate::cancelation_token::CancelationToken;
+	use crate::tests::*;
+	use crate::timer::ManualClock;
+
+	#[test]
+	fn test_wait_any_reports_index() {
+		let clock = ManualClock::new();
+		let timer = Timer::new(clock.clone());
+
+		let (completion_token, _completable) = CompletionToken::<()>::new();
+		let (cancelation_token, cancelable) = CancelationToken::new();
+		let deadline_token = DeadlineToken::with_timer(timer.now() + Duration::from_secs(10), timer);
+
+		let observables: Vec<Box<dyn Observable>> = vec![Box::new(completion_token), Box::new(cancelable.future()), Box::new(deadline_token)];
+
+		let mut pool = LocalPool::new();
+		let index = pool.spawner().spawn_local_with_handle(wait_any(observables)).unwrap();
+		pool.run_until_stalled();
+
+		cancelation_token.cancel();
+		assert_eq!(pool.run_until(index), 1, "The cancelation should win");
+
+		// A signaled observable with a lower index wins
+		let observables: Vec<Box<dyn Observable>> = vec![Box::new(cancelable.clone()), Box::new(cancelable)];
+		assert_eq!(pool.run_until(wait_any(observables)), 0, "The lowest index should win");
+	}
+
+	#[test]
+	fn test_wait_any_deadline() {
+		let clock = ManualClock::new();
+		let timer = Timer::new(clock.clone());
+
+		let (_cancelation_token, cancelable) = CancelationToken::new();
+		let deadline_token = DeadlineToken::with_timer(timer.now() + Duration::from_secs(10), timer);
+
+		let observables: Vec<Box<dyn Observable>> = vec![Box::new(cancelable), Box::new(deadline_token)];
+
+		let mut pool = LocalPool::new();
+		let index = pool.spawner().spawn_local_with_handle(wait_any(observables)).unwrap();
+		pool.run_until_stalled();
+
+		clock.advance(Duration::from_secs(10));
+		assert_eq!(pool.run_until(index), 1, "The deadline should win");
+	}
+
+	#[test]
+	fn test_wait_all() {
+		let (completion_token, completable) = CompletionToken::<()>::new();
+		let (cancelation_token, cancelable) = CancelationToken::new();
+
+		let test_waker = TestWaker::new();
+		let waker = test_waker.clone().into_waker();
+		let mut cx = Context::from_waker(&waker);
+
+		let observables: Vec<Box<dyn Observable>> = vec![Box::new(completion_token), Box::new(cancelable.clone())];
+		let mut wait_all = wait_all(observables);
+
+		assert_eq!(Pin::new(&mut wait_all).poll(&mut cx), Poll::Pending, "Nothing is signaled yet");
+		assert_eq!(wait_all.remaining(), vec![0, 1], "Both should remain");
+
+		completable.complete(());
+		assert!(test_waker.woke(), "Completing should wake the task");
+		assert_eq!(Pin::new(&mut wait_all).poll(&mut cx), Poll::Pending, "The cancelation remains");
+		assert_eq!(wait_all.remaining(), vec![1], "Only the cancelation should remain");
+
+		cancelation_token.cancel();
+		assert_eq!(Pin::new(&mut wait_all).poll(&mut cx), Poll::Ready(()), "Everything is signaled");
+		assert!(cancelable.is_signaled(), "The cancelable should be signaled");
+	}
+
+	#[test]
+	fn test_wait_all_empty() {
+		assert_eq!(futures::executor::block_on(wait_all(Vec::new())), (), "Nothing to wait for");
+	}
+
+	#[test]
+	#[should_panic(expected = "wait_any needs at least one observable")]
+	fn test_wait_any_empty_panics() {
+		wait_any(Vec::new());
+	}
+
+	#[test]
+	fn test_is_signaled() {
+		let clock = ManualClock::new();
+		let timer = Timer::new(clock.clone());
+
+		let (completion_token, completable) = CompletionToken::<()>::new();
+		let (cancelation_token, cancelable) = CancelationToken::new();
+		let cancelation_token_future = cancelable.future();
+		let deadline_token = DeadlineToken::with_timer(timer.now() + Duration::from_secs(1), timer);
+
+		assert!(!completion_token.is_signaled() && !cancelation_token_future.is_signaled() && !deadline_token.is_signaled(), "Nothing is signaled yet");
+
+		completable.complete(());
+		cancelation_token.cancel();
+		clock.advance(Duration::from_secs(1));
+
+		assert!(completion_token.is_signaled() && cancelation_token_future.is_signaled() && deadline_token.is_signaled(), "Everything should be signaled");
+	}
+}
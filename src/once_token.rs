@@ -0,0 +1,284 @@
+// https://github.com/GWBasic/sync-tokens
+// (c) Andrew Rondeau
+// Apache 2.0 license
+// See https://github.com/GWBasic/sync-tokens/blob/main/LICENSE
+
+//! Contains a value that is initialized asynchronously, exactly once. See [`OnceToken`](struct.OnceToken.html)
+use std::convert::Infallible;
+use std::future::Future;
+use std::sync::{Mutex, OnceLock};
+
+use crate::completion_token::{Completable, CompletionToken};
+
+/// Holds a value that is initialized asynchronously the first time it's needed.
+///
+/// The first caller of [`get_or_try_init()`](struct.OnceToken.html#method.get_or_try_init) runs its initializer.
+/// Everyone else who calls while the initializer runs waits on a [`CompletionToken`](../completion_token/struct.CompletionToken.html),
+/// and then receives a reference to the same value.
+///
+/// If the initializer returns an error, or is canceled (its future is dropped), the token isn't poisoned: a
+/// waiting caller runs its own initializer instead
+///
+/// ```
+/// use sync_tokens::once_token::OnceToken;
+///
+/// # async_std::task::block_on(async {
+/// let config = OnceToken::new();
+///
+/// let value = config.get_or_init(|| async { "loaded".to_string() }).await;
+/// assert_eq!(value, "loaded");
+///
+/// // The initializer only runs once
+/// let value = config.get_or_init(|| async { "loaded again".to_string() }).await;
+/// assert_eq!(value, "loaded");
+/// # });
+/// ```
+#[derive(Debug)]
+pub struct OnceToken<T> {
+	value: OnceLock<T>,
+	shared_state: Mutex<OnceTokenState>
+}
+
+#[derive(Debug)]
+struct OnceTokenState {
+	initializing: bool,
+	waiters: Vec<Completable<()>>
+}
+
+// Clears the initializing flag when the initializer returns or is canceled. Waiters are completed if the value
+// was set, and abandoned otherwise; either way they check the value again
+struct InitializingGuard<'a, T> {
+	once_token: &'a OnceToken<T>
+}
+
+impl<T> OnceToken<T> {
+	/// Creates a new, uninitialized, [`OnceToken`](struct.OnceToken.html)
+	pub const fn new() -> OnceToken<T> {
+		OnceToken {
+			value: OnceLock::new(),
+			shared_state: Mutex::new(OnceTokenState {
+				initializing: false,
+				waiters: Vec::new()
+			})
+		}
+	}
+
+	/// Returns the value if it's initialized
+	pub fn get(&self) -> Option<&T> {
+		self.value.get()
+	}
+
+	/// Returns the value, running init to create it if no one has yet
+	pub async fn get_or_init<TFuture, F>(&self, init: F) -> &T where
+	F: FnOnce() -> TFuture,
+	TFuture: Future<Output = T> {
+		let result = self.get_or_try_init(|| async {
+			Ok::<T, Infallible>(init().await)
+		}).await;
+
+		match result {
+			Ok(value) => value,
+			Err(never) => match never {}
+		}
+	}
+
+	/// Returns the value, running init to create it if no one has yet. If init returns an error, the error is
+	/// returned and the value stays uninitialized, so that the next caller tries again
+	pub async fn get_or_try_init<TFuture, F, E>(&self, init: F) -> Result<&T, E> where
+	F: FnOnce() -> TFuture,
+	TFuture: Future<Output = Result<T, E>> {
+		loop {
+			if let Some(value) = self.value.get() {
+				return Ok(value);
+			}
+
+			let completion_token = {
+				let mut shared_state = self.shared_state.lock().unwrap();
+
+				// The value might have been set while waiting for the lock
+				if let Some(value) = self.value.get() {
+					return Ok(value);
+				}
+
+				if shared_state.initializing {
+					let (completion_token, completable) = CompletionToken::new();
+					shared_state.waiters.push(completable);
+					Some(completion_token)
+				} else {
+					shared_state.initializing = true;
+					None
+				}
+			};
+
+			match completion_token {
+				// Whether the initializer succeeded or was abandoned, check the value again
+				Some(completion_token) => {
+					let _ = completion_token.try_wait().await;
+				},
+				None => {
+					let _initializing_guard = InitializingGuard { once_token: self };
+
+					let value = init().await?;
+					if self.value.set(value).is_err() {
+						unreachable!("OnceToken initialized while another initializer was running");
+					}
+
+					return Ok(self.value.get().unwrap());
+				}
+			}
+		}
+	}
+
+	/// Consumes the token, returning the value if it's initialized
+	pub fn into_inner(self) -> Option<T> {
+		self.value.into_inner()
+	}
+}
+
+impl<T> Default for OnceToken<T> {
+	fn default() -> Self {
+		OnceToken::new()
+	}
+}
+
+impl<'a, T> Drop for InitializingGuard<'a, T> {
+	fn drop(&mut self) {
+		let waiters = {
+			let mut shared_state = self.once_token.shared_state.lock().unwrap();
+			shared_state.initializing = false;
+			std::mem::take(&mut shared_state.waiters)
+		};
+
+		// Dropping the waiters without completing them abandons them
+		if self.once_token.value.get().is_some() {
+			for waiter in waiters {
+				waiter.complete(());
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::Arc;
+	use std::sync::atomic::{AtomicUsize, Ordering};
+	use std::time::Duration;
+
+	use async_std::task;
+
+	use super::*;
+	use crate::cancelation_token::CancelationToken;
+
+	runtime_test! {
+		async fn test_get_or_init() {
+			let once_token = OnceToken::new();
+			assert!(once_token.get().is_none(), "Value shouldn't be initialized");
+
+			let value = once_token.get_or_init(|| async { 42 }).await;
+			assert_eq!(*value, 42, "Wrong value");
+			assert_eq!(once_token.get(), Some(&42), "Value should be initialized");
+
+			let value = once_token.get_or_init(|| async { panic!("Initializer shouldn't run twice") }).await;
+			assert_eq!(*value, 42, "Wrong value");
+
+			assert_eq!(once_token.into_inner(), Some(42), "Wrong value");
+		}
+	}
+
+	runtime_test! {
+		async fn test_race() {
+			let once_token = Arc::new(OnceToken::new());
+			let init_count = Arc::new(AtomicUsize::new(0));
+
+			let mut handles = Vec::new();
+			for i in 0..10 {
+				let once_token = once_token.clone();
+				let init_count = init_count.clone();
+
+				handles.push(task::spawn(async move {
+					*once_token.get_or_init(|| async move {
+						init_count.fetch_add(1, Ordering::SeqCst);
+						task::sleep(Duration::from_millis(20)).await;
+						i
+					}).await
+				}));
+			}
+
+			let mut results = Vec::new();
+			for handle in handles {
+				results.push(handle.await);
+			}
+
+			assert_eq!(init_count.load(Ordering::SeqCst), 1, "Initializer should run once");
+			assert!(results.iter().all(|result| *result == results[0]), "Everyone should get the same value: {:?}", results);
+		}
+	}
+
+	runtime_test! {
+		async fn test_failing_first_attempt() {
+			let once_token = Arc::new(OnceToken::new());
+			let attempts = Arc::new(AtomicUsize::new(0));
+
+			let mut handles = Vec::new();
+			for _ in 0..5 {
+				let once_token = once_token.clone();
+				let attempts = attempts.clone();
+
+				handles.push(task::spawn(async move {
+					once_token.get_or_try_init(|| async move {
+						let attempt = attempts.fetch_add(1, Ordering::SeqCst);
+						task::sleep(Duration::from_millis(20)).await;
+
+						if attempt == 0 {
+							Err("first attempt fails")
+						} else {
+							Ok("initialized")
+						}
+					}).await.copied()
+				}));
+			}
+
+			let mut results = Vec::new();
+			for handle in handles {
+				results.push(handle.await);
+			}
+
+			assert_eq!(attempts.load(Ordering::SeqCst), 2, "Exactly one retry should happen");
+			assert_eq!(results.iter().filter(|result| result.is_err()).count(), 1, "Only the first attempt should fail: {:?}", results);
+			assert_eq!(results.iter().filter(|result| **result == Ok("initialized")).count(), 4, "Everyone else should get the value: {:?}", results);
+		}
+	}
+
+	runtime_test! {
+		async fn test_canceled_initializer() {
+			let once_token = Arc::new(OnceToken::new());
+			let (cancelation_token, cancelable) = CancelationToken::new();
+
+			let canceled = {
+				let once_token = once_token.clone();
+				task::spawn(async move {
+					let init = once_token.get_or_init(|| async {
+						futures::future::pending::<&str>().await
+					});
+
+					cancelable.allow_cancel(Box::pin(async { Some(*init.await) }), None).await
+				})
+			};
+
+			task::sleep(Duration::from_millis(10)).await;
+
+			let waiter = {
+				let once_token = once_token.clone();
+				task::spawn(async move {
+					*once_token.get_or_init(|| async { "retried" }).await
+				})
+			};
+
+			task::sleep(Duration::from_millis(10)).await;
+			cancelation_token.cancel();
+
+			assert_eq!(canceled.await, None, "Initializer should be canceled");
+			assert_eq!(waiter.await, "retried", "Waiter should retry after the initializer is canceled");
+		}
+	}
+}
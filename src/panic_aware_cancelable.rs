@@ -0,0 +1,222 @@
+// https://github.com/GWBasic/sync-tokens
+// (c) Andrew Rondeau
+// Apache 2.0 license
+// See https://github.com/GWBasic/sync-tokens/blob/main/LICENSE
+
+//! Contains a [`Cancelable`](../cancelation_token/struct.Cancelable.html) that reports when its task panics. See
+//! [`PanicAwareCancelable`](struct.PanicAwareCancelable.html)
+use std::any::Any;
+use std::fmt;
+use std::future::Future;
+use std::panic::{AssertUnwindSafe, catch_unwind};
+
+use futures::FutureExt;
+
+use crate::cancelation_token::Cancelable;
+
+/// Wraps a [`Cancelable`](../cancelation_token/struct.Cancelable.html), and calls on_task_panic if the task that
+/// holds it panics, so that whoever holds the [`CancelationToken`](../cancelation_token/struct.CancelationToken.html)
+/// can tell a clean shutdown from a panic.
+///
+/// Run the task's work with [`run()`](struct.PanicAwareCancelable.html#method.run) or
+/// [`run_async()`](struct.PanicAwareCancelable.html#method.run_async), which catch the panic and pass its payload to
+/// on_task_panic. If the task panics while holding the [`PanicAwareCancelable`](struct.PanicAwareCancelable.html)
+/// outside of them, on_task_panic is still called when it's dropped during unwinding, but the payload isn't
+/// available, so on_task_panic receives [`PanicPayloadUnavailable`](struct.PanicPayloadUnavailable.html) instead
+///
+/// ```
+/// use std::sync::mpsc;
+///
+/// use sync_tokens::cancelation_token::CancelationToken;
+/// use sync_tokens::panic_aware_cancelable::PanicAwareCancelable;
+///
+/// let (_cancelation_token, cancelable) = CancelationToken::new();
+/// let (sender, receiver) = mpsc::channel();
+///
+/// let panic_aware_cancelable = PanicAwareCancelable::new(cancelable, move |payload| {
+///     sender.send(*payload.downcast::<&str>().unwrap()).unwrap();
+/// });
+///
+/// std::thread::spawn(move || {
+///     panic_aware_cancelable.run(|_cancelable| panic!("worker failed"))
+/// }).join().unwrap();
+///
+/// assert_eq!(receiver.recv().unwrap(), "worker failed");
+/// ```
+pub struct PanicAwareCancelable {
+	cancelable: Cancelable,
+	on_task_panic: Option<PanicCallback>
+}
+
+type PanicCallback = Box<dyn FnOnce(Box<dyn Any + Send>) + Send>;
+
+/// Passed to on_task_panic when the task panicked outside of
+/// [`PanicAwareCancelable::run()`](struct.PanicAwareCancelable.html#method.run) or
+/// [`PanicAwareCancelable::run_async()`](struct.PanicAwareCancelable.html#method.run_async), where the panic's payload
+/// can't be caught
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PanicPayloadUnavailable;
+
+impl PanicAwareCancelable {
+	/// Wraps cancelable, so that on_task_panic is called with the panic's payload if the task panics
+	pub fn new<F>(cancelable: Cancelable, on_task_panic: F) -> PanicAwareCancelable where
+	F: FnOnce(Box<dyn Any + Send>) + Send + 'static {
+		PanicAwareCancelable {
+			cancelable,
+			on_task_panic: Some(Box::new(on_task_panic))
+		}
+	}
+
+	/// Runs work with the [`Cancelable`](../cancelation_token/struct.Cancelable.html). Returns work's result, or None if
+	/// it panicked, after passing the panic's payload to on_task_panic
+	pub fn run<F, R>(mut self, work: F) -> Option<R> where
+	F: FnOnce(&Cancelable) -> R {
+		let cancelable = &self.cancelable;
+
+		match catch_unwind(AssertUnwindSafe(|| work(cancelable))) {
+			Ok(result) => Some(result),
+			Err(payload) => {
+				self.notify_panic(payload);
+				None
+			}
+		}
+	}
+
+	/// Runs the future that work returns with the [`Cancelable`](../cancelation_token/struct.Cancelable.html). Returns
+	/// its result, or None if it panicked, after passing the panic's payload to on_task_panic
+	pub async fn run_async<F, TFuture, R>(mut self, work: F) -> Option<R> where
+	F: FnOnce(Cancelable) -> TFuture,
+	TFuture: Future<Output = R> {
+		match AssertUnwindSafe(work(self.cancelable.clone())).catch_unwind().await {
+			Ok(result) => Some(result),
+			Err(payload) => {
+				self.notify_panic(payload);
+				None
+			}
+		}
+	}
+
+	/// Returns the wrapped [`Cancelable`](../cancelation_token/struct.Cancelable.html)
+	pub fn cancelable(&self) -> &Cancelable {
+		&self.cancelable
+	}
+
+	/// Returns true if the [`CancelationToken`](../cancelation_token/struct.CancelationToken.html) is canceled
+	pub fn is_canceled(&self) -> bool {
+		self.cancelable.is_canceled()
+	}
+
+	fn notify_panic(&mut self, payload: Box<dyn Any + Send>) {
+		if let Some(on_task_panic) = self.on_task_panic.take() {
+			on_task_panic(payload);
+		}
+	}
+}
+
+impl Drop for PanicAwareCancelable {
+	fn drop(&mut self) {
+		if std::thread::panicking() {
+			self.notify_panic(Box::new(PanicPayloadUnavailable));
+		}
+	}
+}
+
+impl fmt::Debug for PanicAwareCancelable {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("PanicAwareCancelable")
+			.field("cancelable", &self.cancelable)
+			.finish()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::{Arc, Mutex};
+	use std::thread;
+
+	use super::*;
+	use crate::cancelation_token::CancelationToken;
+
+	// Formatted panics carry a String, and literal ones a &str
+	fn message(payload: &(dyn Any + Send)) -> Option<&str> {
+		payload.downcast_ref::<String>().map(String::as_str).or_else(|| payload.downcast_ref::<&str>().copied())
+	}
+
+	type Payloads = Arc<Mutex<Vec<Box<dyn Any + Send>>>>;
+
+	// Records what on_task_panic received
+	fn recording_cancelable() -> (PanicAwareCancelable, Payloads) {
+		let (_cancelation_token, cancelable) = CancelationToken::new();
+		let payloads = Arc::new(Mutex::new(Vec::new()));
+
+		let panic_aware_cancelable = {
+			let payloads = payloads.clone();
+			PanicAwareCancelable::new(cancelable, move |payload| payloads.lock().unwrap().push(payload))
+		};
+
+		(panic_aware_cancelable, payloads)
+	}
+
+	#[test]
+	fn test_run_panics() {
+		let (panic_aware_cancelable, payloads) = recording_cancelable();
+
+		let result = thread::spawn(move || {
+			panic_aware_cancelable.run(|_cancelable| -> u32 { panic!("worker failed: {}", 42) })
+		}).join().unwrap();
+
+		assert_eq!(result, None, "A panic should return None");
+
+		let payloads = payloads.lock().unwrap();
+		assert_eq!(payloads.len(), 1, "on_task_panic should be called once");
+		assert_eq!(message(payloads[0].as_ref()), Some("worker failed: 42"), "Wrong payload");
+	}
+
+	#[test]
+	fn test_run_clean() {
+		let (panic_aware_cancelable, payloads) = recording_cancelable();
+
+		let result = thread::spawn(move || panic_aware_cancelable.run(|cancelable| cancelable.is_canceled())).join().unwrap();
+
+		assert_eq!(result, Some(false), "Wrong result");
+		assert!(payloads.lock().unwrap().is_empty(), "on_task_panic shouldn't be called");
+	}
+
+	runtime_test! {
+		async fn test_run_async_panics() {
+			let (panic_aware_cancelable, payloads) = recording_cancelable();
+
+			let result = async_std::task::spawn(panic_aware_cancelable.run_async(|_cancelable| async {
+				async_std::task::yield_now().await;
+				panic!("worker failed")
+			})).await;
+
+			assert_eq!(result, None::<()>, "A panic should return None");
+			assert_eq!(message(payloads.lock().unwrap()[0].as_ref()), Some("worker failed"), "Wrong payload");
+		}
+	}
+
+	#[test]
+	fn test_dropped_while_panicking() {
+		let (panic_aware_cancelable, payloads) = recording_cancelable();
+
+		let joined = thread::spawn(move || {
+			let _panic_aware_cancelable = panic_aware_cancelable;
+			panic!("worker failed")
+		}).join();
+
+		assert!(joined.is_err(), "The thread should panic");
+
+		let payloads = payloads.lock().unwrap();
+		assert_eq!(payloads.len(), 1, "on_task_panic should be called once");
+		assert!(payloads[0].is::<PanicPayloadUnavailable>(), "The payload isn't available outside run");
+	}
+
+	#[test]
+	fn test_dropped_cleanly() {
+		let (panic_aware_cancelable, payloads) = recording_cancelable();
+
+		thread::spawn(move || drop(panic_aware_cancelable)).join().unwrap();
+		assert!(payloads.lock().unwrap().is_empty(), "on_task_panic shouldn't be called");
+	}
+}
@@ -0,0 +1,196 @@
+// https://github.com/GWBasic/sync-tokens
+// (c) Andrew Rondeau
+// Apache 2.0 license
+// See https://github.com/GWBasic/sync-tokens/blob/main/LICENSE
+
+//! Contains a token that cancels with a policy, so that the operation can decide how to stop. See
+//! [`PolicyCancelationToken`](struct.PolicyCancelationToken.html)
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use crate::cancelation_token::{Cancelable, CancelationToken};
+
+/// Cancels an operation with a policy of type P, such as an enum of shutdown modes. This generalizes
+/// [`CancelationToken::cancel_with_message()`](../cancelation_token/struct.CancelationToken.html#method.cancel_with_message)
+/// to a value that the operation can match on.
+///
+/// Canceling wakes everything waiting on the [`Cancelable`](../cancelation_token/struct.Cancelable.html), regardless
+/// of the policy, so [`Cancelable::allow_cancel()`](../cancelation_token/struct.Cancelable.html#method.allow_cancel)
+/// works the same way. The operation reads the policy with
+/// [`Cancelable::cancel_policy()`](../cancelation_token/struct.Cancelable.html#method.cancel_policy)
+///
+/// ```
+/// use sync_tokens::policy_cancelation_token::PolicyCancelationToken;
+///
+/// # #[allow(dead_code)]
+/// #[derive(Debug, Clone, PartialEq, Eq)]
+/// enum Shutdown {
+///     Graceful,
+///     Immediate
+/// }
+///
+/// # async_std::task::block_on(async {
+/// let (policy_cancelation_token, cancelable) = PolicyCancelationToken::new();
+///
+/// let worker = async_std::task::spawn(async move {
+///     cancelable.future().await;
+///
+///     match cancelable.cancel_policy::<Shutdown>() {
+///         Some(Shutdown::Graceful) => "flushed",
+///         _ => "dropped"
+///     }
+/// });
+///
+/// policy_cancelation_token.cancel(Shutdown::Graceful);
+/// assert_eq!(worker.await, "flushed");
+/// # });
+/// ```
+pub struct PolicyCancelationToken<P> {
+	cancelation_token: CancelationToken,
+	policy: PhantomData<fn(P)>
+}
+
+impl<P> PolicyCancelationToken<P> where
+P: Eq + Clone + Send + Sync + 'static {
+	/// Creates a new [`PolicyCancelationToken`](struct.PolicyCancelationToken.html) and
+	/// [`Cancelable`](../cancelation_token/struct.Cancelable.html)
+	pub fn new() -> (PolicyCancelationToken<P>, Cancelable) {
+		let (cancelation_token, cancelable) = CancelationToken::new();
+
+		let policy_cancelation_token = PolicyCancelationToken {
+			cancelation_token,
+			policy: PhantomData
+		};
+
+		(policy_cancelation_token, cancelable)
+	}
+
+	/// Cancels the operation with policy. The policy is passed on to child tokens. This can be called multiple times
+	/// safely, but if the operation is already canceled, the policy is ignored, the same as a message given to
+	/// [`CancelationToken::cancel_with_message()`](../cancelation_token/struct.CancelationToken.html#method.cancel_with_message)
+	pub fn cancel(&self, policy: P) {
+		self.cancelation_token.cancel_with_policy(Arc::new(policy));
+	}
+
+	/// Returns true once the operation is canceled
+	pub fn is_canceled(&self) -> bool {
+		self.cancelation_token.is_canceled()
+	}
+
+	/// Returns the policy that the operation was canceled with, or None if it isn't canceled
+	pub fn cancel_policy(&self) -> Option<P> {
+		self.cancelation_token.cancel_policy()
+	}
+
+	/// Returns the underlying [`CancelationToken`](../cancelation_token/struct.CancelationToken.html), for creating
+	/// children or resetting. Canceling it directly cancels without a policy
+	pub fn cancelation_token(&self) -> &CancelationToken {
+		&self.cancelation_token
+	}
+}
+
+impl<P> Clone for PolicyCancelationToken<P> {
+	fn clone(&self) -> Self {
+		PolicyCancelationToken {
+			cancelation_token: self.cancelation_token.clone(),
+			policy: PhantomData
+		}
+	}
+}
+
+impl<P> fmt::Debug for PolicyCancelationToken<P> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("PolicyCancelationToken")
+			.field("cancelation_token", &self.cancelation_token)
+			.finish()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[derive(Debug, Clone, PartialEq, Eq)]
+	enum Shutdown {
+		Graceful,
+		Immediate,
+		ForcefulKill { exit_code: i32 }
+	}
+
+	async fn run_until_canceled(cancelable: Cancelable) -> String {
+		cancelable.future().await;
+
+		match cancelable.cancel_policy::<Shutdown>() {
+			Some(Shutdown::Graceful) => "finished current work".to_string(),
+			Some(Shutdown::Immediate) => "stopped".to_string(),
+			Some(Shutdown::ForcefulKill { exit_code }) => format!("killed with {}", exit_code),
+			None => "canceled without a policy".to_string()
+		}
+	}
+
+	runtime_test! {
+		async fn test_task_branches_on_policy() {
+			let policies = vec![
+				(Shutdown::Graceful, "finished current work"),
+				(Shutdown::Immediate, "stopped"),
+				(Shutdown::ForcefulKill { exit_code: 9 }, "killed with 9")
+			];
+
+			for (policy, expected) in policies {
+				let (policy_cancelation_token, cancelable) = PolicyCancelationToken::new();
+				let worker = async_std::task::spawn(run_until_canceled(cancelable));
+
+				assert_eq!(policy_cancelation_token.cancel_policy(), None, "Shouldn't have a policy before canceling");
+
+				policy_cancelation_token.cancel(policy.clone());
+				assert!(policy_cancelation_token.is_canceled(), "Should be canceled");
+				assert_eq!(policy_cancelation_token.cancel_policy(), Some(policy), "Wrong policy");
+				assert_eq!(worker.await, expected, "The task should branch on the policy");
+			}
+		}
+	}
+
+	runtime_test! {
+		async fn test_allow_cancel_ignores_policy() {
+			let (policy_cancelation_token, cancelable) = PolicyCancelationToken::new();
+			let waiting = async_std::task::spawn({
+				let cancelable = cancelable.clone();
+				async move { cancelable.allow_cancel(futures::future::pending(), "canceled").await }
+			});
+
+			policy_cancelation_token.cancel(Shutdown::Graceful);
+
+			assert_eq!(waiting.await, "canceled", "Any policy should cancel");
+			assert_eq!(cancelable.cancel_policy(), Some(Shutdown::Graceful), "Wrong policy");
+		}
+	}
+
+	#[test]
+	fn test_first_policy_wins() {
+		let (policy_cancelation_token, cancelable) = PolicyCancelationToken::new();
+
+		policy_cancelation_token.cancel(Shutdown::Graceful);
+		policy_cancelation_token.cancel(Shutdown::Immediate);
+
+		assert_eq!(cancelable.cancel_policy(), Some(Shutdown::Graceful), "Canceling again shouldn't change the policy");
+	}
+
+	#[test]
+	fn test_children_and_reset() {
+		let (policy_cancelation_token, cancelable) = PolicyCancelationToken::new();
+		let (_child_token, child_cancelable) = policy_cancelation_token.cancelation_token().child();
+
+		policy_cancelation_token.cancel(Shutdown::Immediate);
+		assert_eq!(child_cancelable.cancel_policy(), Some(Shutdown::Immediate), "Children should get the policy");
+		assert_eq!(policy_cancelation_token.cancelation_token().child().1.cancel_policy(), Some(Shutdown::Immediate), "Late children should get the policy");
+		assert_eq!(cancelable.cancel_policy::<u32>(), None, "A different type shouldn't match");
+
+		policy_cancelation_token.cancelation_token().reset();
+		assert_eq!(cancelable.cancel_policy::<Shutdown>(), None, "Resetting should clear the policy");
+
+		policy_cancelation_token.cancelation_token().cancel();
+		assert!(cancelable.is_canceled(), "Should be canceled");
+		assert_eq!(cancelable.cancel_policy::<Shutdown>(), None, "Canceling without a policy shouldn't have one");
+	}
+}
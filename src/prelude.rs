@@ -0,0 +1,19 @@
+// https://github.com/GWBasic/sync-tokens
+// (c) Andrew Rondeau
+// Apache 2.0 license
+// See https://github.com/GWBasic/sync-tokens/blob/main/LICENSE
+
+//! Re-exports the traits that are commonly used with sync-tokens
+//!
+//! ```
+//! use sync_tokens::cancelation_token::CancelationToken;
+//! use sync_tokens::prelude::*;
+//!
+//! # async_std::task::block_on(async {
+//! let (_cancelation_token, cancelable) = CancelationToken::new();
+//! assert_eq!(futures::future::ready("finished").with_cancel(&cancelable, "canceled").await, "finished");
+//! # });
+//! ```
+pub use crate::box_cancelable::CancelationSource;
+pub use crate::cancelable_future_ext::CancelableFutureExt;
+pub use crate::completion_future_ext::CompletionFutureExt;
@@ -0,0 +1,336 @@
+// https://github.com/GWBasic/sync-tokens
+// (c) Andrew Rondeau
+// Apache 2.0 license
+// See https://github.com/GWBasic/sync-tokens/blob/main/LICENSE
+
+//! Contains structs to wait for a task to complete, while receiving its progress. See
+//! [`ProgressCompletionToken`](struct.ProgressCompletionToken.html)
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use crate::wakers::{WakerKey, WakerList, wake_each};
+
+/// Allows waiting for a task to complete, and receiving the progress that it reports until then. The task reports
+/// progress and completes with the corresponding [`ProgressCompletable`](struct.ProgressCompletable.html).
+///
+/// Every reported progress value is kept, in order, until it's received with
+/// [`next_progress()`](struct.ProgressCompletionToken.html#method.next_progress), which returns None once the task
+/// completes and no progress is left.
+///
+/// If the [`ProgressCompletable`](struct.ProgressCompletable.html) is dropped without calling complete,
+/// [`wait_complete()`](struct.ProgressCompletionToken.html#method.wait_complete) never returns, the same as a
+/// [`CompletionToken`](../completion_token/struct.CompletionToken.html)
+///
+/// ```
+/// use sync_tokens::progress_token::ProgressCompletionToken;
+///
+/// # async_std::task::block_on(async {
+/// let (mut progress_completion_token, progress_completable) = ProgressCompletionToken::new();
+///
+/// async_std::task::spawn(async move {
+///     for percent in [25, 50, 75].iter() {
+///         progress_completable.report_progress(*percent);
+///     }
+///
+///     progress_completable.complete("done");
+/// });
+///
+/// while let Some(percent) = progress_completion_token.next_progress().await {
+///     println!("{}%", percent);
+/// }
+///
+/// assert_eq!(progress_completion_token.wait_complete().await, "done");
+/// # });
+/// ```
+#[derive(Debug)]
+pub struct ProgressCompletionToken<T, P> {
+	shared_state: Arc<Mutex<ProgressState<T, P>>>,
+	waker_key: Option<WakerKey>
+}
+
+/// Reports progress, and then completes, the corresponding [`ProgressCompletionToken`](struct.ProgressCompletionToken.html)
+#[derive(Debug)]
+pub struct ProgressCompletable<T, P> {
+	shared_state: Arc<Mutex<ProgressState<T, P>>>
+}
+
+/// Future returned by [`ProgressCompletionToken::next_progress()`](struct.ProgressCompletionToken.html#method.next_progress)
+#[derive(Debug)]
+pub struct NextProgressFuture<'a, T, P> {
+	progress_completion_token: &'a mut ProgressCompletionToken<T, P>
+}
+
+/// Future returned by [`ProgressCompletionToken::wait_complete()`](struct.ProgressCompletionToken.html#method.wait_complete)
+#[derive(Debug)]
+pub struct WaitCompleteFuture<T, P> {
+	progress_completion_token: ProgressCompletionToken<T, P>
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stage {
+	ReportingProgress,
+	Complete,
+	Done
+}
+
+#[derive(Debug)]
+struct ProgressState<T, P> {
+	stage: Stage,
+	abandoned: bool,
+	progress: VecDeque<P>,
+	result: Option<T>,
+	wakers: WakerList
+}
+
+impl<T, P> ProgressCompletionToken<T, P> {
+	/// Creates a new [`ProgressCompletionToken`](struct.ProgressCompletionToken.html) and
+	/// [`ProgressCompletable`](struct.ProgressCompletable.html)
+	pub fn new() -> (ProgressCompletionToken<T, P>, ProgressCompletable<T, P>) {
+		let shared_state = Arc::new(Mutex::new(ProgressState {
+			stage: Stage::ReportingProgress,
+			abandoned: false,
+			progress: VecDeque::new(),
+			result: None,
+			wakers: WakerList::with_capacity(1)
+		}));
+
+		let progress_completion_token = ProgressCompletionToken {
+			shared_state: shared_state.clone(),
+			waker_key: None
+		};
+
+		(progress_completion_token, ProgressCompletable { shared_state })
+	}
+
+	/// Waits for the next progress value. Returns None once the task completed, or dropped its
+	/// [`ProgressCompletable`](struct.ProgressCompletable.html), and every progress value was received
+	pub fn next_progress(&mut self) -> NextProgressFuture<'_, T, P> {
+		NextProgressFuture {
+			progress_completion_token: self
+		}
+	}
+
+	/// Waits for the task to complete, and returns its result. Progress that wasn't received is discarded
+	pub fn wait_complete(self) -> WaitCompleteFuture<T, P> {
+		WaitCompleteFuture {
+			progress_completion_token: self
+		}
+	}
+}
+
+impl<T, P> ProgressCompletable<T, P> {
+	/// Reports progress to the [`ProgressCompletionToken`](struct.ProgressCompletionToken.html)
+	///
+	/// # Panics
+	///
+	/// Panics if called after complete
+	pub fn report_progress(&self, progress: P) {
+		let mut shared_state = self.shared_state.lock().unwrap();
+
+		if shared_state.stage != Stage::ReportingProgress {
+			// Unlock first so that dropping the completable while unwinding doesn't find a poisoned lock
+			drop(shared_state);
+			panic!("Progress can't be reported after completing")
+		}
+
+		shared_state.progress.push_back(progress);
+		let wakers = shared_state.wakers.take_all();
+		drop(shared_state);
+		wake_each(wakers);
+	}
+
+	/// Call to indicate that the operation is complete. Progress that was already reported can still be received
+	///
+	/// # Panics
+	///
+	/// Complete will panic if it is called multiple times
+	pub fn complete(&self, result: T) {
+		let mut shared_state = self.shared_state.lock().unwrap();
+
+		if shared_state.stage != Stage::ReportingProgress {
+			drop(shared_state);
+			panic!("Progress completion token is already complete")
+		}
+
+		shared_state.stage = Stage::Complete;
+		shared_state.result = Some(result);
+		let wakers = shared_state.wakers.take_all();
+		drop(shared_state);
+		wake_each(wakers);
+	}
+}
+
+impl<T, P> Drop for ProgressCompletable<T, P> {
+	fn drop(&mut self) {
+		let mut shared_state = self.shared_state.lock().unwrap();
+
+		if shared_state.stage == Stage::ReportingProgress {
+			shared_state.abandoned = true;
+			let wakers = shared_state.wakers.take_all();
+			drop(shared_state);
+			wake_each(wakers);
+		}
+	}
+}
+
+impl<T, P> Future for NextProgressFuture<'_, T, P> {
+	type Output = Option<P>;
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+		let progress_completion_token = &mut *self.get_mut().progress_completion_token;
+		let mut shared_state = progress_completion_token.shared_state.lock().unwrap();
+
+		if let Some(progress) = shared_state.progress.pop_front() {
+			Poll::Ready(Some(progress))
+		} else if shared_state.stage != Stage::ReportingProgress || shared_state.abandoned {
+			Poll::Ready(None)
+		} else {
+			shared_state.wakers.register(&mut progress_completion_token.waker_key, cx.waker());
+			Poll::Pending
+		}
+	}
+}
+
+impl<T, P> Future for WaitCompleteFuture<T, P> {
+	type Output = T;
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+		let progress_completion_token = &mut self.get_mut().progress_completion_token;
+		let mut shared_state = progress_completion_token.shared_state.lock().unwrap();
+
+		match shared_state.stage {
+			Stage::Complete => {
+				shared_state.stage = Stage::Done;
+				shared_state.progress.clear();
+				Poll::Ready(shared_state.result.take().unwrap())
+			},
+			// Only reachable by polling after Ready. Released first, so that the panic doesn't poison the lock
+			Stage::Done => {
+				drop(shared_state);
+				panic!("Progress completion token was already awaited")
+			},
+			// Awaiting an abandoned token never returns
+			Stage::ReportingProgress => {
+				shared_state.wakers.register(&mut progress_completion_token.waker_key, cx.waker());
+				Poll::Pending
+			}
+		}
+	}
+}
+
+impl<T, P> Drop for ProgressCompletionToken<T, P> {
+	fn drop(&mut self) {
+		if self.waker_key.is_some() {
+			let mut shared_state = self.shared_state.lock().unwrap();
+			shared_state.wakers.remove(self.waker_key);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use cooked_waker::IntoWaker;
+
+	use super::*;
+	use crate::tests::*;
+
+	runtime_test! {
+		async fn test_progress_then_complete() {
+			let (mut progress_completion_token, progress_completable) = ProgressCompletionToken::new();
+
+			let consumer = async_std::task::spawn(async move {
+				let mut received = Vec::new();
+
+				while let Some(progress) = progress_completion_token.next_progress().await {
+					received.push(progress);
+				}
+
+				(received, progress_completion_token.wait_complete().await)
+			});
+
+			for progress in 1..=3 {
+				async_std::task::sleep(std::time::Duration::from_millis(5)).await;
+				progress_completable.report_progress(progress);
+			}
+
+			progress_completable.complete("final");
+
+			let (received, result) = consumer.await;
+			assert_eq!(received, vec![1, 2, 3], "Every progress value should be received in order");
+			assert_eq!(result, "final", "Wrong result");
+		}
+	}
+
+	#[test]
+	fn test_wakes_on_progress() {
+		let (mut progress_completion_token, progress_completable) = ProgressCompletionToken::<(), _>::new();
+
+		let test_waker = TestWaker::new();
+		let waker = test_waker.clone().into_waker();
+		let mut cx = Context::from_waker(&waker);
+
+		{
+			let mut next_progress = progress_completion_token.next_progress();
+			assert!(Pin::new(&mut next_progress).poll(&mut cx).is_pending(), "No progress yet");
+		}
+
+		progress_completable.report_progress("half");
+		assert!(test_waker.woke(), "Reporting progress should wake");
+
+		let mut next_progress = progress_completion_token.next_progress();
+		assert_eq!(Pin::new(&mut next_progress).poll(&mut cx), Poll::Ready(Some("half")), "Wrong progress");
+	}
+
+	#[test]
+	fn test_complete_discards_progress() {
+		let (progress_completion_token, progress_completable) = ProgressCompletionToken::new();
+
+		progress_completable.report_progress(1);
+		progress_completable.complete("final");
+
+		assert_eq!(futures::executor::block_on(progress_completion_token.wait_complete()), "final", "Wrong result");
+	}
+
+	#[test]
+	fn test_abandoned_ends_progress() {
+		let (mut progress_completion_token, progress_completable) = ProgressCompletionToken::<(), u32>::new();
+
+		progress_completable.report_progress(1);
+		drop(progress_completable);
+
+		assert_eq!(futures::executor::block_on(progress_completion_token.next_progress()), Some(1), "Reported progress should be kept");
+		assert_eq!(futures::executor::block_on(progress_completion_token.next_progress()), None, "Progress should end when abandoned");
+	}
+
+	#[test]
+	#[should_panic(expected = "Progress can't be reported after completing")]
+	fn test_progress_after_complete_panics() {
+		let (_progress_completion_token, progress_completable) = ProgressCompletionToken::new();
+
+		progress_completable.complete(());
+		progress_completable.report_progress(1);
+	}
+	#[test]
+	fn test_polled_after_ready_doesnt_poison() {
+		let (progress_completion_token, progress_completable) = ProgressCompletionToken::<_, u32>::new();
+
+		let test_waker = TestWaker::new();
+		let waker = test_waker.into_waker();
+		let mut cx = Context::from_waker(&waker);
+
+		progress_completable.complete("final");
+
+		let mut wait_complete = progress_completion_token.wait_complete();
+		assert_eq!(Pin::new(&mut wait_complete).poll(&mut cx), Poll::Ready("final"), "Wrong result");
+
+		let polled_again = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| Pin::new(&mut wait_complete).poll(&mut cx)));
+		assert!(polled_again.is_err(), "Polling after Ready should panic");
+
+		// Both sides lock when they're dropped, which would panic if the lock was poisoned
+		drop(wait_complete);
+		drop(progress_completable);
+	}
+}
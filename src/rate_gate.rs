@@ -0,0 +1,320 @@
+// https://github.com/GWBasic/sync-tokens
+// (c) Andrew Rondeau
+// Apache 2.0 license
+// See https://github.com/GWBasic/sync-tokens/blob/main/LICENSE
+
+//! Contains a rate limiter whose waiters can be canceled. See [`RateGate`](struct.RateGate.html)
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use crate::cancelation_token::{Cancelable, CancelationTokenFuture, Canceled};
+use crate::timer::{Instant, Sleep, Timer};
+
+/// Limits how often an operation runs: [`acquire()`](struct.RateGate.html#method.acquire) delays callers so that, on
+/// average, no more than permits_per_interval operations start per interval.
+///
+/// Up to the burst size operations can start at once after the gate was idle; by default, the burst size is
+/// permits_per_interval. Callers are let through in the order that they called
+/// [`acquire()`](struct.RateGate.html#method.acquire).
+///
+/// Waiting can be canceled by passing a [`Cancelable`](../cancelation_token/struct.Cancelable.html), so that shutdown
+/// doesn't strand callers that wait for their slot. A caller that's canceled, or stops waiting, gives its slot back if
+/// nobody reserved a later slot
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use sync_tokens::cancelation_token::CancelationToken;
+/// use sync_tokens::rate_gate::RateGate;
+///
+/// # async_std::task::block_on(async {
+/// let rate_gate = RateGate::new(100, Duration::from_secs(1));
+/// let (cancelation_token, cancelable) = CancelationToken::new();
+///
+/// for _ in 0..10 {
+///     rate_gate.acquire(Some(&cancelable)).await.unwrap();
+/// }
+///
+/// cancelation_token.cancel();
+/// assert!(rate_gate.acquire(Some(&cancelable)).await.is_err());
+/// # });
+/// ```
+#[derive(Debug, Clone)]
+pub struct RateGate {
+	shared_state: Arc<Mutex<RateGateState>>,
+	timer: Timer
+}
+
+/// Future returned by [`RateGate::acquire()`](struct.RateGate.html#method.acquire)
+#[derive(Debug)]
+pub struct RateGateAcquireFuture {
+	shared_state: Arc<Mutex<RateGateState>>,
+	timer: Timer,
+	cancelation_token_future: Option<CancelationTokenFuture>,
+	reservation: Option<Instant>,
+	sleep: Option<Sleep>
+}
+
+#[derive(Debug)]
+struct RateGateState {
+	// How far apart permits are
+	emission_interval: Duration,
+	burst: u32,
+	// When the next permit is due if no burst was allowed
+	theoretical_arrival: Instant
+}
+
+impl RateGate {
+	/// Creates a new [`RateGate`](struct.RateGate.html) that uses the system clock
+	///
+	/// # Panics
+	///
+	/// Panics if permits_per_interval is 0
+	pub fn new(permits_per_interval: u32, interval: Duration) -> RateGate {
+		RateGate::with_timer(permits_per_interval, interval, Timer::default())
+	}
+
+	/// Creates a new [`RateGate`](struct.RateGate.html) that uses the given [`Timer`](../timer/struct.Timer.html)
+	///
+	/// # Panics
+	///
+	/// Panics if permits_per_interval is 0
+	pub fn with_timer(permits_per_interval: u32, interval: Duration, timer: Timer) -> RateGate {
+		assert!(permits_per_interval > 0, "permits_per_interval must be at least 1");
+
+		RateGate {
+			shared_state: Arc::new(Mutex::new(RateGateState {
+				emission_interval: interval / permits_per_interval,
+				burst: permits_per_interval,
+				theoretical_arrival: timer.now()
+			})),
+			timer
+		}
+	}
+
+	/// Sets how many operations can start at once after the gate was idle
+	///
+	/// # Panics
+	///
+	/// Panics if burst is 0
+	pub fn with_burst(self, burst: u32) -> RateGate {
+		assert!(burst > 0, "burst must be at least 1");
+
+		self.shared_state.lock().unwrap().burst = burst;
+		self
+	}
+
+	/// Returns a future that returns once the caller can start. Returns [`Canceled`](../cancelation_token/struct.Canceled.html)
+	/// if cancelable is canceled first
+	pub fn acquire(&self, cancelable: Option<&Cancelable>) -> RateGateAcquireFuture {
+		RateGateAcquireFuture {
+			shared_state: self.shared_state.clone(),
+			timer: self.timer.clone(),
+			cancelation_token_future: cancelable.map(|cancelable| cancelable.future()),
+			reservation: None,
+			sleep: None
+		}
+	}
+
+	/// Returns true, and lets the caller start, if the caller doesn't need to wait
+	pub fn try_acquire(&self) -> bool {
+		let mut shared_state = self.shared_state.lock().unwrap();
+		let now = self.timer.now();
+
+		shared_state.try_reserve(now)
+	}
+}
+
+impl RateGateState {
+	// Takes the next slot. Returns when the slot starts if the caller must wait
+	fn reserve(&mut self, now: Instant) -> Option<Instant> {
+		let start = self.theoretical_arrival.max(now);
+		let burst_tolerance = self.emission_interval * (self.burst - 1);
+		self.theoretical_arrival = start + self.emission_interval;
+
+		if start <= now + burst_tolerance {
+			None
+		} else {
+			Some(start - burst_tolerance)
+		}
+	}
+
+	// Takes the next slot only if the caller doesn't need to wait
+	fn try_reserve(&mut self, now: Instant) -> bool {
+		let start = self.theoretical_arrival.max(now);
+		let burst_tolerance = self.emission_interval * (self.burst - 1);
+
+		if start <= now + burst_tolerance {
+			self.theoretical_arrival = start + self.emission_interval;
+			true
+		} else {
+			false
+		}
+	}
+
+	// Gives back a slot that's no longer needed, unless a later slot was already reserved
+	fn refund(&mut self, theoretical_arrival: Instant) {
+		if self.theoretical_arrival == theoretical_arrival {
+			self.theoretical_arrival -= self.emission_interval;
+		}
+	}
+}
+
+impl RateGateAcquireFuture {
+	fn refund(&mut self) {
+		if let Some(theoretical_arrival) = self.reservation.take() {
+			self.shared_state.lock().unwrap().refund(theoretical_arrival);
+		}
+	}
+}
+
+impl Future for RateGateAcquireFuture {
+	type Output = Result<(), Canceled>;
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+		let this = self.get_mut();
+
+		if let Some(cancelation_token_future) = &mut this.cancelation_token_future {
+			if Pin::new(cancelation_token_future).poll(cx).is_ready() {
+				this.refund();
+				this.sleep = None;
+				return Poll::Ready(Err(Canceled));
+			}
+		}
+
+		if this.sleep.is_none() {
+			let mut shared_state = this.shared_state.lock().unwrap();
+
+			match shared_state.reserve(this.timer.now()) {
+				None => return Poll::Ready(Ok(())),
+				Some(allowed_at) => {
+					this.reservation = Some(shared_state.theoretical_arrival);
+					this.sleep = Some(this.timer.sleep_until(allowed_at));
+				}
+			}
+		}
+
+		match this.sleep.as_mut() {
+			Some(sleep) => match Pin::new(sleep).poll(cx) {
+				Poll::Ready(()) => {
+					this.reservation = None;
+					this.sleep = None;
+					Poll::Ready(Ok(()))
+				},
+				Poll::Pending => Poll::Pending
+			},
+			None => Poll::Pending
+		}
+	}
+}
+
+impl Drop for RateGateAcquireFuture {
+	fn drop(&mut self) {
+		self.refund();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use futures::executor::LocalPool;
+	use futures::task::LocalSpawnExt;
+
+	use super::*;
+	use crate::cancelation_token::CancelationToken;
+	use crate::timer::ManualClock;
+
+	#[test]
+	fn test_rate() {
+		let clock = ManualClock::new();
+		let timer = Timer::new(clock.clone());
+		let rate_gate = RateGate::with_timer(1, Duration::from_secs(1), timer.clone());
+
+		let mut pool = LocalPool::new();
+		let elapsed = pool.spawner().spawn_local_with_handle(async move {
+			let start = timer.now();
+
+			for _ in 0..3 {
+				rate_gate.acquire(None).await.unwrap();
+			}
+
+			timer.now() - start
+		}).unwrap();
+
+		pool.run_until_stalled();
+
+		clock.advance(Duration::from_millis(999));
+		pool.run_until_stalled();
+
+		clock.advance(Duration::from_millis(1));
+		pool.run_until_stalled();
+
+		clock.advance(Duration::from_secs(1));
+		assert_eq!(pool.run_until(elapsed), Duration::from_secs(2), "3 permits at 1 per second should take 2 seconds");
+	}
+
+	#[test]
+	fn test_cancel_while_waiting() {
+		let clock = ManualClock::new();
+		let rate_gate = RateGate::with_timer(1, Duration::from_secs(1), Timer::new(clock.clone()));
+		let (cancelation_token, cancelable) = CancelationToken::new();
+
+		assert!(rate_gate.try_acquire(), "The first permit should be available");
+
+		let mut pool = LocalPool::new();
+		let acquired = pool.spawner().spawn_local_with_handle({
+			let rate_gate = rate_gate.clone();
+			async move {
+				rate_gate.acquire(Some(&cancelable)).await
+			}
+		}).unwrap();
+
+		pool.run_until_stalled();
+		cancelation_token.cancel();
+
+		assert_eq!(pool.run_until(acquired), Err(Canceled), "Canceling should return promptly");
+
+		// The canceled caller gave its slot back
+		clock.advance(Duration::from_secs(1));
+		assert!(rate_gate.try_acquire(), "The canceled slot should be available");
+		assert!(!rate_gate.try_acquire(), "Only one permit should be available");
+	}
+
+	#[test]
+	fn test_burst() {
+		let clock = ManualClock::new();
+		let rate_gate = RateGate::with_timer(1, Duration::from_secs(1), Timer::new(clock.clone())).with_burst(3);
+
+		for _ in 0..3 {
+			assert!(rate_gate.try_acquire(), "The burst should be available");
+		}
+
+		assert!(!rate_gate.try_acquire(), "The burst should be used up");
+
+		clock.advance(Duration::from_secs(1));
+		assert!(rate_gate.try_acquire(), "A permit should be available after an interval");
+		assert!(!rate_gate.try_acquire(), "Only one permit should be available");
+
+		clock.advance(Duration::from_secs(10));
+		for _ in 0..3 {
+			assert!(rate_gate.try_acquire(), "The burst should be available after idling");
+		}
+
+		assert!(!rate_gate.try_acquire(), "Idling shouldn't allow more than the burst");
+	}
+
+	runtime_test! {
+		async fn test_system_timer() {
+			let rate_gate = RateGate::new(50, Duration::from_secs(1));
+			let start = Instant::now();
+
+			for _ in 0..52 {
+				rate_gate.acquire(None).await.unwrap();
+			}
+
+			assert!(start.elapsed() >= Duration::from_millis(40), "Rate exceeded");
+		}
+	}
+}
@@ -0,0 +1,322 @@
+// https://github.com/GWBasic/sync-tokens
+// (c) Andrew Rondeau
+// Apache 2.0 license
+// See https://github.com/GWBasic/sync-tokens/blob/main/LICENSE
+
+//! Contains a [`Completable`](../completion_token/struct.Completable.html) that forwards at most one completion per
+//! interval. See [`RateLimitedCompletable`](struct.RateLimitedCompletable.html)
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::time::Duration;
+
+use crate::background;
+use crate::completion_token::Completable;
+use crate::timer::{Instant, Timer};
+
+/// Wraps a [`Completable`](../completion_token/struct.Completable.html), and forwards at most one completion per
+/// interval. This debounces rapid completion signals, such as from a UI or a sensor.
+///
+/// The first completion is forwarded right away. Completions that arrive within the interval, or while there's no
+/// [`Completable`](../completion_token/struct.Completable.html) to forward to, are handled according to the
+/// [`RateLimitOverflow`](enum.RateLimitOverflow.html). Call [`rearm()`](struct.RateLimitedCompletable.html#method.rearm)
+/// with a new [`Completable`](../completion_token/struct.Completable.html) to receive the next completion.
+///
+/// Unlike a [`Completable`](../completion_token/struct.Completable.html), calling complete multiple times never panics
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use sync_tokens::completion_token::CompletionToken;
+/// use sync_tokens::rate_limited_completable::RateLimitedCompletable;
+///
+/// # async_std::task::block_on(async {
+/// let (completion_token, completable) = CompletionToken::new();
+/// let rate_limited_completable = RateLimitedCompletable::new(completable, Duration::from_millis(100));
+///
+/// for reading in 0..10 {
+///     rate_limited_completable.complete(reading);
+/// }
+///
+/// assert_eq!(completion_token.await, 0);
+/// # });
+/// ```
+#[derive(Debug)]
+pub struct RateLimitedCompletable<T> {
+	shared_state: Arc<Mutex<RateLimitedState<T>>>,
+	timer: Timer
+}
+
+/// What a [`RateLimitedCompletable`](struct.RateLimitedCompletable.html) does with completions that can't be forwarded
+/// right away
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimitOverflow {
+	/// Completions are dropped
+	Drop,
+	/// The most recent completion is kept, and forwarded once the interval passes and there's a
+	/// [`Completable`](../completion_token/struct.Completable.html) to forward to
+	QueueLatest
+}
+
+#[derive(Debug)]
+struct RateLimitedState<T> {
+	completable: Option<Completable<T>>,
+	min_interval: Duration,
+	overflow: RateLimitOverflow,
+	last_forwarded: Option<Instant>,
+	queued: Option<T>,
+	flush_scheduled: bool
+}
+
+impl<T> RateLimitedCompletable<T> where
+T: Send + 'static {
+	/// Wraps completable, forwarding at most one completion per min_interval, using the system clock
+	pub fn new(completable: Completable<T>, min_interval: Duration) -> RateLimitedCompletable<T> {
+		RateLimitedCompletable::with_timer(completable, min_interval, Timer::default())
+	}
+
+	/// Wraps completable, forwarding at most one completion per min_interval, using the given
+	/// [`Timer`](../timer/struct.Timer.html)
+	pub fn with_timer(completable: Completable<T>, min_interval: Duration, timer: Timer) -> RateLimitedCompletable<T> {
+		RateLimitedCompletable {
+			shared_state: Arc::new(Mutex::new(RateLimitedState {
+				completable: Some(completable),
+				min_interval,
+				overflow: RateLimitOverflow::Drop,
+				last_forwarded: None,
+				queued: None,
+				flush_scheduled: false
+			})),
+			timer
+		}
+	}
+
+	/// Sets what happens to completions that can't be forwarded right away. The default is
+	/// [`RateLimitOverflow::Drop`](enum.RateLimitOverflow.html#variant.Drop)
+	pub fn with_overflow(self, overflow: RateLimitOverflow) -> RateLimitedCompletable<T> {
+		self.shared_state.lock().unwrap().overflow = overflow;
+		self
+	}
+
+	/// Forwards result if the interval passed since the last forwarded completion. Otherwise, result is dropped or
+	/// queued
+	pub fn complete(&self, result: T) {
+		let mut shared_state = self.shared_state.lock().unwrap();
+		let now = self.timer.now();
+
+		if shared_state.completable.is_some() && shared_state.interval_passed(now) {
+			shared_state.queued = None;
+			RateLimitedState::forward(shared_state, result, now);
+		} else if shared_state.overflow == RateLimitOverflow::QueueLatest {
+			shared_state.queued = Some(result);
+			self.schedule_flush(&mut shared_state);
+		}
+	}
+
+	/// Supplies the [`Completable`](../completion_token/struct.Completable.html) that receives the next forwarded
+	/// completion, replacing the current one if it wasn't completed yet
+	pub fn rearm(&self, completable: Completable<T>) {
+		let mut shared_state = self.shared_state.lock().unwrap();
+		shared_state.completable = Some(completable);
+
+		if shared_state.queued.is_some() {
+			let now = self.timer.now();
+
+			if shared_state.interval_passed(now) {
+				let queued = shared_state.queued.take().unwrap();
+				RateLimitedState::forward(shared_state, queued, now);
+			} else {
+				self.schedule_flush(&mut shared_state);
+			}
+		}
+	}
+
+	fn schedule_flush(&self, shared_state: &mut RateLimitedState<T>) {
+		if shared_state.flush_scheduled || shared_state.completable.is_none() {
+			return;
+		}
+
+		let next_allowed = match shared_state.next_allowed() {
+			Some(next_allowed) => next_allowed,
+			None => return
+		};
+
+		shared_state.flush_scheduled = true;
+
+		let sleep = self.timer.sleep_until(next_allowed);
+		// Weak, so that the queued completion and the completable are released once every clone is dropped
+		let shared_state = Arc::downgrade(&self.shared_state);
+		let timer = self.timer.clone();
+
+		background::spawn(async move {
+			sleep.await;
+
+			let shared_state = match Weak::upgrade(&shared_state) {
+				Some(shared_state) => shared_state,
+				None => return
+			};
+
+			let mut shared_state = shared_state.lock().unwrap();
+			shared_state.flush_scheduled = false;
+
+			if shared_state.completable.is_some() {
+				if let Some(queued) = shared_state.queued.take() {
+					RateLimitedState::forward(shared_state, queued, timer.now());
+				}
+			}
+		});
+	}
+}
+
+impl<T> RateLimitedState<T> {
+	fn next_allowed(&self) -> Option<Instant> {
+		self.last_forwarded.map(|last_forwarded| last_forwarded + self.min_interval)
+	}
+
+	fn interval_passed(&self, now: Instant) -> bool {
+		match self.next_allowed() {
+			Some(next_allowed) => now >= next_allowed,
+			None => true
+		}
+	}
+
+	// Completed after the lock is released, so that a waker that runs the awaiting task right away doesn't wait for it
+	fn forward(mut shared_state: MutexGuard<'_, RateLimitedState<T>>, result: T, now: Instant) {
+		let completable = shared_state.completable.take();
+
+		if completable.is_some() {
+			shared_state.last_forwarded = Some(now);
+		}
+
+		drop(shared_state);
+
+		if let Some(completable) = completable {
+			completable.complete(result);
+		}
+	}
+}
+
+impl<T> Clone for RateLimitedCompletable<T> {
+	fn clone(&self) -> Self {
+		RateLimitedCompletable {
+			shared_state: self.shared_state.clone(),
+			timer: self.timer.clone()
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::future::Future;
+	use std::pin::Pin;
+	use std::task::Context;
+
+	use cooked_waker::IntoWaker;
+
+	use super::*;
+	use crate::completion_token::{Abandoned, CompletionToken};
+	use crate::tests::*;
+	use crate::timer::ManualClock;
+
+	fn is_complete<T>(completion_token: &mut CompletionToken<T>) -> bool {
+		let waker = TestWaker::new().into_waker();
+		Pin::new(completion_token).poll(&mut Context::from_waker(&waker)).is_ready()
+	}
+
+	#[test]
+	fn test_drops_within_interval() {
+		let clock = ManualClock::new();
+		let (completion_token, completable) = CompletionToken::new();
+		let rate_limited_completable = RateLimitedCompletable::with_timer(completable, Duration::from_millis(100), Timer::new(clock.clone()));
+
+		for i in 0..10 {
+			rate_limited_completable.complete(i);
+		}
+
+		assert_eq!(futures::executor::block_on(completion_token), 0, "Only the first completion should be forwarded");
+
+		// Still within the interval
+		let (mut completion_token, completable) = CompletionToken::new();
+		rate_limited_completable.rearm(completable);
+		clock.advance(Duration::from_millis(99));
+
+		for i in 10..20 {
+			rate_limited_completable.complete(i);
+		}
+
+		assert!(!is_complete(&mut completion_token), "Completions within the interval should be dropped");
+
+		clock.advance(Duration::from_millis(1));
+
+		for i in 20..30 {
+			rate_limited_completable.complete(i);
+		}
+
+		assert_eq!(futures::executor::block_on(completion_token), 20, "The first completion of the next interval should be forwarded");
+	}
+
+	#[test]
+	fn test_queue_latest() {
+		let clock = ManualClock::new();
+		let (completion_token, completable) = CompletionToken::new();
+		let rate_limited_completable = RateLimitedCompletable::with_timer(completable, Duration::from_millis(100), Timer::new(clock.clone()))
+			.with_overflow(RateLimitOverflow::QueueLatest);
+
+		for i in 0..10 {
+			rate_limited_completable.complete(i);
+		}
+
+		assert_eq!(futures::executor::block_on(completion_token), 0, "The first completion should be forwarded right away");
+
+		let (mut completion_token, completable) = CompletionToken::new();
+		rate_limited_completable.rearm(completable);
+
+		std::thread::sleep(Duration::from_millis(10));
+		assert!(!is_complete(&mut completion_token), "The queued completion should wait for the interval");
+
+		clock.advance(Duration::from_millis(100));
+		assert_eq!(futures::executor::block_on(completion_token), 9, "The latest queued completion should be forwarded");
+	}
+
+	#[test]
+	fn test_rearm_after_interval_forwards_queued() {
+		let clock = ManualClock::new();
+		let (completion_token, completable) = CompletionToken::new();
+		let rate_limited_completable = RateLimitedCompletable::with_timer(completable, Duration::from_millis(100), Timer::new(clock.clone()))
+			.with_overflow(RateLimitOverflow::QueueLatest);
+
+		rate_limited_completable.complete("first");
+		rate_limited_completable.complete("second");
+		assert_eq!(futures::executor::block_on(completion_token), "first", "Wrong completion");
+
+		clock.advance(Duration::from_millis(150));
+
+		let (mut completion_token, completable) = CompletionToken::new();
+		rate_limited_completable.rearm(completable);
+
+		assert!(is_complete(&mut completion_token), "The queued completion should be forwarded when rearmed");
+	}
+
+	#[test]
+	fn test_pending_flush_doesnt_keep_state_alive() {
+		let clock = ManualClock::new();
+		let (completion_token, completable) = CompletionToken::new();
+		let rate_limited_completable = RateLimitedCompletable::with_timer(completable, Duration::from_millis(100), Timer::new(clock.clone()))
+			.with_overflow(RateLimitOverflow::QueueLatest);
+
+		rate_limited_completable.complete("first");
+		assert_eq!(futures::executor::block_on(completion_token), "first", "Wrong completion");
+
+		let (completion_token, completable) = CompletionToken::new();
+		rate_limited_completable.rearm(completable);
+		rate_limited_completable.complete("second");
+
+		// The flush is still waiting for the interval
+		drop(rate_limited_completable);
+
+		assert_eq!(
+			futures::executor::block_on(completion_token.try_wait()),
+			Err(Abandoned),
+			"The completable should be dropped with the last clone, not when the flush wakes");
+
+		clock.advance(Duration::from_millis(100));
+	}
+}
@@ -0,0 +1,327 @@
+// https://github.com/GWBasic/sync-tokens
+// (c) Andrew Rondeau
+// Apache 2.0 license
+// See https://github.com/GWBasic/sync-tokens/blob/main/LICENSE
+
+//! Contains a set of named components that report when they're ready. See [`ReadySet`](struct.ReadySet.html)
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use crate::completion_token::{Completable, CompletionToken};
+
+/// Waits for a set of named components, such as a database pool and a listener, to report that they're ready.
+///
+/// Each component calls [`register()`](struct.ReadySet.html#method.register) and completes the returned
+/// [`Completable`](../completion_token/struct.Completable.html) when it's ready. An observer awaits
+/// [`all_ready()`](struct.ReadySet.html#method.all_ready). While waiting,
+/// [`pending_components()`](struct.ReadySet.html#method.pending_components) lists who hasn't reported yet, which helps
+/// find out why startup is slow.
+///
+/// If a component drops its [`Completable`](../completion_token/struct.Completable.html) without completing it,
+/// [`all_ready()`](struct.ReadySet.html#method.all_ready) returns the component's name in
+/// [`ComponentsAbandoned`](struct.ComponentsAbandoned.html).
+///
+/// What happens when a component registers after [`all_ready()`](struct.ReadySet.html#method.all_ready) returned
+/// depends on the [`LateRegistration`](enum.LateRegistration.html) mode
+///
+/// ```
+/// use sync_tokens::ready_set::ReadySet;
+///
+/// # async_std::task::block_on(async {
+/// let ready_set = ReadySet::new();
+///
+/// let db_pool = ready_set.register("db-pool").unwrap();
+/// let listener = ready_set.register("listener").unwrap();
+///
+/// listener.complete(());
+/// assert_eq!(ready_set.pending_components(), vec!["db-pool"]);
+///
+/// db_pool.complete(());
+/// ready_set.all_ready().await.unwrap();
+/// # });
+/// ```
+#[derive(Debug, Clone)]
+pub struct ReadySet {
+	shared_state: Arc<Mutex<ReadySetState>>
+}
+
+/// Future returned by [`ReadySet::all_ready()`](struct.ReadySet.html#method.all_ready)
+#[derive(Debug)]
+pub struct ReadySetFuture {
+	shared_state: Arc<Mutex<ReadySetState>>,
+	// This future's own clones of the components' tokens, so that each future has its own wakers
+	completion_tokens: Vec<CompletionToken<()>>
+}
+
+/// What a [`ReadySet`](struct.ReadySet.html) does when a component registers after
+/// [`all_ready()`](struct.ReadySet.html#method.all_ready) returned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LateRegistration {
+	/// Registration returns [`RegisterError::AlreadyReady`](enum.RegisterError.html)
+	Reject,
+	/// The set is no longer ready; later calls to [`all_ready()`](struct.ReadySet.html#method.all_ready) wait for the
+	/// new component
+	Rearm
+}
+
+/// Error returned by [`ReadySet::register()`](struct.ReadySet.html#method.register)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegisterError {
+	/// The set was already ready, and late registration is rejected
+	AlreadyReady,
+	/// A component with the same name is already registered
+	Duplicate(String)
+}
+
+/// Error returned by [`ReadySet::all_ready()`](struct.ReadySet.html#method.all_ready) when components dropped their
+/// [`Completable`](../completion_token/struct.Completable.html) without completing it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ComponentsAbandoned {
+	/// The names of the abandoned components, in the order they registered
+	pub names: Vec<String>
+}
+
+#[derive(Debug)]
+struct ReadySetState {
+	components: Vec<(String, CompletionToken<()>)>,
+	late_registration: LateRegistration,
+	ready: bool
+}
+
+impl ReadySet {
+	/// Creates a new, empty, [`ReadySet`](struct.ReadySet.html) that rejects late registration
+	pub fn new() -> ReadySet {
+		ReadySet::with_late_registration(LateRegistration::Reject)
+	}
+
+	/// Creates a new, empty, [`ReadySet`](struct.ReadySet.html) that handles late registration according to
+	/// late_registration
+	pub fn with_late_registration(late_registration: LateRegistration) -> ReadySet {
+		ReadySet {
+			shared_state: Arc::new(Mutex::new(ReadySetState {
+				components: Vec::new(),
+				late_registration,
+				ready: false
+			}))
+		}
+	}
+
+	/// Registers a component. The component completes the returned [`Completable`](../completion_token/struct.Completable.html)
+	/// when it's ready
+	pub fn register<N>(&self, name: N) -> Result<Completable<()>, RegisterError> where
+	N: Into<String> {
+		let name = name.into();
+		let mut shared_state = self.shared_state.lock().unwrap();
+
+		if shared_state.ready {
+			match shared_state.late_registration {
+				LateRegistration::Reject => return Err(RegisterError::AlreadyReady),
+				LateRegistration::Rearm => shared_state.ready = false
+			}
+		}
+
+		if shared_state.components.iter().any(|(existing, _)| *existing == name) {
+			return Err(RegisterError::Duplicate(name));
+		}
+
+		// Subscribed, so that every future's clone sees the result
+		let (completion_token, completable) = CompletionToken::new();
+		shared_state.components.push((name, completion_token.subscribe()));
+
+		Ok(completable)
+	}
+
+	/// Returns a future that returns once every registered component is ready
+	pub fn all_ready(&self) -> ReadySetFuture {
+		ReadySetFuture {
+			shared_state: self.shared_state.clone(),
+			completion_tokens: Vec::new()
+		}
+	}
+
+	/// The names of the components that haven't reported that they're ready, in the order they registered
+	pub fn pending_components(&self) -> Vec<String> {
+		let shared_state = self.shared_state.lock().unwrap();
+
+		shared_state.components.iter()
+			.filter(|(_, completion_token)| !completion_token.is_complete())
+			.map(|(name, _)| name.clone())
+			.collect()
+	}
+}
+
+impl Default for ReadySet {
+	fn default() -> Self {
+		ReadySet::new()
+	}
+}
+
+impl Future for ReadySetFuture {
+	type Output = Result<(), ComponentsAbandoned>;
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+		let this = self.get_mut();
+		let mut shared_state = this.shared_state.lock().unwrap();
+
+		// Picks up components that registered since the last poll
+		for (_, completion_token) in shared_state.components.iter().skip(this.completion_tokens.len()) {
+			this.completion_tokens.push(completion_token.clone());
+		}
+
+		let mut pending = false;
+		let mut abandoned = Vec::new();
+
+		for (completion_token, (name, _)) in this.completion_tokens.iter_mut().zip(shared_state.components.iter()) {
+			match completion_token.poll_result(cx) {
+				Poll::Ready(Ok(())) => {},
+				Poll::Ready(Err(_)) => abandoned.push(name.clone()),
+				Poll::Pending => pending = true
+			}
+		}
+
+		if !abandoned.is_empty() {
+			Poll::Ready(Err(ComponentsAbandoned { names: abandoned }))
+		} else if pending {
+			Poll::Pending
+		} else {
+			shared_state.ready = true;
+			Poll::Ready(Ok(()))
+		}
+	}
+}
+
+impl fmt::Display for RegisterError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			RegisterError::AlreadyReady => write!(f, "The ready set is already ready"),
+			RegisterError::Duplicate(name) => write!(f, "A component named {} is already registered", name)
+		}
+	}
+}
+
+impl Error for RegisterError {}
+
+impl fmt::Display for ComponentsAbandoned {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "Components were dropped without becoming ready: {}", self.names.join(", "))
+	}
+}
+
+impl Error for ComponentsAbandoned {}
+
+#[cfg(test)]
+mod tests {
+	use cooked_waker::IntoWaker;
+
+	use super::*;
+	use crate::tests::*;
+
+	#[test]
+	fn test_all_ready() {
+		let ready_set = ReadySet::new();
+
+		let test_waker = TestWaker::new();
+		let waker = test_waker.clone().into_waker();
+		let mut cx = Context::from_waker(&waker);
+
+		let db_pool = ready_set.register("db-pool").unwrap();
+		let listener = ready_set.register("listener").unwrap();
+		let cache = ready_set.register("cache").unwrap();
+
+		let mut all_ready = ready_set.all_ready();
+		assert!(Pin::new(&mut all_ready).poll(&mut cx).is_pending(), "Shouldn't be ready");
+
+		listener.complete(());
+		assert_eq!(ready_set.pending_components(), vec!["db-pool", "cache"], "Wrong pending components");
+
+		db_pool.complete(());
+		cache.complete(());
+
+		assert!(test_waker.woke(), "Should be woken");
+		assert_eq!(Pin::new(&mut all_ready).poll(&mut cx), Poll::Ready(Ok(())), "Should be ready");
+		assert!(ready_set.pending_components().is_empty(), "Nothing should be pending");
+	}
+
+	runtime_test! {
+		async fn test_multiple_observers() {
+			let ready_set = ReadySet::new();
+			let completable = ready_set.register("db-pool").unwrap();
+
+			let observers: Vec<_> = (0..3).map(|_| async_std::task::spawn(ready_set.all_ready())).collect();
+
+			async_std::task::sleep(std::time::Duration::from_millis(10)).await;
+			completable.complete(());
+
+			for observer in observers {
+				assert_eq!(observer.await, Ok(()), "Every observer should see the set become ready");
+			}
+		}
+	}
+
+	runtime_test! {
+		async fn test_abandoned() {
+			let ready_set = ReadySet::new();
+
+			let db_pool = ready_set.register("db-pool").unwrap();
+			let listener = ready_set.register("listener").unwrap();
+			let _cache = ready_set.register("cache").unwrap();
+
+			db_pool.complete(());
+			drop(listener);
+
+			let result = ready_set.all_ready().await;
+			assert_eq!(result, Err(ComponentsAbandoned { names: vec!["listener".to_string()] }), "Listener should be abandoned");
+			assert_eq!(result.unwrap_err().to_string(), "Components were dropped without becoming ready: listener", "Wrong message");
+		}
+	}
+
+	runtime_test! {
+		async fn test_late_registration_rejected() {
+			let ready_set = ReadySet::new();
+			ready_set.register("db-pool").unwrap().complete(());
+
+			ready_set.all_ready().await.unwrap();
+
+			assert_eq!(ready_set.register("late").err(), Some(RegisterError::AlreadyReady), "Late registration should be rejected");
+		}
+	}
+
+	#[test]
+	fn test_late_registration_rearms() {
+		let ready_set = ReadySet::with_late_registration(LateRegistration::Rearm);
+		ready_set.register("db-pool").unwrap().complete(());
+
+		assert_eq!(futures::executor::block_on(ready_set.all_ready()), Ok(()), "Should be ready");
+
+		let late = ready_set.register("late").unwrap();
+
+		let test_waker = TestWaker::new();
+		let waker = test_waker.into_waker();
+		let mut cx = Context::from_waker(&waker);
+
+		let mut all_ready = ready_set.all_ready();
+		assert!(Pin::new(&mut all_ready).poll(&mut cx).is_pending(), "Should wait for the late component");
+		assert_eq!(ready_set.pending_components(), vec!["late"], "Wrong pending components");
+
+		late.complete(());
+		assert_eq!(Pin::new(&mut all_ready).poll(&mut cx), Poll::Ready(Ok(())), "Should be ready");
+	}
+
+	#[test]
+	fn test_duplicate() {
+		let ready_set = ReadySet::new();
+		let _db_pool = ready_set.register("db-pool").unwrap();
+
+		assert_eq!(ready_set.register("db-pool").err(), Some(RegisterError::Duplicate("db-pool".to_string())), "Duplicates should be rejected");
+	}
+
+	#[test]
+	fn test_empty() {
+		let ready_set = ReadySet::new();
+		assert_eq!(futures::executor::block_on(ready_set.all_ready()), Ok(()), "An empty set is ready");
+	}
+}
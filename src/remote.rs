@@ -0,0 +1,252 @@
+// https://github.com/GWBasic/sync-tokens
+// (c) Andrew Rondeau
+// Apache 2.0 license
+// See https://github.com/GWBasic/sync-tokens/blob/main/LICENSE
+
+//! Propagates cancelation across a byte stream, such as a unix socket between a parent and a worker process. Requires
+//! the `remote` feature. See [`export()`](fn.export.html) and [`import()`](fn.import.html)
+//!
+//! When canceled, the exporting side writes a marker byte, followed by the reason's length, as a big-endian u32,
+//! and the reason in UTF-8. Nothing else is ever written to the stream. Reasons are limited to 64 KiB, so that a
+//! corrupt or hostile peer can't make the importing side allocate more than that
+//!
+//! ```
+//! use futures::io::Cursor;
+//! use sync_tokens::cancelation_token::CancelationToken;
+//! use sync_tokens::remote::{self, RemoteCancelation};
+//!
+//! # async_std::task::block_on(async {
+//! let (cancelation_token, cancelable) = CancelationToken::new();
+//! let mut stream = Cursor::new(Vec::new());
+//!
+//! cancelation_token.cancel();
+//! remote::export_with_reason(cancelable, &mut stream, "shutting down").await.unwrap();
+//!
+//! let (_cancelation_token, cancelable, import_driver) = remote::import(stream.get_ref().as_slice());
+//!
+//! let remote_cancelation = import_driver.run().await.unwrap();
+//! assert_eq!(remote_cancelation, RemoteCancelation::Canceled { reason: "shutting down".to_string() });
+//! assert!(cancelable.is_canceled());
+//! # });
+//! ```
+use std::convert::TryFrom;
+use std::io;
+
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::cancelation_token::{Cancelable, CancelationToken};
+
+const CANCEL_MARKER: u8 = 1;
+
+// The longest reason that's sent or accepted, in bytes
+const MAX_REASON_LEN: u32 = 64 * 1024;
+
+/// Why an [`ImportDriver`](struct.ImportDriver.html) canceled its [`Cancelable`](../cancelation_token/struct.Cancelable.html)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RemoteCancelation {
+	/// The other side was canceled
+	Canceled {
+		/// The reason that the other side sent. Empty if it was exported without a reason
+		reason: String
+	},
+	/// The stream closed without a cancelation, for example, because the other process exited
+	Closed
+}
+
+/// Reads cancelation from a stream, and cancels the matching [`Cancelable`](../cancelation_token/struct.Cancelable.html).
+/// Returned by [`import()`](fn.import.html)
+#[derive(Debug)]
+pub struct ImportDriver<R> {
+	reader: R,
+	cancelation_token: CancelationToken
+}
+
+/// Waits for cancelable to be canceled, and then writes the cancelation to writer. The returned future must be
+/// spawned, or otherwise awaited, for the cancelation to be sent
+pub async fn export<W>(cancelable: Cancelable, writer: W) -> io::Result<()> where
+W: AsyncWrite + Unpin {
+	export_with_reason(cancelable, writer, "").await
+}
+
+/// Waits for cancelable to be canceled, and then writes the cancelation, with reason, to writer. The returned future
+/// must be spawned, or otherwise awaited, for the cancelation to be sent. Returns
+/// [`io::ErrorKind::InvalidInput`](https://doc.rust-lang.org/std/io/enum.ErrorKind.html#variant.InvalidInput),
+/// without waiting, if reason is longer than 64 KiB
+pub async fn export_with_reason<W>(cancelable: Cancelable, mut writer: W, reason: &str) -> io::Result<()> where
+W: AsyncWrite + Unpin {
+	let reason_len = u32::try_from(reason.len())
+		.ok()
+		.filter(|reason_len| *reason_len <= MAX_REASON_LEN)
+		.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "The reason is too long"))?;
+
+	cancelable.future().await;
+
+	let mut message = Vec::with_capacity(5 + reason.len());
+	message.push(CANCEL_MARKER);
+	message.extend_from_slice(&reason_len.to_be_bytes());
+	message.extend_from_slice(reason.as_bytes());
+
+	writer.write_all(&message).await?;
+	writer.flush().await
+}
+
+/// Creates a new [`CancelationToken`](../cancelation_token/struct.CancelationToken.html) and
+/// [`Cancelable`](../cancelation_token/struct.Cancelable.html) that are canceled when reader receives a cancelation
+/// from [`export()`](fn.export.html), or when reader closes. Nothing happens until the returned
+/// [`ImportDriver`](struct.ImportDriver.html) is run
+pub fn import<R>(reader: R) -> (CancelationToken, Cancelable, ImportDriver<R>) where
+R: AsyncRead + Unpin {
+	let (cancelation_token, cancelable) = CancelationToken::new();
+
+	let import_driver = ImportDriver {
+		reader,
+		cancelation_token: cancelation_token.clone()
+	};
+
+	(cancelation_token, cancelable, import_driver)
+}
+
+impl<R> ImportDriver<R> where
+R: AsyncRead + Unpin {
+	/// Reads from the stream until it receives a cancelation or closes, and then cancels. The returned future must be
+	/// spawned, or otherwise awaited. A reason that the other side sent is passed on to
+	/// [`Cancelable::cancel_reason()`](../cancelation_token/struct.Cancelable.html#method.cancel_reason). If reading
+	/// fails, or the stream has unexpected data, this cancels and returns the error
+	pub async fn run(mut self) -> io::Result<RemoteCancelation> {
+		let result = self.read_cancelation().await;
+
+		match &result {
+			Ok(RemoteCancelation::Canceled { reason }) if !reason.is_empty() => self.cancelation_token.cancel_with_message(reason),
+			_ => self.cancelation_token.cancel()
+		}
+
+		result
+	}
+
+	async fn read_cancelation(&mut self) -> io::Result<RemoteCancelation> {
+		let mut marker = [0u8; 1];
+
+		if self.reader.read(&mut marker).await? == 0 {
+			return Ok(RemoteCancelation::Closed);
+		}
+
+		if marker[0] != CANCEL_MARKER {
+			return Err(io::Error::new(io::ErrorKind::InvalidData, "Unexpected data instead of a cancelation"));
+		}
+
+		let mut reason_len = [0u8; 4];
+		self.reader.read_exact(&mut reason_len).await?;
+
+		let reason_len = u32::from_be_bytes(reason_len);
+		if reason_len > MAX_REASON_LEN {
+			return Err(io::Error::new(io::ErrorKind::InvalidData, "The reason is too long"));
+		}
+
+		let mut reason = vec![0u8; reason_len as usize];
+		self.reader.read_exact(&mut reason).await?;
+
+		let reason = String::from_utf8(reason).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+		Ok(RemoteCancelation::Canceled { reason })
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use futures::io::Cursor;
+
+	use super::*;
+
+	async fn round_trip(reason: &str) -> (io::Result<RemoteCancelation>, Cancelable) {
+		let (cancelation_token, cancelable) = CancelationToken::new();
+		let mut stream = Cursor::new(Vec::new());
+
+		cancelation_token.cancel();
+		export_with_reason(cancelable, &mut stream, reason).await.unwrap();
+
+		let (_cancelation_token, cancelable, import_driver) = import(Cursor::new(stream.into_inner()));
+		(import_driver.run().await, cancelable)
+	}
+
+	runtime_test! {
+		async fn test_round_trip() {
+			let (remote_cancelation, cancelable) = round_trip("shutting down").await;
+
+			assert_eq!(remote_cancelation.unwrap(), RemoteCancelation::Canceled { reason: "shutting down".to_string() }, "Wrong reason");
+			assert!(cancelable.is_canceled(), "Should be canceled");
+			assert_eq!(cancelable.cancel_reason(), Some("shutting down".to_string()), "The reason should be passed on");
+
+			let (remote_cancelation, cancelable) = round_trip("").await;
+			assert_eq!(remote_cancelation.unwrap(), RemoteCancelation::Canceled { reason: String::new() }, "The reason should be empty");
+			assert_eq!(cancelable.cancel_reason(), None, "No reason was sent");
+		}
+	}
+
+	runtime_test! {
+		async fn test_reason_too_long() {
+			let mut message = vec![CANCEL_MARKER];
+			message.extend_from_slice(&u32::MAX.to_be_bytes());
+
+			let (_cancelation_token, cancelable, import_driver) = import(Cursor::new(message));
+
+			assert_eq!(import_driver.run().await.unwrap_err().kind(), io::ErrorKind::InvalidData, "A huge length should be rejected before reading");
+			assert!(cancelable.is_canceled(), "A broken stream should cancel");
+
+			let (cancelation_token, cancelable) = CancelationToken::new();
+			cancelation_token.cancel();
+
+			let reason = "x".repeat(MAX_REASON_LEN as usize + 1);
+			let exported = export_with_reason(cancelable, Cursor::new(Vec::new()), &reason).await;
+			assert_eq!(exported.unwrap_err().kind(), io::ErrorKind::InvalidInput, "A reason that's too long shouldn't be sent");
+		}
+	}
+
+	runtime_test! {
+		async fn test_closed() {
+			let (_cancelation_token, cancelable, import_driver) = import(Cursor::new(Vec::new()));
+
+			assert_eq!(import_driver.run().await.unwrap(), RemoteCancelation::Closed, "Should be closed");
+			assert!(cancelable.is_canceled(), "Closing should cancel");
+		}
+	}
+
+	runtime_test! {
+		async fn test_truncated() {
+			let (_cancelation_token, cancelable, import_driver) = import(Cursor::new(vec![CANCEL_MARKER, 0, 0]));
+
+			assert_eq!(import_driver.run().await.unwrap_err().kind(), io::ErrorKind::UnexpectedEof, "Wrong error");
+			assert!(cancelable.is_canceled(), "A broken stream should cancel");
+		}
+	}
+
+	runtime_test! {
+		async fn test_unexpected_data() {
+			let (_cancelation_token, _cancelable, import_driver) = import(Cursor::new(vec![42]));
+
+			assert_eq!(import_driver.run().await.unwrap_err().kind(), io::ErrorKind::InvalidData, "Wrong error");
+		}
+	}
+
+	runtime_test! {
+		#[cfg(unix)]
+		async fn test_across_socket() {
+			use async_std::os::unix::net::UnixStream;
+
+			let (parent_stream, worker_stream) = UnixStream::pair().unwrap();
+
+			let (parent_cancelation_token, parent_cancelable) = CancelationToken::new();
+			let exporting = async_std::task::spawn(export_with_reason(parent_cancelable, parent_stream, "parent stopped"));
+
+			let (_cancelation_token, worker_cancelable, import_driver) = import(worker_stream);
+			let importing = async_std::task::spawn(import_driver.run());
+
+			async_std::task::sleep(std::time::Duration::from_millis(10)).await;
+			assert!(!worker_cancelable.is_canceled(), "Shouldn't be canceled yet");
+
+			parent_cancelation_token.cancel();
+			exporting.await.unwrap();
+
+			assert_eq!(importing.await.unwrap(), RemoteCancelation::Canceled { reason: "parent stopped".to_string() }, "Wrong reason");
+			assert!(worker_cancelable.is_canceled(), "The worker should be canceled");
+		}
+	}
+}
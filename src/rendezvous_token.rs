@@ -0,0 +1,287 @@
+// https://github.com/GWBasic/sync-tokens
+// (c) Andrew Rondeau
+// Apache 2.0 license
+// See https://github.com/GWBasic/sync-tokens/blob/main/LICENSE
+
+//! Contains a one-shot meeting point where two tasks exchange values. See
+//! [`RendezvousToken`](struct.RendezvousToken.html)
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+
+/// One end of a one-shot rendezvous: it sends a value of type `T` and receives a value of type `U`.
+///
+/// Each task calls [`exchange()`](struct.RendezvousToken.html#method.exchange) on its end, and neither proceeds until
+/// both have arrived. A task that's waiting can still leave: if its exchange future is dropped before the other task
+/// arrives, its value is withdrawn, and the other task gets [`RendezvousAbandoned`](struct.RendezvousAbandoned.html)
+/// instead of proceeding alone. The same happens if an end is dropped without exchanging
+///
+/// ```
+/// use async_std::task;
+/// use sync_tokens::rendezvous_token::RendezvousToken;
+///
+/// # task::block_on(async {
+/// let (left, right) = RendezvousToken::new();
+///
+/// let right_task = task::spawn(async move {
+///     right.exchange("from the right").await
+/// });
+///
+/// assert_eq!(left.exchange(42).await, Ok("from the right"));
+/// assert_eq!(right_task.await, Ok(42));
+/// # });
+/// ```
+#[derive(Debug)]
+pub struct RendezvousToken<T, U> {
+	mine: Arc<Mutex<RendezvousSlot<T>>>,
+	theirs: Arc<Mutex<RendezvousSlot<U>>>,
+	// Both slots are always locked in the same order: the first end's slot, then the second end's slot
+	first: bool
+}
+
+/// Future returned by [`RendezvousToken::exchange()`](struct.RendezvousToken.html#method.exchange)
+#[derive(Debug)]
+pub struct RendezvousFuture<T, U> {
+	rendezvous_token: RendezvousToken<T, U>,
+	value: Option<T>
+}
+
+/// Error returned when the other end of a [`RendezvousToken`](struct.RendezvousToken.html) is dropped, or stops
+/// waiting, before both ends arrived
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RendezvousAbandoned;
+
+// The state of one end. The end's value waits here until the other end arrives and takes it
+#[derive(Debug)]
+struct RendezvousSlot<T> {
+	value: Option<T>,
+	arrived: bool,
+	matched: bool,
+	left: bool,
+	waker: Option<Waker>
+}
+
+impl<T, U> RendezvousToken<T, U> {
+	/// Creates both ends of a rendezvous
+	pub fn new() -> (RendezvousToken<T, U>, RendezvousToken<U, T>) {
+		let first = Arc::new(Mutex::new(RendezvousSlot::new()));
+		let second = Arc::new(Mutex::new(RendezvousSlot::new()));
+
+		let first_end = RendezvousToken {
+			mine: first.clone(),
+			theirs: second.clone(),
+			first: true
+		};
+
+		let second_end = RendezvousToken {
+			mine: second,
+			theirs: first,
+			first: false
+		};
+
+		(first_end, second_end)
+	}
+
+	/// Offers value to the other end, and waits for the other end's value. Returns
+	/// [`RendezvousAbandoned`](struct.RendezvousAbandoned.html) if the other end leaves first
+	pub fn exchange(self, value: T) -> RendezvousFuture<T, U> {
+		RendezvousFuture {
+			rendezvous_token: self,
+			value: Some(value)
+		}
+	}
+
+	fn lock(&self) -> (MutexGuard<'_, RendezvousSlot<T>>, MutexGuard<'_, RendezvousSlot<U>>) {
+		if self.first {
+			let mine = self.mine.lock().unwrap();
+			let theirs = self.theirs.lock().unwrap();
+			(mine, theirs)
+		} else {
+			let theirs = self.theirs.lock().unwrap();
+			let mine = self.mine.lock().unwrap();
+			(mine, theirs)
+		}
+	}
+}
+
+impl<T> RendezvousSlot<T> {
+	fn new() -> RendezvousSlot<T> {
+		RendezvousSlot {
+			value: None,
+			arrived: false,
+			matched: false,
+			left: false,
+			waker: None
+		}
+	}
+}
+
+impl<T, U> Drop for RendezvousToken<T, U> {
+	fn drop(&mut self) {
+		let (mut mine, mut theirs) = self.lock();
+
+		if !mine.matched {
+			// Withdraw, so that the other end can't proceed alone
+			mine.value = None;
+			mine.arrived = false;
+			mine.left = true;
+
+			wake_after_unlocking(theirs.waker.take(), mine, theirs);
+		}
+	}
+}
+
+// Woken after both locks are released, so that a waker that polls the other end right away doesn't wait for them
+fn wake_after_unlocking<T, U>(waker: Option<Waker>, mine: MutexGuard<'_, RendezvousSlot<T>>, theirs: MutexGuard<'_, RendezvousSlot<U>>) {
+	drop(mine);
+	drop(theirs);
+
+	if let Some(waker) = waker {
+		waker.wake();
+	}
+}
+
+// The value is never pinned
+impl<T, U> Unpin for RendezvousFuture<T, U> {}
+
+impl<T, U> Future for RendezvousFuture<T, U> {
+	type Output = Result<U, RendezvousAbandoned>;
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+		let this = self.get_mut();
+		let (mut mine, mut theirs) = this.rendezvous_token.lock();
+
+		if mine.matched {
+			// The other end arrived second, took this end's value, and left its own
+			let value = theirs.value.take().expect("Rendezvous already exchanged");
+			return Poll::Ready(Ok(value));
+		}
+
+		if theirs.left {
+			return Poll::Ready(Err(RendezvousAbandoned));
+		}
+
+		if theirs.arrived {
+			// Arrived second: swap values while holding both locks
+			let value = theirs.value.take().expect("Rendezvous already exchanged");
+			mine.value = this.value.take();
+			mine.matched = true;
+			theirs.matched = true;
+
+			wake_after_unlocking(theirs.waker.take(), mine, theirs);
+
+			return Poll::Ready(Ok(value));
+		}
+
+		if let Some(value) = this.value.take() {
+			mine.value = Some(value);
+			mine.arrived = true;
+		}
+
+		mine.waker = Some(cx.waker().clone());
+		Poll::Pending
+	}
+}
+
+impl fmt::Display for RendezvousAbandoned {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "The other end of the rendezvous left without exchanging")
+	}
+}
+
+impl Error for RendezvousAbandoned {}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use async_std::task;
+	use cooked_waker::IntoWaker;
+
+	use super::*;
+	use crate::tests::*;
+
+	runtime_test! {
+		async fn test_first_arrives_first() {
+			let (first, second) = RendezvousToken::new();
+
+			let first_task = task::spawn(first.exchange(1));
+			task::sleep(Duration::from_millis(10)).await;
+
+			assert_eq!(second.exchange("second").await, Ok(1), "Wrong value");
+			assert_eq!(first_task.await, Ok("second"), "Wrong value");
+		}
+	}
+
+	runtime_test! {
+		async fn test_second_arrives_first() {
+			let (first, second) = RendezvousToken::new();
+
+			let second_task = task::spawn(second.exchange("second"));
+			task::sleep(Duration::from_millis(10)).await;
+
+			assert_eq!(first.exchange(1).await, Ok("second"), "Wrong value");
+			assert_eq!(second_task.await, Ok(1), "Wrong value");
+		}
+	}
+
+	#[test]
+	fn test_neither_proceeds_alone() {
+		let (first, second) = RendezvousToken::<i32, i32>::new();
+
+		let first_waker = TestWaker::new();
+		let waker = first_waker.clone().into_waker();
+		let mut first_cx = Context::from_waker(&waker);
+
+		let mut first_future = first.exchange(1);
+		assert!(Pin::new(&mut first_future).poll(&mut first_cx).is_pending(), "Shouldn't proceed alone");
+		assert!(Pin::new(&mut first_future).poll(&mut first_cx).is_pending(), "Shouldn't proceed alone");
+
+		let second_waker = TestWaker::new();
+		let waker = second_waker.into_waker();
+		let mut second_cx = Context::from_waker(&waker);
+
+		let mut second_future = second.exchange(2);
+		assert_eq!(Pin::new(&mut second_future).poll(&mut second_cx), Poll::Ready(Ok(1)), "Wrong value");
+
+		assert!(first_waker.woke(), "First should be woken");
+		assert_eq!(Pin::new(&mut first_future).poll(&mut first_cx), Poll::Ready(Ok(2)), "Wrong value");
+	}
+
+	runtime_test! {
+		async fn test_abandoned() {
+			let (first, second) = RendezvousToken::<i32, i32>::new();
+
+			let first_task = task::spawn(first.exchange(1));
+			task::sleep(Duration::from_millis(10)).await;
+
+			drop(second);
+			assert_eq!(first_task.await, Err(RendezvousAbandoned), "First should be abandoned");
+
+			let (first, second) = RendezvousToken::<i32, i32>::new();
+			drop(first);
+			assert_eq!(second.exchange(2).await, Err(RendezvousAbandoned), "Second should be abandoned");
+		}
+	}
+
+	#[test]
+	fn test_canceled_mid_exchange() {
+		let (first, second) = RendezvousToken::<i32, i32>::new();
+
+		let test_waker = TestWaker::new();
+		let waker = test_waker.into_waker();
+		let mut cx = Context::from_waker(&waker);
+
+		let mut first_future = first.exchange(1);
+		assert!(Pin::new(&mut first_future).poll(&mut cx).is_pending(), "First should wait");
+
+		// First stops waiting, so its value is withdrawn
+		drop(first_future);
+
+		let mut second_future = second.exchange(2);
+		assert_eq!(Pin::new(&mut second_future).poll(&mut cx), Poll::Ready(Err(RendezvousAbandoned)), "Second shouldn't proceed alone");
+	}
+}
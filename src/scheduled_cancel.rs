@@ -0,0 +1,340 @@
+// https://github.com/GWBasic/sync-tokens
+// (c) Andrew Rondeau
+// Apache 2.0 license
+// See https://github.com/GWBasic/sync-tokens/blob/main/LICENSE
+
+//! Contains a handle to a cancelation that's scheduled for a deadline. See
+//! [`ScheduledCancel`](struct.ScheduledCancel.html)
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use crate::background;
+use crate::cancelation_token::{CancelationToken, WeakCancelationToken};
+use crate::timer::{Instant, Sleep, Timer};
+
+/// Cancels a [`CancelationToken`](../cancelation_token/struct.CancelationToken.html) at a deadline. Returned by
+/// [`CancelationToken::cancel_after()`](../cancelation_token/struct.CancelationToken.html#method.cancel_after) and
+/// [`CancelationToken::cancel_at()`](../cancelation_token/struct.CancelationToken.html#method.cancel_at).
+///
+/// The deadline can be moved with [`reschedule()`](struct.ScheduledCancel.html#method.reschedule), for example, when
+/// a client sends a keep-alive, or given up on with [`abort()`](struct.ScheduledCancel.html#method.abort). Each
+/// reschedule replaces the timer, so a timer for an old deadline never cancels after a successful reschedule.
+///
+/// Dropping the handle doesn't abort the cancelation. The timer stops once the token is canceled, for any reason, and
+/// it doesn't keep the token alive, so it doesn't count as a clone for
+/// [`CancelationToken::new_cancel_on_drop()`](../cancelation_token/struct.CancelationToken.html#method.new_cancel_on_drop)
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use sync_tokens::cancelation_token::CancelationToken;
+///
+/// # async_std::task::block_on(async {
+/// let (cancelation_token, cancelable) = CancelationToken::new();
+/// let scheduled_cancel = cancelation_token.cancel_after(Duration::from_secs(60));
+///
+/// // The client sent a keep-alive
+/// scheduled_cancel.reschedule_after(Duration::from_millis(10));
+///
+/// cancelable.future().await;
+/// assert!(scheduled_cancel.is_fired());
+/// # });
+/// ```
+#[derive(Debug)]
+pub struct ScheduledCancel {
+	shared_state: Arc<Mutex<ScheduledCancelState>>,
+	timer: Timer
+}
+
+// Sleeps until the deadline, and cancels the token, unless it's already canceled
+#[derive(Debug)]
+struct ScheduledCancelDriver {
+	shared_state: Arc<Mutex<ScheduledCancelState>>,
+	cancelation_token: WeakCancelationToken
+}
+
+#[derive(Debug)]
+struct ScheduledCancelState {
+	deadline: Instant,
+	// Created by whoever sets the deadline, on their thread, so that a timer that needs a runtime's context finds it
+	sleep: Sleep,
+	fired: bool,
+	aborted: bool,
+	// Set when the token was canceled some other way
+	canceled: bool,
+	driver_waker: Option<Waker>
+}
+
+impl ScheduledCancel {
+	/// Schedules cancelation_token to be canceled at deadline, using the given [`Timer`](../timer/struct.Timer.html)
+	pub fn with_timer(cancelation_token: CancelationToken, deadline: Instant, timer: Timer) -> ScheduledCancel {
+		let (scheduled_cancel, driver) = ScheduledCancel::schedule(&cancelation_token, deadline, timer);
+		background::spawn(driver);
+		scheduled_cancel
+	}
+
+	fn schedule(cancelation_token: &CancelationToken, deadline: Instant, timer: Timer) -> (ScheduledCancel, ScheduledCancelDriver) {
+		let shared_state = Arc::new(Mutex::new(ScheduledCancelState {
+			deadline,
+			sleep: timer.sleep_until(deadline),
+			fired: false,
+			aborted: false,
+			canceled: false,
+			driver_waker: None
+		}));
+
+		let driver = ScheduledCancelDriver {
+			shared_state: shared_state.clone(),
+			cancelation_token: cancelation_token.downgrade()
+		};
+
+		(ScheduledCancel { shared_state, timer }, driver)
+	}
+
+	/// Moves the deadline. Returns false if the token was already canceled, or the cancelation was aborted
+	pub fn reschedule(&self, deadline: Instant) -> bool {
+		let sleep = self.timer.sleep_until(deadline);
+		let mut shared_state = self.shared_state.lock().unwrap();
+
+		if shared_state.is_settled() {
+			return false;
+		}
+
+		shared_state.deadline = deadline;
+		shared_state.sleep = sleep;
+		ScheduledCancelState::wake_driver(shared_state);
+
+		true
+	}
+
+	/// Moves the deadline to duration from now. Returns false if the token was already canceled, or the cancelation
+	/// was aborted
+	pub fn reschedule_after(&self, duration: Duration) -> bool {
+		self.reschedule(self.timer.now() + duration)
+	}
+
+	/// Gives up on canceling the token. The token can still be canceled directly. Returns false if the token was
+	/// already canceled
+	pub fn abort(&self) -> bool {
+		let mut shared_state = self.shared_state.lock().unwrap();
+
+		if shared_state.fired || shared_state.canceled {
+			return false;
+		}
+
+		shared_state.aborted = true;
+		ScheduledCancelState::wake_driver(shared_state);
+
+		true
+	}
+
+	/// The deadline, or None if the token was already canceled, or the cancelation was aborted
+	pub fn deadline(&self) -> Option<Instant> {
+		let shared_state = self.shared_state.lock().unwrap();
+
+		if shared_state.is_settled() {
+			None
+		} else {
+			Some(shared_state.deadline)
+		}
+	}
+
+	/// Returns true once the deadline passed and the token was canceled
+	pub fn is_fired(&self) -> bool {
+		self.shared_state.lock().unwrap().fired
+	}
+}
+
+impl ScheduledCancelState {
+	fn is_settled(&self) -> bool {
+		self.fired || self.aborted || self.canceled
+	}
+
+	// Woken after the lock is released, so that a waker that runs the driver right away doesn't wait for the lock
+	fn wake_driver(mut shared_state: MutexGuard<'_, ScheduledCancelState>) {
+		let driver_waker = shared_state.driver_waker.take();
+		drop(shared_state);
+
+		if let Some(driver_waker) = driver_waker {
+			driver_waker.wake();
+		}
+	}
+}
+
+impl Future for ScheduledCancelDriver {
+	type Output = ();
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+		let this = self.get_mut();
+		let mut shared_state = this.shared_state.lock().unwrap();
+
+		if shared_state.aborted {
+			return Poll::Ready(());
+		}
+
+		if this.cancelation_token.poll_canceled(cx).is_ready() {
+			shared_state.canceled = true;
+			return Poll::Ready(());
+		}
+
+		// Woken when the deadline moves, because the new sleep hasn't seen this waker yet
+		shared_state.driver_waker = Some(cx.waker().clone());
+
+		if Pin::new(&mut shared_state.sleep).poll(cx).is_pending() {
+			return Poll::Pending;
+		}
+
+		shared_state.fired = true;
+		drop(shared_state);
+
+		// Canceled without holding the lock, so that anything that runs on cancelation can use the handle
+		this.cancelation_token.cancel();
+		Poll::Ready(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use futures::executor::LocalPool;
+	use futures::task::LocalSpawnExt;
+
+	use super::*;
+	use crate::timer::ManualClock;
+
+	fn schedule(deadline: Duration) -> (ManualClock, CancelationToken, ScheduledCancel, LocalPool) {
+		let clock = ManualClock::new();
+		let timer = Timer::new(clock.clone());
+		let (cancelation_token, _cancelable) = CancelationToken::new();
+
+		let (scheduled_cancel, driver) = ScheduledCancel::schedule(&cancelation_token, timer.now() + deadline, timer);
+
+		let pool = LocalPool::new();
+		pool.spawner().spawn_local(driver).unwrap();
+
+		(clock, cancelation_token, scheduled_cancel, pool)
+	}
+
+	#[test]
+	fn test_fires_at_deadline() {
+		let (clock, cancelation_token, scheduled_cancel, mut pool) = schedule(Duration::from_secs(10));
+		pool.run_until_stalled();
+
+		clock.advance(Duration::from_secs(10) - Duration::from_millis(1));
+		pool.run_until_stalled();
+		assert!(!cancelation_token.is_canceled(), "Shouldn't cancel before the deadline");
+
+		clock.advance(Duration::from_millis(1));
+		pool.run_until_stalled();
+		assert!(cancelation_token.is_canceled(), "Should cancel at the deadline");
+		assert!(scheduled_cancel.is_fired(), "Should be fired");
+		assert_eq!(scheduled_cancel.deadline(), None, "A fired cancelation has no deadline");
+		assert!(!scheduled_cancel.reschedule_after(Duration::from_secs(10)), "A fired cancelation can't be rescheduled");
+	}
+
+	#[test]
+	fn test_reschedule_wins_race_with_old_deadline() {
+		let (clock, cancelation_token, scheduled_cancel, mut pool) = schedule(Duration::from_secs(10));
+		pool.run_until_stalled();
+
+		// The old timer fires, but the driver doesn't run before the deadline is extended
+		clock.advance(Duration::from_secs(10));
+		assert!(scheduled_cancel.reschedule_after(Duration::from_secs(10)), "Should reschedule");
+
+		pool.run_until_stalled();
+		assert!(!cancelation_token.is_canceled(), "The old timer shouldn't cancel after the deadline was extended");
+		assert!(!scheduled_cancel.is_fired(), "Shouldn't be fired");
+		assert_eq!(scheduled_cancel.deadline(), Some(scheduled_cancel.timer.now() + Duration::from_secs(10)), "Wrong deadline");
+
+		clock.advance(Duration::from_secs(10));
+		pool.run_until_stalled();
+		assert!(cancelation_token.is_canceled(), "Should cancel at the new deadline");
+	}
+
+	#[test]
+	fn test_reschedule_earlier() {
+		let (clock, cancelation_token, scheduled_cancel, mut pool) = schedule(Duration::from_secs(60));
+		pool.run_until_stalled();
+
+		assert!(scheduled_cancel.reschedule_after(Duration::from_secs(1)), "Should reschedule");
+		pool.run_until_stalled();
+
+		clock.advance(Duration::from_secs(1));
+		pool.run_until_stalled();
+		assert!(cancelation_token.is_canceled(), "Should cancel at the earlier deadline");
+	}
+
+	#[test]
+	fn test_abort() {
+		let (clock, cancelation_token, scheduled_cancel, mut pool) = schedule(Duration::from_secs(10));
+		pool.run_until_stalled();
+
+		assert!(scheduled_cancel.abort(), "Should abort");
+		assert_eq!(scheduled_cancel.deadline(), None, "An aborted cancelation has no deadline");
+
+		clock.advance(Duration::from_secs(10));
+		pool.run_until_stalled();
+		assert!(!cancelation_token.is_canceled(), "An aborted cancelation shouldn't cancel");
+		assert!(!scheduled_cancel.reschedule_after(Duration::from_secs(10)), "An aborted cancelation can't be rescheduled");
+
+		cancelation_token.cancel();
+		assert!(cancelation_token.is_canceled(), "The token should still be usable");
+	}
+
+	#[test]
+	fn test_stops_when_canceled() {
+		let (clock, cancelation_token, scheduled_cancel, mut pool) = schedule(Duration::from_secs(10));
+		pool.run_until_stalled();
+
+		cancelation_token.cancel();
+		pool.run_until_stalled();
+		assert_eq!(scheduled_cancel.deadline(), None, "A canceled token has no deadline");
+		assert!(!scheduled_cancel.reschedule_after(Duration::from_secs(10)), "A canceled token can't be rescheduled");
+		assert!(!scheduled_cancel.abort(), "A canceled token can't be aborted");
+
+		cancelation_token.reset();
+		clock.advance(Duration::from_secs(10));
+		pool.run_until_stalled();
+		assert!(!cancelation_token.is_canceled(), "The timer should have stopped");
+		assert!(!scheduled_cancel.is_fired(), "Shouldn't be fired");
+	}
+
+	#[test]
+	fn test_doesnt_keep_cancel_on_drop_alive() {
+		let (cancelation_token, cancelable) = CancelationToken::new_cancel_on_drop();
+		let scheduled_cancel = cancelation_token.cancel_after(Duration::from_secs(3600));
+
+		drop(cancelation_token);
+		assert!(cancelable.is_canceled(), "The scheduled cancel shouldn't count as a clone");
+		assert!(!scheduled_cancel.is_fired(), "Shouldn't be fired");
+	}
+
+	#[cfg(feature = "tokio")]
+	#[tokio::test]
+	async fn test_tokio_timer() {
+		let (cancelation_token, cancelable) = CancelationToken::new();
+		let timer = Timer::tokio();
+		let start = Instant::now();
+
+		let scheduled_cancel = ScheduledCancel::with_timer(cancelation_token, timer.now() + Duration::from_secs(3600), timer);
+		assert!(scheduled_cancel.reschedule_after(Duration::from_millis(20)), "Should reschedule");
+
+		cancelable.future().await;
+		assert!(start.elapsed() >= Duration::from_millis(20), "Canceled early");
+		assert!(scheduled_cancel.is_fired(), "Should be fired");
+	}
+
+	runtime_test! {
+		async fn test_system_timer() {
+			let (cancelation_token, cancelable) = CancelationToken::new();
+			let start = Instant::now();
+
+			let _scheduled_cancel = cancelation_token.cancel_after(Duration::from_millis(20));
+
+			cancelable.future().await;
+			assert!(start.elapsed() >= Duration::from_millis(20), "Canceled early");
+		}
+	}
+}
@@ -0,0 +1,490 @@
+// https://github.com/GWBasic/sync-tokens
+// (c) Andrew Rondeau
+// Apache 2.0 license
+// See https://github.com/GWBasic/sync-tokens/blob/main/LICENSE
+
+//! Contains an asynchronous semaphore whose acquisition can be canceled. See [`Semaphore`](struct.Semaphore.html)
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use crate::cancelation_token::{Cancelable, CancelationTokenFuture, Canceled};
+
+/// Bounds how many operations run at the same time. Each operation holds a
+/// [`SemaphorePermit`](struct.SemaphorePermit.html) while it runs, and the permit is returned when it's dropped.
+///
+/// Waiting for a permit can be canceled, either by passing a [`Cancelable`](../cancelation_token/struct.Cancelable.html)
+/// to [`acquire()`](struct.Semaphore.html#method.acquire), or by passing the returned future to
+/// [`allow_cancel()`](../cancelation_token/struct.Cancelable.html#method.allow_cancel). In both cases, a waiter that
+/// is canceled never consumes or leaks a permit, even if a permit was handed to it right before it was canceled.
+///
+/// Waiters receive permits in the order that they started waiting
+///
+/// ```
+/// use sync_tokens::cancelation_token::CancelationToken;
+/// use sync_tokens::semaphore::Semaphore;
+///
+/// # async_std::task::block_on(async {
+/// let semaphore = Semaphore::new(32);
+/// let (cancelation_token, cancelable) = CancelationToken::new();
+///
+/// let permit = semaphore.acquire(Some(&cancelable)).await.unwrap();
+/// assert_eq!(semaphore.available_permits(), 31);
+/// drop(permit);
+///
+/// cancelation_token.cancel();
+/// assert!(semaphore.acquire(Some(&cancelable)).await.is_err());
+/// assert_eq!(semaphore.available_permits(), 32);
+/// # });
+/// ```
+#[derive(Debug, Clone)]
+pub struct Semaphore {
+	shared_state: Arc<Mutex<SemaphoreState>>
+}
+
+/// Held while an operation runs. The permit is returned to its [`Semaphore`](struct.Semaphore.html) when dropped
+#[derive(Debug)]
+pub struct SemaphorePermit {
+	shared_state: Arc<Mutex<SemaphoreState>>
+}
+
+/// Future returned by [`Semaphore::acquire()`](struct.Semaphore.html#method.acquire)
+///
+/// Dropping this future while it waits removes it from the queue. If a permit was already handed to it, the permit
+/// is passed on to the next waiter
+#[derive(Debug)]
+pub struct SemaphoreAcquireFuture {
+	shared_state: Arc<Mutex<SemaphoreState>>,
+	cancelation_token_future: Option<CancelationTokenFuture>,
+	waiter_id: Option<usize>
+}
+
+#[derive(Debug)]
+struct SemaphoreState {
+	permits: usize,
+	waiters: VecDeque<SemaphoreWaiter>,
+	next_waiter_id: usize
+}
+
+#[derive(Debug)]
+struct SemaphoreWaiter {
+	id: usize,
+	granted: bool,
+	waker: Option<Waker>
+}
+
+impl Semaphore {
+	/// Creates a new [`Semaphore`](struct.Semaphore.html) with the given number of permits
+	pub fn new(permits: usize) -> Semaphore {
+		Semaphore {
+			shared_state: Arc::new(Mutex::new(SemaphoreState {
+				permits,
+				waiters: VecDeque::new(),
+				next_waiter_id: 0
+			}))
+		}
+	}
+
+	/// Waits for a permit. If a [`Cancelable`](../cancelation_token/struct.Cancelable.html) is given, the returned
+	/// future resolves to [`Canceled`](../cancelation_token/struct.Canceled.html) as soon as it's canceled
+	pub fn acquire(&self, cancelable: Option<&Cancelable>) -> SemaphoreAcquireFuture {
+		SemaphoreAcquireFuture {
+			shared_state: self.shared_state.clone(),
+			cancelation_token_future: cancelable.map(|cancelable| cancelable.future()),
+			waiter_id: None
+		}
+	}
+
+	/// Returns a permit if one is available without waiting
+	pub fn try_acquire(&self) -> Option<SemaphorePermit> {
+		let mut shared_state = self.shared_state.lock().unwrap();
+
+		if shared_state.permits > 0 {
+			shared_state.permits -= 1;
+			Some(SemaphorePermit {
+				shared_state: self.shared_state.clone()
+			})
+		} else {
+			None
+		}
+	}
+
+	/// Adds permits to the semaphore, waking waiters as needed
+	pub fn add_permits(&self, permits: usize) {
+		let mut shared_state = self.shared_state.lock().unwrap();
+		shared_state.release(permits);
+	}
+
+	/// The number of permits that can be acquired without waiting
+	pub fn available_permits(&self) -> usize {
+		let shared_state = self.shared_state.lock().unwrap();
+		shared_state.permits
+	}
+}
+
+impl SemaphoreState {
+	fn release(&mut self, permits: usize) {
+		self.permits += permits;
+
+		// Permits are handed directly to waiters, in order, so that a new caller can't jump the queue
+		for waiter in self.waiters.iter_mut() {
+			if self.permits == 0 {
+				break;
+			}
+
+			if !waiter.granted {
+				waiter.granted = true;
+				self.permits -= 1;
+
+				if let Some(waker) = waiter.waker.take() {
+					waker.wake()
+				}
+			}
+		}
+	}
+
+	fn remove_waiter(&mut self, id: usize) {
+		if let Some(position) = self.waiters.iter().position(|waiter| waiter.id == id) {
+			let waiter = self.waiters.remove(position).unwrap();
+
+			if waiter.granted {
+				self.release(1);
+			}
+		}
+	}
+}
+
+impl Future for SemaphoreAcquireFuture {
+	type Output = Result<SemaphorePermit, Canceled>;
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+		let this = self.get_mut();
+
+		if let Some(cancelation_token_future) = this.cancelation_token_future.as_mut() {
+			if Pin::new(cancelation_token_future).poll(cx).is_ready() {
+				if let Some(waiter_id) = this.waiter_id.take() {
+					let mut shared_state = this.shared_state.lock().unwrap();
+					shared_state.remove_waiter(waiter_id);
+				}
+
+				return Poll::Ready(Err(Canceled));
+			}
+		}
+
+		let mut shared_state = this.shared_state.lock().unwrap();
+
+		match this.waiter_id {
+			None => {
+				// Permits are only ever available when every waiter already has one
+				if shared_state.permits > 0 {
+					shared_state.permits -= 1;
+					return Poll::Ready(Ok(SemaphorePermit {
+						shared_state: this.shared_state.clone()
+					}));
+				}
+
+				let id = shared_state.next_waiter_id;
+				shared_state.next_waiter_id = shared_state.next_waiter_id.wrapping_add(1);
+				shared_state.waiters.push_back(SemaphoreWaiter {
+					id,
+					granted: false,
+					waker: Some(cx.waker().clone())
+				});
+
+				this.waiter_id = Some(id);
+				Poll::Pending
+			},
+			Some(waiter_id) => {
+				let position = shared_state.waiters.iter()
+					.position(|waiter| waiter.id == waiter_id)
+					.expect("Waiter missing from the semaphore's queue");
+
+				if shared_state.waiters[position].granted {
+					shared_state.waiters.remove(position);
+					this.waiter_id = None;
+
+					Poll::Ready(Ok(SemaphorePermit {
+						shared_state: this.shared_state.clone()
+					}))
+				} else {
+					let waker = &mut shared_state.waiters[position].waker;
+
+					// The same task usually polls again, so avoid cloning the same waker
+					if !waker.as_ref().is_some_and(|waker| waker.will_wake(cx.waker())) {
+						*waker = Some(cx.waker().clone());
+					}
+
+					Poll::Pending
+				}
+			}
+		}
+	}
+}
+
+impl Drop for SemaphoreAcquireFuture {
+	fn drop(&mut self) {
+		if let Some(waiter_id) = self.waiter_id.take() {
+			let mut shared_state = self.shared_state.lock().unwrap();
+			shared_state.remove_waiter(waiter_id);
+		}
+	}
+}
+
+impl Drop for SemaphorePermit {
+	fn drop(&mut self) {
+		let mut shared_state = self.shared_state.lock().unwrap();
+		shared_state.release(1);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::atomic::{AtomicUsize, Ordering};
+	use std::time::Duration;
+
+	use async_std::task;
+	use cooked_waker::IntoWaker;
+
+	use super::*;
+	use crate::cancelation_token::CancelationToken;
+	use crate::tests::*;
+
+	fn poll_acquire(future: &mut SemaphoreAcquireFuture, cx: &mut Context<'_>) -> Poll<Result<SemaphorePermit, Canceled>> {
+		Pin::new(future).poll(cx)
+	}
+
+	#[test]
+	fn test_try_acquire() {
+		let semaphore = Semaphore::new(2);
+
+		let first = semaphore.try_acquire().expect("First permit should be available");
+		let second = semaphore.try_acquire().expect("Second permit should be available");
+		assert!(semaphore.try_acquire().is_none(), "No permits should be available");
+		assert_eq!(semaphore.available_permits(), 0, "Wrong number of permits");
+
+		drop(first);
+		assert_eq!(semaphore.available_permits(), 1, "Permit not returned");
+
+		drop(second);
+		assert_eq!(semaphore.available_permits(), 2, "Permit not returned");
+	}
+
+	#[test]
+	fn test_add_permits() {
+		let semaphore = Semaphore::new(0);
+
+		let test_waker = TestWaker::new();
+		let waker = test_waker.clone().into_waker();
+		let mut cx = Context::from_waker(&waker);
+
+		let mut future = semaphore.acquire(None);
+		assert!(poll_acquire(&mut future, &mut cx).is_pending(), "Acquire should wait");
+
+		semaphore.add_permits(2);
+		assert!(test_waker.woke(), "Waiter should be woken");
+		assert_eq!(semaphore.available_permits(), 1, "One permit should go to the waiter");
+
+		match poll_acquire(&mut future, &mut cx) {
+			Poll::Ready(Ok(permit)) => drop(permit),
+			_ => panic!("Acquire should have a permit")
+		}
+
+		assert_eq!(semaphore.available_permits(), 2, "Permit not returned");
+	}
+
+	#[test]
+	fn test_waiters_are_served_in_order() {
+		let semaphore = Semaphore::new(1);
+		let permit = semaphore.try_acquire().unwrap();
+
+		let test_waker = TestWaker::new();
+		let waker = test_waker.clone().into_waker();
+		let mut cx = Context::from_waker(&waker);
+
+		let mut first = semaphore.acquire(None);
+		let mut second = semaphore.acquire(None);
+		assert!(poll_acquire(&mut first, &mut cx).is_pending(), "First should wait");
+		assert!(poll_acquire(&mut second, &mut cx).is_pending(), "Second should wait");
+
+		drop(permit);
+
+		assert!(poll_acquire(&mut second, &mut cx).is_pending(), "Second shouldn't jump the queue");
+		assert!(semaphore.try_acquire().is_none(), "try_acquire shouldn't jump the queue");
+
+		let permit = match poll_acquire(&mut first, &mut cx) {
+			Poll::Ready(Ok(permit)) => permit,
+			_ => panic!("First should have the permit")
+		};
+
+		drop(permit);
+
+		assert!(poll_acquire(&mut second, &mut cx).is_ready(), "Second should have the permit");
+	}
+
+	#[test]
+	fn test_canceled_while_queued() {
+		let semaphore = Semaphore::new(1);
+		let permit = semaphore.try_acquire().unwrap();
+
+		let (cancelation_token, cancelable) = CancelationToken::new();
+
+		let test_waker = TestWaker::new();
+		let waker = test_waker.clone().into_waker();
+		let mut cx = Context::from_waker(&waker);
+
+		let mut future = semaphore.acquire(Some(&cancelable));
+		assert!(poll_acquire(&mut future, &mut cx).is_pending(), "Acquire should wait");
+
+		cancelation_token.cancel();
+		assert!(test_waker.woke(), "Cancel should wake the waiter");
+
+		match poll_acquire(&mut future, &mut cx) {
+			Poll::Ready(Err(Canceled)) => {},
+			_ => panic!("Acquire should be canceled")
+		}
+
+		drop(permit);
+		assert_eq!(semaphore.available_permits(), 1, "Canceled waiter consumed a permit");
+
+		drop(future);
+		assert_eq!(semaphore.available_permits(), 1, "Canceled waiter consumed a permit");
+	}
+
+	#[test]
+	fn test_canceled_after_grant() {
+		let semaphore = Semaphore::new(1);
+		let permit = semaphore.try_acquire().unwrap();
+
+		let (cancelation_token, cancelable) = CancelationToken::new();
+
+		let test_waker = TestWaker::new();
+		let waker = test_waker.clone().into_waker();
+		let mut cx = Context::from_waker(&waker);
+
+		let mut future = semaphore.acquire(Some(&cancelable));
+		assert!(poll_acquire(&mut future, &mut cx).is_pending(), "Acquire should wait");
+
+		// The permit is handed to the waiter, but it's canceled before it can observe it
+		drop(permit);
+		cancelation_token.cancel();
+
+		match poll_acquire(&mut future, &mut cx) {
+			Poll::Ready(Err(Canceled)) => {},
+			_ => panic!("Acquire should be canceled")
+		}
+
+		assert_eq!(semaphore.available_permits(), 1, "Granted permit leaked");
+	}
+
+	#[test]
+	fn test_dropped_after_grant() {
+		let semaphore = Semaphore::new(1);
+		let permit = semaphore.try_acquire().unwrap();
+
+		let test_waker = TestWaker::new();
+		let waker = test_waker.clone().into_waker();
+		let mut cx = Context::from_waker(&waker);
+
+		let mut first = semaphore.acquire(None);
+		let mut second = semaphore.acquire(None);
+		assert!(poll_acquire(&mut first, &mut cx).is_pending(), "First should wait");
+		assert!(poll_acquire(&mut second, &mut cx).is_pending(), "Second should wait");
+
+		drop(permit);
+		drop(first);
+
+		assert!(poll_acquire(&mut second, &mut cx).is_ready(), "The dropped waiter's permit should pass to the next waiter");
+		assert_eq!(semaphore.available_permits(), 1, "Permit leaked");
+	}
+
+	runtime_test! {
+		async fn test_via_allow_cancel() {
+			let semaphore = Semaphore::new(1);
+			let permit = semaphore.try_acquire().unwrap();
+
+			let (cancelation_token, cancelable) = CancelationToken::new();
+
+			let waiter = {
+				let semaphore = semaphore.clone();
+				task::spawn(async move {
+					cancelable.allow_cancel(semaphore.acquire(None), Err(Canceled)).await.map(|_| ())
+				})
+			};
+
+			task::sleep(Duration::from_millis(10)).await;
+			cancelation_token.cancel();
+
+			assert_eq!(waiter.await, Err(Canceled), "Acquire should be canceled");
+
+			drop(permit);
+			assert_eq!(semaphore.available_permits(), 1, "Canceled waiter consumed a permit");
+		}
+	}
+
+	async fn stress(use_allow_cancel: bool) {
+		const PERMITS: usize = 32;
+		const TASKS: usize = 500;
+
+		let semaphore = Semaphore::new(PERMITS);
+		let (cancelation_token, cancelable) = CancelationToken::new();
+		let in_flight = Arc::new(AtomicUsize::new(0));
+		let max_in_flight = Arc::new(AtomicUsize::new(0));
+
+		let mut handles = Vec::new();
+		for i in 0..TASKS {
+			let semaphore = semaphore.clone();
+			let cancelable = cancelable.clone();
+			let in_flight = in_flight.clone();
+			let max_in_flight = max_in_flight.clone();
+
+			handles.push(task::spawn(async move {
+				let permit = if use_allow_cancel {
+					cancelable.allow_cancel(semaphore.acquire(None), Err(Canceled)).await
+				} else {
+					semaphore.acquire(Some(&cancelable)).await
+				};
+
+				if let Ok(permit) = permit {
+					let now_in_flight = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+					max_in_flight.fetch_max(now_in_flight, Ordering::SeqCst);
+
+					task::sleep(Duration::from_micros((i % 7) as u64 * 100)).await;
+
+					in_flight.fetch_sub(1, Ordering::SeqCst);
+					drop(permit);
+				}
+			}));
+		}
+
+		task::sleep(Duration::from_millis(5)).await;
+		cancelation_token.cancel();
+
+		for handle in handles {
+			handle.await;
+		}
+
+		assert!(max_in_flight.load(Ordering::SeqCst) <= PERMITS, "Too many permits handed out");
+		assert_eq!(semaphore.available_permits(), PERMITS, "Permits leaked");
+
+		let permits: Vec<_> = (0..PERMITS).map(|_| semaphore.try_acquire()).collect();
+		assert!(permits.iter().all(|permit| permit.is_some()), "All permits should be available");
+	}
+
+	runtime_test! {
+		async fn test_stress_cancelable_acquire() {
+			for _ in 0..10 {
+				stress(false).await;
+			}
+		}
+	}
+
+	runtime_test! {
+		async fn test_stress_allow_cancel() {
+			for _ in 0..10 {
+				stress(true).await;
+			}
+		}
+	}
+}
@@ -0,0 +1,205 @@
+// https://github.com/GWBasic/sync-tokens
+// (c) Andrew Rondeau
+// Apache 2.0 license
+// See https://github.com/GWBasic/sync-tokens/blob/main/LICENSE
+
+//! Contains a trait for background services that can be started, waited on until they're ready, and stopped. See
+//! [`Service`](trait.Service.html) and [`ServiceHandle`](struct.ServiceHandle.html)
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+
+use crate::cancelation_token::CancelationToken;
+use crate::completion_token::{Abandoned, CompletionToken};
+
+/// A service that runs on a background task. Starting it returns a [`ServiceHandle`](struct.ServiceHandle.html), which
+/// waits until the service is ready, and stops it.
+///
+/// This is the pattern shown in [`sync-tokens`](../index.html): the service completes a
+/// [`CompletionToken`](../completion_token/struct.CompletionToken.html) once it's ready, and stops when its
+/// [`CancelationToken`](../cancelation_token/struct.CancelationToken.html) is canceled
+///
+/// ```
+/// use std::io::{Error, ErrorKind};
+///
+/// use async_std::io::Result;
+/// use async_std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+/// use async_std::task;
+///
+/// use sync_tokens::cancelation_token::{Cancelable, CancelationToken};
+/// use sync_tokens::completion_token::{Completable, CompletionToken};
+/// use sync_tokens::service::{Service, ServiceHandle};
+///
+/// struct Server;
+///
+/// impl Service for Server {
+///     type Ready = SocketAddr;
+///     type Output = Result<()>;
+///
+///     fn start(self) -> ServiceHandle<Self::Ready, Self::Output> {
+///         let (completion_token, completable) = CompletionToken::new();
+///         let (cancelation_token, cancelable) = CancelationToken::new();
+///
+///         let join_handle = task::spawn(run_server(completable, cancelable));
+///
+///         ServiceHandle::from_parts(join_handle, completion_token, cancelation_token)
+///     }
+/// }
+///
+/// // If binding fails, the completable is dropped, so ready() returns Abandoned, and stop() returns the error
+/// async fn run_server(completable: Completable<SocketAddr>, cancelable: Cancelable) -> Result<()> {
+///     let listener = TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).await?;
+///     completable.complete(listener.local_addr()?);
+///
+///     let mut incoming_future = task::spawn(accept(listener));
+///
+///     loop {
+///         let (listener, _) = cancelable.allow_cancel(
+///             incoming_future,
+///             Err(Error::new(ErrorKind::Interrupted, "Server terminated")))
+///             .await?;
+///
+///         incoming_future = task::spawn(accept(listener));
+///     }
+/// }
+///
+/// async fn accept(listener: TcpListener) -> Result<(TcpListener, TcpStream)> {
+///     let (stream, _) = listener.accept().await?;
+///     Ok((listener, stream))
+/// }
+///
+/// # task::block_on(async {
+/// let service_handle = Server.start();
+///
+/// let local_addr = service_handle.ready().await.unwrap();
+/// TcpStream::connect(local_addr).await.unwrap();
+///
+/// let err = service_handle.stop().await.unwrap_err();
+/// assert_eq!(err.kind(), ErrorKind::Interrupted);
+/// # });
+/// ```
+pub trait Service {
+	/// What the service reports once it's ready, such as the address it's listening on
+	type Ready;
+	/// What the service's task returns once it stops
+	type Output;
+
+	/// Starts the service on a background task
+	fn start(self) -> ServiceHandle<Self::Ready, Self::Output>;
+}
+
+/// Controls a started [`Service`](trait.Service.html). Can also be made from the parts that existing code already
+/// returns, via [`from_parts()`](struct.ServiceHandle.html#method.from_parts)
+pub struct ServiceHandle<R, O = ()> {
+	join_handle: Pin<Box<dyn Future<Output = O> + Send>>,
+	completion_token: CompletionToken<R>,
+	cancelation_token: CancelationToken
+}
+
+impl<R, O> ServiceHandle<R, O> {
+	/// Creates a [`ServiceHandle`](struct.ServiceHandle.html) from the service's task (typically a `JoinHandle`), the
+	/// [`CompletionToken`](../completion_token/struct.CompletionToken.html) that it completes once it's ready, and the
+	/// [`CancelationToken`](../cancelation_token/struct.CancelationToken.html) that stops it
+	pub fn from_parts<J>(join_handle: J, completion_token: CompletionToken<R>, cancelation_token: CancelationToken) -> ServiceHandle<R, O> where
+	J: Future<Output = O> + Send + 'static {
+		ServiceHandle {
+			join_handle: Box::pin(join_handle),
+			completion_token,
+			cancelation_token
+		}
+	}
+
+	/// Waits until the service is ready, and returns a clone of the value that it's ready with. Returns
+	/// [`Abandoned`](../completion_token/struct.Abandoned.html) if the service stopped without becoming ready. The
+	/// value is retained, so this can be called any number of times
+	pub async fn ready(&self) -> Result<R, Abandoned> where
+	R: Clone {
+		self.completion_token.subscribe().try_wait().await
+	}
+
+	/// Cancels the service, and then waits for its task to finish
+	pub async fn stop(self) -> O {
+		self.cancelation_token.cancel();
+		self.join().await
+	}
+
+	/// Waits for the service's task to finish, without canceling it
+	pub async fn join(self) -> O {
+		self.join_handle.await
+	}
+
+	/// The [`CancelationToken`](../cancelation_token/struct.CancelationToken.html) that stops the service
+	pub fn cancelation_token(&self) -> &CancelationToken {
+		&self.cancelation_token
+	}
+}
+
+impl<R, O> fmt::Debug for ServiceHandle<R, O> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("ServiceHandle")
+			.field("cancelation_token", &self.cancelation_token)
+			.finish()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::cancelation_token::Cancelable;
+	use crate::completion_token::Completable;
+
+	struct Worker;
+
+	impl Service for Worker {
+		type Ready = &'static str;
+		type Output = u32;
+
+		fn start(self) -> ServiceHandle<&'static str, u32> {
+			let (completion_token, completable) = CompletionToken::new();
+			let (cancelation_token, cancelable) = CancelationToken::new();
+
+			ServiceHandle::from_parts(async_std::task::spawn(work(completable, cancelable)), completion_token, cancelation_token)
+		}
+	}
+
+	async fn work(completable: Completable<&'static str>, cancelable: Cancelable) -> u32 {
+		completable.complete("ready");
+		cancelable.future().await;
+		42
+	}
+
+	runtime_test! {
+		async fn test_start_ready_stop() {
+			let service_handle = Worker.start();
+
+			assert_eq!(service_handle.ready().await, Ok("ready"), "Wrong ready value");
+			assert_eq!(service_handle.ready().await, Ok("ready"), "The ready value should be retained");
+			assert!(!service_handle.cancelation_token().is_canceled(), "Shouldn't be canceled yet");
+
+			assert_eq!(service_handle.stop().await, 42, "Wrong output");
+		}
+	}
+
+	runtime_test! {
+		async fn test_join() {
+			let service_handle = Worker.start();
+			service_handle.cancelation_token().clone().cancel();
+
+			assert_eq!(service_handle.join().await, 42, "Wrong output");
+		}
+	}
+
+	runtime_test! {
+		async fn test_never_ready() {
+			let (completion_token, completable) = CompletionToken::<()>::new();
+			let (cancelation_token, _cancelable) = CancelationToken::new();
+
+			let service_handle: ServiceHandle<(), ()> = ServiceHandle::from_parts(async_std::task::spawn(async move {
+				drop(completable);
+			}), completion_token, cancelation_token);
+
+			assert_eq!(service_handle.ready().await, Err(Abandoned), "A service that stops before it's ready should be abandoned");
+			service_handle.join().await;
+		}
+	}
+}
@@ -0,0 +1,470 @@
+// https://github.com/GWBasic/sync-tokens
+// (c) Andrew Rondeau
+// Apache 2.0 license
+// See https://github.com/GWBasic/sync-tokens/blob/main/LICENSE
+
+//! Contains structs to cancel a group of workers and wait, up to a deadline, for them to finish. See
+//! [`ShutdownController`](struct.ShutdownController.html) and [`graceful_shutdown()`](fn.graceful_shutdown.html)
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::future::{Either, select};
+use futures::stream::{FuturesUnordered, StreamExt};
+
+use crate::cancelation_token::{Cancelable, CancelationToken};
+use crate::timer::Timer;
+use crate::wakers::{WakerKey, WakerList, wake_each};
+
+/// Cancels a group of workers, and then waits for them to finish, up to a grace period.
+///
+/// Each worker calls [`subscribe()`](struct.ShutdownController.html#method.subscribe) to get a
+/// [`Cancelable`](../cancelation_token/struct.Cancelable.html) and a [`ShutdownGuard`](struct.ShutdownGuard.html).
+/// The worker stops when it's canceled, and drops the guard once it's finished cleaning up.
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use async_std::task;
+/// use sync_tokens::shutdown_controller::ShutdownController;
+///
+/// # task::block_on(async {
+/// let shutdown_controller = ShutdownController::new();
+///
+/// for _ in 0..10 {
+///     let (cancelable, shutdown_guard) = shutdown_controller.subscribe();
+///     task::spawn(async move {
+///         cancelable.future().await;
+///         drop(shutdown_guard);
+///     });
+/// }
+///
+/// let shutdown_report = shutdown_controller.shutdown(Duration::from_secs(5)).await;
+/// assert_eq!(shutdown_report.finished, 10);
+/// assert_eq!(shutdown_report.abandoned, 0);
+/// # });
+/// ```
+#[derive(Debug, Clone)]
+pub struct ShutdownController {
+	cancelation_token: CancelationToken,
+	cancelable: Cancelable,
+	shared_state: Arc<Mutex<ShutdownState>>,
+	timer: Timer
+}
+
+/// Held by a worker until it finishes. Dropping the guard tells the [`ShutdownController`](struct.ShutdownController.html)
+/// that the worker is finished
+#[derive(Debug)]
+pub struct ShutdownGuard {
+	shared_state: Arc<Mutex<ShutdownState>>
+}
+
+/// What happened during [`ShutdownController::shutdown()`](struct.ShutdownController.html#method.shutdown)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShutdownReport {
+	/// Workers that finished within the grace period
+	pub finished: usize,
+	/// Workers that were still running when the grace period ended
+	pub abandoned: usize,
+	/// How long shutdown waited
+	pub elapsed: Duration
+}
+
+/// What happened during [`graceful_shutdown()`](fn.graceful_shutdown.html) and
+/// [`graceful_shutdown_labeled()`](fn.graceful_shutdown_labeled.html). Tasks are identified by their index, or by
+/// their label
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TaskShutdownReport<L> {
+	/// Tasks that finished within the grace period, in the order that they finished
+	pub finished: Vec<L>,
+	/// Tasks that were still pending when the grace period ended, in the order that they were given
+	pub pending: Vec<L>,
+	/// How long shutdown waited
+	pub elapsed: Duration
+}
+
+#[derive(Debug)]
+struct ShutdownState {
+	running: usize,
+	wakers: WakerList
+}
+
+struct AllFinishedFuture {
+	shared_state: Arc<Mutex<ShutdownState>>,
+	waker_key: Option<WakerKey>
+}
+
+impl ShutdownController {
+	/// Creates a new [`ShutdownController`](struct.ShutdownController.html) that uses the system clock
+	pub fn new() -> ShutdownController {
+		ShutdownController::with_timer(Timer::default())
+	}
+
+	/// Creates a new [`ShutdownController`](struct.ShutdownController.html) that uses the given
+	/// [`Timer`](../timer/struct.Timer.html) for the grace period
+	pub fn with_timer(timer: Timer) -> ShutdownController {
+		let (cancelation_token, cancelable) = CancelationToken::new();
+
+		ShutdownController {
+			cancelation_token,
+			cancelable,
+			shared_state: Arc::new(Mutex::new(ShutdownState {
+				running: 0,
+				wakers: WakerList::new()
+			})),
+			timer
+		}
+	}
+
+	/// Subscribes a worker. The worker should stop when the [`Cancelable`](../cancelation_token/struct.Cancelable.html)
+	/// is canceled, and drop the [`ShutdownGuard`](struct.ShutdownGuard.html) when it's finished. Workers that
+	/// subscribe after shutdown starts are canceled immediately
+	pub fn subscribe(&self) -> (Cancelable, ShutdownGuard) {
+		let mut shared_state = self.shared_state.lock().unwrap();
+		shared_state.running += 1;
+
+		let shutdown_guard = ShutdownGuard {
+			shared_state: self.shared_state.clone()
+		};
+
+		(self.cancelable.clone(), shutdown_guard)
+	}
+
+	/// The number of subscribed workers that haven't dropped their [`ShutdownGuard`](struct.ShutdownGuard.html)
+	pub fn running(&self) -> usize {
+		self.shared_state.lock().unwrap().running
+	}
+
+	/// Returns true once shutdown starts
+	pub fn is_shutting_down(&self) -> bool {
+		self.cancelation_token.is_canceled()
+	}
+
+	/// Cancels every worker, and then waits up to grace for them to finish
+	pub async fn shutdown(&self, grace: Duration) -> ShutdownReport {
+		let start = self.timer.now();
+		let running_at_start = self.running();
+
+		self.cancelation_token.cancel();
+
+		let all_finished = AllFinishedFuture {
+			shared_state: self.shared_state.clone(),
+			waker_key: None
+		};
+
+		let abandoned = match select(all_finished, self.timer.sleep(grace)).await {
+			Either::Left(_) => 0,
+			Either::Right(_) => self.running()
+		};
+
+		ShutdownReport {
+			finished: running_at_start.saturating_sub(abandoned),
+			abandoned,
+			elapsed: self.timer.now() - start
+		}
+	}
+}
+
+/// Cancels cancelation_token, and then waits up to grace for every task, typically a
+/// [`JoinHandle`](https://docs.rs/async-std/latest/async_std/task/struct.JoinHandle.html), to finish. Tasks are
+/// identified by their index in the report.
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use async_std::task;
+/// use sync_tokens::cancelation_token::CancelationToken;
+/// use sync_tokens::shutdown_controller::graceful_shutdown;
+///
+/// # task::block_on(async {
+/// let (cancelation_token, cancelable) = CancelationToken::new();
+///
+/// let tasks: Vec<_> = (0..3).map(|_| {
+///     let cancelable = cancelable.clone();
+///     task::spawn(async move { cancelable.future().await })
+/// }).collect();
+///
+/// let task_shutdown_report = graceful_shutdown(&cancelation_token, tasks, Duration::from_secs(5)).await;
+/// assert_eq!(task_shutdown_report.finished.len(), 3);
+/// assert!(task_shutdown_report.pending.is_empty());
+/// # });
+/// ```
+pub async fn graceful_shutdown<F>(cancelation_token: &CancelationToken, tasks: Vec<F>, grace: Duration) -> TaskShutdownReport<usize> where
+F: Future<Output = ()> {
+	graceful_shutdown_labeled(cancelation_token, tasks.into_iter().enumerate().collect(), grace).await
+}
+
+/// Cancels cancelation_token, and then waits up to grace for every task to finish. Each task is paired with a label,
+/// such as its name, that identifies it in the report
+pub async fn graceful_shutdown_labeled<L, F>(cancelation_token: &CancelationToken, tasks: Vec<(L, F)>, grace: Duration) -> TaskShutdownReport<L> where
+F: Future<Output = ()> {
+	graceful_shutdown_with_timer(cancelation_token, tasks, grace, &Timer::default()).await
+}
+
+async fn graceful_shutdown_with_timer<L, F>(cancelation_token: &CancelationToken, tasks: Vec<(L, F)>, grace: Duration, timer: &Timer) -> TaskShutdownReport<L> where
+F: Future<Output = ()> {
+	let start = timer.now();
+	let deadline = timer.sleep(grace);
+
+	cancelation_token.cancel();
+
+	let mut labels = Vec::with_capacity(tasks.len());
+	let mut running = FuturesUnordered::new();
+
+	for (index, (label, task)) in tasks.into_iter().enumerate() {
+		labels.push(Some(label));
+		running.push(async move {
+			task.await;
+			index
+		});
+	}
+
+	let mut finished = Vec::new();
+
+	let all_finished = async {
+		while let Some(index) = running.next().await {
+			finished.push(labels[index].take().unwrap());
+		}
+	};
+
+	select(Box::pin(all_finished), deadline).await;
+
+	TaskShutdownReport {
+		finished,
+		pending: labels.into_iter().flatten().collect(),
+		elapsed: timer.now() - start
+	}
+}
+
+impl Default for ShutdownController {
+	fn default() -> Self {
+		ShutdownController::new()
+	}
+}
+
+impl Drop for ShutdownGuard {
+	fn drop(&mut self) {
+		let mut shared_state = self.shared_state.lock().unwrap();
+		shared_state.running -= 1;
+
+		if shared_state.running == 0 {
+			let wakers = shared_state.wakers.take_all();
+			drop(shared_state);
+			wake_each(wakers);
+		}
+	}
+}
+
+impl Future for AllFinishedFuture {
+	type Output = ();
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+		let this = self.get_mut();
+		let mut shared_state = this.shared_state.lock().unwrap();
+
+		if shared_state.running == 0 {
+			Poll::Ready(())
+		} else {
+			shared_state.wakers.register(&mut this.waker_key, cx.waker());
+			Poll::Pending
+		}
+	}
+}
+
+impl Drop for AllFinishedFuture {
+	fn drop(&mut self) {
+		if self.waker_key.is_some() {
+			let mut shared_state = self.shared_state.lock().unwrap();
+			shared_state.wakers.remove(self.waker_key);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use futures::executor::LocalPool;
+	use futures::future::LocalBoxFuture;
+	use futures::task::LocalSpawnExt;
+
+	use super::*;
+	use crate::timer::ManualClock;
+
+	#[test]
+	fn test_all_finish() {
+		let clock = ManualClock::new();
+		let shutdown_controller = ShutdownController::with_timer(Timer::new(clock.clone()));
+
+		let mut pool = LocalPool::new();
+		let spawner = pool.spawner();
+
+		for _ in 0..3 {
+			let (cancelable, shutdown_guard) = shutdown_controller.subscribe();
+			spawner.spawn_local(async move {
+				cancelable.future().await;
+				drop(shutdown_guard);
+			}).unwrap();
+		}
+
+		pool.run_until_stalled();
+		assert_eq!(shutdown_controller.running(), 3, "Workers should be running");
+
+		let shutdown_report = pool.run_until(shutdown_controller.shutdown(Duration::from_secs(5)));
+
+		assert!(shutdown_controller.is_shutting_down(), "Controller should be shutting down");
+		assert_eq!(shutdown_report, ShutdownReport {
+			finished: 3,
+			abandoned: 0,
+			elapsed: Duration::ZERO
+		}, "Wrong report");
+	}
+
+	#[test]
+	fn test_timeout() {
+		let clock = ManualClock::new();
+		let shutdown_controller = ShutdownController::with_timer(Timer::new(clock.clone()));
+		let timer = Timer::new(clock.clone());
+
+		let mut pool = LocalPool::new();
+		let spawner = pool.spawner();
+
+		// Finishes right away
+		let (cancelable, shutdown_guard) = shutdown_controller.subscribe();
+		spawner.spawn_local(async move {
+			cancelable.future().await;
+			drop(shutdown_guard);
+		}).unwrap();
+
+		// Takes 2 seconds to clean up
+		let (cancelable, shutdown_guard) = shutdown_controller.subscribe();
+		spawner.spawn_local(async move {
+			cancelable.future().await;
+			timer.sleep(Duration::from_secs(2)).await;
+			drop(shutdown_guard);
+		}).unwrap();
+
+		// Never finishes
+		let (_cancelable, _stuck_shutdown_guard) = shutdown_controller.subscribe();
+
+		let shutdown = spawner.spawn_local_with_handle({
+			let shutdown_controller = shutdown_controller.clone();
+			async move {
+				shutdown_controller.shutdown(Duration::from_secs(5)).await
+			}
+		}).unwrap();
+
+		pool.run_until_stalled();
+		assert_eq!(shutdown_controller.running(), 2, "One worker should be finished");
+
+		clock.advance(Duration::from_secs(2));
+		pool.run_until_stalled();
+		assert_eq!(shutdown_controller.running(), 1, "Two workers should be finished");
+
+		clock.advance(Duration::from_secs(2));
+		pool.run_until_stalled();
+
+		clock.advance(Duration::from_secs(1));
+		let shutdown_report = pool.run_until(shutdown);
+
+		assert_eq!(shutdown_report, ShutdownReport {
+			finished: 2,
+			abandoned: 1,
+			elapsed: Duration::from_secs(5)
+		}, "Wrong report");
+	}
+
+	#[test]
+	fn test_subscribe_after_shutdown() {
+		let shutdown_controller = ShutdownController::new();
+
+		let shutdown_report = futures::executor::block_on(shutdown_controller.shutdown(Duration::from_secs(5)));
+		assert_eq!((shutdown_report.finished, shutdown_report.abandoned), (0, 0), "Wrong report");
+
+		let (cancelable, _shutdown_guard) = shutdown_controller.subscribe();
+		assert!(cancelable.is_canceled(), "Late subscribers should be canceled");
+	}
+
+	#[test]
+	fn test_graceful_shutdown() {
+		let clock = ManualClock::new();
+		let timer = Timer::new(clock.clone());
+		let (cancelation_token, cancelable) = CancelationToken::new();
+
+		// Finishes right away, takes 2 seconds to clean up, and never finishes
+		let tasks: Vec<(&str, LocalBoxFuture<'static, ()>)> = vec![
+			("listener", Box::pin({
+				let cancelable = cancelable.clone();
+				async move { cancelable.future().await }
+			})),
+			("db-pool", Box::pin({
+				let cancelable = cancelable.clone();
+				let timer = timer.clone();
+				async move {
+					cancelable.future().await;
+					timer.sleep(Duration::from_secs(2)).await;
+				}
+			})),
+			("stuck", Box::pin(futures::future::pending()))
+		];
+
+		let mut pool = LocalPool::new();
+		let shutdown = pool.spawner().spawn_local_with_handle({
+			let timer = timer.clone();
+			async move {
+				graceful_shutdown_with_timer(&cancelation_token, tasks, Duration::from_secs(5), &timer).await
+			}
+		}).unwrap();
+
+		pool.run_until_stalled();
+		assert!(cancelable.is_canceled(), "Should cancel before waiting");
+
+		clock.advance(Duration::from_secs(2));
+		pool.run_until_stalled();
+
+		clock.advance(Duration::from_secs(3));
+		let task_shutdown_report = pool.run_until(shutdown);
+
+		assert_eq!(task_shutdown_report, TaskShutdownReport {
+			finished: vec!["listener", "db-pool"],
+			pending: vec!["stuck"],
+			elapsed: Duration::from_secs(5)
+		}, "Wrong report");
+	}
+
+	runtime_test! {
+		async fn test_graceful_shutdown_by_index() {
+			let (cancelation_token, cancelable) = CancelationToken::new();
+
+			let tasks: Vec<_> = (0..4).map(|index| {
+				let cancelable = cancelable.clone();
+				async_std::task::spawn(async move {
+					if index % 2 == 0 {
+						cancelable.future().await;
+					} else {
+						futures::future::pending::<()>().await;
+					}
+				})
+			}).collect();
+
+			let mut task_shutdown_report = graceful_shutdown(&cancelation_token, tasks, Duration::from_millis(20)).await;
+			task_shutdown_report.finished.sort_unstable();
+
+			assert_eq!(task_shutdown_report.finished, vec![0, 2], "Wrong finished tasks");
+			assert_eq!(task_shutdown_report.pending, vec![1, 3], "Wrong pending tasks");
+			assert!(task_shutdown_report.elapsed >= Duration::from_millis(20), "Shutdown returned early");
+		}
+	}
+
+	runtime_test! {
+		async fn test_system_timer() {
+			let shutdown_controller = ShutdownController::new();
+			let (_cancelable, _shutdown_guard) = shutdown_controller.subscribe();
+
+			let shutdown_report = shutdown_controller.shutdown(Duration::from_millis(20)).await;
+
+			assert_eq!((shutdown_report.finished, shutdown_report.abandoned), (0, 1), "Wrong report");
+			assert!(shutdown_report.elapsed >= Duration::from_millis(20), "Shutdown returned early");
+		}
+	}
+}
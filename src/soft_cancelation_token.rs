@@ -0,0 +1,229 @@
+// https://github.com/GWBasic/sync-tokens
+// (c) Andrew Rondeau
+// Apache 2.0 license
+// See https://github.com/GWBasic/sync-tokens/blob/main/LICENSE
+
+//! Contains a token that cancels in two levels: a soft-cancel that lets an operation finish its current work, and a
+//! hard-cancel that stops it. See [`SoftCancelationToken`](struct.SoftCancelationToken.html)
+use std::time::Duration;
+
+use crate::cancelation_token::{Cancelable, CancelationToken};
+use crate::timer::Timer;
+
+/// Cancels an operation in two levels.
+///
+/// [`soft_cancel()`](struct.SoftCancelationToken.html#method.soft_cancel) only sets a flag, which the operation
+/// checks with [`Cancelable::is_soft_canceled()`](../cancelation_token/struct.Cancelable.html#method.is_soft_canceled),
+/// so that it can finish its current work and stop gracefully. Nothing waiting on the
+/// [`Cancelable`](../cancelation_token/struct.Cancelable.html) is woken.
+///
+/// [`hard_cancel()`](struct.SoftCancelationToken.html#method.hard_cancel) cancels the same way as
+/// [`CancelationToken::cancel()`](../cancelation_token/struct.CancelationToken.html#method.cancel), so that waiting
+/// operations stop immediately
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use sync_tokens::soft_cancelation_token::SoftCancelationToken;
+///
+/// # async_std::task::block_on(async {
+/// let (soft_cancelation_token, cancelable) = SoftCancelationToken::new();
+///
+/// let worker = async_std::task::spawn(async move {
+///     let mut batches = 0;
+///
+///     while !cancelable.is_soft_canceled() {
+///         // Each batch finishes, even if it's soft-canceled part way through
+///         async_std::task::sleep(Duration::from_millis(1)).await;
+///         batches += 1;
+///     }
+///
+///     batches
+/// });
+///
+/// async_std::task::sleep(Duration::from_millis(10)).await;
+/// soft_cancelation_token.soft_cancel_with_deadline(Duration::from_secs(1)).await;
+///
+/// assert!(worker.await > 0);
+/// # });
+/// ```
+#[derive(Debug, Clone)]
+pub struct SoftCancelationToken {
+	cancelation_token: CancelationToken,
+	timer: Timer
+}
+
+impl SoftCancelationToken {
+	/// Creates a new [`SoftCancelationToken`](struct.SoftCancelationToken.html) and
+	/// [`Cancelable`](../cancelation_token/struct.Cancelable.html)
+	pub fn new() -> (SoftCancelationToken, Cancelable) {
+		SoftCancelationToken::with_timer(Timer::default())
+	}
+
+	/// Creates a new [`SoftCancelationToken`](struct.SoftCancelationToken.html) and
+	/// [`Cancelable`](../cancelation_token/struct.Cancelable.html), that uses the given
+	/// [`Timer`](../timer/struct.Timer.html) for deadlines
+	pub fn with_timer(timer: Timer) -> (SoftCancelationToken, Cancelable) {
+		let (cancelation_token, cancelable) = CancelationToken::new();
+
+		let soft_cancelation_token = SoftCancelationToken {
+			cancelation_token,
+			timer
+		};
+
+		(soft_cancelation_token, cancelable)
+	}
+
+	/// Asks the operation to stop once it finishes its current work. This can be called multiple times safely
+	pub fn soft_cancel(&self) {
+		self.cancelation_token.soft_cancel();
+	}
+
+	/// Stops the operation immediately, by canceling the [`Cancelable`](../cancelation_token/struct.Cancelable.html).
+	/// This can be called multiple times safely
+	pub fn hard_cancel(&self) {
+		self.cancelation_token.cancel();
+	}
+
+	/// Soft-cancels the operation, and then hard-cancels it once grace passes. Returns after the hard-cancel
+	pub async fn soft_cancel_with_deadline(&self, grace: Duration) {
+		self.soft_cancel();
+		self.timer.sleep(grace).await;
+		self.hard_cancel();
+	}
+
+	/// Returns true once the operation is soft-canceled or hard-canceled
+	pub fn is_soft_canceled(&self) -> bool {
+		self.cancelation_token.is_soft_canceled()
+	}
+
+	/// Returns true once the operation is hard-canceled
+	pub fn is_hard_canceled(&self) -> bool {
+		self.cancelation_token.is_canceled()
+	}
+
+	/// Returns the underlying [`CancelationToken`](../cancelation_token/struct.CancelationToken.html). Canceling it
+	/// hard-cancels the operation
+	pub fn cancelation_token(&self) -> &CancelationToken {
+		&self.cancelation_token
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::Arc;
+	use std::sync::atomic::{AtomicUsize, Ordering};
+
+	use futures::executor::LocalPool;
+	use futures::future::FutureExt;
+	use futures::task::LocalSpawnExt;
+
+	use super::*;
+	use crate::timer::ManualClock;
+
+	#[test]
+	fn test_soft_cancel_detected_in_loop() {
+		let (soft_cancelation_token, cancelable) = SoftCancelationToken::new();
+		let mut iterations = 0;
+
+		while !cancelable.is_soft_canceled() {
+			iterations += 1;
+
+			if iterations == 3 {
+				soft_cancelation_token.soft_cancel();
+			}
+		}
+
+		assert_eq!(iterations, 3, "The loop should stop after soft-cancel");
+		assert!(!cancelable.is_canceled(), "Soft-cancel shouldn't cancel");
+		assert!(soft_cancelation_token.is_soft_canceled(), "Should be soft-canceled");
+		assert!(!soft_cancelation_token.is_hard_canceled(), "Shouldn't be hard-canceled");
+	}
+
+	#[test]
+	fn test_soft_cancel_finishes_gracefully() {
+		let clock = ManualClock::new();
+		let timer = Timer::new(clock.clone());
+		let (soft_cancelation_token, cancelable) = SoftCancelationToken::with_timer(timer.clone());
+		let mut pool = LocalPool::new();
+
+		let work = {
+			let timer = timer.clone();
+			async move {
+				timer.sleep(Duration::from_secs(1)).await;
+				"finished"
+			}
+		};
+
+		let result = pool.spawner().spawn_local_with_handle(async move {
+			cancelable.allow_soft_cancel(work.boxed(), "soft", "hard").await
+		}).unwrap();
+
+		pool.run_until_stalled();
+		soft_cancelation_token.soft_cancel();
+		pool.run_until_stalled();
+
+		clock.advance(Duration::from_secs(1));
+		assert_eq!(pool.run_until(result), "soft", "Work should finish after soft-cancel");
+	}
+
+	#[test]
+	fn test_hard_cancel_interrupts() {
+		let clock = ManualClock::new();
+		let timer = Timer::new(clock.clone());
+		let (soft_cancelation_token, cancelable) = SoftCancelationToken::with_timer(timer.clone());
+		let mut pool = LocalPool::new();
+
+		let batches_started = Arc::new(AtomicUsize::new(0));
+
+		let work = {
+			let batches_started = batches_started.clone();
+			let cancelable = cancelable.clone();
+			async move {
+				// Ignores soft-cancel, so that only hard-cancel can stop it
+				loop {
+					batches_started.fetch_add(1, Ordering::SeqCst);
+					timer.sleep(Duration::from_secs(1)).await;
+
+					if batches_started.load(Ordering::SeqCst) > 100 || cancelable.is_canceled() {
+						return "finished";
+					}
+				}
+			}
+		};
+
+		let result = pool.spawner().spawn_local_with_handle(async move {
+			cancelable.allow_soft_cancel(work.boxed(), "soft", "hard").await
+		}).unwrap();
+
+		let deadline = pool.spawner().spawn_local_with_handle({
+			let soft_cancelation_token = soft_cancelation_token.clone();
+			async move {
+				soft_cancelation_token.soft_cancel_with_deadline(Duration::from_millis(1500)).await
+			}
+		}).unwrap();
+
+		pool.run_until_stalled();
+		assert!(soft_cancelation_token.is_soft_canceled(), "Should be soft-canceled");
+		assert!(!soft_cancelation_token.is_hard_canceled(), "Shouldn't be hard-canceled before the deadline");
+
+		clock.advance(Duration::from_secs(1));
+		pool.run_until_stalled();
+		assert_eq!(batches_started.load(Ordering::SeqCst), 2, "Should be in the middle of the second batch");
+
+		clock.advance(Duration::from_millis(500));
+		pool.run_until(deadline);
+
+		assert!(soft_cancelation_token.is_hard_canceled(), "Should be hard-canceled at the deadline");
+		assert_eq!(pool.run_until(result), "hard", "Hard-cancel should interrupt the batch");
+		assert_eq!(batches_started.load(Ordering::SeqCst), 2, "No more batches should start");
+	}
+
+	#[test]
+	fn test_no_cancel() {
+		let (_soft_cancelation_token, cancelable) = SoftCancelationToken::new();
+
+		let result = futures::executor::block_on(cancelable.allow_soft_cancel(async { "finished" }.boxed(), "soft", "hard"));
+		assert_eq!(result, "finished", "Wrong result");
+	}
+}
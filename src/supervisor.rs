@@ -0,0 +1,308 @@
+// https://github.com/GWBasic/sync-tokens
+// (c) Andrew Rondeau
+// Apache 2.0 license
+// See https://github.com/GWBasic/sync-tokens/blob/main/LICENSE
+
+//! Contains a way to restart a task, with backoff, until it's canceled. See [`supervise()`](fn.supervise.html)
+use std::future::Future;
+use std::time::Duration;
+
+use futures::FutureExt;
+
+use crate::cancelation_token::Cancelable;
+use crate::timer::Timer;
+
+/// Passed to each attempt that [`supervise()`](fn.supervise.html) starts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Attempt {
+	/// Starts at 1 for the first attempt
+	pub number: usize,
+	/// How many attempts in a row returned an error, right before this attempt
+	pub consecutive_failures: usize
+}
+
+/// Controls how [`supervise()`](fn.supervise.html) waits between attempts, and how many times it restarts.
+///
+/// After an attempt returns an error, the backoff starts at the initial backoff and doubles with each consecutive error,
+/// up to the maximum backoff. After an attempt returns Ok, the next attempt waits the initial backoff
+#[derive(Debug, Clone)]
+pub struct RestartPolicy {
+	initial_backoff: Duration,
+	max_backoff: Duration,
+	max_restarts: Option<usize>,
+	timer: Timer
+}
+
+/// Why [`supervise()`](fn.supervise.html) stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SupervisionEnd {
+	/// The [`Cancelable`](../cancelation_token/struct.Cancelable.html) was canceled
+	Canceled,
+	/// The [`RestartPolicy`](struct.RestartPolicy.html)'s maximum restarts were used up
+	MaxRestartsReached
+}
+
+/// Returned by [`supervise()`](fn.supervise.html)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SupervisionReport<E> {
+	/// How many attempts were started, including an attempt that was interrupted by cancelation
+	pub attempts: usize,
+	/// The error returned by the most recent attempt that failed
+	pub last_error: Option<E>,
+	/// Why supervision stopped
+	pub end: SupervisionEnd
+}
+
+impl RestartPolicy {
+	/// Creates a [`RestartPolicy`](struct.RestartPolicy.html) that restarts forever, with a backoff from 100 milliseconds
+	/// up to 30 seconds, using the system clock
+	pub fn new() -> RestartPolicy {
+		RestartPolicy {
+			initial_backoff: Duration::from_millis(100),
+			max_backoff: Duration::from_secs(30),
+			max_restarts: None,
+			timer: Timer::default()
+		}
+	}
+
+	/// Sets the backoff after the first error, and the most that the backoff can grow to
+	pub fn with_backoff(mut self, initial_backoff: Duration, max_backoff: Duration) -> RestartPolicy {
+		self.initial_backoff = initial_backoff;
+		self.max_backoff = max_backoff.max(initial_backoff);
+		self
+	}
+
+	/// Stops after restarting max_restarts times. The first attempt isn't a restart
+	pub fn with_max_restarts(mut self, max_restarts: usize) -> RestartPolicy {
+		self.max_restarts = Some(max_restarts);
+		self
+	}
+
+	/// Uses the given [`Timer`](../timer/struct.Timer.html) for the backoff
+	pub fn with_timer(mut self, timer: Timer) -> RestartPolicy {
+		self.timer = timer;
+		self
+	}
+
+	fn backoff(&self, consecutive_failures: usize) -> Duration {
+		let doublings = consecutive_failures.saturating_sub(1).min(31) as u32;
+
+		self.initial_backoff
+			.checked_mul(1 << doublings)
+			.map_or(self.max_backoff, |backoff| backoff.min(self.max_backoff))
+	}
+}
+
+impl Default for RestartPolicy {
+	fn default() -> Self {
+		RestartPolicy::new()
+	}
+}
+
+/// Runs the futures returned by make_future, one after another, restarting whenever an attempt returns, until cancelable
+/// is canceled or the [`RestartPolicy`](struct.RestartPolicy.html)'s maximum restarts are used up. Canceling interrupts
+/// both a running attempt and the backoff between attempts.
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use sync_tokens::cancelation_token::CancelationToken;
+/// use sync_tokens::supervisor::{RestartPolicy, SupervisionEnd, supervise};
+///
+/// # async_std::task::block_on(async {
+/// let (cancelation_token, cancelable) = CancelationToken::new();
+/// let restart_policy = RestartPolicy::new()
+///     .with_backoff(Duration::from_millis(1), Duration::from_millis(10))
+///     .with_max_restarts(2);
+///
+/// let supervision_report = supervise(&cancelable, restart_policy, |attempt| async move {
+///     Err::<(), _>(format!("attempt {} failed", attempt.number))
+/// }).await;
+///
+/// assert_eq!(supervision_report.attempts, 3);
+/// assert_eq!(supervision_report.last_error, Some("attempt 3 failed".to_string()));
+/// assert_eq!(supervision_report.end, SupervisionEnd::MaxRestartsReached);
+/// # });
+/// ```
+pub async fn supervise<M, F, E>(cancelable: &Cancelable, restart_policy: RestartPolicy, mut make_future: M) -> SupervisionReport<E> where
+M: FnMut(Attempt) -> F,
+F: Future<Output = Result<(), E>> {
+	let mut attempts = 0;
+	let mut consecutive_failures = 0;
+	let mut last_error = None;
+
+	loop {
+		if cancelable.is_canceled() {
+			return SupervisionReport { attempts, last_error, end: SupervisionEnd::Canceled };
+		}
+
+		attempts += 1;
+		let attempt = Attempt { number: attempts, consecutive_failures };
+
+		let result = cancelable.allow_cancel(Box::pin(make_future(attempt).map(Some)), None).await;
+		match result {
+			None => return SupervisionReport { attempts, last_error, end: SupervisionEnd::Canceled },
+			Some(Ok(())) => consecutive_failures = 0,
+			Some(Err(err)) => {
+				consecutive_failures += 1;
+				last_error = Some(err);
+			}
+		}
+
+		if restart_policy.max_restarts.is_some_and(|max_restarts| attempts > max_restarts) {
+			return SupervisionReport { attempts, last_error, end: SupervisionEnd::MaxRestartsReached };
+		}
+
+		let backoff = restart_policy.timer.sleep(restart_policy.backoff(consecutive_failures)).map(|_| true);
+		if !cancelable.allow_cancel(backoff, false).await {
+			return SupervisionReport { attempts, last_error, end: SupervisionEnd::Canceled };
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::cell::RefCell;
+	use std::rc::Rc;
+
+	use futures::executor::LocalPool;
+	use futures::future;
+	use futures::task::LocalSpawnExt;
+
+	use super::*;
+	use crate::cancelation_token::CancelationToken;
+	use crate::timer::ManualClock;
+
+	fn manual_policy(clock: &ManualClock) -> RestartPolicy {
+		RestartPolicy::new()
+			.with_backoff(Duration::from_secs(1), Duration::from_secs(4))
+			.with_timer(Timer::new(clock.clone()))
+	}
+
+	#[test]
+	fn test_backoff() {
+		let restart_policy = RestartPolicy::new().with_backoff(Duration::from_secs(1), Duration::from_secs(5));
+
+		assert_eq!(restart_policy.backoff(0), Duration::from_secs(1), "A clean exit waits the initial backoff");
+		assert_eq!(restart_policy.backoff(1), Duration::from_secs(1), "Wrong backoff");
+		assert_eq!(restart_policy.backoff(2), Duration::from_secs(2), "Wrong backoff");
+		assert_eq!(restart_policy.backoff(3), Duration::from_secs(4), "Wrong backoff");
+		assert_eq!(restart_policy.backoff(4), Duration::from_secs(5), "The backoff should be capped");
+		assert_eq!(restart_policy.backoff(1000), Duration::from_secs(5), "The backoff should be capped");
+	}
+
+	#[test]
+	fn test_restarts_with_backoff() {
+		let clock = ManualClock::new();
+		let (_cancelation_token, cancelable) = CancelationToken::new();
+
+		let seen = Rc::new(RefCell::new(Vec::new()));
+
+		let mut pool = LocalPool::new();
+		let supervised = pool.spawner().spawn_local_with_handle({
+			let seen = seen.clone();
+			let restart_policy = manual_policy(&clock).with_max_restarts(3);
+			async move {
+				supervise(&cancelable, restart_policy, |attempt| {
+					seen.borrow_mut().push(attempt);
+					future::ready(Err(attempt.number))
+				}).await
+			}
+		}).unwrap();
+
+		pool.run_until_stalled();
+		assert_eq!(seen.borrow().len(), 1, "Only the first attempt should run before the backoff");
+
+		clock.advance(Duration::from_secs(1));
+		pool.run_until_stalled();
+		assert_eq!(seen.borrow().len(), 2, "Should restart after 1 second");
+
+		clock.advance(Duration::from_millis(1999));
+		pool.run_until_stalled();
+		assert_eq!(seen.borrow().len(), 2, "The backoff should double");
+
+		clock.advance(Duration::from_millis(1));
+		pool.run_until_stalled();
+		assert_eq!(seen.borrow().len(), 3, "Should restart after 2 seconds");
+
+		clock.advance(Duration::from_secs(4));
+		let supervision_report = pool.run_until(supervised);
+
+		assert_eq!(supervision_report, SupervisionReport {
+			attempts: 4,
+			last_error: Some(4),
+			end: SupervisionEnd::MaxRestartsReached
+		}, "Wrong report");
+
+		assert_eq!(seen.borrow()[3], Attempt { number: 4, consecutive_failures: 3 }, "Wrong attempt");
+	}
+
+	#[test]
+	fn test_cancel_during_run() {
+		let clock = ManualClock::new();
+		let (cancelation_token, cancelable) = CancelationToken::new();
+
+		let mut pool = LocalPool::new();
+		let supervised = pool.spawner().spawn_local_with_handle({
+			let restart_policy = manual_policy(&clock);
+			async move {
+				supervise(&cancelable, restart_policy, |attempt| async move {
+					if attempt.number == 1 {
+						Err("failed")
+					} else {
+						future::pending().await
+					}
+				}).await
+			}
+		}).unwrap();
+
+		pool.run_until_stalled();
+		clock.advance(Duration::from_secs(1));
+		pool.run_until_stalled();
+
+		cancelation_token.cancel();
+
+		assert_eq!(pool.run_until(supervised), SupervisionReport {
+			attempts: 2,
+			last_error: Some("failed"),
+			end: SupervisionEnd::Canceled
+		}, "Canceling should interrupt the running attempt");
+	}
+
+	#[test]
+	fn test_cancel_during_backoff() {
+		let clock = ManualClock::new();
+		let (cancelation_token, cancelable) = CancelationToken::new();
+
+		let mut pool = LocalPool::new();
+		let supervised = pool.spawner().spawn_local_with_handle({
+			let restart_policy = manual_policy(&clock);
+			async move {
+				supervise(&cancelable, restart_policy, |_| future::ready(Err("failed"))).await
+			}
+		}).unwrap();
+
+		pool.run_until_stalled();
+		cancelation_token.cancel();
+
+		assert_eq!(pool.run_until(supervised), SupervisionReport {
+			attempts: 1,
+			last_error: Some("failed"),
+			end: SupervisionEnd::Canceled
+		}, "Canceling should interrupt the backoff without waiting for the clock");
+	}
+
+	#[test]
+	fn test_already_canceled() {
+		let (cancelation_token, cancelable) = CancelationToken::new();
+		cancelation_token.cancel();
+
+		let supervision_report = futures::executor::block_on(supervise(&cancelable, RestartPolicy::new(), |_| async {
+			panic!("Shouldn't start an attempt");
+			#[allow(unreachable_code)]
+			Ok::<(), ()>(())
+		}));
+
+		assert_eq!(supervision_report, SupervisionReport { attempts: 0, last_error: None, end: SupervisionEnd::Canceled }, "Wrong report");
+	}
+}
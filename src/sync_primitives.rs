@@ -0,0 +1,385 @@
+// https://github.com/GWBasic/sync-tokens
+// (c) Andrew Rondeau
+// Apache 2.0 license
+// See https://github.com/GWBasic/sync-tokens/blob/main/LICENSE
+
+//! Contains fully synchronous versions of [`CancelationToken`](../cancelation_token/struct.CancelationToken.html) and
+//! [`CompletionToken`](../completion_token/struct.CompletionToken.html), for threads that don't run an async executor.
+//! They block on a `std::sync::Condvar` instead of waking a task. See
+//! [`SyncCancelationToken`](struct.SyncCancelationToken.html) and [`SyncCompletionToken`](struct.SyncCompletionToken.html).
+//! Not available on wasm, which has no threads to block
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::completion_token::Abandoned;
+
+/// Allows canceling an operation running on another thread. Whoever has a
+/// [`SyncCancelationToken`](struct.SyncCancelationToken.html) can cancel an operation that uses a
+/// [`SyncCancelable`](struct.SyncCancelable.html). This is the synchronous version of
+/// [`CancelationToken`](../cancelation_token/struct.CancelationToken.html)
+///
+/// ```
+/// use sync_tokens::sync_primitives::SyncCancelationToken;
+///
+/// let (cancelation_token, cancelable) = SyncCancelationToken::new();
+///
+/// let worker = std::thread::spawn(move || {
+///     cancelable.wait_for_cancel();
+///     "stopped"
+/// });
+///
+/// cancelation_token.cancel();
+/// assert_eq!(worker.join().unwrap(), "stopped");
+/// ```
+#[derive(Debug, Clone)]
+pub struct SyncCancelationToken {
+	shared_state: Arc<SyncCancelationState>
+}
+
+/// Blocks until a [`SyncCancelationToken`](struct.SyncCancelationToken.html) is canceled, or checks whether it is.
+/// This is the synchronous version of [`Cancelable`](../cancelation_token/struct.Cancelable.html)
+#[derive(Debug, Clone)]
+pub struct SyncCancelable {
+	shared_state: Arc<SyncCancelationState>
+}
+
+#[derive(Debug)]
+struct SyncCancelationState {
+	canceled: Mutex<bool>,
+	condvar: Condvar
+}
+
+/// Allows blocking until another thread calls
+/// [`SyncCompletable::complete()`](struct.SyncCompletable.html#method.complete). This is the synchronous version of
+/// [`CompletionToken`](../completion_token/struct.CompletionToken.html)
+///
+/// If the [`SyncCompletable`](struct.SyncCompletable.html) is dropped without calling complete,
+/// [`wait()`](struct.SyncCompletionToken.html#method.wait) never returns. Use
+/// [`try_wait()`](struct.SyncCompletionToken.html#method.try_wait) to find out when this happens
+///
+/// ```
+/// use sync_tokens::sync_primitives::SyncCompletionToken;
+///
+/// let (completion_token, completable) = SyncCompletionToken::new();
+///
+/// std::thread::spawn(move || {
+///     completable.complete("listening");
+/// });
+///
+/// assert_eq!(completion_token.wait(), "listening");
+/// ```
+#[derive(Debug)]
+pub struct SyncCompletionToken<T> {
+	shared_state: Arc<SyncCompletionState<T>>
+}
+
+/// Unblocks a thread that waits on a [`SyncCompletionToken`](struct.SyncCompletionToken.html). This is the
+/// synchronous version of [`Completable`](../completion_token/struct.Completable.html)
+#[derive(Debug)]
+pub struct SyncCompletable<T> {
+	shared_state: Arc<SyncCompletionState<T>>
+}
+
+#[derive(Debug)]
+struct SyncCompletionState<T> {
+	completion: Mutex<SyncCompletion<T>>,
+	condvar: Condvar
+}
+
+#[derive(Debug)]
+struct SyncCompletion<T> {
+	complete: bool,
+	abandoned: bool,
+	result: Option<T>
+}
+
+impl SyncCancelationToken {
+	/// Creates a new [`SyncCancelationToken`](struct.SyncCancelationToken.html) and
+	/// [`SyncCancelable`](struct.SyncCancelable.html) pair
+	pub fn new() -> (SyncCancelationToken, SyncCancelable) {
+		let shared_state = Arc::new(SyncCancelationState {
+			canceled: Mutex::new(false),
+			condvar: Condvar::new()
+		});
+
+		let cancelable = SyncCancelable {
+			shared_state: shared_state.clone()
+		};
+
+		(SyncCancelationToken { shared_state }, cancelable)
+	}
+
+	/// Cancels, and unblocks every thread that waits on a [`SyncCancelable`](struct.SyncCancelable.html). This can be
+	/// called multiple times safely
+	pub fn cancel(&self) {
+		*self.shared_state.canceled.lock().unwrap() = true;
+		self.shared_state.condvar.notify_all();
+	}
+
+	/// Returns true if canceled
+	pub fn is_canceled(&self) -> bool {
+		*self.shared_state.canceled.lock().unwrap()
+	}
+}
+
+impl SyncCancelable {
+	/// Blocks the calling thread until canceled. Returns immediately if already canceled
+	pub fn wait_for_cancel(&self) {
+		let canceled = self.shared_state.canceled.lock().unwrap();
+		let _canceled = self.shared_state.condvar.wait_while(canceled, |canceled| !*canceled).unwrap();
+	}
+
+	/// Blocks the calling thread until canceled, or until timeout passes. Returns true if canceled
+	pub fn wait_for_cancel_timeout(&self, timeout: Duration) -> bool {
+		let canceled = self.shared_state.canceled.lock().unwrap();
+		let (canceled, _) = self.shared_state.condvar.wait_timeout_while(canceled, timeout, |canceled| !*canceled).unwrap();
+		*canceled
+	}
+
+	/// Returns true if canceled. Long-running loops can check this between steps
+	pub fn is_canceled(&self) -> bool {
+		*self.shared_state.canceled.lock().unwrap()
+	}
+}
+
+impl<T> SyncCompletionToken<T> {
+	/// Creates a new [`SyncCompletionToken`](struct.SyncCompletionToken.html) and
+	/// [`SyncCompletable`](struct.SyncCompletable.html) pair
+	pub fn new() -> (SyncCompletionToken<T>, SyncCompletable<T>) {
+		let shared_state = Arc::new(SyncCompletionState {
+			completion: Mutex::new(SyncCompletion {
+				complete: false,
+				abandoned: false,
+				result: None
+			}),
+			condvar: Condvar::new()
+		});
+
+		let completable = SyncCompletable {
+			shared_state: shared_state.clone()
+		};
+
+		(SyncCompletionToken { shared_state }, completable)
+	}
+
+	/// Blocks the calling thread until the [`SyncCompletable`](struct.SyncCompletable.html) completes, and returns
+	/// its result. Never returns if the [`SyncCompletable`](struct.SyncCompletable.html) is dropped without
+	/// completing
+	pub fn wait(self) -> T {
+		let completion = self.shared_state.completion.lock().unwrap();
+		let mut completion = self.shared_state.condvar.wait_while(completion, |completion| !completion.complete).unwrap();
+
+		completion.result.take().expect("The result is only taken once")
+	}
+
+	/// Blocks the calling thread until the [`SyncCompletable`](struct.SyncCompletable.html) completes, or returns
+	/// [`Abandoned`](../completion_token/struct.Abandoned.html) if it's dropped without completing
+	pub fn try_wait(self) -> Result<T, Abandoned> {
+		let completion = self.shared_state.completion.lock().unwrap();
+		let mut completion = self.shared_state.condvar.wait_while(completion, |completion| !completion.complete && !completion.abandoned).unwrap();
+
+		completion.result.take().ok_or(Abandoned)
+	}
+
+	/// Blocks the calling thread until the [`SyncCompletable`](struct.SyncCompletable.html) completes, or until
+	/// timeout passes. Returns the token back if it times out, so that it can be waited on again
+	pub fn wait_timeout(self, timeout: Duration) -> Result<T, SyncCompletionToken<T>> {
+		let deadline = Instant::now() + timeout;
+
+		let result = {
+			let completion = self.shared_state.completion.lock().unwrap();
+			let (mut completion, _) = self.shared_state.condvar.wait_timeout_while(
+				completion,
+				deadline.saturating_duration_since(Instant::now()),
+				|completion| !completion.complete).unwrap();
+
+			completion.result.take()
+		};
+
+		result.ok_or(self)
+	}
+
+	/// Returns true if the [`SyncCompletable`](struct.SyncCompletable.html) completed
+	pub fn is_complete(&self) -> bool {
+		self.shared_state.completion.lock().unwrap().complete
+	}
+}
+
+impl<T> SyncCompletable<T> {
+	/// Call to indicate that the operation is complete, and unblock the thread that waits on the
+	/// [`SyncCompletionToken`](struct.SyncCompletionToken.html)
+	///
+	/// # Panics
+	///
+	/// Complete will panic if it is called multiple times
+	pub fn complete(&self, result: T) {
+		{
+			let mut completion = self.shared_state.completion.lock().unwrap();
+
+			if completion.complete {
+				drop(completion);
+				panic!("Completion token is already complete")
+			}
+
+			completion.complete = true;
+			completion.result = Some(result);
+		}
+
+		self.shared_state.condvar.notify_all();
+	}
+}
+
+impl<T> Drop for SyncCompletable<T> {
+	fn drop(&mut self) {
+		{
+			let mut completion = self.shared_state.completion.lock().unwrap();
+
+			if completion.complete {
+				return;
+			}
+
+			completion.abandoned = true;
+		}
+
+		self.shared_state.condvar.notify_all();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::atomic::{AtomicBool, Ordering};
+	use std::thread;
+
+	use super::*;
+
+	#[test]
+	fn test_cancel_unblocks_waiters() {
+		let (cancelation_token, cancelable) = SyncCancelationToken::new();
+		let started = Arc::new(AtomicBool::new(false));
+
+		let waiters: Vec<_> = (0..4).map(|_| {
+			let cancelable = cancelable.clone();
+			thread::spawn(move || cancelable.wait_for_cancel())
+		}).collect();
+
+		let worker = {
+			let started = started.clone();
+			thread::spawn(move || {
+				let mut steps = 0;
+				while !cancelable.is_canceled() {
+					started.store(true, Ordering::SeqCst);
+					steps += 1;
+					thread::yield_now();
+				}
+
+				steps
+			})
+		};
+
+		while !started.load(Ordering::SeqCst) {
+			thread::yield_now();
+		}
+
+		assert!(!cancelation_token.is_canceled(), "Shouldn't be canceled yet");
+		cancelation_token.cancel();
+		cancelation_token.cancel();
+		assert!(cancelation_token.is_canceled(), "Should be canceled");
+
+		for waiter in waiters {
+			waiter.join().unwrap();
+		}
+
+		assert!(worker.join().unwrap() > 0, "The worker should have run until canceled");
+	}
+
+	#[test]
+	fn test_wait_for_cancel_after_cancel() {
+		let (cancelation_token, cancelable) = SyncCancelationToken::new();
+
+		cancelation_token.cancel();
+
+		// Returns immediately
+		cancelable.wait_for_cancel();
+		assert!(cancelable.is_canceled(), "Should be canceled");
+	}
+
+	#[test]
+	fn test_wait_for_cancel_timeout() {
+		let (cancelation_token, cancelable) = SyncCancelationToken::new();
+
+		assert!(!cancelable.wait_for_cancel_timeout(Duration::from_millis(10)), "Should time out");
+
+		let canceling = thread::spawn(move || {
+			thread::sleep(Duration::from_millis(10));
+			cancelation_token.cancel();
+		});
+
+		assert!(cancelable.wait_for_cancel_timeout(Duration::from_secs(60)), "Should be canceled");
+		canceling.join().unwrap();
+	}
+
+	#[test]
+	fn test_complete_unblocks_wait() {
+		let (completion_token, completable) = SyncCompletionToken::new();
+
+		let waiting = thread::spawn(move || completion_token.wait());
+
+		thread::sleep(Duration::from_millis(10));
+		completable.complete(vec![1, 2, 3]);
+
+		assert_eq!(waiting.join().unwrap(), vec![1, 2, 3], "Wrong result");
+	}
+
+	#[test]
+	fn test_complete_before_wait() {
+		let (completion_token, completable) = SyncCompletionToken::new();
+
+		thread::spawn(move || completable.complete(42)).join().unwrap();
+
+		assert!(completion_token.is_complete(), "Should be complete");
+		assert_eq!(completion_token.wait(), 42, "Wrong result");
+	}
+
+	#[test]
+	fn test_try_wait_abandoned() {
+		let (completion_token, completable) = SyncCompletionToken::<u32>::new();
+
+		let abandoning = thread::spawn(move || {
+			thread::sleep(Duration::from_millis(10));
+			drop(completable);
+		});
+
+		assert_eq!(completion_token.try_wait(), Err(Abandoned), "Dropping the completable should abandon");
+		abandoning.join().unwrap();
+
+		let (completion_token, completable) = SyncCompletionToken::new();
+		completable.complete("complete");
+		drop(completable);
+
+		assert_eq!(completion_token.try_wait(), Ok("complete"), "Dropping after completing shouldn't abandon");
+	}
+
+	#[test]
+	fn test_wait_timeout() {
+		let (completion_token, completable) = SyncCompletionToken::new();
+
+		let completion_token = completion_token.wait_timeout(Duration::from_millis(10)).unwrap_err();
+
+		let completing = thread::spawn(move || {
+			thread::sleep(Duration::from_millis(10));
+			completable.complete("complete");
+		});
+
+		assert_eq!(completion_token.wait_timeout(Duration::from_secs(60)).unwrap(), "complete", "Should complete");
+		completing.join().unwrap();
+	}
+
+	#[test]
+	#[should_panic(expected = "Completion token is already complete")]
+	fn test_complete_twice() {
+		let (_completion_token, completable) = SyncCompletionToken::new();
+
+		completable.complete(1);
+		completable.complete(2);
+	}
+}
@@ -0,0 +1,285 @@
+// https://github.com/GWBasic/sync-tokens
+// (c) Andrew Rondeau
+// Apache 2.0 license
+// See https://github.com/GWBasic/sync-tokens/blob/main/LICENSE
+
+//! Contains a handle to a background task, that bundles the task's join handle with the tokens that signal when it's
+//! ready and stop it. See [`TaskHandle`](struct.TaskHandle.html)
+use std::fmt;
+use std::future::Future;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+#[cfg(any(test, feature = "async-std", feature = "tokio"))]
+use futures::FutureExt;
+#[cfg(any(test, feature = "async-std", feature = "tokio"))]
+use futures::future::{Either, select};
+
+use crate::cancelation_token::CancelationToken;
+use crate::completion_token::{Abandoned, CompletionToken};
+#[cfg(any(test, feature = "async-std", feature = "tokio"))]
+use crate::completion_token::Completable;
+
+/// A handle to a background task, such as a service that a library runs. Waits for the task to be ready, and stops
+/// it. Created by `spawn_task()` in [`async_std_runtime`](../async_std_runtime/fn.spawn_task.html) or
+/// [`tokio_runtime`](../tokio_runtime/fn.spawn_task.html); R is the value that the task is ready with, and J is the
+/// runtime's join handle.
+///
+/// By default, dropping the handle cancels the task. The task still has to stop on its own, by checking its
+/// [`Cancelable`](../cancelation_token/struct.Cancelable.html). Use
+/// [`detach()`](struct.TaskHandle.html#method.detach) to keep it running instead
+pub struct TaskHandle<R, J> {
+	join_handle: Option<J>,
+	completion_token: CompletionToken<R>,
+	cancelation_token: CancelationToken,
+	finished: Arc<AtomicBool>,
+	cancel_on_drop: bool
+}
+
+// Marks the task as finished when the worker finishes, panics, or is dropped
+#[cfg(any(test, feature = "async-std", feature = "tokio"))]
+pub(crate) struct FinishedGuard(pub(crate) Arc<AtomicBool>);
+
+// Runs a worker that was started with its own completable, and forwards the value that ready_token is completed with
+// to completable. If the worker finishes, or panics, without becoming ready, completable is only dropped after the
+// task is marked finished, so that once ready() returns Abandoned, is_finished() returns true
+#[cfg(any(test, feature = "async-std", feature = "tokio"))]
+pub(crate) async fn run_worker<F, R>(worker: F, ready_token: CompletionToken<R>, completable: Completable<R>, finished: FinishedGuard) -> F::Output where
+F: Future {
+	// Declared before the guard, so that it's dropped after it, even when unwinding
+	let completable = completable;
+	let _finished = finished;
+
+	match select(Box::pin(worker), ready_token.try_wait()).await {
+		Either::Left((output, ready)) => {
+			// The worker might have become ready just before it finished
+			if let Some(Ok(value)) = ready.now_or_never() {
+				completable.complete(value);
+			}
+
+			output
+		},
+		Either::Right((ready, worker)) => {
+			if let Ok(value) = ready {
+				completable.complete(value);
+			}
+
+			worker.await
+		}
+	}
+}
+
+impl<R, J> TaskHandle<R, J> where
+J: Future + Unpin {
+	#[cfg(any(test, feature = "async-std", feature = "tokio"))]
+	pub(crate) fn new(join_handle: J, completion_token: CompletionToken<R>, cancelation_token: CancelationToken, finished: Arc<AtomicBool>) -> TaskHandle<R, J> {
+		TaskHandle {
+			join_handle: Some(join_handle),
+			completion_token,
+			cancelation_token,
+			finished,
+			cancel_on_drop: true
+		}
+	}
+
+	/// Sets whether dropping the handle cancels the task. Defaults to true
+	pub fn with_cancel_on_drop(mut self, cancel_on_drop: bool) -> TaskHandle<R, J> {
+		self.cancel_on_drop = cancel_on_drop;
+		self
+	}
+
+	/// Waits until the task is ready, and returns a clone of the value that it's ready with. Returns
+	/// [`Abandoned`](../completion_token/struct.Abandoned.html) if the task finished, or panicked, without becoming
+	/// ready. The value is retained, so this can be called any number of times
+	pub async fn ready(&self) -> Result<R, Abandoned> where
+	R: Clone {
+		self.completion_token.subscribe().try_wait().await
+	}
+
+	/// Asks the task to stop, without waiting for it. This can be called multiple times safely
+	pub fn cancel(&self) {
+		self.cancelation_token.cancel();
+	}
+
+	/// Asks the task to stop, and waits until it does. Returns what the runtime's join handle returns
+	pub async fn stop(mut self) -> J::Output {
+		self.cancel();
+		self.join_handle.take().expect("The join handle is only taken when stopping").await
+	}
+
+	/// Returns true once the task finished, panicked, or was dropped by the runtime
+	pub fn is_finished(&self) -> bool {
+		self.finished.load(Ordering::Acquire)
+	}
+
+	/// Lets the task keep running in the background, even if it isn't ready yet. It can't be canceled afterwards
+	pub fn detach(mut self) {
+		self.cancel_on_drop = false;
+	}
+
+	/// Returns the [`CancelationToken`](../cancelation_token/struct.CancelationToken.html) that stops the task, for
+	/// example, to cancel it as a child of another token
+	pub fn cancelation_token(&self) -> &CancelationToken {
+		&self.cancelation_token
+	}
+}
+
+impl<R, J> Drop for TaskHandle<R, J> {
+	fn drop(&mut self) {
+		if self.cancel_on_drop && self.join_handle.is_some() {
+			self.cancelation_token.cancel();
+		}
+	}
+}
+
+impl<R, J> fmt::Debug for TaskHandle<R, J> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("TaskHandle")
+			.field("cancelation_token", &self.cancelation_token)
+			.field("finished", &self.finished.load(Ordering::Acquire))
+			.field("cancel_on_drop", &self.cancel_on_drop)
+			.finish()
+	}
+}
+
+#[cfg(any(test, feature = "async-std", feature = "tokio"))]
+impl Drop for FinishedGuard {
+	fn drop(&mut self) {
+		self.0.store(true, Ordering::Release);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use async_std::task::JoinHandle;
+
+	use super::*;
+	use crate::cancelation_token::Cancelable;
+
+	// Spawns on async-std directly, so that these tests don't need a runtime feature
+	fn spawn<W, F, R, T>(worker: W) -> TaskHandle<R, JoinHandle<T>> where
+	W: FnOnce(Completable<R>, Cancelable) -> F,
+	F: Future<Output = T> + Send + 'static,
+	R: Send + 'static,
+	T: Send + 'static {
+		let (completion_token, completable) = CompletionToken::new();
+		let (cancelation_token, cancelable) = CancelationToken::new();
+		let finished = Arc::new(AtomicBool::new(false));
+
+		let (ready_token, ready_completable) = CompletionToken::new();
+		let worker = worker(ready_completable, cancelable);
+
+		let join_handle = async_std::task::spawn(run_worker(worker, ready_token, completable, FinishedGuard(finished.clone())));
+
+		TaskHandle::new(join_handle, completion_token, cancelation_token, finished)
+	}
+
+	async fn serve(completable: Completable<u16>, cancelable: Cancelable) -> &'static str {
+		async_std::task::sleep(Duration::from_millis(10)).await;
+		completable.complete(8080);
+
+		cancelable.future().await;
+		"stopped"
+	}
+
+	runtime_test! {
+		async fn test_ready_then_stop() {
+			let task_handle = spawn(serve);
+
+			assert_eq!(task_handle.ready().await, Ok(8080), "Should be ready");
+			assert_eq!(task_handle.ready().await, Ok(8080), "Should stay ready");
+			assert!(!task_handle.is_finished(), "Shouldn't be finished yet");
+
+			assert_eq!(task_handle.stop().await, "stopped", "Stopping should return the task's result");
+		}
+	}
+
+	runtime_test! {
+		async fn test_stop_before_ready() {
+			let task_handle = spawn(serve);
+			let cancelation_token = task_handle.cancelation_token().clone();
+
+			assert_eq!(task_handle.stop().await, "stopped", "Stopping should return the task's result");
+			assert!(cancelation_token.is_canceled(), "Stopping should cancel");
+		}
+	}
+
+	runtime_test! {
+		async fn test_cancel_before_ready() {
+			let task_handle = spawn(|completable: Completable<u16>, cancelable: Cancelable| async move {
+				let starting = async {
+					async_std::task::sleep(Duration::from_secs(60)).await;
+					true
+				};
+
+				if !cancelable.allow_cancel(Box::pin(starting), false).await {
+					return "canceled";
+				}
+
+				completable.complete(8080);
+				"finished"
+			});
+
+			task_handle.cancel();
+			task_handle.cancel();
+
+			assert_eq!(task_handle.ready().await, Err(Abandoned), "A task that stops before it's ready should abandon");
+			assert!(task_handle.is_finished(), "Should be finished");
+			assert_eq!(task_handle.stop().await, "canceled", "Wrong result");
+		}
+	}
+
+	runtime_test! {
+		async fn test_ready_after_finished() {
+			let task_handle = spawn(|completable, _cancelable| async move {
+				completable.complete("ready");
+			});
+
+			while !task_handle.is_finished() {
+				async_std::task::sleep(Duration::from_millis(1)).await;
+			}
+
+			assert_eq!(task_handle.ready().await, Ok("ready"), "The ready value should be kept after finishing");
+		}
+	}
+
+	runtime_test! {
+		async fn test_drop_cancels() {
+			let task_handle = spawn(serve);
+			let cancelation_token = task_handle.cancelation_token().clone();
+
+			drop(task_handle);
+			assert!(cancelation_token.is_canceled(), "Dropping should cancel");
+
+			let task_handle = spawn(serve).with_cancel_on_drop(false);
+			let cancelation_token = task_handle.cancelation_token().clone();
+
+			drop(task_handle);
+			assert!(!cancelation_token.is_canceled(), "Dropping shouldn't cancel");
+			cancelation_token.cancel();
+		}
+	}
+
+	runtime_test! {
+		async fn test_detach() {
+			let task_handle = spawn(serve);
+			let cancelation_token = task_handle.cancelation_token().clone();
+
+			task_handle.detach();
+			assert!(!cancelation_token.is_canceled(), "Detaching shouldn't cancel");
+			cancelation_token.cancel();
+		}
+	}
+
+	#[test]
+	fn test_debug() {
+		let (completion_token, _completable) = CompletionToken::<()>::new();
+		let (cancelation_token, _cancelable) = CancelationToken::new_with_id(42);
+
+		let task_handle = TaskHandle::new(futures::future::ready(()), completion_token, cancelation_token, Arc::new(AtomicBool::new(false)));
+
+		assert_eq!(format!("{:?}", task_handle), "TaskHandle { cancelation_token: CancelationToken { id: 42, state: Active }, finished: false, cancel_on_drop: true }", "Wrong debug output");
+	}
+}
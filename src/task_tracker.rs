@@ -0,0 +1,347 @@
+// https://github.com/GWBasic/sync-tokens
+// (c) Andrew Rondeau
+// Apache 2.0 license
+// See https://github.com/GWBasic/sync-tokens/blob/main/LICENSE
+
+//! Contains structs to wait for a group of futures to finish. See [`TaskTracker`](struct.TaskTracker.html)
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use pin_project_lite::pin_project;
+
+use crate::wakers::{WakerKey, WakerList, wake_each};
+
+/// Keeps count of running futures so that shutdown can wait for all of them to finish. A future is counted
+/// from when it's wrapped with [`track()`](struct.TaskTracker.html#method.track) until it finishes or is dropped, so
+/// a tracked future that panics, or is canceled, is never counted forever.
+///
+/// Together with a [`CancelationToken`](../cancelation_token/struct.CancelationToken.html), this allows cleanly
+/// shutting down an accept loop: cancel the loop, [`close()`](struct.TaskTracker.html#method.close) the tracker, and
+/// then wait for the connections that are still running
+///
+/// ```
+/// use async_std::task;
+/// use sync_tokens::cancelation_token::CancelationToken;
+/// use sync_tokens::task_tracker::TaskTracker;
+///
+/// # task::block_on(async {
+/// let task_tracker = TaskTracker::new();
+/// let (cancelation_token, cancelable) = CancelationToken::new();
+///
+/// for _ in 0..10 {
+///     let cancelable = cancelable.clone();
+///     task::spawn(task_tracker.track(async move {
+///         // Handle a connection until the server stops
+///         cancelable.future().await;
+///     }).unwrap());
+/// }
+///
+/// cancelation_token.cancel();
+/// task_tracker.close();
+/// task_tracker.wait().await;
+///
+/// assert!(task_tracker.is_empty());
+/// # });
+/// ```
+#[derive(Debug, Clone)]
+pub struct TaskTracker {
+	shared_state: Arc<Mutex<TaskTrackerState>>
+}
+
+pin_project! {
+	/// A future that's counted by a [`TaskTracker`](struct.TaskTracker.html) until it finishes or is dropped
+	#[derive(Debug)]
+	pub struct TrackedFuture<F> {
+		#[pin]
+		future: F,
+		shared_state: Arc<Mutex<TaskTrackerState>>,
+		tracked: bool
+	}
+
+	impl<F> PinnedDrop for TrackedFuture<F> {
+		fn drop(this: Pin<&mut Self>) {
+			let this = this.project();
+			untrack(this.shared_state, this.tracked);
+		}
+	}
+}
+
+/// Future returned by [`TaskTracker::wait()`](struct.TaskTracker.html#method.wait)
+#[derive(Debug)]
+pub struct TaskTrackerFuture {
+	shared_state: Arc<Mutex<TaskTrackerState>>,
+	waker_key: Option<WakerKey>
+}
+
+/// Error returned when tracking a future after the [`TaskTracker`](struct.TaskTracker.html) is closed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TaskTrackerClosed;
+
+#[derive(Debug)]
+struct TaskTrackerState {
+	running: usize,
+	closed: bool,
+	wakers: WakerList
+}
+
+impl TaskTracker {
+	/// Creates a new, open, [`TaskTracker`](struct.TaskTracker.html)
+	pub fn new() -> TaskTracker {
+		TaskTracker {
+			shared_state: Arc::new(Mutex::new(TaskTrackerState {
+				running: 0,
+				closed: false,
+				wakers: WakerList::new()
+			}))
+		}
+	}
+
+	/// Wraps the future so that it's counted until it finishes or is dropped. Returns
+	/// [`TaskTrackerClosed`](struct.TaskTrackerClosed.html) if the tracker is closed
+	pub fn track<F>(&self, future: F) -> Result<TrackedFuture<F>, TaskTrackerClosed> where
+	F: Future {
+		let mut shared_state = self.shared_state.lock().unwrap();
+
+		if shared_state.closed {
+			return Err(TaskTrackerClosed);
+		}
+
+		shared_state.running += 1;
+
+		Ok(TrackedFuture {
+			future,
+			shared_state: self.shared_state.clone(),
+			tracked: true
+		})
+	}
+
+	/// Stops tracking new futures. [`wait()`](struct.TaskTracker.html#method.wait) only returns after the tracker is
+	/// closed. This can be called multiple times safely
+	pub fn close(&self) {
+		let mut shared_state = self.shared_state.lock().unwrap();
+		shared_state.closed = true;
+
+		if shared_state.running == 0 {
+			let wakers = shared_state.wakers.take_all();
+			drop(shared_state);
+			wake_each(wakers);
+		}
+	}
+
+	/// Returns true once the tracker is closed
+	pub fn is_closed(&self) -> bool {
+		self.shared_state.lock().unwrap().closed
+	}
+
+	/// The number of tracked futures that haven't finished
+	pub fn len(&self) -> usize {
+		self.shared_state.lock().unwrap().running
+	}
+
+	/// Returns true if every tracked future finished
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+
+	/// Returns a future that returns once the tracker is closed and every tracked future has finished
+	pub fn wait(&self) -> TaskTrackerFuture {
+		TaskTrackerFuture {
+			shared_state: self.shared_state.clone(),
+			waker_key: None
+		}
+	}
+}
+
+impl Default for TaskTracker {
+	fn default() -> Self {
+		TaskTracker::new()
+	}
+}
+
+fn untrack(shared_state: &Arc<Mutex<TaskTrackerState>>, tracked: &mut bool) {
+	if *tracked {
+		*tracked = false;
+
+		let mut shared_state = shared_state.lock().unwrap();
+		shared_state.running -= 1;
+
+		if shared_state.running == 0 && shared_state.closed {
+			let wakers = shared_state.wakers.take_all();
+			drop(shared_state);
+			wake_each(wakers);
+		}
+	}
+}
+
+impl<F> Future for TrackedFuture<F> where
+F: Future {
+	type Output = F::Output;
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+		let this = self.project();
+
+		match this.future.poll(cx) {
+			Poll::Ready(result) => {
+				untrack(this.shared_state, this.tracked);
+				Poll::Ready(result)
+			},
+			Poll::Pending => Poll::Pending
+		}
+	}
+}
+
+impl Future for TaskTrackerFuture {
+	type Output = ();
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+		let this = self.get_mut();
+		let mut shared_state = this.shared_state.lock().unwrap();
+
+		if shared_state.closed && shared_state.running == 0 {
+			Poll::Ready(())
+		} else {
+			shared_state.wakers.register(&mut this.waker_key, cx.waker());
+			Poll::Pending
+		}
+	}
+}
+
+impl Drop for TaskTrackerFuture {
+	fn drop(&mut self) {
+		if self.waker_key.is_some() {
+			let mut shared_state = self.shared_state.lock().unwrap();
+			shared_state.wakers.remove(self.waker_key);
+		}
+	}
+}
+
+impl fmt::Display for TaskTrackerClosed {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "The task tracker is closed")
+	}
+}
+
+impl Error for TaskTrackerClosed {}
+
+#[cfg(test)]
+mod tests {
+	use std::panic::AssertUnwindSafe;
+	use std::thread;
+	use std::time::Duration;
+
+	use async_std::task;
+	use cooked_waker::IntoWaker;
+	use futures::FutureExt;
+
+	use super::*;
+	use crate::completion_token::CompletionToken;
+	use crate::tests::*;
+
+	#[test]
+	fn test_via_poll() {
+		let task_tracker = TaskTracker::new();
+		let (completion_token, completable) = CompletionToken::new();
+
+		let mut tracked = Box::pin(task_tracker.track(completion_token).unwrap());
+		assert_eq!(task_tracker.len(), 1, "Future should be tracked");
+
+		let test_waker = TestWaker::new();
+		let waker = test_waker.clone().into_waker();
+		let mut cx = Context::from_waker(&waker);
+
+		let mut wait = task_tracker.wait();
+		assert!(Pin::new(&mut wait).poll(&mut cx).is_pending(), "Wait should be pending");
+
+		task_tracker.close();
+		assert!(Pin::new(&mut wait).poll(&mut cx).is_pending(), "Wait should be pending while a future runs");
+		assert!(!test_waker.woke(), "Waiter shouldn't be woken");
+
+		completable.complete(());
+		assert!(tracked.as_mut().poll(&mut cx).is_ready(), "Tracked future should finish");
+
+		assert!(task_tracker.is_empty(), "Nothing should be tracked");
+		assert!(test_waker.woke(), "Waiter should be woken");
+		assert!(Pin::new(&mut wait).poll(&mut cx).is_ready(), "Wait should finish");
+	}
+
+	#[test]
+	fn test_wait_requires_close() {
+		let task_tracker = TaskTracker::new();
+
+		let test_waker = TestWaker::new();
+		let waker = test_waker.clone().into_waker();
+		let mut cx = Context::from_waker(&waker);
+
+		let mut wait = task_tracker.wait();
+		assert!(Pin::new(&mut wait).poll(&mut cx).is_pending(), "Wait shouldn't finish until closed");
+
+		task_tracker.close();
+		assert!(test_waker.woke(), "Closing should wake the waiter");
+		assert!(Pin::new(&mut wait).poll(&mut cx).is_ready(), "Wait should finish");
+	}
+
+	#[test]
+	fn test_track_after_close() {
+		let task_tracker = TaskTracker::new();
+		task_tracker.close();
+
+		assert!(task_tracker.is_closed(), "Tracker should be closed");
+		assert_eq!(task_tracker.track(async {}).err(), Some(TaskTrackerClosed), "Tracking should fail after close");
+	}
+
+	#[test]
+	fn test_dropped_future_is_untracked() {
+		let task_tracker = TaskTracker::new();
+
+		let tracked = task_tracker.track(futures::future::pending::<()>()).unwrap();
+		assert_eq!(task_tracker.len(), 1, "Future should be tracked");
+
+		drop(tracked);
+		assert!(task_tracker.is_empty(), "Dropped future should be untracked");
+	}
+
+	runtime_test! {
+		async fn test_panic_is_untracked() {
+			let task_tracker = TaskTracker::new();
+
+			let tracked = task_tracker.track(async {
+				panic!("Tracked future panics");
+			}).unwrap();
+
+			let result = AssertUnwindSafe(tracked).catch_unwind().await;
+			assert!(result.is_err(), "Future should panic");
+			assert!(task_tracker.is_empty(), "Panicked future should be untracked");
+
+			let tracked = task_tracker.track(async {
+				panic!("Tracked future panics on another thread");
+			}).unwrap();
+
+			let result = thread::spawn(move || futures::executor::block_on(tracked)).join();
+			assert!(result.is_err(), "Thread should panic");
+			assert!(task_tracker.is_empty(), "Panicked future should be untracked");
+		}
+	}
+
+	runtime_test! {
+		async fn test_spawned_tasks() {
+			let task_tracker = TaskTracker::new();
+
+			for i in 0..10 {
+				task::spawn(task_tracker.track(async move {
+					task::sleep(Duration::from_millis(20 + i * 5)).await;
+				}).unwrap());
+			}
+
+			assert_eq!(task_tracker.len(), 10, "Every task should be tracked");
+
+			task_tracker.close();
+			task_tracker.wait().await;
+
+			assert!(task_tracker.is_empty(), "Every task should be finished");
+		}
+	}
+}
@@ -0,0 +1,219 @@
+// https://github.com/GWBasic/sync-tokens
+// (c) Andrew Rondeau
+// Apache 2.0 license
+// See https://github.com/GWBasic/sync-tokens/blob/main/LICENSE
+
+//! Contains a [`Cancelable`](../cancelation_token/struct.Cancelable.html) that limits how many tasks handle
+//! cancelation at the same time. See [`ThrottledCancelable`](struct.ThrottledCancelable.html)
+use std::future::Future;
+
+use futures::future::{Either, select};
+
+use crate::cancelation_token::Cancelable;
+use crate::semaphore::{Semaphore, SemaphorePermit};
+
+/// Wraps a [`Cancelable`](../cancelation_token/struct.Cancelable.html), and limits how many tasks run their cancel
+/// handlers at the same time. Once max_concurrent_cancels tasks are handling cancelation, the others wait for one of
+/// them to finish. This models a graceful shutdown where cleanup uses a limited resource, such as database
+/// connections.
+///
+/// Clones share the same limit
+///
+/// ```
+/// use sync_tokens::cancelation_token::CancelationToken;
+/// use sync_tokens::throttled_cancelable::ThrottledCancelable;
+///
+/// # async_std::task::block_on(async {
+/// let (cancelation_token, cancelable) = CancelationToken::new();
+/// let throttled_cancelable = ThrottledCancelable::new(cancelable, 2);
+///
+/// let workers: Vec<_> = (0..5).map(|_| {
+///     let throttled_cancelable = throttled_cancelable.clone();
+///     async_std::task::spawn(async move {
+///         throttled_cancelable.allow_cancel(futures::future::pending(), || async {
+///             // Flush state, at most 2 at a time
+///             "flushed"
+///         }).await
+///     })
+/// }).collect();
+///
+/// cancelation_token.cancel();
+///
+/// for worker in workers {
+///     assert_eq!(worker.await, "flushed");
+/// }
+/// # });
+/// ```
+#[derive(Debug, Clone)]
+pub struct ThrottledCancelable {
+	cancelable: Cancelable,
+	semaphore: Semaphore
+}
+
+impl ThrottledCancelable {
+	/// Wraps cancelable, so that at most max_concurrent_cancels tasks handle cancelation at the same time
+	///
+	/// # Panics
+	///
+	/// Panics if max_concurrent_cancels is 0
+	pub fn new(cancelable: Cancelable, max_concurrent_cancels: usize) -> ThrottledCancelable {
+		assert!(max_concurrent_cancels > 0, "At least one task must be able to handle cancelation");
+
+		ThrottledCancelable {
+			cancelable,
+			semaphore: Semaphore::new(max_concurrent_cancels)
+		}
+	}
+
+	/// Waits until the [`CancelationToken`](../cancelation_token/struct.CancelationToken.html) is canceled, and then
+	/// until there's a slot to handle the cancelation. The task handles cancelation until it drops the returned
+	/// permit
+	pub async fn canceled(&self) -> SemaphorePermit {
+		self.cancelable.future().await;
+
+		self.semaphore.acquire(None).await.expect("Acquiring without a cancelable can't be canceled")
+	}
+
+	/// Runs future until it finishes, or until the [`CancelationToken`](../cancelation_token/struct.CancelationToken.html)
+	/// is canceled. When canceled, future is dropped right away, without waiting for a slot, and cancel_handler runs
+	/// once there's a slot. Returns the result of whichever ran last
+	pub async fn allow_cancel<TFuture, T, H, HFuture>(&self, future: TFuture, cancel_handler: H) -> T where
+	TFuture: Future<Output = T>,
+	H: FnOnce() -> HFuture,
+	HFuture: Future<Output = T> {
+		let future = Box::pin(future);
+
+		match select(future, self.cancelable.future()).await {
+			Either::Left((result, _)) => result,
+			Either::Right(((), future)) => {
+				// Not polled again while waiting for a slot
+				drop(future);
+
+				let permit = self.semaphore.acquire(None).await.expect("Acquiring without a cancelable can't be canceled");
+				let result = cancel_handler().await;
+				drop(permit);
+				result
+			}
+		}
+	}
+
+	/// Returns the wrapped [`Cancelable`](../cancelation_token/struct.Cancelable.html)
+	pub fn cancelable(&self) -> &Cancelable {
+		&self.cancelable
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::Arc;
+	use std::sync::atomic::{AtomicUsize, Ordering};
+	use std::time::Duration;
+
+	use super::*;
+	use crate::cancelation_token::CancelationToken;
+
+	runtime_test! {
+		async fn test_limits_concurrent_cancels() {
+			let (cancelation_token, cancelable) = CancelationToken::new();
+			let throttled_cancelable = ThrottledCancelable::new(cancelable, 2);
+
+			let handling = Arc::new(AtomicUsize::new(0));
+			let max_handling = Arc::new(AtomicUsize::new(0));
+
+			let tasks: Vec<_> = (0..5).map(|i| {
+				let throttled_cancelable = throttled_cancelable.clone();
+				let handling = handling.clone();
+				let max_handling = max_handling.clone();
+
+				async_std::task::spawn(async move {
+					throttled_cancelable.allow_cancel(futures::future::pending(), || async move {
+						let now_handling = handling.fetch_add(1, Ordering::SeqCst) + 1;
+						max_handling.fetch_max(now_handling, Ordering::SeqCst);
+
+						async_std::task::sleep(Duration::from_millis(20)).await;
+
+						handling.fetch_sub(1, Ordering::SeqCst);
+						i
+					}).await
+				})
+			}).collect();
+
+			async_std::task::sleep(Duration::from_millis(10)).await;
+			cancelation_token.cancel();
+
+			let mut handled = Vec::new();
+			for task in tasks {
+				handled.push(task.await);
+			}
+
+			assert_eq!(handled, vec![0, 1, 2, 3, 4], "Every task should handle cancelation");
+			assert_eq!(max_handling.load(Ordering::SeqCst), 2, "No more than 2 tasks should handle cancelation at once");
+		}
+	}
+
+	runtime_test! {
+		async fn test_finishes_without_cancel() {
+			let (_cancelation_token, cancelable) = CancelationToken::new();
+			let throttled_cancelable = ThrottledCancelable::new(cancelable, 1);
+
+			let result = throttled_cancelable.allow_cancel(async { "finished" }, || async { "canceled" }).await;
+			assert_eq!(result, "finished", "Wrong result");
+		}
+	}
+
+	runtime_test! {
+		async fn test_drops_future_before_waiting_for_a_slot() {
+			let (cancelation_token, cancelable) = CancelationToken::new();
+			let throttled_cancelable = ThrottledCancelable::new(cancelable, 1);
+
+			cancelation_token.cancel();
+			let permit = throttled_cancelable.canceled().await;
+
+			// Dropped along with the future
+			let alive = Arc::new(());
+			let future = {
+				let alive = alive.clone();
+				async move {
+					let _alive = alive;
+					futures::future::pending::<&str>().await
+				}
+			};
+
+			let waiting = async_std::task::spawn({
+				let throttled_cancelable = throttled_cancelable.clone();
+				async move {
+					throttled_cancelable.allow_cancel(future, || async { "canceled" }).await
+				}
+			});
+
+			async_std::task::sleep(Duration::from_millis(10)).await;
+			assert_eq!(Arc::strong_count(&alive), 1, "The future should be dropped while the task waits for a slot");
+
+			drop(permit);
+			assert_eq!(waiting.await, "canceled", "Wrong result");
+		}
+	}
+
+	runtime_test! {
+		async fn test_canceled_holds_slot() {
+			let (cancelation_token, cancelable) = CancelationToken::new();
+			let throttled_cancelable = ThrottledCancelable::new(cancelable, 1);
+
+			cancelation_token.cancel();
+			let permit = throttled_cancelable.canceled().await;
+
+			let waiting = async_std::task::spawn({
+				let throttled_cancelable = throttled_cancelable.clone();
+				async move {
+					throttled_cancelable.canceled().await;
+				}
+			});
+
+			async_std::task::sleep(Duration::from_millis(10)).await;
+			assert!(futures::FutureExt::now_or_never(throttled_cancelable.canceled()).is_none(), "The only slot should be taken");
+
+			drop(permit);
+			waiting.await;
+		}
+	}
+}
@@ -0,0 +1,459 @@
+// https://github.com/GWBasic/sync-tokens
+// (c) Andrew Rondeau
+// Apache 2.0 license
+// See https://github.com/GWBasic/sync-tokens/blob/main/LICENSE
+
+//! Contains a registry that cancels many tokens at their deadlines from a single timer. See
+//! [`TimeoutRegistry`](struct.TimeoutRegistry.html)
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use crate::cancelation_token::CancelationToken;
+use crate::timer::{DeadlineSleep, Instant, Timer};
+
+/// Cancels [`CancelationToken`](../cancelation_token/struct.CancelationToken.html)s at their deadlines. Every scheduled
+/// cancelation shares one timer, so thousands of per-request deadlines don't need thousands of timers.
+///
+/// The cancelations are driven by [`driver()`](struct.TimeoutRegistry.html#method.driver), a future that's spawned
+/// once and runs until it's dropped. [`schedule()`](struct.TimeoutRegistry.html#method.schedule) returns a
+/// [`ScheduleHandle`](struct.ScheduleHandle.html) that can move the deadline; dropping the handle deschedules the
+/// cancelation
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use async_std::task;
+/// use sync_tokens::cancelation_token::CancelationToken;
+/// use sync_tokens::timeout_registry::TimeoutRegistry;
+///
+/// # task::block_on(async {
+/// let timeout_registry = TimeoutRegistry::new();
+/// task::spawn(timeout_registry.driver());
+///
+/// let (cancelation_token, cancelable) = CancelationToken::new();
+/// let schedule_handle = timeout_registry.schedule_after(cancelation_token, Duration::from_millis(10));
+///
+/// cancelable.future().await;
+/// assert!(schedule_handle.is_fired());
+/// # });
+/// ```
+#[derive(Debug, Clone)]
+pub struct TimeoutRegistry {
+	shared_state: Arc<Mutex<TimeoutRegistryState>>,
+	timer: Timer
+}
+
+/// A scheduled cancelation. Dropping the handle deschedules it
+#[derive(Debug)]
+pub struct ScheduleHandle {
+	shared_state: Arc<Mutex<TimeoutRegistryState>>,
+	timer: Timer,
+	id: u64
+}
+
+/// Future returned by [`TimeoutRegistry::driver()`](struct.TimeoutRegistry.html#method.driver). Cancels tokens as
+/// their deadlines pass. It never returns
+#[derive(Debug)]
+pub struct TimeoutDriver {
+	shared_state: Arc<Mutex<TimeoutRegistryState>>,
+	sleep: DeadlineSleep
+}
+
+#[derive(Debug)]
+struct TimeoutRegistryState {
+	entries: HashMap<u64, TimeoutEntry>,
+	// Deadlines in order. Rescheduled and descheduled entries leave stale items behind, which are skipped
+	deadlines: BinaryHeap<Reverse<(Instant, u64, u64)>>,
+	next_id: u64,
+	fired: HashSet<u64>,
+	driver_waker: Option<Waker>,
+	// The deadline that the driver sleeps until
+	armed: Option<Instant>
+}
+
+#[derive(Debug)]
+struct TimeoutEntry {
+	deadline: Instant,
+	generation: u64,
+	cancelation_token: CancelationToken
+}
+
+impl TimeoutRegistry {
+	/// Creates a new [`TimeoutRegistry`](struct.TimeoutRegistry.html) that uses the system clock
+	pub fn new() -> TimeoutRegistry {
+		TimeoutRegistry::with_timer(Timer::default())
+	}
+
+	/// Creates a new [`TimeoutRegistry`](struct.TimeoutRegistry.html) that uses the given
+	/// [`Timer`](../timer/struct.Timer.html)
+	pub fn with_timer(timer: Timer) -> TimeoutRegistry {
+		TimeoutRegistry {
+			shared_state: Arc::new(Mutex::new(TimeoutRegistryState {
+				entries: HashMap::new(),
+				deadlines: BinaryHeap::new(),
+				next_id: 0,
+				fired: HashSet::new(),
+				driver_waker: None,
+				armed: None
+			})),
+			timer
+		}
+	}
+
+	/// Schedules cancelation_token to be canceled at deadline
+	pub fn schedule(&self, cancelation_token: CancelationToken, deadline: Instant) -> ScheduleHandle {
+		let mut shared_state = self.shared_state.lock().unwrap();
+
+		let id = shared_state.next_id;
+		shared_state.next_id += 1;
+
+		shared_state.entries.insert(id, TimeoutEntry {
+			deadline,
+			generation: 0,
+			cancelation_token
+		});
+
+		shared_state.push_deadline(deadline, id, 0);
+
+		ScheduleHandle {
+			shared_state: self.shared_state.clone(),
+			timer: self.timer.clone(),
+			id
+		}
+	}
+
+	/// Schedules cancelation_token to be canceled after duration
+	pub fn schedule_after(&self, cancelation_token: CancelationToken, duration: Duration) -> ScheduleHandle {
+		self.schedule(cancelation_token, self.timer.now() + duration)
+	}
+
+	/// The number of scheduled cancelations that haven't fired
+	pub fn len(&self) -> usize {
+		self.shared_state.lock().unwrap().entries.len()
+	}
+
+	/// Returns true if no cancelations are scheduled
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+
+	/// Returns the future that cancels tokens as their deadlines pass. Spawn it once; if more than one driver runs,
+	/// they share the work
+	pub fn driver(&self) -> TimeoutDriver {
+		TimeoutDriver {
+			shared_state: self.shared_state.clone(),
+			sleep: DeadlineSleep::new(self.timer.clone())
+		}
+	}
+}
+
+impl Default for TimeoutRegistry {
+	fn default() -> Self {
+		TimeoutRegistry::new()
+	}
+}
+
+impl ScheduleHandle {
+	/// Moves the deadline. Returns false if the cancelation already fired
+	pub fn reschedule(&self, deadline: Instant) -> bool {
+		let mut shared_state = self.shared_state.lock().unwrap();
+
+		let generation = match shared_state.entries.get_mut(&self.id) {
+			Some(entry) => {
+				entry.deadline = deadline;
+				entry.generation += 1;
+				entry.generation
+			},
+			None => return false
+		};
+
+		shared_state.push_deadline(deadline, self.id, generation);
+		shared_state.compact();
+		true
+	}
+
+	/// Moves the deadline to duration from now. Returns false if the cancelation already fired
+	pub fn reschedule_after(&self, duration: Duration) -> bool {
+		self.reschedule(self.timer.now() + duration)
+	}
+
+	/// The deadline, or None if the cancelation already fired
+	pub fn deadline(&self) -> Option<Instant> {
+		let shared_state = self.shared_state.lock().unwrap();
+		shared_state.entries.get(&self.id).map(|entry| entry.deadline)
+	}
+
+	/// Returns true once the token is canceled
+	pub fn is_fired(&self) -> bool {
+		self.shared_state.lock().unwrap().fired.contains(&self.id)
+	}
+}
+
+impl Drop for ScheduleHandle {
+	fn drop(&mut self) {
+		let mut shared_state = self.shared_state.lock().unwrap();
+		shared_state.entries.remove(&self.id);
+		shared_state.fired.remove(&self.id);
+		shared_state.compact();
+	}
+}
+
+impl TimeoutRegistryState {
+	fn push_deadline(&mut self, deadline: Instant, id: u64, generation: u64) {
+		self.deadlines.push(Reverse((deadline, id, generation)));
+
+		// Only wake the driver when it sleeps past the new deadline
+		let wake_driver = match self.armed {
+			Some(armed) => deadline < armed,
+			None => true
+		};
+
+		if wake_driver {
+			if let Some(waker) = self.driver_waker.take() {
+				waker.wake();
+			}
+		}
+	}
+
+	// Removes every entry whose deadline passed, and returns its token
+	fn take_expired(&mut self, now: Instant) -> Vec<CancelationToken> {
+		let mut expired = Vec::new();
+
+		while let Some(Reverse((deadline, id, generation))) = self.deadlines.peek().copied() {
+			if deadline > now {
+				break;
+			}
+
+			self.deadlines.pop();
+
+			let is_current = self.entries.get(&id)
+				.map(|entry| entry.generation == generation)
+				.unwrap_or(false);
+
+			if is_current {
+				let entry = self.entries.remove(&id).unwrap();
+				self.fired.insert(id);
+				expired.push(entry.cancelation_token);
+			}
+		}
+
+		expired
+	}
+
+	// Drops stale deadlines once they outnumber the live ones
+	fn compact(&mut self) {
+		if self.deadlines.len() > 64 && self.deadlines.len() > self.entries.len() * 2 {
+			let entries = &self.entries;
+			self.deadlines.retain(|Reverse((_, id, generation))| {
+				entries.get(id).map(|entry| entry.generation == *generation).unwrap_or(false)
+			});
+		}
+	}
+}
+
+impl Future for TimeoutDriver {
+	type Output = ();
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+		let this = self.get_mut();
+
+		loop {
+			let (expired, next_deadline) = {
+				let mut shared_state = this.shared_state.lock().unwrap();
+				let expired = shared_state.take_expired(this.sleep.now());
+
+				let next_deadline = shared_state.deadlines.peek().map(|Reverse((deadline, _, _))| *deadline);
+				shared_state.armed = next_deadline;
+				shared_state.driver_waker = Some(cx.waker().clone());
+
+				(expired, next_deadline)
+			};
+
+			// Tokens are canceled without holding the registry's lock
+			for cancelation_token in expired {
+				cancelation_token.cancel();
+			}
+
+			let deadline = match next_deadline {
+				Some(deadline) => deadline,
+				None => {
+					this.sleep.clear();
+					return Poll::Pending;
+				}
+			};
+
+			if this.sleep.poll_until(deadline, cx).is_pending() {
+				return Poll::Pending;
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::thread;
+
+	use futures::executor::LocalPool;
+	use futures::task::LocalSpawnExt;
+
+	use super::*;
+	use crate::timer::ManualClock;
+
+	fn manual_registry() -> (ManualClock, TimeoutRegistry, LocalPool) {
+		let clock = ManualClock::new();
+		let timeout_registry = TimeoutRegistry::with_timer(Timer::new(clock.clone()));
+
+		let pool = LocalPool::new();
+		pool.spawner().spawn_local(timeout_registry.driver()).unwrap();
+
+		(clock, timeout_registry, pool)
+	}
+
+	#[test]
+	fn test_fires_in_deadline_order() {
+		let (clock, timeout_registry, mut pool) = manual_registry();
+
+		let tokens: Vec<_> = (0..3).map(|_| CancelationToken::new()).collect();
+		let handles: Vec<_> = tokens.iter().zip([3, 1, 2].iter())
+			.map(|((cancelation_token, _), seconds)| timeout_registry.schedule_after(cancelation_token.clone(), Duration::from_secs(*seconds)))
+			.collect();
+
+		pool.run_until_stalled();
+		assert_eq!(timeout_registry.len(), 3, "Everything should be scheduled");
+
+		let mut canceled = Vec::new();
+		for _ in 0..3 {
+			clock.advance(Duration::from_secs(1));
+			pool.run_until_stalled();
+
+			canceled.push(tokens.iter().map(|(_, cancelable)| cancelable.is_canceled()).collect::<Vec<_>>());
+		}
+
+		assert_eq!(canceled, vec![
+			vec![false, true, false],
+			vec![false, true, true],
+			vec![true, true, true]
+		], "Canceled in the wrong order");
+
+		assert!(handles.iter().all(|schedule_handle| schedule_handle.is_fired()), "Everything should be fired");
+		assert!(timeout_registry.is_empty(), "Nothing should be scheduled");
+	}
+
+	#[test]
+	fn test_reschedule() {
+		let (clock, timeout_registry, mut pool) = manual_registry();
+		let (cancelation_token, cancelable) = CancelationToken::new();
+
+		let schedule_handle = timeout_registry.schedule_after(cancelation_token, Duration::from_secs(5));
+		pool.run_until_stalled();
+
+		// Later, right before the old deadline
+		clock.advance(Duration::from_secs(4));
+		assert!(schedule_handle.reschedule_after(Duration::from_secs(5)), "Should reschedule");
+
+		clock.advance(Duration::from_secs(1));
+		pool.run_until_stalled();
+		assert!(!cancelable.is_canceled(), "The old deadline shouldn't fire");
+
+		// Earlier, which must wake the sleeping driver
+		assert!(schedule_handle.reschedule_after(Duration::from_secs(1)), "Should reschedule");
+		pool.run_until_stalled();
+
+		clock.advance(Duration::from_secs(1));
+		pool.run_until_stalled();
+		assert!(cancelable.is_canceled(), "The new deadline should fire");
+
+		assert!(!schedule_handle.reschedule_after(Duration::from_secs(1)), "Fired cancelations can't be rescheduled");
+		assert_eq!(schedule_handle.deadline(), None, "Fired cancelations have no deadline");
+	}
+
+	#[test]
+	fn test_deschedule() {
+		let (clock, timeout_registry, mut pool) = manual_registry();
+		let (cancelation_token, cancelable) = CancelationToken::new();
+
+		let schedule_handle = timeout_registry.schedule_after(cancelation_token, Duration::from_secs(5));
+		pool.run_until_stalled();
+
+		// Dropped right at the deadline, before the driver runs
+		clock.advance(Duration::from_secs(5));
+		drop(schedule_handle);
+		pool.run_until_stalled();
+
+		assert!(!cancelable.is_canceled(), "Descheduled cancelations shouldn't fire");
+		assert!(timeout_registry.is_empty(), "Nothing should be scheduled");
+	}
+
+	#[test]
+	fn test_many_reschedules_are_compacted() {
+		let (clock, timeout_registry, mut pool) = manual_registry();
+		let (cancelation_token, _cancelable) = CancelationToken::new();
+
+		let schedule_handle = timeout_registry.schedule_after(cancelation_token, Duration::from_secs(5));
+		for i in 0..1000 {
+			schedule_handle.reschedule_after(Duration::from_secs(5 + i));
+		}
+
+
+		let deadlines = timeout_registry.shared_state.lock().unwrap().deadlines.len();
+		assert!(deadlines <= 64, "Stale deadlines should be compacted, found {}", deadlines);
+
+		clock.advance(Duration::from_secs(2000));
+		pool.run_until_stalled();
+		assert!(schedule_handle.is_fired(), "Should fire");
+	}
+
+	#[test]
+	fn test_races() {
+		let timeout_registry = TimeoutRegistry::new();
+		let driver = thread::spawn({
+			let driver = timeout_registry.driver();
+			move || futures::executor::block_on(futures::future::select(driver, futures_timer::Delay::new(Duration::from_millis(200))))
+		});
+
+		let threads: Vec<_> = (0..4).map(|_| {
+			let timeout_registry = timeout_registry.clone();
+
+			thread::spawn(move || {
+				let mut kept = Vec::new();
+				let mut descheduled = Vec::new();
+
+				for i in 0..250 {
+					let (cancelation_token, cancelable) = CancelationToken::new();
+					let schedule_handle = timeout_registry.schedule_after(cancelation_token, Duration::from_millis(i % 5 + 20));
+
+					match i % 3 {
+						0 => {
+							drop(schedule_handle);
+							descheduled.push(cancelable);
+						},
+						1 => {
+							schedule_handle.reschedule_after(Duration::from_millis(i % 7));
+							kept.push((cancelable, schedule_handle));
+						},
+						_ => kept.push((cancelable, schedule_handle))
+					}
+				}
+
+				(kept, descheduled)
+			})
+		}).collect();
+
+		let results: Vec<_> = threads.into_iter().map(|thread| thread.join().unwrap()).collect();
+		drop(driver.join().unwrap());
+
+		for (kept, descheduled) in results {
+			for (cancelable, schedule_handle) in kept {
+				assert!(cancelable.is_canceled(), "Scheduled cancelations should fire");
+				assert!(schedule_handle.is_fired(), "Scheduled cancelations should be fired");
+			}
+
+			assert!(descheduled.iter().all(|cancelable| !cancelable.is_canceled()), "Descheduled cancelations shouldn't fire");
+		}
+	}
+}
@@ -0,0 +1,415 @@
+// https://github.com/GWBasic/sync-tokens
+// (c) Andrew Rondeau
+// Apache 2.0 license
+// See https://github.com/GWBasic/sync-tokens/blob/main/LICENSE
+
+//! Contains the timer used by everything in this crate that waits for time to pass. See [`Timer`](struct.Timer.html)
+//! and [`ManualClock`](struct.ManualClock.html)
+use std::fmt::Debug;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use crate::wakers::{WakerKey, WakerList, wake_each};
+
+/// The point in time used by [`Timer`](struct.Timer.html). This is `std::time::Instant`, except on wasm, where the
+/// standard library's clock isn't available and [`web_time::Instant`](https://docs.rs/web-time) is used instead
+#[cfg(not(target_arch = "wasm32"))]
+pub use std::time::Instant;
+
+/// The point in time used by [`Timer`](struct.Timer.html). This is `std::time::Instant`, except on wasm, where the
+/// standard library's clock isn't available and [`web_time::Instant`](https://docs.rs/web-time) is used instead
+#[cfg(target_arch = "wasm32")]
+pub use web_time::Instant;
+
+/// Provides the current time and sleeping to a [`Timer`](struct.Timer.html). Implement this to drive the crate's
+/// timers from a runtime's own timer, or from a simulated clock
+pub trait TimerProvider: Debug + Send + Sync {
+	/// Returns the current time
+	fn now(&self) -> Instant;
+
+	/// Returns a future that returns once deadline is reached
+	fn sleep_until(&self, deadline: Instant) -> Pin<Box<dyn Future<Output = ()> + Send>>;
+}
+
+/// The timer used by everything in this crate that waits for time to pass. Cloning a [`Timer`](struct.Timer.html)
+/// is cheap; clones share the same [`TimerProvider`](trait.TimerProvider.html).
+///
+/// The default timer uses the system clock. Tests can use a [`ManualClock`](struct.ManualClock.html) instead, so that
+/// time only passes when the test advances it
+#[derive(Debug, Clone)]
+pub struct Timer {
+	provider: Arc<dyn TimerProvider>
+}
+
+/// Future returned by [`Timer::sleep()`](struct.Timer.html#method.sleep) and
+/// [`Timer::sleep_until()`](struct.Timer.html#method.sleep_until)
+pub struct Sleep {
+	future: Pin<Box<dyn Future<Output = ()> + Send>>
+}
+
+// Sleeps until a deadline that can move. The sleep is kept while the deadline stays the same, and replaced once it
+// changes, so a future that's polled often doesn't create a new timer each time
+#[derive(Debug)]
+pub(crate) struct DeadlineSleep {
+	timer: Timer,
+	sleep: Option<(Instant, Sleep)>
+}
+
+/// [`TimerProvider`](trait.TimerProvider.html) that uses the system clock. On wasm, this sleeps with the browser's
+/// `setTimeout`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemTimer;
+
+/// [`TimerProvider`](trait.TimerProvider.html) that uses tokio's timer. Paused time, from `tokio::time::pause()`, is
+/// respected
+#[cfg(feature = "tokio")]
+#[cfg_attr(feature = "docs", doc(cfg(feature = "tokio")))]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokioTimer;
+
+/// [`TimerProvider`](trait.TimerProvider.html) that uses async-std's timer
+#[cfg(feature = "async-std")]
+#[cfg_attr(feature = "docs", doc(cfg(feature = "async-std")))]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AsyncStdTimer;
+
+/// A simulated clock for tests. Time only passes when [`advance()`](struct.ManualClock.html#method.advance) is called,
+/// which makes tests that involve timeouts exact and fast.
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use futures::executor::LocalPool;
+/// use futures::task::LocalSpawnExt;
+/// use sync_tokens::timer::{ManualClock, Timer};
+///
+/// let clock = ManualClock::new();
+/// let timer = Timer::new(clock.clone());
+///
+/// let mut pool = LocalPool::new();
+/// let slept = pool.spawner().spawn_local_with_handle(timer.sleep(Duration::from_secs(10))).unwrap();
+///
+/// pool.run_until_stalled();
+/// clock.advance(Duration::from_secs(10));
+/// pool.run_until(slept);
+/// ```
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+	shared_state: Arc<Mutex<ManualClockState>>
+}
+
+#[derive(Debug)]
+struct ManualClockState {
+	now: Instant,
+	wakers: WakerList
+}
+
+#[derive(Debug)]
+struct ManualSleep {
+	shared_state: Arc<Mutex<ManualClockState>>,
+	deadline: Instant,
+	waker_key: Option<WakerKey>
+}
+
+impl Timer {
+	/// Creates a [`Timer`](struct.Timer.html) that uses the given [`TimerProvider`](trait.TimerProvider.html)
+	pub fn new<P>(provider: P) -> Timer where
+	P: TimerProvider + 'static {
+		Timer {
+			provider: Arc::new(provider)
+		}
+	}
+
+	/// Creates a [`Timer`](struct.Timer.html) that uses the system clock
+	pub fn system() -> Timer {
+		Timer::new(SystemTimer)
+	}
+
+	/// Creates a [`Timer`](struct.Timer.html) that uses tokio's timer. Sleeping must happen within a tokio runtime
+	#[cfg(feature = "tokio")]
+	#[cfg_attr(feature = "docs", doc(cfg(feature = "tokio")))]
+	pub fn tokio() -> Timer {
+		Timer::new(TokioTimer)
+	}
+
+	/// Creates a [`Timer`](struct.Timer.html) that uses async-std's timer
+	#[cfg(feature = "async-std")]
+	#[cfg_attr(feature = "docs", doc(cfg(feature = "async-std")))]
+	pub fn async_std() -> Timer {
+		Timer::new(AsyncStdTimer)
+	}
+
+	/// Returns the current time
+	pub fn now(&self) -> Instant {
+		self.provider.now()
+	}
+
+	/// Returns a future that returns once duration passes
+	pub fn sleep(&self, duration: Duration) -> Sleep {
+		self.sleep_until(self.now() + duration)
+	}
+
+	/// Returns a future that returns once deadline is reached
+	pub fn sleep_until(&self, deadline: Instant) -> Sleep {
+		Sleep {
+			future: self.provider.sleep_until(deadline)
+		}
+	}
+}
+
+impl Default for Timer {
+	fn default() -> Self {
+		Timer::system()
+	}
+}
+
+impl Future for Sleep {
+	type Output = ();
+
+	fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+		self.future.as_mut().poll(cx)
+	}
+}
+
+impl DeadlineSleep {
+	pub(crate) fn new(timer: Timer) -> DeadlineSleep {
+		DeadlineSleep {
+			timer,
+			sleep: None
+		}
+	}
+
+	pub(crate) fn now(&self) -> Instant {
+		self.timer.now()
+	}
+
+	// Returns Ready once deadline is reached. The sleep is dropped then, so that the next call starts a new one
+	pub(crate) fn poll_until(&mut self, deadline: Instant, cx: &mut Context<'_>) -> Poll<()> {
+		let sleep = match &mut self.sleep {
+			Some((sleep_deadline, sleep)) if *sleep_deadline == deadline => sleep,
+			_ => &mut self.sleep.insert((deadline, self.timer.sleep_until(deadline))).1
+		};
+
+		if Pin::new(sleep).poll(cx).is_pending() {
+			return Poll::Pending;
+		}
+
+		self.sleep = None;
+		Poll::Ready(())
+	}
+
+	// Drops the sleep, for when there's nothing left to wait for
+	pub(crate) fn clear(&mut self) {
+		self.sleep = None;
+	}
+}
+
+impl Debug for Sleep {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("Sleep").finish()
+	}
+}
+
+impl TimerProvider for SystemTimer {
+	fn now(&self) -> Instant {
+		Instant::now()
+	}
+
+	fn sleep_until(&self, deadline: Instant) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+		Box::pin(futures_timer::Delay::new(deadline.saturating_duration_since(Instant::now())))
+	}
+}
+
+#[cfg(feature = "tokio")]
+impl TimerProvider for TokioTimer {
+	fn now(&self) -> Instant {
+		tokio::time::Instant::now().into_std()
+	}
+
+	fn sleep_until(&self, deadline: Instant) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+		Box::pin(tokio::time::sleep_until(tokio::time::Instant::from_std(deadline)))
+	}
+}
+
+#[cfg(feature = "async-std")]
+impl TimerProvider for AsyncStdTimer {
+	fn now(&self) -> Instant {
+		Instant::now()
+	}
+
+	fn sleep_until(&self, deadline: Instant) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+		Box::pin(async_std::task::sleep(deadline.saturating_duration_since(Instant::now())))
+	}
+}
+
+impl ManualClock {
+	/// Creates a new [`ManualClock`](struct.ManualClock.html). Time is frozen until
+	/// [`advance()`](struct.ManualClock.html#method.advance) is called
+	pub fn new() -> ManualClock {
+		ManualClock {
+			shared_state: Arc::new(Mutex::new(ManualClockState {
+				now: Instant::now(),
+				wakers: WakerList::new()
+			}))
+		}
+	}
+
+	/// Moves time forward, waking everything that sleeps until a deadline that's now reached
+	pub fn advance(&self, duration: Duration) {
+		let mut shared_state = self.shared_state.lock().unwrap();
+		shared_state.now += duration;
+
+		// Sleepers check their own deadline when polled
+		let wakers = shared_state.wakers.take_all();
+		drop(shared_state);
+		wake_each(wakers);
+	}
+}
+
+impl Default for ManualClock {
+	fn default() -> Self {
+		ManualClock::new()
+	}
+}
+
+impl TimerProvider for ManualClock {
+	fn now(&self) -> Instant {
+		self.shared_state.lock().unwrap().now
+	}
+
+	fn sleep_until(&self, deadline: Instant) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+		Box::pin(ManualSleep {
+			shared_state: self.shared_state.clone(),
+			deadline,
+			waker_key: None
+		})
+	}
+}
+
+impl Future for ManualSleep {
+	type Output = ();
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+		let this = self.get_mut();
+		let mut shared_state = this.shared_state.lock().unwrap();
+
+		if shared_state.now >= this.deadline {
+			Poll::Ready(())
+		} else {
+			shared_state.wakers.register(&mut this.waker_key, cx.waker());
+			Poll::Pending
+		}
+	}
+}
+
+impl Drop for ManualSleep {
+	fn drop(&mut self) {
+		if self.waker_key.is_some() {
+			let mut shared_state = self.shared_state.lock().unwrap();
+			shared_state.wakers.remove(self.waker_key);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use cooked_waker::IntoWaker;
+
+	use super::*;
+	use crate::tests::*;
+
+	#[test]
+	fn test_manual_clock() {
+		let clock = ManualClock::new();
+		let timer = Timer::new(clock.clone());
+		let start = timer.now();
+
+		let test_waker = TestWaker::new();
+		let waker = test_waker.clone().into_waker();
+		let mut cx = Context::from_waker(&waker);
+
+		let mut sleep = timer.sleep(Duration::from_secs(10));
+		assert!(Pin::new(&mut sleep).poll(&mut cx).is_pending(), "Sleep should be pending");
+
+		clock.advance(Duration::from_secs(9));
+		assert_eq!(timer.now() - start, Duration::from_secs(9), "Wrong time");
+		assert!(Pin::new(&mut sleep).poll(&mut cx).is_pending(), "Sleep should be pending before the deadline");
+
+		clock.advance(Duration::from_secs(1));
+		assert!(test_waker.woke(), "Sleep should be woken");
+		assert!(Pin::new(&mut sleep).poll(&mut cx).is_ready(), "Sleep should finish at the deadline");
+	}
+
+	#[test]
+	fn test_deadline_sleep_moves() {
+		let clock = ManualClock::new();
+		let mut deadline_sleep = DeadlineSleep::new(Timer::new(clock.clone()));
+		let start = deadline_sleep.now();
+
+		let test_waker = TestWaker::new();
+		let waker = test_waker.clone().into_waker();
+		let mut cx = Context::from_waker(&waker);
+
+		assert!(deadline_sleep.poll_until(start + Duration::from_secs(10), &mut cx).is_pending(), "Should sleep");
+
+		// Moving the deadline later replaces the sleep, so the old deadline doesn't count
+		clock.advance(Duration::from_secs(10));
+		assert!(deadline_sleep.poll_until(start + Duration::from_secs(20), &mut cx).is_pending(), "Should sleep until the new deadline");
+
+		clock.advance(Duration::from_secs(10));
+		assert!(deadline_sleep.poll_until(start + Duration::from_secs(20), &mut cx).is_ready(), "Should finish at the new deadline");
+	}
+
+	runtime_test! {
+		async fn test_system_timer() {
+			let timer = Timer::default();
+			let start = Instant::now();
+
+			timer.sleep(Duration::from_millis(20)).await;
+
+			assert!(start.elapsed() >= Duration::from_millis(20), "Sleep returned early");
+		}
+	}
+
+	#[cfg(feature = "tokio")]
+	#[tokio::test(start_paused = true)]
+	async fn test_tokio_timer() {
+		let timer = Timer::tokio();
+		let start = timer.now();
+
+		timer.sleep(Duration::from_secs(60)).await;
+
+		assert_eq!(timer.now() - start, Duration::from_secs(60), "Paused time should advance exactly");
+	}
+
+	#[cfg(feature = "async-std")]
+	#[async_std::test]
+	async fn test_async_std_timer() {
+		let timer = Timer::async_std();
+		let start = Instant::now();
+
+		timer.sleep(Duration::from_millis(20)).await;
+
+		assert!(start.elapsed() >= Duration::from_millis(20), "Sleep returned early");
+	}
+}
+
+#[cfg(all(test, target_arch = "wasm32"))]
+mod wasm_tests {
+	use wasm_bindgen_test::wasm_bindgen_test;
+
+	use super::*;
+
+	#[wasm_bindgen_test]
+	async fn test_system_timer() {
+		let timer = Timer::default();
+		let start = timer.now();
+
+		timer.sleep(Duration::from_millis(20)).await;
+
+		assert!(timer.now() - start >= Duration::from_millis(20), "Sleep returned early");
+	}
+}
@@ -0,0 +1,379 @@
+// https://github.com/GWBasic/sync-tokens
+// (c) Andrew Rondeau
+// Apache 2.0 license
+// See https://github.com/GWBasic/sync-tokens/blob/main/LICENSE
+
+//! Contains conveniences for spawning cancelable tasks on tokio. Requires the `tokio` feature. See
+//! [`spawn_cancelable()`](fn.spawn_cancelable.html), [`bind_to()`](fn.bind_to.html),
+//! [`spawn_coordinated()`](fn.spawn_coordinated.html) and [`spawn_task()`](fn.spawn_task.html). A
+//! [`Timer`](../timer/struct.Timer.html) that uses tokio's timer is created with
+//! [`Timer::tokio()`](../timer/struct.Timer.html#method.tokio)
+use std::future::Future;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+
+use futures::future::{Either, select};
+use tokio::task::{JoinError, JoinHandle};
+
+use crate::cancelation_token::{Cancelable, CancelationToken};
+use crate::completion_token::{Completable, CompletionToken};
+use crate::task_handle::{FinishedGuard, run_worker};
+
+/// Spawns future on tokio, so that it stops when cancelable is canceled. The task returns canceled_result when
+/// canceled
+///
+/// ```
+/// use sync_tokens::cancelation_token::CancelationToken;
+/// use sync_tokens::tokio_runtime::spawn_cancelable;
+///
+/// # tokio::runtime::Runtime::new().unwrap().block_on(async {
+/// let (cancelation_token, cancelable) = CancelationToken::new();
+///
+/// let join_handle = spawn_cancelable(&cancelable, futures::future::pending(), "canceled");
+/// cancelation_token.cancel();
+///
+/// assert_eq!(join_handle.await.unwrap(), "canceled");
+/// # });
+/// ```
+pub fn spawn_cancelable<F, T>(cancelable: &Cancelable, future: F, canceled_result: T) -> JoinHandle<T> where
+F: Future<Output = T> + Send + 'static,
+T: Send + 'static {
+	tokio::spawn(cancelable.allow_cancel(Box::pin(future), canceled_result))
+}
+
+/// Aborts an already spawned task when cancelable is canceled. Returns a handle that finishes when the task finishes,
+/// or once it's aborted; an aborted task returns a [`JoinError`](https://docs.rs/tokio/latest/tokio/task/struct.JoinError.html)
+/// where `is_cancelled()` is true
+pub fn bind_to<T>(cancelable: &Cancelable, join_handle: JoinHandle<T>) -> JoinHandle<Result<T, JoinError>> where
+T: Send + 'static {
+	let canceled = cancelable.future();
+
+	tokio::spawn(async move {
+		match select(join_handle, canceled).await {
+			Either::Left((result, _)) => result,
+			Either::Right((_, join_handle)) => {
+				join_handle.abort();
+				join_handle.await
+			}
+		}
+	})
+}
+
+/// Spawns a worker on tokio, with a [`Completable`](../completion_token/struct.Completable.html) to signal when it's
+/// ready and a [`Cancelable`](../cancelation_token/struct.Cancelable.html) to stop it. Returns the task's handle, and
+/// the matching [`CompletionToken`](../completion_token/struct.CompletionToken.html) and
+/// [`CancelationToken`](../cancelation_token/struct.CancelationToken.html).
+/// 
+/// If the worker panics before it's ready, the completion token is abandoned, and awaiting the handle returns a
+/// [`JoinError`](https://docs.rs/tokio/latest/tokio/task/struct.JoinError.html) where `is_panic()` is true
+///
+/// ```
+/// use sync_tokens::tokio_runtime::spawn_coordinated;
+///
+/// # tokio::runtime::Runtime::new().unwrap().block_on(async {
+/// let (join_handle, completion_token, cancelation_token) = spawn_coordinated(|completable, cancelable| async move {
+///     completable.complete("listening");
+///     cancelable.allow_cancel(futures::future::pending(), "stopped").await
+/// });
+///
+/// assert_eq!(completion_token.await, "listening");
+/// cancelation_token.cancel();
+/// assert_eq!(join_handle.await.unwrap(), "stopped");
+/// # });
+/// ```
+pub fn spawn_coordinated<W, F, T, R>(worker: W) -> (JoinHandle<T>, CompletionToken<R>, CancelationToken) where
+W: FnOnce(Completable<R>, Cancelable) -> F,
+F: Future<Output = T> + Send + 'static,
+T: Send + 'static {
+	let (completion_token, completable) = CompletionToken::new();
+	let (cancelation_token, cancelable) = CancelationToken::new();
+
+	// Tokio drops a task that panics, along with its completable, so the completion token is abandoned
+	let join_handle = tokio::spawn(worker(completable, cancelable));
+
+	(join_handle, completion_token, cancelation_token)
+}
+
+/// A [`TaskHandle`](../task_handle/struct.TaskHandle.html) for a task on tokio, that's ready with R and returns T.
+/// Returned by [`spawn_task()`](fn.spawn_task.html)
+pub type TaskHandle<R, T> = crate::task_handle::TaskHandle<R, JoinHandle<T>>;
+
+/// Spawns a worker on tokio, the same as [`spawn_coordinated()`](fn.spawn_coordinated.html), and returns a
+/// [`TaskHandle`](../task_handle/struct.TaskHandle.html) to wait for it to be ready and to stop it
+///
+/// ```
+/// use sync_tokens::tokio_runtime::spawn_task;
+///
+/// # tokio::runtime::Runtime::new().unwrap().block_on(async {
+/// let task_handle = spawn_task(|completable, cancelable| async move {
+///     completable.complete("listening");
+///     cancelable.future().await;
+///     "stopped"
+/// });
+///
+/// assert_eq!(task_handle.ready().await, Ok("listening"));
+/// assert_eq!(task_handle.stop().await.unwrap(), "stopped");
+/// # });
+/// ```
+pub fn spawn_task<W, F, T, R>(worker: W) -> TaskHandle<R, T> where
+W: FnOnce(Completable<R>, Cancelable) -> F,
+F: Future<Output = T> + Send + 'static,
+T: Send + 'static,
+R: Send + 'static {
+	let finished = Arc::new(AtomicBool::new(false));
+	let guard = FinishedGuard(finished.clone());
+
+	let (join_handle, completion_token, cancelation_token) = spawn_coordinated(move |completable, cancelable| {
+		// The worker gets its own completable, so that the task is marked finished before the token is abandoned
+		let (ready_token, ready_completable) = CompletionToken::new();
+		run_worker(worker(ready_completable, cancelable), ready_token, completable, guard)
+	});
+
+	crate::task_handle::TaskHandle::new(join_handle, completion_token, cancelation_token, finished)
+}
+
+// Runs the crate's async scenarios under tokio, including its multi-threaded scheduler, to catch problems specific to
+// tokio's wakers
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use super::*;
+	use crate::completion_token::Abandoned;
+	use crate::once_token::OnceToken;
+	use crate::rate_gate::RateGate;
+	use crate::semaphore::Semaphore;
+	use crate::shutdown_controller::ShutdownController;
+	use crate::supervisor::{RestartPolicy, SupervisionEnd, supervise};
+	use crate::task_tracker::TaskTracker;
+	use crate::timer::Timer;
+
+	#[tokio::test]
+	async fn test_spawn_cancelable() {
+		let (cancelation_token, cancelable) = CancelationToken::new();
+
+		let completed = spawn_cancelable(&cancelable, async { "completed" }, "canceled");
+		assert_eq!(completed.await.unwrap(), "completed", "Wrong result");
+
+		let canceled = spawn_cancelable(&cancelable, futures::future::pending(), "canceled");
+		tokio::task::yield_now().await;
+		cancelation_token.cancel();
+
+		assert_eq!(canceled.await.unwrap(), "canceled", "Wrong result");
+	}
+
+	#[tokio::test]
+	async fn test_spawn_coordinated() {
+		let (join_handle, completion_token, cancelation_token) = spawn_coordinated(|completable, cancelable| async move {
+			tokio::time::sleep(Duration::from_millis(10)).await;
+			completable.complete(8080);
+
+			cancelable.future().await;
+			"stopped"
+		});
+
+		assert_eq!(completion_token.await, 8080, "Should be ready");
+
+		cancelation_token.cancel();
+		assert_eq!(join_handle.await.unwrap(), "stopped", "Should stop once canceled");
+	}
+
+	#[tokio::test]
+	async fn test_spawn_task() {
+		let task_handle = spawn_task(|completable, cancelable| async move {
+			tokio::time::sleep(Duration::from_millis(10)).await;
+			completable.complete(8080);
+
+			cancelable.future().await;
+			"stopped"
+		});
+
+		assert_eq!(task_handle.ready().await, Ok(8080), "Should be ready");
+		assert!(!task_handle.is_finished(), "Shouldn't be finished yet");
+		assert_eq!(task_handle.stop().await.unwrap(), "stopped", "Should stop once canceled");
+
+		let task_handle = spawn_task(|completable: Completable<u16>, _cancelable| async move {
+			let _completable = completable;
+			panic!("Worker failed")
+		});
+
+		assert_eq!(task_handle.ready().await, Err(Abandoned), "A panic should abandon");
+
+		// The completable is dropped while the panic unwinds, so the task might not be finished yet
+		for _ in 0..1000 {
+			if task_handle.is_finished() {
+				break;
+			}
+
+			tokio::time::sleep(Duration::from_millis(1)).await;
+		}
+
+		assert!(task_handle.is_finished(), "A panicked task is finished");
+		assert!(task_handle.stop().await.unwrap_err().is_panic(), "The panic should be passed on");
+	}
+
+	#[tokio::test]
+	async fn test_spawn_coordinated_panics() {
+		let (join_handle, completion_token, _cancelation_token) = spawn_coordinated(|_completable: Completable<u16>, _cancelable| async move {
+			panic!("Worker failed")
+		});
+
+		assert_eq!(completion_token.try_wait().await, Err(Abandoned), "A panic should abandon the completion token");
+		assert!(join_handle.await.unwrap_err().is_panic(), "The panic should be passed on");
+	}
+
+	#[tokio::test]
+	async fn test_bind_to() {
+		let (cancelation_token, cancelable) = CancelationToken::new();
+
+		let finished = bind_to(&cancelable, tokio::spawn(async { 42 }));
+		assert_eq!(finished.await.unwrap().unwrap(), 42, "Wrong result");
+
+		let stuck = bind_to(&cancelable, tokio::spawn(futures::future::pending::<()>()));
+		cancelation_token.cancel();
+
+		assert!(stuck.await.unwrap().unwrap_err().is_cancelled(), "The task should be aborted");
+	}
+
+	#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+	async fn test_cancelation_token() {
+		let (cancelation_token, cancelable) = CancelationToken::new();
+
+		let waiting: Vec<_> = (0..10).map(|_| {
+			let cancelable = cancelable.clone();
+			tokio::spawn(async move {
+				cancelable.allow_cancel(Box::pin(futures::future::pending()), "canceled").await
+			})
+		}).collect();
+
+		tokio::time::sleep(Duration::from_millis(10)).await;
+		cancelation_token.cancel();
+
+		for waiting in waiting {
+			assert_eq!(waiting.await.unwrap(), "canceled", "Every waiter should be canceled");
+		}
+	}
+
+	#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+	async fn test_completion_token() {
+		let (completion_token, completable) = CompletionToken::new();
+		let subscribers: Vec<_> = (0..10).map(|_| tokio::spawn(completion_token.subscribe())).collect();
+
+		tokio::time::sleep(Duration::from_millis(10)).await;
+		completable.complete("ready");
+
+		for subscriber in subscribers {
+			assert_eq!(subscriber.await.unwrap(), "ready", "Every subscriber should see the result");
+		}
+
+		let (completion_token, completable) = CompletionToken::<()>::new();
+		let waiting = tokio::spawn(completion_token.try_wait());
+		drop(completable);
+
+		assert_eq!(waiting.await.unwrap(), Err(Abandoned), "Should be abandoned");
+	}
+
+	#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+	async fn test_semaphore() {
+		let semaphore = Semaphore::new(2);
+
+		let tasks: Vec<_> = (0..20).map(|_| {
+			let semaphore = semaphore.clone();
+			tokio::spawn(async move {
+				let _permit = semaphore.acquire(None).await;
+				tokio::task::yield_now().await;
+			})
+		}).collect();
+
+		for task in tasks {
+			task.await.unwrap();
+		}
+
+		assert_eq!(semaphore.available_permits(), 2, "Every permit should be returned");
+	}
+
+	#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+	async fn test_once_token() {
+		let once_token = std::sync::Arc::new(OnceToken::new());
+
+		let tasks: Vec<_> = (0..10).map(|i| {
+			let once_token = once_token.clone();
+			tokio::spawn(async move {
+				*once_token.get_or_init(|| async move {
+					tokio::time::sleep(Duration::from_millis(5)).await;
+					i
+				}).await
+			})
+		}).collect();
+
+		let mut results = Vec::new();
+		for task in tasks {
+			results.push(task.await.unwrap());
+		}
+
+		assert!(results.iter().all(|result| *result == results[0]), "Every task should see the same value");
+	}
+
+	#[tokio::test]
+	async fn test_task_tracker() {
+		let task_tracker = TaskTracker::new();
+
+		for _ in 0..5 {
+			tokio::spawn(task_tracker.track(tokio::time::sleep(Duration::from_millis(5))).unwrap());
+		}
+
+		task_tracker.close();
+		task_tracker.wait().await;
+
+		assert!(task_tracker.is_empty(), "Every task should be finished");
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn test_shutdown_controller() {
+		let shutdown_controller = ShutdownController::with_timer(Timer::tokio());
+
+		let (cancelable, shutdown_guard) = shutdown_controller.subscribe();
+		tokio::spawn(async move {
+			cancelable.future().await;
+			drop(shutdown_guard);
+		});
+
+		let (_cancelable, _stuck_shutdown_guard) = shutdown_controller.subscribe();
+
+		let shutdown_report = shutdown_controller.shutdown(Duration::from_secs(5)).await;
+
+		assert_eq!((shutdown_report.finished, shutdown_report.abandoned), (1, 1), "Wrong report");
+		assert_eq!(shutdown_report.elapsed, Duration::from_secs(5), "Paused time should advance exactly");
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn test_rate_gate() {
+		let rate_gate = RateGate::with_timer(1, Duration::from_secs(1), Timer::tokio());
+		let start = tokio::time::Instant::now();
+
+		for _ in 0..3 {
+			rate_gate.acquire(None).await.unwrap();
+		}
+
+		assert_eq!(start.elapsed(), Duration::from_secs(2), "3 permits at 1 per second should take 2 seconds");
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn test_supervise() {
+		let (cancelation_token, cancelable) = CancelationToken::new();
+		let restart_policy = RestartPolicy::new()
+			.with_backoff(Duration::from_secs(1), Duration::from_secs(1))
+			.with_timer(Timer::tokio());
+
+		let supervised = tokio::spawn(async move {
+			supervise(&cancelable, restart_policy, |_| async { Err("failed") }).await
+		});
+
+		tokio::time::sleep(Duration::from_millis(2500)).await;
+		cancelation_token.cancel();
+
+		let supervision_report = supervised.await.unwrap();
+		assert_eq!(supervision_report.attempts, 3, "Should restart once per second");
+		assert_eq!(supervision_report.end, SupervisionEnd::Canceled, "Should be canceled");
+	}
+}
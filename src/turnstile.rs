@@ -0,0 +1,399 @@
+// https://github.com/GWBasic/sync-tokens
+// (c) Andrew Rondeau
+// Apache 2.0 license
+// See https://github.com/GWBasic/sync-tokens/blob/main/LICENSE
+
+//! Contains structs to make concurrent tasks take turns, in ticket order, for one step. See
+//! [`Turnstile`](struct.Turnstile.html)
+use std::collections::{BTreeMap, BTreeSet};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+/// Makes concurrent tasks perform one step in ticket order, while everything else runs freely.
+///
+/// Each task takes a numbered [`Ticket`](struct.Ticket.html) up front with
+/// [`ticket()`](struct.Turnstile.html#method.ticket), and later calls
+/// [`wait_my_turn()`](struct.Turnstile.html#method.wait_my_turn). The returned [`TurnGuard`](struct.TurnGuard.html)
+/// holds the turn; dropping it moves the turnstile to the next ticket. Tasks can arrive in any order.
+///
+/// What happens when a ticket is dropped without being used depends on the
+/// [`DroppedTicketPolicy`](enum.DroppedTicketPolicy.html)
+///
+/// ```
+/// use std::sync::{Arc, Mutex};
+///
+/// use async_std::task;
+/// use sync_tokens::turnstile::Turnstile;
+///
+/// # task::block_on(async {
+/// let turnstile = Turnstile::new();
+/// let order = Arc::new(Mutex::new(Vec::new()));
+///
+/// let tickets: Vec<_> = (0..5).map(|_| turnstile.ticket()).collect();
+///
+/// // Start the tasks in reverse order
+/// let tasks: Vec<_> = tickets.into_iter().rev().map(|ticket| {
+///     let turnstile = turnstile.clone();
+///     let order = order.clone();
+///
+///     task::spawn(async move {
+///         let number = ticket.number();
+///         let _turn_guard = turnstile.wait_my_turn(ticket).await;
+///         order.lock().unwrap().push(number);
+///     })
+/// }).collect();
+///
+/// for task in tasks {
+///     task.await;
+/// }
+///
+/// assert_eq!(*order.lock().unwrap(), vec![0, 1, 2, 3, 4]);
+/// # });
+/// ```
+#[derive(Debug, Clone)]
+pub struct Turnstile {
+	shared_state: Arc<Mutex<TurnstileState>>
+}
+
+/// A place in line at a [`Turnstile`](struct.Turnstile.html)
+#[derive(Debug)]
+pub struct Ticket {
+	shared_state: Arc<Mutex<TurnstileState>>,
+	number: u64,
+	used: bool
+}
+
+/// Holds the turn at a [`Turnstile`](struct.Turnstile.html). Dropping it moves the turnstile to the next ticket
+#[derive(Debug)]
+pub struct TurnGuard {
+	shared_state: Arc<Mutex<TurnstileState>>,
+	number: u64
+}
+
+/// Future returned by [`Turnstile::wait_my_turn()`](struct.Turnstile.html#method.wait_my_turn)
+#[derive(Debug)]
+pub struct TurnstileFuture {
+	ticket: Option<Ticket>
+}
+
+/// What a [`Turnstile`](struct.Turnstile.html) does when a [`Ticket`](struct.Ticket.html) is dropped without being
+/// used, including when a [`TurnstileFuture`](struct.TurnstileFuture.html) is dropped before its turn
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DroppedTicketPolicy {
+	/// The ticket's turn is skipped
+	Skip,
+	/// The turnstile waits at the ticket's turn until [`advance()`](struct.Turnstile.html#method.advance) is called
+	Wait
+}
+
+#[derive(Debug)]
+struct TurnstileState {
+	current: u64,
+	next_ticket: u64,
+	dropped_ticket_policy: DroppedTicketPolicy,
+	skipped: BTreeSet<u64>,
+	waiters: BTreeMap<u64, Waker>
+}
+
+impl Turnstile {
+	/// Creates a new [`Turnstile`](struct.Turnstile.html) that skips dropped tickets
+	pub fn new() -> Turnstile {
+		Turnstile::with_dropped_ticket_policy(DroppedTicketPolicy::Skip)
+	}
+
+	/// Creates a new [`Turnstile`](struct.Turnstile.html) that handles dropped tickets according to
+	/// dropped_ticket_policy
+	pub fn with_dropped_ticket_policy(dropped_ticket_policy: DroppedTicketPolicy) -> Turnstile {
+		Turnstile {
+			shared_state: Arc::new(Mutex::new(TurnstileState {
+				current: 0,
+				next_ticket: 0,
+				dropped_ticket_policy,
+				skipped: BTreeSet::new(),
+				waiters: BTreeMap::new()
+			}))
+		}
+	}
+
+	/// Takes the next numbered [`Ticket`](struct.Ticket.html)
+	pub fn ticket(&self) -> Ticket {
+		let mut shared_state = self.shared_state.lock().unwrap();
+
+		let number = shared_state.next_ticket;
+		shared_state.next_ticket += 1;
+
+		Ticket {
+			shared_state: self.shared_state.clone(),
+			number,
+			used: false
+		}
+	}
+
+	/// Returns a future that returns a [`TurnGuard`](struct.TurnGuard.html) once it's the ticket's turn
+	///
+	/// # Panics
+	///
+	/// Panics if ticket was taken from a different [`Turnstile`](struct.Turnstile.html)
+	pub fn wait_my_turn(&self, ticket: Ticket) -> TurnstileFuture {
+		assert!(Arc::ptr_eq(&self.shared_state, &ticket.shared_state), "The ticket is from a different turnstile");
+
+		TurnstileFuture {
+			ticket: Some(ticket)
+		}
+	}
+
+	/// Moves to the next ticket, even if the current turn is held, or its ticket is waiting to be used
+	pub fn advance(&self) {
+		self.shared_state.lock().unwrap().advance();
+	}
+
+	/// The number of the ticket whose turn it is
+	pub fn current_turn(&self) -> u64 {
+		self.shared_state.lock().unwrap().current
+	}
+}
+
+impl Default for Turnstile {
+	fn default() -> Self {
+		Turnstile::new()
+	}
+}
+
+impl Ticket {
+	/// The ticket's number. Tickets are numbered in the order they're taken, starting from 0
+	pub fn number(&self) -> u64 {
+		self.number
+	}
+}
+
+impl TurnGuard {
+	/// The number of the ticket whose turn this is
+	pub fn number(&self) -> u64 {
+		self.number
+	}
+}
+
+impl TurnstileState {
+	fn advance(&mut self) {
+		self.current += 1;
+
+		while self.skipped.remove(&self.current) {
+			self.current += 1;
+		}
+
+		if let Some(waker) = self.waiters.remove(&self.current) {
+			waker.wake();
+		}
+	}
+}
+
+impl Drop for Ticket {
+	fn drop(&mut self) {
+		if self.used {
+			return;
+		}
+
+		let mut shared_state = self.shared_state.lock().unwrap();
+		shared_state.waiters.remove(&self.number);
+
+		if shared_state.dropped_ticket_policy == DroppedTicketPolicy::Skip {
+			if shared_state.current == self.number {
+				shared_state.advance();
+			} else if shared_state.current < self.number {
+				shared_state.skipped.insert(self.number);
+			}
+		}
+	}
+}
+
+impl Drop for TurnGuard {
+	fn drop(&mut self) {
+		let mut shared_state = self.shared_state.lock().unwrap();
+
+		// The turnstile may already have been advanced past this turn
+		if shared_state.current == self.number {
+			shared_state.advance();
+		}
+	}
+}
+
+impl Future for TurnstileFuture {
+	type Output = TurnGuard;
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+		let this = self.get_mut();
+		let ticket = this.ticket.as_mut().expect("TurnstileFuture polled after completion");
+
+		{
+			let mut shared_state = ticket.shared_state.lock().unwrap();
+
+			if shared_state.current < ticket.number {
+				shared_state.waiters.insert(ticket.number, cx.waker().clone());
+				return Poll::Pending;
+			}
+		}
+
+		// It's this ticket's turn, or the turnstile was advanced past it
+		ticket.used = true;
+		let turn_guard = TurnGuard {
+			shared_state: ticket.shared_state.clone(),
+			number: ticket.number
+		};
+
+		this.ticket = None;
+		Poll::Ready(turn_guard)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use async_std::task;
+	use cooked_waker::IntoWaker;
+
+	use super::*;
+	use crate::tests::*;
+
+	fn poll_turn(turnstile_future: &mut TurnstileFuture, test_waker: &TestWaker) -> Poll<TurnGuard> {
+		let waker = test_waker.clone().into_waker();
+		Pin::new(turnstile_future).poll(&mut Context::from_waker(&waker))
+	}
+
+	#[test]
+	fn test_turn_order() {
+		let turnstile = Turnstile::new();
+
+		let first = turnstile.ticket();
+		let second = turnstile.ticket();
+		assert_eq!((first.number(), second.number()), (0, 1), "Wrong ticket numbers");
+
+		let second_waker = TestWaker::new();
+		let mut second_future = turnstile.wait_my_turn(second);
+		assert!(poll_turn(&mut second_future, &second_waker).is_pending(), "Second should wait for first");
+
+		let first_waker = TestWaker::new();
+		let mut first_future = turnstile.wait_my_turn(first);
+		let first_guard = match poll_turn(&mut first_future, &first_waker) {
+			Poll::Ready(turn_guard) => turn_guard,
+			Poll::Pending => panic!("First should have its turn")
+		};
+
+		assert!(!second_waker.woke(), "Second shouldn't be woken while first holds the turn");
+
+		drop(first_guard);
+		assert!(second_waker.woke(), "Second should be woken");
+		assert_eq!(turnstile.current_turn(), 1, "Wrong turn");
+
+		match poll_turn(&mut second_future, &second_waker) {
+			Poll::Ready(turn_guard) => assert_eq!(turn_guard.number(), 1, "Wrong turn"),
+			Poll::Pending => panic!("Second should have its turn")
+		}
+
+		assert_eq!(turnstile.current_turn(), 2, "Wrong turn");
+	}
+
+	runtime_test! {
+		async fn test_out_of_order_arrival() {
+			let turnstile = Turnstile::new();
+			let order = Arc::new(Mutex::new(Vec::new()));
+
+			let tickets: Vec<_> = (0..20).map(|_| turnstile.ticket()).collect();
+
+			let tasks: Vec<_> = tickets.into_iter().rev().enumerate().map(|(i, ticket)| {
+				let turnstile = turnstile.clone();
+				let order = order.clone();
+
+				task::spawn(async move {
+					task::sleep(Duration::from_millis(i as u64 % 3)).await;
+
+					let number = ticket.number();
+					let _turn_guard = turnstile.wait_my_turn(ticket).await;
+					order.lock().unwrap().push(number);
+				})
+			}).collect();
+
+			for task in tasks {
+				task.await;
+			}
+
+			assert_eq!(*order.lock().unwrap(), (0..20).collect::<Vec<_>>(), "Turns taken out of order");
+		}
+	}
+
+	#[test]
+	fn test_dropped_ticket_is_skipped() {
+		let turnstile = Turnstile::new();
+
+		let first = turnstile.ticket();
+		let second = turnstile.ticket();
+		let third = turnstile.ticket();
+
+		let test_waker = TestWaker::new();
+		let mut third_future = turnstile.wait_my_turn(third);
+		assert!(poll_turn(&mut third_future, &test_waker).is_pending(), "Third should wait");
+
+		// Dropped before its turn, and then dropped on its turn
+		drop(second);
+		assert!(!test_waker.woke(), "Third shouldn't be woken yet");
+
+		drop(first);
+
+		assert!(test_waker.woke(), "Third should be woken");
+		assert!(poll_turn(&mut third_future, &test_waker).is_ready(), "Third should have its turn");
+	}
+
+	#[test]
+	fn test_wait_for_dropped_ticket() {
+		let turnstile = Turnstile::with_dropped_ticket_policy(DroppedTicketPolicy::Wait);
+
+		let first = turnstile.ticket();
+		let second = turnstile.ticket();
+
+		let test_waker = TestWaker::new();
+		let mut second_future = turnstile.wait_my_turn(second);
+
+		drop(first);
+		assert!(poll_turn(&mut second_future, &test_waker).is_pending(), "Second should wait for the controller");
+
+		turnstile.advance();
+		assert!(test_waker.woke(), "Second should be woken");
+		assert!(poll_turn(&mut second_future, &test_waker).is_ready(), "Second should have its turn");
+	}
+
+	#[test]
+	fn test_advance_past_held_turn() {
+		let turnstile = Turnstile::new();
+
+		let first = turnstile.ticket();
+		let second = turnstile.ticket();
+		let third = turnstile.ticket();
+
+		let test_waker = TestWaker::new();
+		let first_guard = match poll_turn(&mut turnstile.wait_my_turn(first), &test_waker) {
+			Poll::Ready(turn_guard) => turn_guard,
+			Poll::Pending => panic!("First should have its turn")
+		};
+
+		turnstile.advance();
+		assert_eq!(turnstile.current_turn(), 1, "Wrong turn");
+
+		drop(first_guard);
+		assert_eq!(turnstile.current_turn(), 1, "Dropping a guard that was advanced past shouldn't advance again");
+
+		drop(second);
+		assert_eq!(turnstile.current_turn(), 2, "Wrong turn");
+		assert!(poll_turn(&mut turnstile.wait_my_turn(third), &test_waker).is_ready(), "Third should have its turn");
+	}
+
+	#[test]
+	#[should_panic(expected = "The ticket is from a different turnstile")]
+	fn test_ticket_from_another_turnstile() {
+		let turnstile = Turnstile::new();
+		let other_turnstile = Turnstile::new();
+
+		drop(turnstile.wait_my_turn(other_turnstile.ticket()));
+	}
+}
@@ -0,0 +1,503 @@
+// https://github.com/GWBasic/sync-tokens
+// (c) Andrew Rondeau
+// Apache 2.0 license
+// See https://github.com/GWBasic/sync-tokens/blob/main/LICENSE
+
+//! Internal storage for the wakers of every future waiting on a shared state
+use std::any::Any;
+use std::cell::Cell;
+use std::panic::{AssertUnwindSafe, catch_unwind, resume_unwind};
+use std::task::Waker;
+
+/// Identifies a registered waker. A slot is reused after its waker is removed or woken, and slots are dropped when the
+/// list shrinks, so every registration gets a new generation from the list, which tells the key apart from a later key
+/// for the same slot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct WakerKey {
+	index: usize,
+	generation: u64
+}
+
+/// Holds one waker per waiting future. Each future keeps the key handed out by
+/// [`register()`](struct.WakerList.html#method.register) so that it can replace its own waker
+/// when polled again, and remove it when dropped.
+///
+/// Wakers are kept in slots that are reused once they're vacated, so registering and removing are O(1), and, once
+/// the list has grown to the number of waiting futures, never allocate. Thousands of tasks can wait on the same
+/// state without the list shifting or reallocating as they come and go.
+///
+/// Once the list empties, it shrinks if far fewer futures waited since it last emptied than it has slots for, so that
+/// a burst of waiters doesn't keep its memory for as long as the state lives. A list that empties after every burst
+/// of the same size keeps its slots
+#[derive(Debug)]
+pub(crate) struct WakerList {
+	slots: Vec<Slot>,
+	// Indexes of vacant slots
+	free: Vec<usize>,
+	len: usize,
+	// The most futures that waited at once since the list last emptied
+	peak: usize,
+	// The list never shrinks below the capacity that it was created with
+	min_capacity: usize,
+	next_generation: u64
+}
+
+#[derive(Debug)]
+struct Slot {
+	generation: u64,
+	waker: Option<Waker>,
+	// Names the waiting task, for diagnostics
+	#[cfg(feature = "diagnostics")]
+	name: Option<&'static str>
+}
+
+impl WakerList {
+	pub(crate) fn new() -> WakerList {
+		WakerList::with_capacity(0)
+	}
+
+	/// Creates a list that holds capacity wakers without reallocating
+	pub(crate) fn with_capacity(capacity: usize) -> WakerList {
+		WakerList {
+			slots: Vec::with_capacity(capacity),
+			free: Vec::with_capacity(capacity),
+			len: 0,
+			peak: 0,
+			min_capacity: capacity,
+			next_generation: 0
+		}
+	}
+
+	/// Registers (or replaces) the waker for the future that holds key. When the same task polls again, its waker
+	/// isn't cloned again
+	pub(crate) fn register(&mut self, key: &mut Option<WakerKey>, waker: &Waker) {
+		if let Some(existing_key) = key {
+			if let Some(existing_waker) = self.get_mut(*existing_key) {
+				if !existing_waker.will_wake(waker) {
+					*existing_waker = waker.clone();
+				}
+
+				return;
+			}
+		}
+
+		let index = match self.free.pop() {
+			Some(index) => index,
+			None => {
+				self.slots.push(Slot {
+					generation: 0,
+					waker: None,
+					#[cfg(feature = "diagnostics")]
+					name: None
+				});
+
+				self.slots.len() - 1
+			}
+		};
+
+		let slot = &mut self.slots[index];
+		slot.generation = self.next_generation;
+		slot.waker = Some(waker.clone());
+		self.next_generation += 1;
+		self.len += 1;
+		self.peak = self.peak.max(self.len);
+
+		*key = Some(WakerKey {
+			index,
+			generation: slot.generation
+		});
+	}
+
+	/// Removes the waker for the future that holds key, if it is still registered
+	pub(crate) fn remove(&mut self, key: Option<WakerKey>) {
+		if let Some(key) = key {
+			if self.get_mut(key).is_some() {
+				self.vacate(key.index);
+				self.shrink_if_idle();
+			}
+		}
+	}
+
+	/// Removes every registered waker, so that they can be woken with [`wake_each()`](fn.wake_each.html) once the
+	/// lock that guards the list is released
+	pub(crate) fn take_all(&mut self) -> Wakers {
+		let mut wakers = Wakers::new();
+		wakers.take_from(self);
+		wakers
+	}
+
+	#[cfg(test)]
+	pub(crate) fn is_empty(&self) -> bool {
+		self.len == 0
+	}
+
+	#[cfg(any(test, feature = "diagnostics"))]
+	pub(crate) fn len(&self) -> usize {
+		self.len
+	}
+
+	/// Names the waker for the future that holds key, if it is still registered. The name is forgotten when the waker
+	/// is removed or woken
+	#[cfg(feature = "diagnostics")]
+	pub(crate) fn set_name(&mut self, key: Option<WakerKey>, name: &'static str) {
+		if let Some(key) = key {
+			match self.slots.get_mut(key.index) {
+				Some(slot) if slot.generation == key.generation && slot.waker.is_some() => slot.name = Some(name),
+				_ => {}
+			}
+		}
+	}
+
+	/// Returns the names of the registered wakers that were named with [`set_name()`](struct.WakerList.html#method.set_name)
+	#[cfg(feature = "diagnostics")]
+	pub(crate) fn names(&self) -> Vec<&'static str> {
+		self.slots.iter()
+			.filter(|slot| slot.waker.is_some())
+			.filter_map(|slot| slot.name)
+			.collect()
+	}
+
+	// Returns the waker that key refers to, unless its slot was vacated since
+	fn get_mut(&mut self, key: WakerKey) -> Option<&mut Waker> {
+		match self.slots.get_mut(key.index) {
+			Some(slot) if slot.generation == key.generation => slot.waker.as_mut(),
+			_ => None
+		}
+	}
+
+	fn vacate(&mut self, index: usize) -> Option<Waker> {
+		let slot = &mut self.slots[index];
+		let waker = slot.waker.take()?;
+
+		#[cfg(feature = "diagnostics")]
+		{
+			slot.name = None;
+		}
+
+		self.free.push(index);
+		self.len -= 1;
+
+		Some(waker)
+	}
+
+	// Drops the slots once the list is empty, if far fewer futures waited since it last emptied than there are slots.
+	// Keys for the dropped slots stay stale, because generations are never reused
+	fn shrink_if_idle(&mut self) {
+		if self.len > 0 {
+			return;
+		}
+
+		if self.peak.saturating_mul(4) < self.slots.len() {
+			let capacity = self.peak.max(self.min_capacity);
+
+			self.slots.clear();
+			self.slots.shrink_to(capacity);
+			self.free.clear();
+			self.free.shrink_to(capacity);
+		}
+
+		self.peak = 0;
+	}
+}
+
+// A larger buffer is freed instead of kept for the thread, so that one burst of waiters doesn't keep its memory for as
+// long as the thread lives
+const MAX_SPARE_WAKERS: usize = 4096;
+
+thread_local! {
+	// The buffer that the thread's last Wakers was woken from
+	static SPARE_WAKERS: Cell<Vec<Waker>> = const { Cell::new(Vec::new()) };
+}
+
+/// Wakers that were taken out of one or more [`WakerList`](struct.WakerList.html)s, so that they're woken after the
+/// locks that guard the lists are released.
+///
+/// The buffer is handed back to the thread once the wakers are woken, and reused by the next
+/// [`Wakers`](struct.Wakers.html), so waking doesn't allocate once the buffer has grown to the number of waiting
+/// futures, up to a few thousand. A waker that takes wakers while it's being woken gets a new buffer
+pub(crate) struct Wakers(Vec<Waker>);
+
+impl Wakers {
+	pub(crate) fn new() -> Wakers {
+		// try_with fails while the thread is being torn down
+		Wakers(SPARE_WAKERS.try_with(Cell::take).unwrap_or_default())
+	}
+
+	/// Removes every waker registered with waker_list, and adds it to the wakers that will be woken
+	pub(crate) fn take_from(&mut self, waker_list: &mut WakerList) {
+		for index in 0..waker_list.slots.len() {
+			if let Some(waker) = waker_list.vacate(index) {
+				self.0.push(waker);
+			}
+		}
+
+		waker_list.shrink_if_idle();
+	}
+
+	/// Wakes every waker, keeping the first panic in wake_panic
+	pub(crate) fn wake(mut self, wake_panic: &mut WakePanic) {
+		for waker in self.0.drain(..) {
+			wake_panic.catch(|| waker.wake());
+		}
+	}
+}
+
+impl Drop for Wakers {
+	fn drop(&mut self) {
+		self.0.clear();
+
+		let buffer = std::mem::take(&mut self.0);
+		if buffer.capacity() > MAX_SPARE_WAKERS {
+			return;
+		}
+
+		let _ = SPARE_WAKERS.try_with(|spare| {
+			// Keeps the larger buffer when wakers were taken while others were being woken
+			let spare_buffer = spare.take();
+			spare.set(if spare_buffer.capacity() > buffer.capacity() { spare_buffer } else { buffer });
+		});
+	}
+}
+
+/// Wakes every waker, even if some of them panic, and then resumes the first panic. Call it without holding a lock,
+/// so that a panicking waker can't poison the state that the woken futures check
+pub(crate) fn wake_each(wakers: Wakers) {
+	let mut wake_panic = WakePanic::new();
+	wakers.wake(&mut wake_panic);
+	wake_panic.resume();
+}
+
+/// Holds the first panic from waking, so that it's resumed after everything else is woken
+pub(crate) struct WakePanic(Option<Box<dyn Any + Send>>);
+
+impl WakePanic {
+	pub(crate) fn new() -> WakePanic {
+		WakePanic(None)
+	}
+
+	/// Runs wake, and keeps its panic if it's the first one
+	pub(crate) fn catch<F>(&mut self, wake: F) where
+	F: FnOnce() {
+		// Unwind safe, because whatever wake consumed is dropped, and the caller's state was updated before waking
+		if let Err(payload) = catch_unwind(AssertUnwindSafe(wake)) {
+			self.0.get_or_insert(payload);
+		}
+	}
+
+	/// Resumes the first panic. It's dropped instead if the thread is already panicking, such as when a token is
+	/// dropped while unwinding, because panicking again would abort
+	pub(crate) fn resume(self) {
+		if let Some(payload) = self.0 {
+			if !std::thread::panicking() {
+				resume_unwind(payload);
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::Arc;
+
+	use cooked_waker::IntoWaker;
+
+	use super::*;
+	use crate::tests::*;
+
+	#[test]
+	fn test_register_same_waker_doesnt_clone() {
+		let counting_waker = Arc::new(CountingWaker);
+		let waker = Waker::from(counting_waker.clone());
+		let mut waker_list = WakerList::new();
+		let mut key = None;
+
+		waker_list.register(&mut key, &waker);
+		assert_eq!(Arc::strong_count(&counting_waker), 3, "Registering should clone the waker once");
+
+		for _ in 0..10 {
+			waker_list.register(&mut key, &waker);
+		}
+
+		assert_eq!(Arc::strong_count(&counting_waker), 3, "Registering the same waker again shouldn't clone it");
+		assert_eq!(waker_list.len(), 1, "The waker should be replaced, not added");
+	}
+
+	#[test]
+	fn test_register_different_waker_replaces() {
+		let first = Arc::new(CountingWaker);
+		let second = Arc::new(CountingWaker);
+		let mut waker_list = WakerList::new();
+		let mut key = None;
+
+		waker_list.register(&mut key, &Waker::from(first.clone()));
+		waker_list.register(&mut key, &Waker::from(second.clone()));
+
+		assert_eq!(Arc::strong_count(&first), 1, "The old waker should be dropped");
+		assert_eq!(Arc::strong_count(&second), 2, "The new waker should be stored");
+
+		wake_each(waker_list.take_all());
+		assert_eq!(Arc::strong_count(&second), 1, "Waking should drop the waker");
+		assert!(waker_list.is_empty(), "Waking should empty the list");
+	}
+
+	#[test]
+	fn test_stale_key() {
+		let first = Arc::new(CountingWaker);
+		let second = Arc::new(CountingWaker);
+		let mut waker_list = WakerList::new();
+		let mut first_key = None;
+		let mut second_key = None;
+
+		waker_list.register(&mut first_key, &Waker::from(first.clone()));
+		wake_each(waker_list.take_all());
+
+		// Reuses the first waker's slot
+		waker_list.register(&mut second_key, &Waker::from(second.clone()));
+		assert_eq!(second_key.map(|key| key.index), first_key.map(|key| key.index), "The vacated slot should be reused");
+
+		// A stale key neither removes nor replaces the waker that reused its slot
+		waker_list.remove(first_key);
+		assert_eq!(waker_list.len(), 1, "A stale key shouldn't remove another future's waker");
+
+		waker_list.register(&mut first_key, &Waker::from(first.clone()));
+		assert_eq!(waker_list.len(), 2, "A stale key should register a new waker");
+		assert_eq!(Arc::strong_count(&second), 2, "A stale key shouldn't replace another future's waker");
+
+		waker_list.remove(second_key);
+		waker_list.remove(first_key);
+		assert!(waker_list.is_empty(), "Both wakers should be removed");
+	}
+
+	#[test]
+	fn test_reuses_slots_without_allocating() {
+		let waker = Waker::from(Arc::new(CountingWaker));
+		let mut waker_list = WakerList::new();
+		let mut keys = vec![None; 1000];
+
+		for key in keys.iter_mut() {
+			waker_list.register(key, &waker);
+		}
+
+		// Grows the slots, and the thread's buffer for waking
+		wake_each(waker_list.take_all());
+		keys.iter_mut().for_each(|key| *key = None);
+
+		let ((), allocations) = count_allocations(|| {
+			for _ in 0..10 {
+				for key in keys.iter_mut() {
+					waker_list.register(key, &waker);
+				}
+
+				for key in keys.iter_mut() {
+					waker_list.remove(key.take());
+				}
+
+				for key in keys.iter_mut() {
+					waker_list.register(key, &waker);
+				}
+
+				wake_each(waker_list.take_all());
+				keys.iter_mut().for_each(|key| *key = None);
+			}
+		});
+
+		assert_eq!(allocations, 0, "Reusing slots shouldn't allocate");
+		assert!(waker_list.is_empty(), "Every waker should be removed");
+	}
+
+	#[test]
+	fn test_shrinks_after_a_burst() {
+		let waker = Waker::from(Arc::new(CountingWaker));
+		let mut waker_list = WakerList::with_capacity(4);
+		let mut burst_keys = vec![None; 1000];
+
+		for key in burst_keys.iter_mut() {
+			waker_list.register(key, &waker);
+		}
+
+		for key in burst_keys.iter() {
+			waker_list.remove(*key);
+		}
+
+		assert!(waker_list.slots.capacity() >= 1000, "The list should keep its slots right after the burst");
+
+		let mut key = None;
+		waker_list.register(&mut key, &waker);
+		wake_each(waker_list.take_all());
+
+		assert!(waker_list.slots.capacity() < 1000, "The list should shrink once a quieter period ends");
+		assert!(waker_list.slots.capacity() >= 4, "The list shouldn't shrink below its initial capacity");
+
+		// Keys from before the list shrank don't match the waker in their old slot
+		let mut new_key = None;
+		waker_list.register(&mut new_key, &waker);
+		waker_list.remove(burst_keys[0]);
+		waker_list.remove(key);
+		assert_eq!(waker_list.len(), 1, "A key from before shrinking shouldn't remove another future's waker");
+	}
+
+	#[test]
+	fn test_take_all_reuses_buffer() {
+		let waker = Waker::from(Arc::new(CountingWaker));
+		let mut waker_list = WakerList::new();
+		let mut keys = vec![None; 1000];
+
+		for key in keys.iter_mut() {
+			waker_list.register(key, &waker);
+		}
+
+		// Grows the thread's buffer
+		wake_each(waker_list.take_all());
+
+		let ((), allocations) = count_allocations(|| {
+			for _ in 0..10 {
+				for key in keys.iter_mut() {
+					*key = None;
+					waker_list.register(key, &waker);
+				}
+
+				wake_each(waker_list.take_all());
+			}
+		});
+
+		assert_eq!(allocations, 0, "Waking should reuse the buffer");
+		assert!(waker_list.is_empty(), "Every waker should be removed");
+	}
+
+	#[test]
+	fn test_wake_each_wakes_past_a_panicking_waker() {
+		let test_waker = TestWaker::new();
+		let mut waker_list = WakerList::new();
+
+		waker_list.register(&mut None, &Waker::from(Arc::new(PanickingWaker)));
+		waker_list.register(&mut None, &test_waker.clone().into_waker());
+
+		let result = catch_unwind(AssertUnwindSafe(|| wake_each(waker_list.take_all())));
+
+		assert!(result.is_err(), "The panic should be resumed");
+		assert!(test_waker.woke(), "The waker after the panicking waker should be woken");
+		assert!(waker_list.is_empty(), "Every waker should be removed");
+	}
+
+	#[cfg(feature = "diagnostics")]
+	#[test]
+	fn test_names_are_forgotten_with_the_waker() {
+		let waker = Waker::from(Arc::new(CountingWaker));
+		let mut waker_list = WakerList::new();
+		let mut named_key = None;
+		let mut unnamed_key = None;
+
+		waker_list.register(&mut named_key, &waker);
+		waker_list.register(&mut unnamed_key, &waker);
+		waker_list.set_name(named_key, "named");
+		assert_eq!(waker_list.names(), vec!["named"], "Only the named waker should be listed");
+
+		wake_each(waker_list.take_all());
+		assert!(waker_list.names().is_empty(), "Waking should forget the names");
+
+		// Reuses the named waker's slot
+		waker_list.register(&mut unnamed_key, &waker);
+		waker_list.set_name(named_key, "stale");
+		assert!(waker_list.names().is_empty(), "A stale key shouldn't name another future's waker");
+	}
+}
@@ -0,0 +1,374 @@
+// https://github.com/GWBasic/sync-tokens
+// (c) Andrew Rondeau
+// Apache 2.0 license
+// See https://github.com/GWBasic/sync-tokens/blob/main/LICENSE
+
+//! Contains a completion token that's backed by tokio's watch channel. Requires the `tokio` feature. See
+//! [`WatchCompletionToken`](struct.WatchCompletionToken.html)
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::sync::watch;
+
+use crate::completion_token::Abandoned;
+
+/// A [`CompletionToken`](../completion_token/struct.CompletionToken.html) that stores its result in a
+/// [`tokio::sync::watch`](https://docs.rs/tokio/latest/tokio/sync/watch/index.html) channel instead of behind a
+/// `Mutex`. Every clone, and every await, returns a clone of the result, so T must be Clone.
+///
+/// Otherwise, it behaves the same as a [`CompletionToken`](../completion_token/struct.CompletionToken.html): awaiting
+/// it never returns if the [`WatchCompletable`](struct.WatchCompletable.html) is dropped without completing, and
+/// [`try_wait()`](struct.WatchCompletionToken.html#method.try_wait) returns [`Abandoned`](../completion_token/struct.Abandoned.html)
+/// instead. Each await allocates the future that waits on the channel
+///
+/// ```
+/// use sync_tokens::watch_completion_token::WatchCompletionToken;
+///
+/// # tokio::runtime::Runtime::new().unwrap().block_on(async {
+/// let (watch_completion_token, watch_completable) = WatchCompletionToken::new();
+/// let waiting = tokio::spawn(watch_completion_token.clone());
+///
+/// watch_completable.complete("ready".to_string());
+///
+/// assert_eq!(waiting.await.unwrap(), "ready");
+/// assert_eq!(watch_completion_token.await, "ready");
+/// # });
+/// ```
+pub struct WatchCompletionToken<T> {
+	receiver: watch::Receiver<Option<T>>,
+	// Created on the first poll, because waiting on the receiver borrows it
+	wait: Option<WaitFuture<T>>
+}
+
+type WaitFuture<T> = Pin<Box<dyn Future<Output = Result<T, Abandoned>> + Send>>;
+
+/// Completes the corresponding [`WatchCompletionToken`](struct.WatchCompletionToken.html)
+#[derive(Debug)]
+pub struct WatchCompletable<T> {
+	sender: watch::Sender<Option<T>>
+}
+
+impl<T> WatchCompletionToken<T> where
+T: Clone + Send + Sync + 'static {
+	/// Creates a new [`WatchCompletionToken`](struct.WatchCompletionToken.html) and
+	/// [`WatchCompletable`](struct.WatchCompletable.html)
+	pub fn new() -> (WatchCompletionToken<T>, WatchCompletable<T>) {
+		let (sender, receiver) = watch::channel(None);
+
+		(WatchCompletionToken { receiver, wait: None }, WatchCompletable { sender })
+	}
+
+	/// Waits for the [`WatchCompletable`](struct.WatchCompletable.html) to complete, or returns
+	/// [`Abandoned`](../completion_token/struct.Abandoned.html) if it's dropped without calling complete
+	pub fn try_wait(self) -> impl Future<Output = Result<T, Abandoned>> {
+		WatchCompletionToken::wait(self.receiver)
+	}
+
+	/// Returns true once the [`WatchCompletable`](struct.WatchCompletable.html) completed
+	pub fn is_complete(&self) -> bool {
+		self.receiver.borrow().is_some()
+	}
+
+	async fn wait(mut receiver: watch::Receiver<Option<T>>) -> Result<T, Abandoned> {
+		// Checks the value before checking whether the sender was dropped, so a result that was sent just before the
+		// completable was dropped is still returned
+		match receiver.wait_for(Option::is_some).await {
+			Ok(result) => Ok(result.clone().expect("wait_for returned without a result")),
+			Err(_) => Err(Abandoned)
+		}
+	}
+}
+
+impl<T> WatchCompletable<T> {
+	/// Call to indicate that the operation is complete, and unblock any calls to await on the
+	/// [`WatchCompletionToken`](struct.WatchCompletionToken.html)
+	///
+	/// # Panics
+	///
+	/// Complete will panic if it is called multiple times
+	pub fn complete(&self, result: T) {
+		let mut result = Some(result);
+
+		// Checked and sent under the channel's lock, so that only one call can complete
+		let completed = self.sender.send_if_modified(|value| {
+			if value.is_some() {
+				false
+			} else {
+				*value = result.take();
+				true
+			}
+		});
+
+		if !completed {
+			panic!("Watch completion token is already complete")
+		}
+	}
+}
+
+impl<T> Future for WatchCompletionToken<T> where
+T: Clone + Send + Sync + 'static {
+	type Output = T;
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+		let this = self.get_mut();
+		let receiver = &this.receiver;
+		let wait = this.wait.get_or_insert_with(|| Box::pin(WatchCompletionToken::wait(receiver.clone())));
+
+		match wait.as_mut().poll(cx) {
+			Poll::Ready(Ok(result)) => {
+				this.wait = None;
+				Poll::Ready(result)
+			},
+			// Awaiting an abandoned token never returns
+			Poll::Ready(Err(Abandoned)) => {
+				this.wait = Some(Box::pin(futures::future::pending()));
+				Poll::Pending
+			},
+			Poll::Pending => Poll::Pending
+		}
+	}
+}
+
+impl<T> Clone for WatchCompletionToken<T> {
+	fn clone(&self) -> Self {
+		// The clone starts waiting the first time it's polled
+		WatchCompletionToken {
+			receiver: self.receiver.clone(),
+			wait: None
+		}
+	}
+}
+
+impl<T> fmt::Debug for WatchCompletionToken<T> where
+T: fmt::Debug {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("WatchCompletionToken")
+			.field("receiver", &self.receiver)
+			.finish()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::Arc;
+	use std::time::Duration;
+
+	use std::task::Waker;
+
+	use cooked_waker::IntoWaker;
+	use futures::FutureExt;
+	use futures::future::{self, Either, select};
+
+	use super::*;
+	use crate::tests::*;
+
+	// Mirrors the tests for the Mutex-based CompletionToken
+
+	#[test]
+	fn test_via_poll() {
+		let (mut watch_completion_token, watch_completable) = WatchCompletionToken::new();
+
+		let test_waker = TestWaker::new();
+		let waker = test_waker.clone().into_waker();
+		let mut cx = Context::from_waker(&waker);
+
+		assert!(Pin::new(&mut watch_completion_token).poll(&mut cx).is_pending(), "Should be pending");
+		assert!(!watch_completion_token.is_complete(), "Shouldn't be complete yet");
+
+		watch_completable.complete("complete");
+		assert!(test_waker.woke(), "Completing should wake");
+		assert!(watch_completion_token.is_complete(), "Should be complete");
+
+		assert_eq!(Pin::new(&mut watch_completion_token).poll(&mut cx), Poll::Ready("complete"), "Wrong result");
+	}
+
+	runtime_test! {
+		async fn test_via_future() {
+			let (mut watch_completion_token, watch_completable) = WatchCompletionToken::new();
+
+			match select(watch_completion_token, future::ready(())).await {
+				Either::Left(_) => panic!("Watch completion token isn't complete"),
+				Either::Right((_, w)) => watch_completion_token = w
+			}
+
+			watch_completable.complete("complete");
+
+			match select(watch_completion_token, future::pending::<()>()).await {
+				Either::Left((result, _)) => assert_eq!(result, "complete", "Wrong result"),
+				Either::Right(_) => panic!("Completion didn't happen")
+			}
+		}
+	}
+
+	runtime_test! {
+		async fn test_try_wait() {
+			let (watch_completion_token, watch_completable) = WatchCompletionToken::new();
+			watch_completable.complete("complete");
+
+			assert_eq!(watch_completion_token.try_wait().await, Ok("complete"), "Wrong result");
+		}
+	}
+
+	#[test]
+	fn test_abandoned_wakes() {
+		let (watch_completion_token, watch_completable) = WatchCompletionToken::<&str>::new();
+
+		let mut try_wait_future = Box::pin(watch_completion_token.clone().try_wait());
+		let mut watch_completion_token = watch_completion_token;
+
+		let test_waker = TestWaker::new();
+		let waker = test_waker.clone().into_waker();
+		let mut cx = Context::from_waker(&waker);
+
+		assert!(try_wait_future.as_mut().poll(&mut cx).is_pending(), "Should be pending");
+
+		drop(watch_completable);
+		assert!(test_waker.woke(), "Waiter should be woken when the completable is dropped");
+
+		assert_eq!(try_wait_future.as_mut().poll(&mut cx), Poll::Ready(Err(Abandoned)), "Should be abandoned");
+		assert!(Pin::new(&mut watch_completion_token).poll(&mut cx).is_pending(), "Awaiting an abandoned token should never return");
+	}
+
+	#[test]
+	fn test_late_clone() {
+		let (watch_completion_token, watch_completable) = WatchCompletionToken::new();
+		let early_clone = watch_completion_token.clone();
+
+		watch_completable.complete(42);
+		assert_eq!(futures::executor::block_on(watch_completion_token.clone()), 42, "Wrong result");
+
+		let late_clone = watch_completion_token.clone();
+
+		let test_waker = TestWaker::new();
+		let waker = test_waker.into_waker();
+		let mut cx = Context::from_waker(&waker);
+
+		for mut clone in [early_clone, late_clone] {
+			assert_eq!(Pin::new(&mut clone).poll(&mut cx), Poll::Ready(42), "Clones should resolve immediately");
+		}
+	}
+
+	#[test]
+	fn test_complete_wakes_every_clone() {
+		let (watch_completion_token, watch_completable) = WatchCompletionToken::new();
+
+		let mut waiting: Vec<_> = (0..10).map(|_| (watch_completion_token.clone(), TestWaker::new())).collect();
+
+		for (clone, test_waker) in &mut waiting {
+			let waker = test_waker.clone().into_waker();
+			assert!(Pin::new(clone).poll(&mut Context::from_waker(&waker)).is_pending(), "Shouldn't be complete yet");
+		}
+
+		watch_completable.complete("complete");
+
+		for (clone, test_waker) in waiting {
+			assert!(test_waker.woke(), "Every clone should be woken");
+			assert_eq!(clone.now_or_never(), Some("complete"), "Every clone should see the result");
+		}
+	}
+
+	#[test]
+	fn test_repeated_poll_doesnt_clone_waker() {
+		let (mut watch_completion_token, _watch_completable) = WatchCompletionToken::<u32>::new();
+
+		let counting_waker = Arc::new(CountingWaker);
+		let waker = Waker::from(counting_waker.clone());
+		let mut cx = Context::from_waker(&waker);
+
+		for _ in 0..10 {
+			assert!(Pin::new(&mut watch_completion_token).poll(&mut cx).is_pending(), "Should be pending");
+		}
+
+		assert_eq!(Arc::strong_count(&counting_waker), 3, "Only one clone of the waker should be stored");
+	}
+
+	#[test]
+	fn test_drop_while_waiting() {
+		let (mut watch_completion_token, watch_completable) = WatchCompletionToken::new();
+
+		let test_waker = TestWaker::new();
+		let waker = test_waker.clone().into_waker();
+		assert!(Pin::new(&mut watch_completion_token).poll(&mut Context::from_waker(&waker)).is_pending(), "Should be pending");
+
+		drop(watch_completion_token);
+		watch_completable.complete(1);
+		assert!(!test_waker.woke(), "A dropped token shouldn't be woken");
+	}
+
+	#[tokio::test]
+	async fn test_complete_before_await() {
+		let (watch_completion_token, watch_completable) = WatchCompletionToken::new();
+
+		watch_completable.complete(42);
+		assert!(watch_completion_token.is_complete(), "Should be complete");
+		assert_eq!(watch_completion_token.await, 42, "Wrong result");
+	}
+
+	#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+	async fn test_complete_while_waiting() {
+		let (watch_completion_token, watch_completable) = WatchCompletionToken::new();
+		let waiting: Vec<_> = (0..10).map(|_| tokio::spawn(watch_completion_token.clone())).collect();
+
+		tokio::time::sleep(Duration::from_millis(10)).await;
+		assert!(!watch_completion_token.is_complete(), "Shouldn't be complete yet");
+
+		watch_completable.complete("ready");
+
+		for waiting in waiting {
+			assert_eq!(waiting.await.unwrap(), "ready", "Every clone should see the result");
+		}
+	}
+
+	#[tokio::test]
+	async fn test_result_is_cloned() {
+		let (watch_completion_token, watch_completable) = WatchCompletionToken::new();
+		let clone = watch_completion_token.clone();
+
+		watch_completable.complete(Arc::new(vec![1, 2, 3]));
+
+		let first = watch_completion_token.await;
+		let second = clone.await;
+		assert!(Arc::ptr_eq(&first, &second), "Clones should share the same result");
+	}
+
+	#[tokio::test]
+	async fn test_abandoned() {
+		let (watch_completion_token, watch_completable) = WatchCompletionToken::<()>::new();
+		let mut never_returns = watch_completion_token.clone();
+
+		drop(watch_completable);
+
+		assert_eq!(watch_completion_token.try_wait().await, Err(Abandoned), "Should be abandoned");
+		assert!((&mut never_returns).now_or_never().is_none(), "Awaiting an abandoned token should never return");
+		assert!((&mut never_returns).now_or_never().is_none(), "Polling again shouldn't return either");
+	}
+
+	#[tokio::test]
+	async fn test_complete_then_drop_isnt_abandoned() {
+		let (watch_completion_token, watch_completable) = WatchCompletionToken::new();
+
+		watch_completable.complete("complete");
+		drop(watch_completable);
+
+		assert_eq!(watch_completion_token.try_wait().await, Ok("complete"), "The result should be kept");
+	}
+
+	#[tokio::test]
+	async fn test_complete_after_tokens_dropped() {
+		let (watch_completion_token, watch_completable) = WatchCompletionToken::new();
+
+		drop(watch_completion_token);
+		watch_completable.complete(1);
+	}
+
+	#[test]
+	#[should_panic(expected = "Watch completion token is already complete")]
+	fn test_complete_twice_panics() {
+		let (_watch_completion_token, watch_completable) = WatchCompletionToken::new();
+
+		watch_completable.complete(1);
+		watch_completable.complete(2);
+	}
+}
@@ -0,0 +1,248 @@
+// https://github.com/GWBasic/sync-tokens
+// (c) Andrew Rondeau
+// Apache 2.0 license
+// See https://github.com/GWBasic/sync-tokens/blob/main/LICENSE
+
+//! Procedural macros for [sync-tokens](https://docs.rs/sync-tokens). Use them through sync-tokens' `macros` feature,
+//! instead of depending on this crate directly
+
+#![warn(missing_docs)]
+#![warn(missing_debug_implementations, rust_2018_idioms)]
+
+use proc_macro::TokenStream;
+use proc_macro2::{Ident, TokenStream as TokenStream2};
+use quote::quote;
+use syn::parse::Parser;
+use syn::punctuated::Punctuated;
+use syn::visit_mut::{self, VisitMut};
+use syn::{Expr, FnArg, Item, ItemFn, Pat, ReturnType, Token, Type, parse_quote};
+
+/// Inserts a checkpoint, `cancelable.checkpoint().await?`, before every `.await` in an async fn that takes a
+/// `&Cancelable` argument, so that it stops promptly when canceled. With `#[cancelable(loops)]`, a checkpoint is also
+/// inserted at the start of every loop iteration, for CPU-heavy loops that rarely await.
+///
+/// If the function returns a `Result`, its error type must implement `From<Canceled>`, and cancelation returns that
+/// error. Otherwise, the function is changed to return `Result<T, Canceled>`.
+///
+/// Awaits inside closures and async blocks are left alone, because `?` in them doesn't return from the function
+#[proc_macro_attribute]
+pub fn cancelable(attr: TokenStream, item: TokenStream) -> TokenStream {
+	match expand(attr.into(), item.into()) {
+		Ok(expanded) => expanded.into(),
+		Err(err) => err.to_compile_error().into()
+	}
+}
+
+fn expand(attr: TokenStream2, item: TokenStream2) -> syn::Result<TokenStream2> {
+	let mut loops = false;
+
+	for option in Punctuated::<Ident, Token![,]>::parse_terminated.parse2(attr)? {
+		if option == "loops" {
+			loops = true;
+		} else {
+			return Err(syn::Error::new(option.span(), "Unknown option, expected `loops`"));
+		}
+	}
+
+	let mut function: ItemFn = syn::parse2(item)?;
+
+	if function.sig.asyncness.is_none() {
+		return Err(syn::Error::new_spanned(function.sig.fn_token, "#[cancelable] only works on async fns"));
+	}
+
+	let cancelable = find_cancelable(&function)?;
+	let returns_result = returns_result(&function.sig.output);
+
+	let mut checkpoints = Checkpoints {
+		cancelable,
+		loops,
+		wrap_returns: !returns_result
+	};
+
+	checkpoints.visit_block_mut(&mut function.block);
+
+	if !returns_result {
+		let output = match &function.sig.output {
+			ReturnType::Default => quote!(()),
+			ReturnType::Type(_, output) => quote!(#output)
+		};
+
+		let block = &function.block;
+		function.sig.output = parse_quote!(-> ::core::result::Result<#output, ::sync_tokens::cancelation_token::Canceled>);
+		function.block = parse_quote!({ ::core::result::Result::Ok(#block) });
+	}
+
+	Ok(quote!(#function))
+}
+
+// Finds the first argument that's a &Cancelable
+fn find_cancelable(function: &ItemFn) -> syn::Result<Ident> {
+	for input in &function.sig.inputs {
+		if let FnArg::Typed(pat_type) = input {
+			if let (Pat::Ident(pat_ident), Type::Reference(reference)) = (&*pat_type.pat, &*pat_type.ty) {
+				if let Type::Path(path) = &*reference.elem {
+					if path.path.segments.last().map(|segment| segment.ident == "Cancelable").unwrap_or(false) {
+						return Ok(pat_ident.ident.clone());
+					}
+				}
+			}
+		}
+	}
+
+	Err(syn::Error::new_spanned(&function.sig, "#[cancelable] needs an argument of type &Cancelable"))
+}
+
+fn returns_result(output: &ReturnType) -> bool {
+	match output {
+		ReturnType::Type(_, output) => match &**output {
+			Type::Path(path) => path.path.segments.last().map(|segment| segment.ident == "Result").unwrap_or(false),
+			_ => false
+		},
+		ReturnType::Default => false
+	}
+}
+
+struct Checkpoints {
+	cancelable: Ident,
+	loops: bool,
+	// When the function is changed to return a Result, returned values are wrapped in Ok
+	wrap_returns: bool
+}
+
+impl VisitMut for Checkpoints {
+	fn visit_expr_mut(&mut self, expr: &mut Expr) {
+		// `?` and `return` inside these don't return from the function
+		if let Expr::Closure(_) | Expr::Async(_) = expr {
+			return;
+		}
+
+		visit_mut::visit_expr_mut(self, expr);
+
+		let cancelable = &self.cancelable;
+
+		match expr {
+			Expr::Await(_) => {
+				let awaited = expr.clone();
+				*expr = parse_quote!(({ #cancelable.checkpoint().await?; #awaited }));
+			},
+			Expr::Return(ret) if self.wrap_returns => {
+				let value = match ret.expr.take() {
+					Some(value) => quote!(#value),
+					None => quote!(())
+				};
+
+				ret.expr = Some(parse_quote!(::core::result::Result::Ok(#value)));
+			},
+			Expr::ForLoop(for_loop) if self.loops => for_loop.body.stmts.insert(0, parse_quote!(#cancelable.checkpoint().await?;)),
+			Expr::While(while_loop) if self.loops => while_loop.body.stmts.insert(0, parse_quote!(#cancelable.checkpoint().await?;)),
+			Expr::Loop(loop_expr) if self.loops => loop_expr.body.stmts.insert(0, parse_quote!(#cancelable.checkpoint().await?;)),
+			_ => {}
+		}
+	}
+
+	// Nested functions have their own returns and awaits
+	fn visit_item_mut(&mut self, _item: &mut Item) {}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn assert_expands(attr: TokenStream2, item: TokenStream2, expected: TokenStream2) {
+		assert_eq!(expand(attr, item).unwrap().to_string(), expected.to_string(), "Wrong expansion");
+	}
+
+	#[test]
+	fn test_awaits() {
+		assert_expands(quote!(), quote! {
+			async fn fetch(cancelable: &Cancelable) -> u32 {
+				let a = first().await;
+				if a > 1 {
+					return a;
+				}
+				a + second().await
+			}
+		}, quote! {
+			async fn fetch(cancelable: &Cancelable) -> ::core::result::Result<u32, ::sync_tokens::cancelation_token::Canceled> {
+				::core::result::Result::Ok({
+					let a = ({ cancelable.checkpoint().await?; first().await });
+					if a > 1 {
+						return ::core::result::Result::Ok(a);
+					}
+					a + ({ cancelable.checkpoint().await?; second().await })
+				})
+			}
+		});
+	}
+
+	#[test]
+	fn test_result() {
+		assert_expands(quote!(), quote! {
+			async fn fetch(url: &str, c: &sync_tokens::cancelation_token::Cancelable) -> Result<(), FetchError> {
+				get(url).await?;
+				Ok(())
+			}
+		}, quote! {
+			async fn fetch(url: &str, c: &sync_tokens::cancelation_token::Cancelable) -> Result<(), FetchError> {
+				({ c.checkpoint().await?; get(url).await })?;
+				Ok(())
+			}
+		});
+	}
+
+	#[test]
+	fn test_loops() {
+		assert_expands(quote!(loops), quote! {
+			async fn work(cancelable: &Cancelable) {
+				for i in 0..10 {
+					step(i);
+				}
+				loop {
+					break;
+				}
+			}
+		}, quote! {
+			async fn work(cancelable: &Cancelable) -> ::core::result::Result<(), ::sync_tokens::cancelation_token::Canceled> {
+				::core::result::Result::Ok({
+					for i in 0..10 {
+						cancelable.checkpoint().await?;
+						step(i);
+					}
+					loop {
+						cancelable.checkpoint().await?;
+						break;
+					}
+				})
+			}
+		});
+	}
+
+	#[test]
+	fn test_skips_closures_and_async_blocks() {
+		assert_expands(quote!(), quote! {
+			async fn spawn(cancelable: &Cancelable) {
+				let task = async { other().await };
+				let f = |x| x;
+			}
+		}, quote! {
+			async fn spawn(cancelable: &Cancelable) -> ::core::result::Result<(), ::sync_tokens::cancelation_token::Canceled> {
+				::core::result::Result::Ok({
+					let task = async { other().await };
+					let f = |x| x;
+				})
+			}
+		});
+	}
+
+	#[test]
+	fn test_errors() {
+		let err = expand(quote!(), quote!(fn sync(cancelable: &Cancelable) {})).unwrap_err();
+		assert_eq!(err.to_string(), "#[cancelable] only works on async fns", "Wrong error");
+
+		let err = expand(quote!(), quote!(async fn no_cancelable(x: u32) {})).unwrap_err();
+		assert_eq!(err.to_string(), "#[cancelable] needs an argument of type &Cancelable", "Wrong error");
+
+		let err = expand(quote!(everything), quote!(async fn f(cancelable: &Cancelable) {})).unwrap_err();
+		assert_eq!(err.to_string(), "Unknown option, expected `loops`", "Wrong error");
+	}
+}